@@ -0,0 +1,70 @@
+// Helpers shared by the benchmarks: running the load balancer binary with a
+// generated config.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn free_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+pub struct LbProcess {
+    pub addr: String,
+    child: Child,
+    dir: PathBuf,
+}
+
+impl LbProcess {
+    // Starts the load balancer in a temporary directory. `config` is added
+    // to a config that sets the listen and admin addresses and turns the
+    // access log off.
+    pub fn start(name: &str, config: &str) -> LbProcess {
+        let dir = env::temp_dir().join(format!("lb-bench-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let addr = free_port();
+        let config = format!(
+            "listen = {:?}\nadmin = {:?}\naccess_log = false\n{}",
+            addr,
+            free_port(),
+            config
+        );
+        fs::write(dir.join("load-balancer.toml"), config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_load-balancer"))
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start the load balancer");
+
+        let started = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "load balancer did not start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        LbProcess { addr, child, dir }
+    }
+}
+
+impl Drop for LbProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
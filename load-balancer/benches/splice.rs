@@ -0,0 +1,75 @@
+/*
+ * TCP mode throughput with and without splice(2).
+ *
+ *   cargo bench --bench splice
+ *
+ * An in-process backend streams BENCH_MB megabytes to every connection and
+ * BENCH_STREAMS clients download through the load balancer at once, first
+ * with `splice = true` and then with the buffered copy.
+ */
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Instant;
+
+use common::{LbProcess, env_or};
+
+fn main() {
+    let megabytes = env_or("BENCH_MB", 1024);
+    let streams = env_or("BENCH_STREAMS", 4);
+    let backend = start_backend(megabytes * 1024 * 1024);
+
+    println!("{} streams of {} MB each", streams, megabytes);
+    println!("{:>8} {:>12}", "method", "MB/s");
+    for splice in [true, false] {
+        let config = format!(
+            "mode = \"tcp\"\nsplice = {}\nbackends = [{:?}]\n",
+            splice, backend
+        );
+        let lb = LbProcess::start(if splice { "splice" } else { "copy" }, &config);
+        let started = Instant::now();
+        let clients: Vec<_> = (0..streams)
+            .map(|_| {
+                let addr = lb.addr.clone();
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(&addr).unwrap();
+                    io::copy(&mut stream, &mut io::sink()).unwrap()
+                })
+            })
+            .collect();
+        let bytes: u64 = clients.into_iter().map(|c| c.join().unwrap()).sum();
+        let rate = bytes as f64 / (1024.0 * 1024.0) / started.elapsed().as_secs_f64();
+        println!(
+            "{:>8} {:>12.0}",
+            if splice { "splice" } else { "copy" },
+            rate
+        );
+    }
+}
+
+// A backend that sends `bytes` bytes on every connection and closes it.
+fn start_backend(bytes: u64) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let buf = vec![0u8; 256 * 1024];
+                let mut left = bytes;
+                while left > 0 {
+                    let n = left.min(buf.len() as u64) as usize;
+                    if stream.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                    left -= n as u64;
+                }
+                // let the proxy see EOF without dropping unread data
+                let _ = stream.shutdown(std::net::Shutdown::Write);
+                let _ = stream.read(&mut [0u8; 1]);
+            });
+        }
+    });
+    addr
+}
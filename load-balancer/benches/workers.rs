@@ -0,0 +1,128 @@
+/*
+ * Throughput against the number of accept workers.
+ *
+ *   cargo bench --bench workers
+ *
+ * Starts the load balancer binary once per worker count (1, 2, 4, ... up to
+ * the number of cores) in front of an in-process backend, then hammers it
+ * with short lived `Connection: close` requests from many client threads,
+ * which is the case where accepting is the bottleneck. BENCH_SECS sets how
+ * long each round runs, BENCH_CLIENTS how many client threads are used.
+ */
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{LbProcess, env_or};
+
+const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+
+fn main() {
+    let secs = env_or("BENCH_SECS", 3);
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let clients = env_or("BENCH_CLIENTS", cores as u64 * 8) as usize;
+    let backend = start_backend();
+
+    let mut counts = Vec::new();
+    let mut workers = 1;
+    while workers < cores {
+        counts.push(workers);
+        workers *= 2;
+    }
+    counts.push(cores);
+
+    println!("{} cores, {} clients, {}s per round", cores, clients, secs);
+    println!("{:>8} {:>12} {:>8}", "workers", "req/s", "speedup");
+    let mut baseline = None;
+    for workers in counts {
+        let config = format!("workers = {}\nbackends = [{:?}]\n", workers, backend);
+        let lb = LbProcess::start(&format!("workers-{}", workers), &config);
+        let rate = drive(&lb.addr, clients, Duration::from_secs(secs));
+        drop(lb);
+        let base = *baseline.get_or_insert(rate);
+        println!("{:>8} {:>12.0} {:>7.2}x", workers, rate, rate / base);
+    }
+}
+
+// A keep-alive HTTP backend that answers every request with "ok".
+fn start_backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || serve_backend(stream));
+        }
+    });
+    addr
+}
+
+fn serve_backend(stream: TcpStream) {
+    let _ = stream.set_nodelay(true);
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        // requests from the proxy have no body here, read up to the blank line
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) if line == "\r\n" => break,
+                Ok(_) => {}
+            }
+        }
+        if writer.write_all(RESPONSE).is_err() {
+            return;
+        }
+    }
+}
+
+// Sends requests from `clients` threads for `duration` and returns the
+// completed requests per second.
+fn drive(addr: &str, clients: usize, duration: Duration) -> f64 {
+    let done = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let request = format!(
+        "GET / HTTP/1.1\r\nhost: {}\r\nconnection: close\r\n\r\n",
+        addr
+    );
+
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let (addr, request) = (addr.to_string(), request.clone());
+            let (done, stop) = (done.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut response = Vec::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok(mut stream) = TcpStream::connect(&addr) else {
+                        continue;
+                    };
+                    response.clear();
+                    if stream.write_all(request.as_bytes()).is_ok()
+                        && stream.read_to_end(&mut response).is_ok()
+                        && response.starts_with(b"HTTP/1.1 200")
+                    {
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = started.elapsed();
+    for thread in threads {
+        let _ = thread.join();
+    }
+    done.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+}
@@ -0,0 +1,44 @@
+/*
+ * Embedding the load balancer with a strategy of its own.
+ *
+ *   cargo run --example custom_strategy
+ *
+ * Runs with the config in the working directory, but every client sticks
+ * to one backend of the default pool, picked by hashing its address.
+ * Backends that are down are skipped as long as another one is up.
+ */
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use load_balancer::{BackendStats, Config, Context, LoadBalancer, Strategy};
+
+struct ClientHash;
+
+impl Strategy for ClientHash {
+    fn pick(&self, context: &Context, backends: &[BackendStats]) -> Option<usize> {
+        let mut hasher = DefaultHasher::new();
+        context.client.hash(&mut hasher);
+        let start = hasher.finish() as usize;
+        let n = backends.len();
+        (0..n)
+            .map(|i| (start + i) % n)
+            .find(|&i| backends[i].weight > 0.0)
+            .or(Some(start % n))
+    }
+}
+
+fn main() {
+    let config = Config::load(Path::new("load-balancer.toml")).unwrap_or_else(|e| {
+        eprintln!("invalid config: {}", e);
+        process::exit(1);
+    });
+    let lb = LoadBalancer::new(config)
+        .with_strategy("default", ClientHash)
+        .expect("the default pool always exists");
+    if let Err(e) = load_balancer::run(Arc::new(lb)) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
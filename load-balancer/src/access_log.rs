@@ -0,0 +1,133 @@
+/*
+ * One line per proxied request, printed to stdout.
+ *
+ * The last field says how the request ended. Timeouts have their own
+ * reasons so a slow backend (connect / first byte / backend idle) can be
+ * told apart from a slow client (client idle).
+ */
+use std::net::SocketAddr;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    Completed,
+    BadRequest,
+    RequestTooLarge,
+    ClientTooSlow,
+    AclDenied,
+    Unauthorized,
+    Maintenance,
+    ConnectFailed,
+    ConnectTimeout,
+    FirstByteTimeout,
+    BackendIdleTimeout,
+    ClientIdleTimeout,
+    TotalTimeout,
+    BackendError,
+    ClientClosed,
+    ConnectionLimit,
+    QueueFull,
+    QueueTimeout,
+    FaultInjected,
+}
+
+impl Termination {
+    pub const ALL: [Termination; 19] = [
+        Termination::Completed,
+        Termination::BadRequest,
+        Termination::RequestTooLarge,
+        Termination::ClientTooSlow,
+        Termination::AclDenied,
+        Termination::Unauthorized,
+        Termination::Maintenance,
+        Termination::ConnectFailed,
+        Termination::ConnectTimeout,
+        Termination::FirstByteTimeout,
+        Termination::BackendIdleTimeout,
+        Termination::ClientIdleTimeout,
+        Termination::TotalTimeout,
+        Termination::BackendError,
+        Termination::ClientClosed,
+        Termination::ConnectionLimit,
+        Termination::QueueFull,
+        Termination::QueueTimeout,
+        Termination::FaultInjected,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Termination::Completed => "completed",
+            Termination::BadRequest => "bad_request",
+            Termination::RequestTooLarge => "request_too_large",
+            Termination::ClientTooSlow => "client_too_slow",
+            Termination::AclDenied => "acl_denied",
+            Termination::Unauthorized => "unauthorized",
+            Termination::Maintenance => "maintenance",
+            Termination::ConnectFailed => "connect_failed",
+            Termination::ConnectTimeout => "connect_timeout",
+            Termination::FirstByteTimeout => "first_byte_timeout",
+            Termination::BackendIdleTimeout => "backend_idle_timeout",
+            Termination::ClientIdleTimeout => "client_idle_timeout",
+            Termination::TotalTimeout => "total_timeout",
+            Termination::BackendError => "backend_error",
+            Termination::ClientClosed => "client_closed",
+            Termination::ConnectionLimit => "connection_limit",
+            Termination::QueueFull => "queue_full",
+            Termination::QueueTimeout => "queue_timeout",
+            Termination::FaultInjected => "fault_injected",
+        }
+    }
+
+    // whether the backend is the one to blame
+    pub fn is_backend_failure(self) -> bool {
+        matches!(
+            self,
+            Termination::ConnectFailed
+                | Termination::ConnectTimeout
+                | Termination::FirstByteTimeout
+                | Termination::BackendIdleTimeout
+                | Termination::BackendError
+        )
+    }
+}
+
+pub struct Entry {
+    pub peer: SocketAddr,
+    pub method: String,
+    pub target: String,
+    pub start: Instant,
+    pub backend: Option<String>,
+    pub status: Option<u16>,
+    pub bytes: u64,
+    pub termination: Termination,
+}
+
+impl Entry {
+    pub fn new(peer: SocketAddr, method: &str, target: &str) -> Entry {
+        Entry {
+            peer,
+            method: method.to_string(),
+            target: target.to_string(),
+            start: Instant::now(),
+            backend: None,
+            status: None,
+            bytes: 0,
+            termination: Termination::Completed,
+        }
+    }
+
+    pub fn write(&self) {
+        let status = self.status.map_or("-".to_string(), |s| s.to_string());
+        println!(
+            "{} \"{} {}\" {} {} {} {}ms {}",
+            self.peer.ip(),
+            self.method,
+            self.target,
+            status,
+            self.bytes,
+            self.backend.as_deref().unwrap_or("-"),
+            self.start.elapsed().as_millis(),
+            self.termination.as_str(),
+        );
+    }
+}
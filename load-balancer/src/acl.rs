@@ -0,0 +1,278 @@
+/*
+ * IP access control lists.
+ *
+ * An ACL is a list of allowed and a list of denied CIDR prefixes, IPv4 and
+ * IPv6 mixed. Deny wins over allow, and an empty allow list allows
+ * everybody that is not denied.
+ *
+ * The prefixes are stored in binary tries (one bit per level), so checking
+ * an address costs at most 32 or 128 steps no matter how many thousands of
+ * prefixes are configured.
+ */
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+// The compiled form of an AclConfig. Parsing happens while the config is
+// loaded, so a bad prefix is reported right away.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "AclConfig")]
+pub struct Acl {
+    allow: IpSet,
+    deny: IpSet,
+}
+
+impl TryFrom<AclConfig> for Acl {
+    type Error = String;
+
+    fn try_from(config: AclConfig) -> Result<Acl, String> {
+        Ok(Acl {
+            allow: IpSet::parse(&config.allow)?,
+            deny: IpSet::parse(&config.deny)?,
+        })
+    }
+}
+
+impl Acl {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.contains(ip) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.contains(ip)
+    }
+
+    // true if the ACL lets every client through
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+// A set of CIDR prefixes.
+#[derive(Debug, Clone, Default)]
+pub struct IpSet {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl IpSet {
+    pub fn parse(prefixes: &[String]) -> Result<IpSet, String> {
+        let mut set = IpSet::default();
+        for prefix in prefixes {
+            set.insert(prefix)?;
+        }
+        Ok(set)
+    }
+
+    // Adds "10.0.0.0/8", "2001:db8::/32" or a single address.
+    fn insert(&mut self, prefix: &str) -> Result<(), String> {
+        let bad = || format!("invalid CIDR prefix {:?}", prefix);
+        let (addr, len) = match prefix.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| bad())?)),
+            None => (prefix, None),
+        };
+        match addr.trim().parse::<IpAddr>().map_err(|_| bad())? {
+            IpAddr::V4(ip) => {
+                let len = len.unwrap_or(32);
+                if len > 32 {
+                    return Err(bad());
+                }
+                self.v4.insert(u32::from(ip) as u128, 32, len);
+            }
+            IpAddr::V6(ip) => {
+                let len = len.unwrap_or(128);
+                if len > 128 {
+                    return Err(bad());
+                }
+                self.v6.insert(u128::from(ip), 128, len);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => self.v6.contains(u128::from(ip), 128),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+// Binary trie over the leading bits of an address. Nodes live in a Vec and
+// point at their children by index, 0 meaning "no child" (the root is
+// never anybody's child).
+#[derive(Debug, Clone)]
+struct Trie {
+    children: Vec<[u32; 2]>,
+    // a prefix ends at this node
+    terminal: Vec<bool>,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie {
+            children: vec![[0, 0]],
+            terminal: vec![false],
+        }
+    }
+}
+
+impl Trie {
+    fn bit(addr: u128, width: u8, i: u8) -> usize {
+        ((addr >> (width - 1 - i)) & 1) as usize
+    }
+
+    fn insert(&mut self, addr: u128, width: u8, len: u8) {
+        let mut node = 0;
+        for i in 0..len {
+            let bit = Trie::bit(addr, width, i);
+            if self.children[node][bit] == 0 {
+                self.children.push([0, 0]);
+                self.terminal.push(false);
+                self.children[node][bit] = (self.children.len() - 1) as u32;
+            }
+            node = self.children[node][bit] as usize;
+        }
+        self.terminal[node] = true;
+    }
+
+    fn contains(&self, addr: u128, width: u8) -> bool {
+        let mut node = 0;
+        for i in 0..width {
+            if self.terminal[node] {
+                return true;
+            }
+            node = self.children[node][Trie::bit(addr, width, i)] as usize;
+            if node == 0 {
+                return false;
+            }
+        }
+        self.terminal[node]
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.terminal[0] && self.children[0] == [0, 0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::rng::Rng;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        Acl::try_from(AclConfig {
+            allow: strings(allow),
+            deny: strings(deny),
+        })
+        .unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes_match_their_addresses_only() {
+        let acl = acl(&["10.0.0.0/8", "192.168.1.7", "2001:db8::/32"], &[]);
+        for allowed in [
+            "10.0.0.0",
+            "10.255.255.255",
+            "192.168.1.7",
+            "2001:db8::1",
+            "2001:db8:ffff::",
+            "::ffff:10.1.2.3",
+        ] {
+            assert!(acl.permits(ip(allowed)), "{}", allowed);
+        }
+        for denied in [
+            "9.255.255.255",
+            "11.0.0.0",
+            "192.168.1.6",
+            "192.168.1.8",
+            "2001:db9::",
+            "::a00:1",
+        ] {
+            assert!(!acl.permits(ip(denied)), "{}", denied);
+        }
+    }
+
+    #[test]
+    fn deny_wins_and_empty_allows_all() {
+        let nested = acl(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(nested.permits(ip("10.0.0.1")));
+        assert!(!nested.permits(ip("10.1.2.3")));
+
+        let open = acl(&[], &["203.0.113.0/24", "::1"]);
+        assert!(open.permits(ip("198.51.100.1")));
+        assert!(open.permits(ip("::2")));
+        assert!(!open.permits(ip("203.0.113.99")));
+        assert!(!open.permits(ip("::1")));
+        assert!(Acl::default().is_empty() && Acl::default().permits(ip("::1")));
+
+        let everybody = acl(&["0.0.0.0/0"], &[]);
+        assert!(everybody.permits(ip("255.255.255.255")));
+        // but only everybody on IPv4
+        assert!(!everybody.permits(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn bad_prefixes_are_refused() {
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            let config = AclConfig {
+                allow: vec![bad.to_string()],
+                deny: Vec::new(),
+            };
+            assert!(Acl::try_from(config).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn the_trie_agrees_with_masking() {
+        let mut rng = Rng::new(30);
+        // short prefixes, so that random addresses hit some of them
+        let prefixes: Vec<(u32, u8)> = (0..200)
+            .map(|_| {
+                let len = (rng.next_u64() % 12 + 4) as u8;
+                let addr = rng.next_u64() as u32 & (u32::MAX << (32 - len));
+                (addr, len)
+            })
+            .collect();
+        let list: Vec<String> = prefixes
+            .iter()
+            .map(|(addr, len)| format!("{}/{}", Ipv4Addr::from(*addr), len))
+            .collect();
+        let set = IpSet::parse(&list).unwrap();
+        let mut hits = 0;
+        for _ in 0..10_000 {
+            let addr = rng.next_u64() as u32;
+            let expected = prefixes
+                .iter()
+                .any(|(prefix, len)| addr & (u32::MAX << (32 - len)) == *prefix);
+            assert_eq!(set.contains(IpAddr::from(addr.to_be_bytes())), expected);
+            hits += expected as u32;
+        }
+        assert!(hits > 1000);
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * The admin server. It runs on its own address so operators can reach it
+ * even when the proxy port is busy, and it handles one request per
+ * connection.
+ *
+ *   GET  /metrics                  Prometheus metrics
+ *   GET  /status                   pools and the health of their backends,
+ *                                  as JSON
+ *   POST /reload                   re-read the config file
+ *   PUT  /routes/<name>/split?a=95&b=5
+ *                                  change the traffic split of a route
+ *   PUT  /routes/<name>/maintenance?enabled=true
+ *                                  put a route in or out of maintenance
+ *   POST /cache/purge?path=/img    drop cached responses whose path starts
+ *                                  with the prefix, all of them without one
+ *
+ * A split or maintenance switch set through the API lasts until the next
+ * reload.
+ */
+use std::collections::BTreeMap;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+
+use crate::balancer::LoadBalancer;
+use crate::cluster::{self, MemberState};
+use crate::config::SplitTarget;
+use crate::http::{self, RequestHead};
+use crate::metrics;
+
+pub fn serve(lb: Arc<LoadBalancer>) {
+    let addr = lb.config().admin.clone();
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("admin server failed to bind {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("admin is {}", addr);
+    for stream in listener.incoming().flatten() {
+        if let Err(e) = handle(&lb, stream) {
+            warn!("admin request failed: {}", e);
+        }
+    }
+}
+
+fn handle(lb: &LoadBalancer, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let head = match RequestHead::read(&mut reader, &lb.config().limits.head())? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    let path = head.path();
+    // PUT /routes/<name>/split?<pool>=<weight>&...
+    let split_route = path
+        .strip_prefix("/routes/")
+        .and_then(|rest| rest.strip_suffix("/split"));
+    // PUT /routes/<name>/maintenance?enabled=<bool>
+    let maintenance_route = path
+        .strip_prefix("/routes/")
+        .and_then(|rest| rest.strip_suffix("/maintenance"));
+
+    let (status, body) = match (head.method.as_str(), path) {
+        ("GET", "/metrics") => {
+            let mut body =
+                lb.metrics
+                    .render(lb.connections.get(), lb.conns.idle_count(), lb.cache.size());
+            let mut pools: Vec<_> = lb.pools.iter().collect();
+            pools.sort_by_key(|(name, _)| name.as_str());
+            metrics::labeled(
+                &mut body,
+                "lb_pool_requests_total",
+                "Requests routed to each pool.",
+                "counter",
+                "pool",
+                pools
+                    .iter()
+                    .map(|(name, pool)| (name.as_str(), pool.requests.load(Ordering::Relaxed))),
+            );
+            metrics::labeled(
+                &mut body,
+                "lb_queue_depth",
+                "Requests waiting for a backend slot, per pool.",
+                "gauge",
+                "pool",
+                pools
+                    .iter()
+                    .map(|(name, pool)| (name.as_str(), pool.queue.depth() as u64)),
+            );
+            // a backend can be in more than one pool
+            let mut active = BTreeMap::new();
+            for backend in pools.iter().flat_map(|(_, pool)| &pool.backends) {
+                *active.entry(backend.addr.as_str()).or_insert(0) += backend.active.get() as u64;
+            }
+            metrics::labeled(
+                &mut body,
+                "lb_backend_connections",
+                "Connections in use per backend.",
+                "gauge",
+                "backend",
+                active.into_iter(),
+            );
+            if lb.config().cluster.enabled() {
+                let members = lb.cluster.members();
+                metrics::labeled(
+                    &mut body,
+                    "lb_cluster_members",
+                    "Other instances in the cluster, by state.",
+                    "gauge",
+                    "state",
+                    MemberState::ALL
+                        .iter()
+                        .map(|&state| (state.as_str(), cluster::count(&members, state))),
+                );
+            }
+            return http::write_response(
+                &mut writer,
+                200,
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+                false,
+            );
+        }
+        ("GET", "/status") => {
+            let body = status(lb).to_string();
+            return http::write_response(
+                &mut writer,
+                200,
+                "application/json",
+                body.as_bytes(),
+                false,
+            );
+        }
+        ("POST", "/reload") => match lb.reload() {
+            Ok(()) => (200, "reloaded\n".to_string()),
+            Err(e) => (400, format!("reload failed: {}\n", e)),
+        },
+        ("POST", "/cache/purge") => {
+            let prefix = head
+                .query()
+                .into_iter()
+                .find(|(k, _)| k == "path")
+                .map_or(String::new(), |(_, v)| v);
+            let purged = lb.cache.purge(&prefix);
+            (200, format!("purged {} responses\n", purged))
+        }
+        ("PUT", _) if split_route.is_some() => {
+            let route = split_route.unwrap_or_default();
+            match split_targets(&head).and_then(|targets| lb.set_split(route, targets)) {
+                Ok(()) => (200, "split updated\n".to_string()),
+                Err(e) => (400, format!("{}\n", e)),
+            }
+        }
+        ("PUT", _) if maintenance_route.is_some() => {
+            let route = maintenance_route.unwrap_or_default();
+            let enabled = head
+                .query()
+                .into_iter()
+                .find(|(k, _)| k == "enabled")
+                .map(|(_, v)| v);
+            match enabled.as_deref() {
+                Some(value @ ("true" | "false")) => {
+                    match lb.set_maintenance(route, value == "true") {
+                        Ok(()) if value == "true" => (200, "maintenance on\n".to_string()),
+                        Ok(()) => (200, "maintenance off\n".to_string()),
+                        Err(e) => (400, format!("{}\n", e)),
+                    }
+                }
+                _ => (
+                    400,
+                    "expected ?enabled=true or ?enabled=false\n".to_string(),
+                ),
+            }
+        }
+        _ => (404, "not found\n".to_string()),
+    };
+    http::write_response(&mut writer, status, "text/plain", body.as_bytes(), false)
+}
+
+fn status(lb: &LoadBalancer) -> Value {
+    let config = lb.config();
+    let mut pools: Vec<_> = lb.pools.iter().collect();
+    pools.sort_by_key(|(name, _)| name.as_str());
+    let pools: Vec<Value> = pools
+        .into_iter()
+        .map(|(name, pool)| {
+            let backends: Vec<Value> = pool
+                .backends
+                .iter()
+                .map(|backend| {
+                    let weight = backend.health.weight(&config.slow_start);
+                    let state = if backend.health.is_down() {
+                        "down"
+                    } else if weight < 1.0 {
+                        "ramping"
+                    } else {
+                        "up"
+                    };
+                    json!({
+                        "addr": backend.addr,
+                        "tier": backend.tier.as_str(),
+                        "state": state,
+                        "weight": weight,
+                        "failures": backend.health.failures(),
+                        "active": backend.active.get(),
+                    })
+                })
+                .collect();
+            json!({
+                "name": name,
+                "requests": pool.requests.load(Ordering::Relaxed),
+                "queued": pool.queue.depth(),
+                "tier_loads": pool.tier_loads(config.health.tier_threshold_percent),
+                "backends": backends,
+            })
+        })
+        .collect();
+    let mut status = json!({
+        "listen": config.listen,
+        "mode": format!("{:?}", config.mode).to_lowercase(),
+        "pools": pools,
+    });
+    if config.cluster.enabled() {
+        let members: Vec<Value> = lb
+            .cluster
+            .members()
+            .into_iter()
+            .map(|member| {
+                json!({
+                    "name": member.name,
+                    "state": member.state.as_str(),
+                    "incarnation": member.incarnation,
+                })
+            })
+            .collect();
+        status["cluster"] = json!({
+            "name": lb.cluster.name(),
+            "incarnation": lb.cluster.incarnation(),
+            "members": members,
+        });
+    }
+    status
+}
+
+// Reads split targets from a query like `?stable=90&canary=10`. The order of
+// the pairs is the order of the targets.
+fn split_targets(head: &RequestHead) -> Result<Vec<SplitTarget>, String> {
+    let query = head.query();
+    if query.is_empty() {
+        return Err("expected pool=weight pairs in the query string".to_string());
+    }
+    query
+        .into_iter()
+        .map(|(pool, weight)| match weight.parse() {
+            Ok(weight) => Ok(SplitTarget { pool, weight }),
+            Err(_) => Err(format!("weight for {:?} is not a number", pool)),
+        })
+        .collect()
+}
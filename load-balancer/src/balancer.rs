@@ -0,0 +1,492 @@
+/*
+ * Shared state of the load balancer. One instance lives behind an Arc and
+ * every connection thread gets a clone of that Arc.
+ *
+ * A pool's backends come in up to three priority tiers: primary, secondary
+ * and backup. Requests go to the highest tier with enough healthy capacity
+ * and spill over as it degrades, the way Envoy does it. A tier with at
+ * least `health.tier_threshold_percent` of its backends up counts as fully
+ * healthy and takes every request the tiers above leave it. Below that its
+ * health falls in proportion, and the share it can't take goes on to the
+ * next tier. With 4 primaries and the default 71.4%, losing one primary
+ * changes nothing, losing two sends 30% of the requests to the secondary
+ * tier. When even all tiers together fall short, their shares are scaled
+ * up to make 100%, and when nothing is up at all the primary tier gets
+ * everything.
+ */
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+
+use crate::cache::Cache;
+use crate::cluster::Cluster;
+use crate::config::{Config, Overrides, SplitTarget};
+use crate::grpc;
+use crate::health::{Health, HealthConfig, SlowStartConfig};
+use crate::limit::{Counter, Queue, Rejected};
+use crate::metrics::Metrics;
+use crate::rng;
+use crate::strategy::{BackendStats, Context, Strategy};
+use crate::trace::Tracer;
+use crate::upstream::ConnPool;
+
+// Where a backend stands in its pool's priority order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Primary,
+    Secondary,
+    Backup,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 3] = [Tier::Primary, Tier::Secondary, Tier::Backup];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Tier::Primary => "primary",
+            Tier::Secondary => "secondary",
+            Tier::Backup => "backup",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    pub addr: String,
+    pub tier: Tier,
+    pub(crate) health: Health,
+    // connections in use, see limit.rs
+    pub(crate) active: Counter,
+    // requests sent to the backend
+    pub requests: AtomicU64,
+}
+
+impl Backend {
+    pub fn stats(&self, slow_start: &SlowStartConfig) -> BackendStats<'_> {
+        BackendStats {
+            addr: &self.addr,
+            weight: self.health.weight(slow_start),
+            active: self.active.get(),
+            requests: self.requests.load(Ordering::Relaxed),
+            failures: self.health.failures(),
+        }
+    }
+
+    // Connecting to the backend failed with `e`, see Health::failed.
+    pub fn failed(&self, config: &HealthConfig, e: &io::Error) {
+        debug!("connecting to {} failed: {}", self.addr, e);
+        self.count_failure(config);
+    }
+
+    // The backend ended a gRPC call with one of grpc.failure_codes.
+    pub fn call_failed(&self, config: &HealthConfig, status: &str) {
+        debug!("call to {} failed with {}", self.addr, status);
+        self.count_failure(config);
+    }
+
+    fn count_failure(&self, config: &HealthConfig) {
+        if self.health.failed(config) {
+            warn!(
+                "backend {} is down for {}ms after {} failures in a row",
+                self.addr,
+                config.cooldown_ms,
+                self.health.failures()
+            );
+        }
+    }
+}
+
+// A named group of backends that requests are balanced over.
+#[derive(Debug)]
+pub struct Pool {
+    pub backends: Vec<Backend>,
+    // requests routed to this pool
+    pub requests: AtomicU64,
+    // requests waiting for a backend with a free slot
+    pub(crate) queue: Queue,
+    // picks the backend for each request
+    strategy: Box<dyn Strategy>,
+}
+
+// A claimed slot on a backend, given back when dropped.
+pub struct Permit<'a> {
+    pool: &'a Pool,
+    pub backend: &'a Backend,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.backend.active.release();
+        self.pool.queue.notify();
+    }
+}
+
+impl Pool {
+    // `tiers` holds the backends of each tier, primary first
+    fn new(tiers: [&[String]; 3], strategy: Box<dyn Strategy>) -> Pool {
+        Pool {
+            backends: Tier::ALL
+                .into_iter()
+                .zip(tiers)
+                .flat_map(|(tier, addrs)| addrs.iter().map(move |addr| (tier, addr)))
+                .map(|(tier, addr)| Backend {
+                    addr: addr.clone(),
+                    tier,
+                    health: Health::default(),
+                    active: Counter::default(),
+                    requests: AtomicU64::new(0),
+                })
+                .collect(),
+            requests: AtomicU64::new(0),
+            queue: Queue::default(),
+            strategy,
+        }
+    }
+
+    // Picks a backend and claims a slot on it, waiting in the queue when
+    // every backend is at max_backend_connections.
+    pub(crate) fn acquire(
+        &self,
+        config: &Config,
+        context: &Context,
+        metrics: &Metrics,
+    ) -> Result<Permit<'_>, Rejected> {
+        let claim = || self.next_backend(context, config);
+        // with others waiting, a newcomer goes to the back of the line
+        if self.queue.depth() == 0
+            && let Some(backend) = claim()
+        {
+            return Ok(Permit {
+                pool: self,
+                backend,
+            });
+        }
+        let queued = Instant::now();
+        let result = self.queue.wait(&config.limits, claim);
+        if !matches!(result, Err(Rejected::QueueFull)) {
+            metrics.queue_wait.observe(queued.elapsed());
+        }
+        result.map(|backend| Permit {
+            pool: self,
+            backend,
+        })
+    }
+
+    // Like acquire, but never waits.
+    pub(crate) fn try_acquire(&self, config: &Config, context: &Context) -> Option<Permit<'_>> {
+        if self.queue.depth() > 0 {
+            return None;
+        }
+        self.next_backend(context, config).map(|backend| Permit {
+            pool: self,
+            backend,
+        })
+    }
+
+    // The share of requests each tier takes right now, indexed like
+    // Tier::ALL. See the top of this file.
+    pub fn tier_loads(&self, threshold_percent: f64) -> [f64; 3] {
+        let mut health = [0.0; 3];
+        for (i, tier) in Tier::ALL.into_iter().enumerate() {
+            let backends = self.backends.iter().filter(|b| b.tier == tier);
+            let (up, total) = backends.fold((0, 0), |(up, total), b| {
+                (up + usize::from(!b.health.is_down()), total + 1)
+            });
+            if total > 0 {
+                let share = up as f64 / total as f64 * 100.0;
+                health[i] = (share / threshold_percent).min(1.0);
+            }
+        }
+        let total = health.iter().sum::<f64>().min(1.0);
+        if total == 0.0 {
+            return [1.0, 0.0, 0.0];
+        }
+        let mut left = 1.0;
+        health.map(|health| {
+            let load = (health / total).min(left);
+            left -= load;
+            load
+        })
+    }
+
+    // Picks the tier for one request by the tier loads.
+    fn pick_tier(&self, threshold_percent: f64) -> Tier {
+        let loads = self.tier_loads(threshold_percent);
+        if loads[0] >= 1.0 {
+            return Tier::Primary;
+        }
+        let mut roll = rng::random_f64();
+        for (tier, load) in Tier::ALL.into_iter().zip(loads) {
+            if roll < load {
+                return tier;
+            }
+            roll -= load;
+        }
+        // rounding left the roll just past the last share
+        Tier::ALL
+            .into_iter()
+            .zip(loads)
+            .rfind(|(_, load)| *load > 0.0)
+            .map_or(Tier::Primary, |(tier, _)| tier)
+    }
+
+    // Asks the strategy for one of the backends of the chosen tier with
+    // fewer than max_backend_connections in use, and claims one on it.
+    fn next_backend(&self, context: &Context, config: &Config) -> Option<&Backend> {
+        let max = config.limits.max_backend_connections;
+        let tier = self.pick_tier(config.health.tier_threshold_percent);
+        // another request can take the last slot between the pick and the
+        // claim, then we pick again
+        for _ in 0..self.backends.len() {
+            let candidates: Vec<&Backend> = self
+                .backends
+                .iter()
+                .filter(|b| b.tier == tier && (max == 0 || b.active.get() < max))
+                .collect();
+            if candidates.is_empty() {
+                return None;
+            }
+            let stats: Vec<BackendStats> = candidates
+                .iter()
+                .map(|b| b.stats(&config.slow_start))
+                .collect();
+            let backend = candidates.get(self.strategy.pick(context, &stats)?)?;
+            if backend.active.try_claim(max) {
+                backend.requests.fetch_add(1, Ordering::Relaxed);
+                return Some(backend);
+            }
+        }
+        None
+    }
+}
+
+pub struct LoadBalancer {
+    // swapped as a whole on reload, see config(). Readers never lock, the
+    // mutex only keeps two writers from overwriting each other's change.
+    config: ArcSwap<Config>,
+    config_write: Mutex<()>,
+    // where the config was loaded from, for reloads
+    config_path: Option<PathBuf>,
+    // command line settings applied to every config that gets loaded
+    overrides: Overrides,
+    pub pools: HashMap<String, Pool>,
+    pub(crate) metrics: Arc<Metrics>,
+    // idle keep-alive connections, shared by all pools
+    pub(crate) conns: ConnPool,
+    // the same for HTTP/2, see grpc.rs
+    pub(crate) grpc_conns: grpc::Conns,
+    // gRPC calls running, against grpc.max_calls
+    pub(crate) grpc_calls: Counter,
+    // responses of routes with caching turned on
+    pub(crate) cache: Cache,
+    // exports the spans of traced requests
+    pub(crate) tracer: Tracer,
+    // shadow requests currently being sent
+    pub(crate) mirrors_in_flight: AtomicUsize,
+    // client connections open, see limit.rs
+    pub(crate) connections: Counter,
+    // the other instances, when cluster mode is on
+    pub(crate) cluster: Cluster,
+}
+
+impl LoadBalancer {
+    pub fn new(config: Config) -> LoadBalancer {
+        let metrics = Arc::new(Metrics::default());
+        let pools = config
+            .all_pools()
+            .into_iter()
+            .map(|(name, pool)| {
+                let pool = Pool::new(pool.tiers(), config.strategy.build());
+                (name.to_string(), pool)
+            })
+            .collect();
+        LoadBalancer {
+            conns: ConnPool::new(
+                config.keepalive.clone(),
+                metrics.clone(),
+                config.worker_count(),
+            ),
+            grpc_conns: grpc::Conns::new(config.keepalive.clone()),
+            cache: Cache::new(config.cache.clone()),
+            cluster: Cluster::new(&config.cluster),
+            tracer: Tracer::new(&config.tracing, metrics.clone()),
+            config: ArcSwap::from_pointee(config),
+            config_write: Mutex::new(()),
+            config_path: None,
+            overrides: Overrides::default(),
+            pools,
+            metrics,
+            mirrors_in_flight: AtomicUsize::new(0),
+            connections: Counter::default(),
+            grpc_calls: Counter::default(),
+        }
+    }
+
+    // Lets the admin API reload the config from `path`. `overrides` are
+    // applied to every config read from it.
+    pub fn with_config_file(mut self, path: PathBuf, overrides: Overrides) -> LoadBalancer {
+        self.config_path = Some(path);
+        self.overrides = overrides;
+        self
+    }
+
+    // Replaces the strategy of a pool, the one from the config included.
+    pub fn with_strategy(
+        mut self,
+        pool: &str,
+        strategy: impl Strategy + 'static,
+    ) -> Result<LoadBalancer, String> {
+        self.pools
+            .get_mut(pool)
+            .ok_or_else(|| format!("no pool named {:?}", pool))?
+            .strategy = Box::new(strategy);
+        Ok(self)
+    }
+
+    // The current config. Callers keep the returned snapshot for the whole
+    // request, so a reload never changes settings halfway through one.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    // Re-reads the config file. Only the parts that can change at runtime
+    // (routes, timeouts, health, logging) may differ from the running config.
+    pub fn reload(&self) -> Result<(), String> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or("the config was not loaded from a file")?;
+        let mut new = Config::load(path)?;
+        self.overrides.apply(&mut new);
+        let _write = self.config_write.lock().unwrap();
+        self.config.load().check_reloadable(&new)?;
+        self.config.store(Arc::new(new));
+        Ok(())
+    }
+
+    // Replaces the split targets of a named route, for example to move a
+    // canary from 5% to 20% without touching the config file.
+    pub fn set_split(&self, route: &str, targets: Vec<SplitTarget>) -> Result<(), String> {
+        let _write = self.config_write.lock().unwrap();
+        let mut new = Config::clone(&self.config.load());
+        new.route_named(route)
+            .ok_or_else(|| format!("no route named {:?}", route))?
+            .split
+            .get_or_insert_with(Default::default)
+            .targets = targets;
+        new.validate()?;
+        self.config.store(Arc::new(new));
+        Ok(())
+    }
+
+    // Puts a named route in or out of maintenance, until the next reload.
+    pub fn set_maintenance(&self, route: &str, enabled: bool) -> Result<(), String> {
+        let _write = self.config_write.lock().unwrap();
+        let mut new = Config::clone(&self.config.load());
+        new.route_named(route)
+            .ok_or_else(|| format!("no route named {:?}", route))?
+            .maintenance
+            .enabled = enabled;
+        self.config.store(Arc::new(new));
+        Ok(())
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Pool> {
+        self.pools.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::strategy::RoundRobin;
+
+    // A pool with that many backends per tier, of which `down` are out.
+    fn pool(tiers: [(usize, usize); 3]) -> Pool {
+        let addrs = tiers.map(|(n, _)| (0..n).map(|i| format!("b{}", i)).collect::<Vec<_>>());
+        let pool = Pool::new(
+            [&addrs[0], &addrs[1], &addrs[2]],
+            Box::new(RoundRobin::default()),
+        );
+        for (tier, (_, down)) in Tier::ALL.into_iter().zip(tiers) {
+            for backend in pool.backends.iter().filter(|b| b.tier == tier).take(down) {
+                backend.health.mark_down(Duration::from_secs(60));
+            }
+        }
+        pool
+    }
+
+    fn loads(tiers: [(usize, usize); 3]) -> [f64; 3] {
+        pool(tiers)
+            .tier_loads(71.4)
+            .map(|load| (load * 1000.0).round() / 1000.0)
+    }
+
+    #[test]
+    fn tiers_spill_over_as_they_degrade() {
+        assert_eq!(loads([(4, 0), (2, 0), (1, 0)]), [1.0, 0.0, 0.0]);
+        // one of four down is within the overprovisioning
+        assert_eq!(loads([(4, 1), (2, 0), (1, 0)]), [1.0, 0.0, 0.0]);
+        // two is not: 50 / 71.4 is 70%
+        assert_eq!(loads([(4, 2), (2, 0), (1, 0)]), [0.7, 0.3, 0.0]);
+        assert_eq!(loads([(4, 4), (2, 0), (1, 0)]), [0.0, 1.0, 0.0]);
+        // a tier with nothing up is skipped, whatever it can't take goes on
+        assert_eq!(loads([(4, 2), (2, 2), (1, 0)]), [0.7, 0.0, 0.3]);
+        assert_eq!(loads([(4, 3), (4, 3), (1, 0)]), [0.35, 0.35, 0.3]);
+    }
+
+    #[test]
+    fn short_tiers_are_scaled_up() {
+        // 70% is all there is, so it takes everything
+        assert_eq!(loads([(4, 2), (0, 0), (0, 0)]), [1.0, 0.0, 0.0]);
+        assert_eq!(loads([(4, 3), (4, 3), (0, 0)]), [0.5, 0.5, 0.0]);
+        // nothing up at all, the primaries get it
+        assert_eq!(loads([(2, 2), (2, 2), (1, 1)]), [1.0, 0.0, 0.0]);
+        assert_eq!(loads([(0, 0), (0, 0), (0, 0)]), [1.0, 0.0, 0.0]);
+        // a threshold of 100 turns overprovisioning off
+        let pool = pool([(4, 1), (4, 0), (0, 0)]);
+        assert_eq!(pool.tier_loads(100.0), [0.75, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn permits_give_their_slot_back() {
+        let mut config = Config::default();
+        config.limits.max_backend_connections = 1;
+        config.limits.queue_timeout_ms = 20;
+        let context = Context {
+            client: "127.0.0.1".parse().unwrap(),
+            request: None,
+        };
+        let metrics = Metrics::default();
+        let pool = pool([(1, 0), (0, 0), (0, 0)]);
+
+        let permit = pool.acquire(&config, &context, &metrics).unwrap();
+        assert_eq!(permit.backend.active.get(), 1);
+        assert!(pool.try_acquire(&config, &context).is_none());
+        assert_eq!(
+            pool.acquire(&config, &context, &metrics).err(),
+            Some(Rejected::QueueTimeout)
+        );
+        drop(permit);
+        assert_eq!(pool.backends[0].active.get(), 0);
+        let permit = pool.acquire(&config, &context, &metrics).unwrap();
+        assert_eq!(permit.backend.requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn requests_follow_the_loads() {
+        rng::seed(47);
+        let pool = pool([(4, 2), (2, 0), (1, 0)]);
+        let secondary = (0..10_000)
+            .filter(|_| pool.pick_tier(71.4) == Tier::Secondary)
+            .count();
+        assert!((2_700..3_300).contains(&secondary), "{}", secondary);
+    }
+}
@@ -0,0 +1,189 @@
+// The load: `connections` threads, each with its own keep-alive connection
+// to the balancer. With a rate, requests go out on a fixed schedule and
+// latency is measured from when a request was due, not from when it was
+// actually sent, so a stalled balancer shows up in the percentiles instead
+// of quietly lowering the load (coordinated omission). Without a rate every
+// thread sends its next request as soon as the last one is answered.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Load {
+    pub target: String,
+    pub path: String,
+    pub connections: usize,
+    // requests per second over all connections, 0 for as fast as possible
+    pub rate: f64,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+pub struct Results {
+    // microseconds, one per answered request
+    pub latencies: Vec<u64>,
+    // responses by status class, index 1 for 1xx up to 5 for 5xx
+    pub statuses: [u64; 6],
+    // requests that got no response at all
+    pub failed: u64,
+    pub elapsed: Duration,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (total, n) in self.statuses.iter_mut().zip(other.statuses) {
+            *total += n;
+        }
+        self.failed += other.failed;
+    }
+}
+
+pub fn run(load: &Load) -> Results {
+    let started = Instant::now();
+    let mut results = Results::default();
+    thread::scope(|s| {
+        let threads: Vec<_> = (0..load.connections)
+            .map(|i| s.spawn(move || drive(load, i, started)))
+            .collect();
+        for thread in threads {
+            results.merge(thread.join().unwrap());
+        }
+    });
+    results.elapsed = started.elapsed();
+    results
+}
+
+fn drive(load: &Load, index: usize, started: Instant) -> Results {
+    let mut results = Results::default();
+    let end = started + load.duration;
+    // this thread's share of the schedule, interleaved with the others
+    let interval =
+        (load.rate > 0.0).then(|| Duration::from_secs_f64(load.connections as f64 / load.rate));
+    let mut due = started
+        + interval.map_or(Duration::ZERO, |i| {
+            i * index as u32 / load.connections as u32
+        });
+    let mut conn: Option<Conn> = None;
+    loop {
+        let now = Instant::now();
+        if interval.is_some() && due > now {
+            thread::sleep(due - now);
+        }
+        let sent = match interval {
+            Some(_) => due,
+            None => Instant::now(),
+        };
+        if sent >= end {
+            break;
+        }
+        let response = match conn.take() {
+            Some(c) => Ok(c),
+            None => Conn::open(&load.target),
+        }
+        .and_then(|mut c| c.request(&load.target, &load.path).map(|r| (c, r)));
+        match response {
+            Ok((c, (status, keep_alive))) => {
+                results.latencies.push(sent.elapsed().as_micros() as u64);
+                results.statuses[(status as usize / 100).min(5)] += 1;
+                if keep_alive {
+                    conn = Some(c);
+                }
+            }
+            Err(_) => {
+                results.failed += 1;
+                // don't spin on a balancer that is down
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        if let Some(interval) = interval {
+            due += interval;
+        }
+    }
+    results
+}
+
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn open(target: &str) -> io::Result<Conn> {
+        let stream = TcpStream::connect(target)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        Ok(Conn {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    // Sends a GET and reads the whole response. Returns the status and
+    // whether the connection can be used again.
+    fn request(&mut self, host: &str, path: &str) -> io::Result<(u16, bool)> {
+        write!(
+            self.writer,
+            "GET {} HTTP/1.1\r\nhost: {}\r\n\r\n",
+            path, host
+        )?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let status = line
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status line"))?;
+        let mut length = None;
+        let mut chunked = false;
+        let mut keep_alive = true;
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let trimmed = line.trim_end();
+            if trimmed.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = trimmed.split_once(':') {
+                let value = value.trim();
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => length = value.parse::<u64>().ok(),
+                    "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                    "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                    _ => {}
+                }
+            }
+        }
+        if chunked {
+            loop {
+                line.clear();
+                self.reader.read_line(&mut line)?;
+                let size = u64::from_str_radix(line.trim_end().split(';').next().unwrap_or(""), 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+                // the chunk and its CRLF, or the final CRLF after size 0
+                self.skip(size + 2)?;
+                if size == 0 {
+                    break;
+                }
+            }
+        } else if let Some(length) = length {
+            self.skip(length)?;
+        } else {
+            io::copy(&mut self.reader, &mut io::sink())?;
+            keep_alive = false;
+        }
+        Ok((status, keep_alive))
+    }
+
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(n), &mut io::sink())?;
+        if skipped < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
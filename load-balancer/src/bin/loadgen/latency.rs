@@ -0,0 +1,83 @@
+// Latency distributions for the mock backends, and the duration syntax
+// used on the command line ("250us", "5ms", "1.5s").
+use std::time::Duration;
+
+use load_balancer::rng;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Latency {
+    Fixed(Duration),
+    // anywhere between the two, all equally likely
+    Uniform(Duration, Duration),
+    // mean and standard deviation, never below zero
+    Normal(Duration, Duration),
+    // exponential with the given mean, a long tail of slow responses
+    Exp(Duration),
+}
+
+impl Latency {
+    // Parses "5ms", "uniform:1ms-10ms", "normal:10ms,2ms" or "exp:5ms".
+    pub fn parse(s: &str) -> Result<Latency, String> {
+        let (kind, args) = s.split_once(':').unwrap_or(("fixed", s));
+        let pair = |sep| {
+            let (a, b) = args
+                .split_once(sep)
+                .ok_or_else(|| format!("{:?} needs two durations split by {:?}", s, sep))?;
+            Ok::<_, String>((parse_duration(a)?, parse_duration(b)?))
+        };
+        match kind {
+            "fixed" => Ok(Latency::Fixed(parse_duration(args)?)),
+            "uniform" => {
+                let (low, high) = pair('-')?;
+                if low > high {
+                    return Err(format!("{:?} has its bounds the wrong way round", s));
+                }
+                Ok(Latency::Uniform(low, high))
+            }
+            "normal" => {
+                let (mean, stddev) = pair(',')?;
+                Ok(Latency::Normal(mean, stddev))
+            }
+            "exp" => Ok(Latency::Exp(parse_duration(args)?)),
+            _ => Err(format!("unknown latency distribution {:?}", kind)),
+        }
+    }
+
+    pub fn sample(&self) -> Duration {
+        let secs = match *self {
+            Latency::Fixed(d) => return d,
+            Latency::Uniform(low, high) => {
+                low.as_secs_f64() + (high - low).as_secs_f64() * rng::random_f64()
+            }
+            Latency::Normal(mean, stddev) => {
+                // Box-Muller, 1 - u keeps ln() away from 0
+                let u = 1.0 - rng::random_f64();
+                let v = rng::random_f64();
+                let z = (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos();
+                mean.as_secs_f64() + stddev.as_secs_f64() * z
+            }
+            Latency::Exp(mean) => -mean.as_secs_f64() * (1.0 - rng::random_f64()).ln(),
+        };
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{:?} is not a duration", s))?;
+    let secs = match unit {
+        "us" => number / 1e6,
+        "ms" => number / 1e3,
+        "s" => number,
+        // a bare 0 needs no unit
+        "" if number == 0.0 => 0.0,
+        _ => return Err(format!("{:?} needs a unit of us, ms or s", s)),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
@@ -0,0 +1,294 @@
+/*
+ * A load generator for the balancer, with its own backends.
+ *
+ *   cargo run --release --bin loadgen -- --backends 3 --rate 2000 \
+ *       --latency uniform:1ms-5ms --latency exp:20ms --error-rate 1
+ *
+ * Starts N mock backends, starts the balancer in front of them (or uses a
+ * running one with --target) and sends GET requests at it for a while. The
+ * report has the latency percentiles, the throughput, the responses by
+ * status class and how many requests each backend got.
+ *
+ * --latency and --error-rate can be given once per backend, the last one
+ * given also applies to the backends after it. --lb-config adds a TOML file
+ * to the balancer's config, for settings like slow_start or health, so two
+ * runs can be compared. It must not set listen, admin or backends, those
+ * come from here. With --target the balancer must already be configured
+ * with the backends, --backend-port 3001 gives them the ports the default
+ * config expects.
+ */
+mod client;
+mod latency;
+mod mock;
+
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use serde_json::json;
+
+use client::{Load, Results};
+use latency::Latency;
+use mock::Backend;
+
+#[derive(Debug, Parser)]
+#[command(about = "Drive load at the balancer in front of mock backends")]
+struct Args {
+    #[arg(long, default_value_t = 3, help = "Mock backends to start")]
+    backends: usize,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Port of the first backend, the others follow it, 0 picks free ports"
+    )]
+    backend_port: u16,
+    #[arg(
+        long,
+        value_parser = Latency::parse,
+        help = "Backend latency: 5ms, uniform:1ms-10ms, normal:10ms,2ms or exp:5ms"
+    )]
+    latency: Vec<Latency>,
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        help = "Share of backend responses that are 500s"
+    )]
+    error_rate: Vec<f64>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        help = "Use a running balancer instead of starting one"
+    )]
+    target: Option<String>,
+    #[arg(long, value_name = "FILE", help = "The balancer binary to start")]
+    balancer: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "More config for the balancer that is started"
+    )]
+    lb_config: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "Requests per second, 0 for as fast as possible"
+    )]
+    rate: f64,
+    #[arg(long, default_value_t = 16, help = "Client connections")]
+    connections: usize,
+    #[arg(long, value_parser = latency::parse_duration, default_value = "10s")]
+    duration: Duration,
+    #[arg(long, default_value = "/")]
+    path: String,
+    #[arg(long, help = "Print the report as JSON")]
+    json: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.backends == 0 || args.connections == 0 {
+        fail("--backends and --connections must be at least 1");
+    }
+    let backends: Vec<Arc<Backend>> = (0..args.backends)
+        .map(|i| {
+            let latency = pick(&args.latency, i).unwrap_or(Latency::Fixed(Duration::ZERO));
+            let error_percent = pick(&args.error_rate, i).unwrap_or(0.0);
+            let port = match args.backend_port {
+                0 => 0,
+                base => base + i as u16,
+            };
+            Backend::start(&format!("127.0.0.1:{}", port), latency, error_percent)
+                .unwrap_or_else(|e| fail(&format!("starting backend {}: {}", i, e)))
+        })
+        .collect();
+
+    let balancer = args
+        .target
+        .is_none()
+        .then(|| Balancer::start(&args, &backends));
+    let target = match &balancer {
+        Some(balancer) => balancer.addr.clone(),
+        None => args.target.clone().unwrap_or_default(),
+    };
+
+    let results = client::run(&Load {
+        target,
+        path: args.path.clone(),
+        connections: args.connections,
+        rate: args.rate,
+        duration: args.duration,
+    });
+    drop(balancer);
+    report(&args, results, &backends);
+}
+
+// the value for backend `i`, the last one given for those past the end
+fn pick<T: Copy>(values: &[T], i: usize) -> Option<T> {
+    values.get(i).or(values.last()).copied()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("loadgen: {}", message);
+    process::exit(1);
+}
+
+// A balancer process started for the run, killed when dropped.
+struct Balancer {
+    addr: String,
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Balancer {
+    fn start(args: &Args, backends: &[Arc<Backend>]) -> Balancer {
+        let binary = args.balancer.clone().unwrap_or_else(|| {
+            // next to this binary, where cargo puts both
+            let exe = env::current_exe().unwrap_or_else(|e| fail(&e.to_string()));
+            exe.with_file_name(format!("load-balancer{}", env::consts::EXE_SUFFIX))
+        });
+        let extra = match &args.lb_config {
+            Some(path) => fs::read_to_string(path)
+                .unwrap_or_else(|e| fail(&format!("reading {}: {}", path.display(), e))),
+            None => String::new(),
+        };
+        let addr = free_port();
+        let addrs: Vec<&str> = backends.iter().map(|b| b.addr.as_str()).collect();
+        let config = format!(
+            "listen = {:?}\nadmin = {:?}\naccess_log = false\nbackends = {:?}\n{}",
+            addr,
+            free_port(),
+            addrs,
+            extra
+        );
+        let dir = env::temp_dir().join(format!("loadgen-{}", process::id()));
+        let path = dir.join("load-balancer.toml");
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&path, config))
+            .unwrap_or_else(|e| fail(&format!("writing {}: {}", path.display(), e)));
+        let child = Command::new(&binary)
+            .args(["--log-level", "warn", "run", "--config"])
+            .arg(&path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| fail(&format!("starting {}: {}", binary.display(), e)));
+        let mut balancer = Balancer { addr, child, dir };
+
+        let started = Instant::now();
+        while TcpStream::connect(&balancer.addr).is_err() {
+            if let Ok(Some(status)) = balancer.child.try_wait() {
+                fail(&format!("the balancer exited with {}", status));
+            }
+            if started.elapsed() > Duration::from_secs(10) {
+                fail("the balancer did not start");
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        balancer
+    }
+}
+
+impl Drop for Balancer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> String {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.to_string())
+        .unwrap_or_else(|e| fail(&format!("finding a free port: {}", e)))
+}
+
+fn report(args: &Args, mut results: Results, backends: &[Arc<Backend>]) {
+    results.latencies.sort_unstable();
+    let answered = results.latencies.len() as u64;
+    let secs = results.elapsed.as_secs_f64();
+    let percentile = |p: f64| match answered {
+        0 => 0,
+        n => results.latencies[((n as f64 * p / 100.0).ceil() as usize).clamp(1, n as usize) - 1],
+    };
+    let percentiles = [
+        ("p50", 50.0),
+        ("p90", 90.0),
+        ("p99", 99.0),
+        ("p99.9", 99.9),
+        ("max", 100.0),
+    ]
+    .map(|(name, p)| (name, percentile(p)));
+    let served: u64 = backends
+        .iter()
+        .map(|b| b.requests.load(Ordering::Relaxed))
+        .sum();
+
+    if args.json {
+        let report = json!({
+            "seconds": secs,
+            "requests": answered + results.failed,
+            "throughput": answered as f64 / secs,
+            "responses": {
+                "1xx": results.statuses[1],
+                "2xx": results.statuses[2],
+                "3xx": results.statuses[3],
+                "4xx": results.statuses[4],
+                "5xx": results.statuses[5],
+                "failed": results.failed,
+            },
+            "latency_us": percentiles
+                .iter()
+                .map(|&(name, us)| (name.to_string(), json!(us)))
+                .collect::<serde_json::Map<_, _>>(),
+            "backends": backends
+                .iter()
+                .map(|b| json!({
+                    "addr": b.addr,
+                    "requests": b.requests.load(Ordering::Relaxed),
+                    "errors": b.errors.load(Ordering::Relaxed),
+                }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", report);
+        return;
+    }
+
+    println!(
+        "requests   {} in {:.1}s, {:.1} req/s",
+        answered + results.failed,
+        secs,
+        answered as f64 / secs
+    );
+    println!(
+        "responses  {} 2xx, {} 3xx, {} 4xx, {} 5xx, {} failed",
+        results.statuses[2],
+        results.statuses[3],
+        results.statuses[4],
+        results.statuses[5],
+        results.failed
+    );
+    let latency: Vec<String> = percentiles
+        .iter()
+        .map(|&(name, us)| format!("{} {:.2}ms", name, us as f64 / 1000.0))
+        .collect();
+    println!("latency    {}", latency.join("  "));
+    println!("backends");
+    for backend in backends {
+        let requests = backend.requests.load(Ordering::Relaxed);
+        println!(
+            "  {:<21} {:>8} {:>6.1}%  {} errors  latency {:?}",
+            backend.addr,
+            requests,
+            requests as f64 * 100.0 / served.max(1) as f64,
+            backend.errors.load(Ordering::Relaxed),
+            backend.latency
+        );
+    }
+}
@@ -0,0 +1,99 @@
+// Mock backends: keep-alive HTTP servers that wait for a sampled latency,
+// then answer 200, or 500 for `error_percent` of the requests. Each counts
+// what it served so the report can show how the balancer spread the load.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use load_balancer::rng;
+
+use crate::latency::Latency;
+
+pub struct Backend {
+    pub addr: String,
+    pub latency: Latency,
+    pub error_percent: f64,
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl Backend {
+    pub fn start(addr: &str, latency: Latency, error_percent: f64) -> io::Result<Arc<Backend>> {
+        let listener = TcpListener::bind(addr)?;
+        let backend = Arc::new(Backend {
+            addr: listener.local_addr()?.to_string(),
+            latency,
+            error_percent,
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        });
+        let serving = backend.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let backend = serving.clone();
+                thread::spawn(move || {
+                    let _ = backend.serve(stream);
+                });
+            }
+        });
+        Ok(backend)
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            // request line and headers, the body is drained after
+            let mut length = 0u64;
+            let mut close = false;
+            let mut first = true;
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                let trimmed = line.trim_end();
+                if trimmed.is_empty() {
+                    break;
+                }
+                if first {
+                    close = trimmed.ends_with("HTTP/1.0");
+                    first = false;
+                } else if let Some((name, value)) = trimmed.split_once(':') {
+                    let value = value.trim();
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.parse().unwrap_or(0);
+                    } else if name.eq_ignore_ascii_case("connection") {
+                        close = value.eq_ignore_ascii_case("close");
+                    }
+                }
+            }
+            io::copy(&mut (&mut reader).take(length), &mut io::sink())?;
+
+            thread::sleep(self.latency.sample());
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let status = if rng::chance(self.error_percent) {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                "500 Internal Server Error"
+            } else {
+                "200 OK"
+            };
+            let body = format!("served by {}\n", self.addr);
+            write!(
+                writer,
+                "HTTP/1.1 {}\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n{}\r\n{}",
+                status,
+                body.len(),
+                if close { "connection: close\r\n" } else { "" },
+                body
+            )?;
+            if close {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,751 @@
+/*
+ * An in-memory cache for GET responses, enabled per route.
+ *
+ * This is a shared cache in the HTTP sense, so it follows what backends say
+ * in Cache-Control (s-maxage, max-age, no-store, no-cache, private) and
+ * Expires, and it never stores responses that set cookies or answer a
+ * request with credentials. Responses without an explicit lifetime are only
+ * stored when they carry an ETag or Last-Modified, and are then revalidated
+ * with If-None-Match / If-Modified-Since on every use.
+ *
+ * Responses are keyed on the pool, host and target, so the pools of a split
+ * route each get their own. Vary is handled by keying each stored variant
+ * on the request headers the backend named, a response that names others
+ * drops the variants stored under the old ones. The cache is bounded by the
+ * total size of the stored responses and evicts the least recently used
+ * ones.
+ *
+ * When many clients miss on the same key at once, the first one fetches
+ * from the backend and the others wait for it to fill the cache (see
+ * Cache::lookup).
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::http::{BodyKind, RequestHead, ResponseHead};
+
+// how long a client waits for somebody else's fetch of the same key before
+// going to the backend itself
+const COALESCE_WAIT: Duration = Duration::from_secs(30);
+
+// statuses that can be stored, the ones RFC 9111 calls heuristically
+// cacheable minus the rarely used ones
+const CACHEABLE: [u16; 5] = [200, 203, 301, 404, 410];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // total size of the stored responses, 0 disables the cache
+    pub max_bytes: usize,
+    // bigger responses are passed through without being stored
+    pub max_object_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            max_object_bytes: 1024 * 1024,
+        }
+    }
+}
+
+// A stored response.
+#[derive(Debug)]
+pub struct Stored {
+    pub head: ResponseHead,
+    pub body: Vec<u8>,
+    // the request target, for purging by path
+    target: String,
+    stored_at: Instant,
+    // the Age the response already had when it arrived
+    initial_age: Duration,
+    fresh_for: Duration,
+}
+
+impl Stored {
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.age() < self.fresh_for
+    }
+
+    pub fn has_validators(&self) -> bool {
+        self.head.headers.get("etag").is_some() || self.head.headers.get("last-modified").is_some()
+    }
+
+    pub fn body_kind(&self) -> BodyKind {
+        match self.body.len() {
+            0 => BodyKind::Empty,
+            n => BodyKind::Length(n as u64),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.body.len() + 512
+    }
+}
+
+pub enum Lookup<'a> {
+    // a fresh response to serve as is
+    Hit(Arc<Stored>),
+    // go to the backend. `stale` is an outdated response that can be
+    // revalidated, `fill` is set when this request is the one other
+    // requests for the key are waiting on.
+    Fetch {
+        key: String,
+        stale: Option<Arc<Stored>>,
+        fill: Option<Fill<'a>>,
+    },
+}
+
+// Held while fetching a key that others may wait for. Dropping it, after
+// the response was stored or when the fetch failed, wakes them up.
+pub struct Fill<'a> {
+    cache: &'a Cache,
+    key: String,
+}
+
+impl Drop for Fill<'_> {
+    fn drop(&mut self) {
+        if let Some(flight) = self.cache.flights.lock().unwrap().remove(&self.key) {
+            *flight.done.lock().unwrap() = true;
+            flight.cv.notify_all();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Flight {
+    done: Mutex<bool>,
+    cv: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Slot>,
+    // entry keys by last use, oldest first
+    lru: BTreeMap<u64, String>,
+    // the Vary header names last seen for each primary key
+    vary: HashMap<String, Vec<String>>,
+    tick: u64,
+    size: usize,
+}
+
+struct Slot {
+    stored: Arc<Stored>,
+    used: u64,
+}
+
+impl State {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(slot) = self.entries.get_mut(key) {
+            self.lru.remove(&slot.used);
+            slot.used = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(slot) = self.entries.remove(key) {
+            self.lru.remove(&slot.used);
+            self.size -= slot.stored.size();
+        }
+    }
+
+    // Drops every variant stored under `primary`.
+    fn remove_variants(&mut self, primary: &str) {
+        let keys: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|key| key.split('\n').next() == Some(primary))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    // The full key of the variant `head` asks for.
+    fn variant_key(&self, primary: &str, head: &RequestHead) -> String {
+        let mut key = primary.to_string();
+        for name in self.vary.get(primary).into_iter().flatten() {
+            key.push('\n');
+            key.push_str(name);
+            key.push(':');
+            key.push_str(head.headers.get(name).unwrap_or(""));
+        }
+        key
+    }
+}
+
+pub struct Cache {
+    settings: CacheConfig,
+    state: Mutex<State>,
+    flights: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+impl Cache {
+    pub fn new(settings: CacheConfig) -> Cache {
+        Cache {
+            settings,
+            state: Mutex::default(),
+            flights: Mutex::default(),
+        }
+    }
+
+    pub fn max_object_bytes(&self) -> usize {
+        self.settings.max_object_bytes
+    }
+
+    // Looks up the response for a request with primary key `primary` (see
+    // key()). On a miss the request either becomes the one that fills the
+    // key, or waits for the request that already does and looks again.
+    pub fn lookup(&self, primary: &str, head: &RequestHead) -> Lookup<'_> {
+        let revalidate = must_revalidate(head);
+        let mut waited = false;
+        loop {
+            let (key, stored) = {
+                let mut state = self.state.lock().unwrap();
+                let key = state.variant_key(primary, head);
+                let stored = state.entries.get(&key).map(|slot| slot.stored.clone());
+                if stored.is_some() {
+                    state.touch(&key);
+                }
+                (key, stored)
+            };
+            if let Some(stored) = &stored
+                && stored.is_fresh()
+                && !revalidate
+            {
+                return Lookup::Hit(stored.clone());
+            }
+            let stale = stored.filter(|s| s.has_validators());
+
+            let flight = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get(&key) {
+                    Some(flight) if !waited => flight.clone(),
+                    // still in flight after waiting once, don't queue up
+                    // behind a backend that is that slow
+                    Some(_) => {
+                        return Lookup::Fetch {
+                            key,
+                            stale,
+                            fill: None,
+                        };
+                    }
+                    None => {
+                        flights.insert(key.clone(), Arc::default());
+                        let fill = Fill {
+                            cache: self,
+                            key: key.clone(),
+                        };
+                        return Lookup::Fetch {
+                            key,
+                            stale,
+                            fill: Some(fill),
+                        };
+                    }
+                }
+            };
+            let done = flight.done.lock().unwrap();
+            let _ = flight
+                .cv
+                .wait_timeout_while(done, COALESCE_WAIT, |done| !*done)
+                .unwrap();
+            waited = true;
+        }
+    }
+
+    // Stores a response. `fresh_for` comes from freshness().
+    pub fn insert(
+        &self,
+        primary: &str,
+        request: &RequestHead,
+        mut head: ResponseHead,
+        body: Vec<u8>,
+        fresh_for: Duration,
+    ) {
+        if body.len() > self.settings.max_object_bytes {
+            return;
+        }
+        let initial_age = age_header(&head);
+        head.headers.remove("age");
+        let vary: Vec<String> = head
+            .headers
+            .tokens("vary")
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        let stored = Stored {
+            head,
+            body,
+            target: request.target.clone(),
+            stored_at: Instant::now(),
+            initial_age,
+            fresh_for,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        // variants keyed on other headers can't be asked for any more
+        if let Some(old) = state.vary.insert(primary.to_string(), vary.clone())
+            && old != vary
+        {
+            state.remove_variants(primary);
+        }
+        let key = state.variant_key(primary, request);
+        state.remove(&key);
+        while state.size + stored.size() > self.settings.max_bytes {
+            let Some((_, oldest)) = state.lru.pop_first() else {
+                return;
+            };
+            state.remove(&oldest);
+        }
+        state.size += stored.size();
+        state.tick += 1;
+        let used = state.tick;
+        state.lru.insert(used, key.clone());
+        state.entries.insert(
+            key,
+            Slot {
+                stored: Arc::new(stored),
+                used,
+            },
+        );
+    }
+
+    // The backend answered a revalidation of `stale` with 304. The stored
+    // response gets the new headers and counts as fresh again.
+    pub fn refresh(
+        &self,
+        key: &str,
+        stale: &Stored,
+        not_modified: &ResponseHead,
+        fresh_for: Duration,
+    ) -> Arc<Stored> {
+        let mut head = stale.head.clone();
+        for name in ["cache-control", "date", "etag", "expires", "last-modified"] {
+            if let Some(value) = not_modified.headers.get(name) {
+                head.headers.set(name, value);
+            }
+        }
+        let stored = Arc::new(Stored {
+            head,
+            body: stale.body.clone(),
+            target: stale.target.clone(),
+            stored_at: Instant::now(),
+            initial_age: age_header(not_modified),
+            fresh_for,
+        });
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = state.entries.get_mut(key) {
+            slot.stored = stored.clone();
+        }
+        stored
+    }
+
+    // Drops every stored response whose request path starts with `prefix`.
+    // Returns how many were dropped.
+    pub fn purge(&self, prefix: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, slot)| slot.stored.target.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            state.remove(key);
+        }
+        keys.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+}
+
+// The primary cache key of a request to `pool`, or None if it must bypass
+// the cache. The pools of a split route may well answer differently.
+pub fn key(pool: &str, head: &RequestHead) -> Option<String> {
+    if head.method != "GET"
+        || !matches!(head.body_kind(), Ok(BodyKind::Empty))
+        || head.headers.get("authorization").is_some()
+        || head.headers.has_token("cache-control", "no-store")
+    {
+        return None;
+    }
+    Some(format!(
+        "{} {} {}",
+        pool,
+        head.headers.get("host").unwrap_or(""),
+        head.target
+    ))
+}
+
+// whether the client insists on a response checked with the backend
+fn must_revalidate(head: &RequestHead) -> bool {
+    head.headers.has_token("cache-control", "no-cache")
+        || head.headers.has_token("cache-control", "max-age=0")
+        || head.headers.has_token("pragma", "no-cache")
+}
+
+// How long a response may be served from the cache, or None if it must not
+// be stored at all.
+pub fn freshness(head: &ResponseHead) -> Option<Duration> {
+    let headers = &head.headers;
+    if !CACHEABLE.contains(&head.status)
+        || headers.has_token("cache-control", "no-store")
+        || headers.has_token("cache-control", "private")
+        || headers.has_token("vary", "*")
+        || headers.get("set-cookie").is_some()
+    {
+        return None;
+    }
+    let validators = headers.get("etag").is_some() || headers.get("last-modified").is_some();
+    if headers.has_token("cache-control", "no-cache") {
+        return validators.then_some(Duration::ZERO);
+    }
+
+    let directive = |name: &str| {
+        headers
+            .tokens("cache-control")
+            .filter_map(|t| t.split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| v.trim().trim_matches('"').parse::<u64>().ok())
+    };
+    let lifetime = match directive("s-maxage").or_else(|| directive("max-age")) {
+        Some(secs) => Some(Duration::from_secs(secs)),
+        // Expires relative to the backend's own clock when it sent a Date,
+        // an invalid date (like "0") means already expired
+        None => headers.get("expires").map(|expires| {
+            let now = headers
+                .get("date")
+                .and_then(parse_http_date)
+                .unwrap_or_else(SystemTime::now);
+            parse_http_date(expires)
+                .and_then(|expires| expires.duration_since(now).ok())
+                .unwrap_or(Duration::ZERO)
+        }),
+    };
+    match lifetime {
+        Some(lifetime) if !lifetime.is_zero() || validators => Some(lifetime),
+        Some(_) => None,
+        None => validators.then_some(Duration::ZERO),
+    }
+}
+
+// Whether a client's conditional request matches the stored response, so
+// it can get a 304 instead of the body.
+pub fn not_modified(request: &RequestHead, stored: &Stored) -> bool {
+    // If-None-Match wins over If-Modified-Since when both are sent
+    if request.headers.get("if-none-match").is_some() {
+        let etag = stored
+            .head
+            .headers
+            .get("etag")
+            .map(|e| e.trim_start_matches("W/"));
+        return request
+            .headers
+            .tokens("if-none-match")
+            .any(|tag| tag == "*" || Some(tag.trim_start_matches("W/")) == etag);
+    }
+    match (
+        request
+            .headers
+            .get("if-modified-since")
+            .and_then(parse_http_date),
+        stored
+            .head
+            .headers
+            .get("last-modified")
+            .and_then(parse_http_date),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn age_header(head: &ResponseHead) -> Duration {
+    head.headers
+        .get("age")
+        .and_then(|age| age.trim().parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs)
+}
+
+// Parses an IMF-fixdate like "Sun, 06 Nov 1994 08:49:37 GMT", the only
+// date format senders are allowed to generate.
+fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let mut parts = s.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == month)? as u64
+        + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (h, m, sec) = (time.next()??, time.next()??, time.next()??);
+    if parts.next() != Some("GMT") || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // days since the epoch, from Howard Hinnant's days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146_097 + doe).checked_sub(719_468)?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + h * 3_600 + m * 60 + sec))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::http::HeadLimits;
+
+    fn request(extra: &str) -> RequestHead {
+        let text = format!("GET /a?b=1 HTTP/1.1\r\nhost: h\r\n{}\r\n", extra);
+        RequestHead::read(&mut text.as_bytes(), &HeadLimits::NONE)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn response(status: u16, extra: &str) -> ResponseHead {
+        let text = format!("HTTP/1.1 {} X\r\n{}\r\n", status, extra);
+        ResponseHead::read(&mut text.as_bytes()).unwrap()
+    }
+
+    fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    // a cache with room for `n` responses of up to 100 bytes
+    fn cache(n: usize) -> Cache {
+        Cache::new(CacheConfig {
+            max_bytes: n * 612,
+            max_object_bytes: 100,
+        })
+    }
+
+    fn store(cache: &Cache, head: &RequestHead, headers: &str, body: &[u8]) {
+        let key = key("p", head).unwrap();
+        let response = response(200, headers);
+        cache.insert(&key, head, response, body.to_vec(), secs(60).unwrap());
+    }
+
+    fn hit(cache: &Cache, head: &RequestHead) -> Option<Vec<u8>> {
+        match cache.lookup(&key("p", head).unwrap(), head) {
+            Lookup::Hit(stored) => Some(stored.body.clone()),
+            Lookup::Fetch { .. } => None,
+        }
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(key("p", &request("")).as_deref(), Some("p h /a?b=1"));
+        assert_ne!(key("p", &request("")), key("q", &request("")));
+        for bypass in [
+            "authorization: Basic eDp5\r\n",
+            "cache-control: no-store\r\n",
+            "content-length: 3\r\n",
+        ] {
+            assert_eq!(key("p", &request(bypass)), None, "{}", bypass);
+        }
+        let post = RequestHead::read(
+            &mut &b"POST / HTTP/1.1\r\nhost: h\r\n\r\n"[..],
+            &HeadLimits::NONE,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(key("p", &post), None);
+    }
+
+    #[test]
+    fn freshness_follows_the_backend() {
+        let fresh = |status, headers| freshness(&response(status, headers));
+        assert_eq!(fresh(200, "cache-control: max-age=60\r\n"), secs(60));
+        assert_eq!(
+            fresh(200, "cache-control: max-age=60, s-maxage=5\r\n"),
+            secs(5)
+        );
+        assert_eq!(
+            fresh(
+                200,
+                "date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                 expires: Sun, 06 Nov 1994 08:50:07 GMT\r\n"
+            ),
+            secs(30)
+        );
+        assert_eq!(fresh(404, "cache-control: max-age=60\r\n"), secs(60));
+
+        // stored, but checked with the backend on every use
+        assert_eq!(fresh(200, "etag: \"x\"\r\n"), secs(0));
+        assert_eq!(
+            fresh(
+                200,
+                "cache-control: no-cache, max-age=60\r\netag: \"x\"\r\n"
+            ),
+            secs(0)
+        );
+
+        for headers in [
+            "",
+            "cache-control: max-age=0\r\n",
+            "expires: 0\r\n",
+            "cache-control: no-cache\r\n",
+            "cache-control: max-age=60, no-store\r\n",
+            "cache-control: private, max-age=60\r\n",
+            "cache-control: max-age=60\r\nset-cookie: a=b\r\n",
+            "cache-control: max-age=60\r\nvary: *\r\n",
+        ] {
+            assert_eq!(fresh(200, headers), None, "{}", headers);
+        }
+        assert_eq!(fresh(500, "cache-control: max-age=60\r\n"), None);
+        assert_eq!(fresh(206, "cache-control: max-age=60\r\n"), None);
+    }
+
+    #[test]
+    fn hits_and_revalidation() {
+        let cache = cache(10);
+        let head = request("");
+        assert_eq!(hit(&cache, &head), None);
+        store(&cache, &head, "etag: \"v1\"\r\n", b"body");
+        assert_eq!(hit(&cache, &head).as_deref(), Some(&b"body"[..]));
+        assert_eq!(cache.size(), 4 + 512);
+
+        // the client wants it checked, the stored one goes along for that
+        let no_cache = request("cache-control: no-cache\r\n");
+        match cache.lookup(&key("p", &no_cache).unwrap(), &no_cache) {
+            Lookup::Fetch { stale, .. } => assert_eq!(stale.unwrap().body, b"body"),
+            Lookup::Hit(_) => panic!("served without revalidation"),
+        }
+
+        let stored = match cache.lookup(&key("p", &head).unwrap(), &head) {
+            Lookup::Hit(stored) => stored,
+            Lookup::Fetch { .. } => panic!("miss"),
+        };
+        assert!(not_modified(&request("if-none-match: \"v1\"\r\n"), &stored));
+        assert!(not_modified(
+            &request("if-none-match: W/\"v1\"\r\n"),
+            &stored
+        ));
+        assert!(!not_modified(
+            &request("if-none-match: \"v2\"\r\n"),
+            &stored
+        ));
+        assert!(!not_modified(&head, &stored));
+    }
+
+    #[test]
+    fn variants() {
+        let cache = cache(10);
+        let gzip = request("accept-encoding: gzip\r\n");
+        let plain = request("");
+        store(&cache, &gzip, "vary: Accept-Encoding\r\n", b"gz");
+        assert_eq!(hit(&cache, &gzip).as_deref(), Some(&b"gz"[..]));
+        assert_eq!(hit(&cache, &plain), None);
+        store(&cache, &plain, "vary: Accept-Encoding\r\n", b"plain");
+        assert_eq!(hit(&cache, &gzip).as_deref(), Some(&b"gz"[..]));
+        assert_eq!(hit(&cache, &plain).as_deref(), Some(&b"plain"[..]));
+        assert_eq!(cache.size(), 2 + 5 + 2 * 512);
+
+        // varying on something else now, the old variants are gone
+        let english = request("accept-encoding: gzip\r\naccept-language: en\r\n");
+        store(&cache, &english, "vary: Accept-Language\r\n", b"en");
+        assert_eq!(cache.size(), 2 + 512);
+        assert_eq!(hit(&cache, &english).as_deref(), Some(&b"en"[..]));
+        assert_eq!(hit(&cache, &plain), None);
+    }
+
+    #[test]
+    fn least_recently_used_go_first() {
+        let cache = cache(2);
+        let heads: Vec<RequestHead> = ["x-n: 1\r\n", "x-n: 2\r\n", "x-n: 3\r\n"]
+            .iter()
+            .map(|h| {
+                let mut head = request(h);
+                head.target = format!("/{}", head.headers.get("x-n").unwrap());
+                head
+            })
+            .collect();
+        store(&cache, &heads[0], "", b"1");
+        store(&cache, &heads[1], "", b"2");
+        assert!(hit(&cache, &heads[0]).is_some());
+        store(&cache, &heads[2], "", b"3");
+        assert!(hit(&cache, &heads[0]).is_some());
+        assert!(hit(&cache, &heads[1]).is_none());
+        assert!(hit(&cache, &heads[2]).is_some());
+
+        // too big to be worth it
+        let big = request("");
+        store(&cache, &big, "", &[0; 101]);
+        assert!(hit(&cache, &big).is_none());
+    }
+
+    #[test]
+    fn purge_by_prefix() {
+        let cache = cache(10);
+        for target in ["/img/a", "/img/b", "/css/c"] {
+            let mut head = request("");
+            head.target = target.to_string();
+            store(&cache, &head, "", b"x");
+        }
+        assert_eq!(cache.purge("/img/"), 2);
+        assert_eq!(cache.size(), 1 + 512);
+        assert_eq!(cache.purge(""), 1);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn concurrent_misses_wait_for_one_fetch() {
+        let cache = cache(10);
+        let head = request("");
+        let primary = key("p", &head).unwrap();
+        let Lookup::Fetch {
+            fill: Some(fill), ..
+        } = cache.lookup(&primary, &head)
+        else {
+            panic!("the first miss should fill");
+        };
+        thread::scope(|scope| {
+            let waiters: Vec<_> = (0..4).map(|_| scope.spawn(|| hit(&cache, &head))).collect();
+            thread::sleep(Duration::from_millis(50));
+            store(&cache, &head, "", b"filled");
+            drop(fill);
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap().as_deref(), Some(&b"filled"[..]));
+            }
+        });
+
+        // a fetch that fails lets the next one in line fetch instead
+        let mut other = request("");
+        other.target = "/other".to_string();
+        let primary = key("p", &other).unwrap();
+        let first = cache.lookup(&primary, &other);
+        thread::scope(|scope| {
+            let second = scope.spawn(|| {
+                matches!(
+                    cache.lookup(&primary, &other),
+                    Lookup::Fetch { fill: Some(_), .. }
+                )
+            });
+            thread::sleep(Duration::from_millis(50));
+            drop(first);
+            assert!(second.join().unwrap());
+        });
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ * The command line.
+ *
+ *   load-balancer [run] [--config FILE]   start the proxy, the default
+ *   load-balancer check [--config FILE]   validate the config and print the
+ *                                         pools and routes it resolves to
+ *   load-balancer status [--admin ADDR]   show the backends of a running
+ *                                         instance and their health
+ *
+ * --log-level and --listen go with any of them. --listen takes the place of
+ * the config's `listen`, also when the config is reloaded.
+ */
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use serde_json::Value;
+
+use load_balancer::balancer::Tier;
+use load_balancer::config::{Config, DEFAULT_POOL, Overrides};
+use load_balancer::http::{BodyReader, ResponseHead};
+use load_balancer::log::{self, Level};
+
+const CONFIG_PATH: &str = "load-balancer.toml";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "load-balancer",
+    version,
+    about = "An HTTP and TCP load balancer"
+)]
+pub struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value = "info",
+        help = "Drop messages below this level"
+    )]
+    pub log_level: Level,
+    #[arg(
+        long,
+        global = true,
+        value_name = "ADDR",
+        help = "Accept clients on ADDR instead of the config's `listen`"
+    )]
+    pub listen: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Start the proxy")]
+    Run(ConfigArgs),
+    #[command(about = "Validate the config and print the resolved pools and routes")]
+    Check(ConfigArgs),
+    #[command(about = "Show the backends of a running instance and their health")]
+    Status {
+        #[arg(
+            long,
+            value_name = "ADDR",
+            default_value = "127.0.0.1:9090",
+            help = "Admin address of the instance"
+        )]
+        admin: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[arg(long, value_name = "FILE", default_value = CONFIG_PATH)]
+    pub config: PathBuf,
+}
+
+impl Default for ConfigArgs {
+    fn default() -> Self {
+        ConfigArgs {
+            config: CONFIG_PATH.into(),
+        }
+    }
+}
+
+impl Cli {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            listen: self.listen.clone(),
+        }
+    }
+}
+
+// Loads the config with the overrides applied, or exits.
+pub fn load_config(path: &Path, overrides: &Overrides) -> Config {
+    match Config::load(path) {
+        Ok(mut config) => {
+            overrides.apply(&mut config);
+            config
+        }
+        Err(e) => {
+            log::write(Level::Error, format_args!("invalid config: {}", e));
+            process::exit(1);
+        }
+    }
+}
+
+pub fn check(path: &Path, overrides: &Overrides) {
+    let config = load_config(path, overrides);
+    println!("{} is valid", path.display());
+    println!();
+    println!(
+        "listen {} ({}, {} workers)",
+        config.listen,
+        format!("{:?}", config.mode).to_lowercase(),
+        config.worker_count()
+    );
+    println!("admin  {}", config.admin);
+    println!("strategy {}", config.strategy.name());
+
+    let mut pools = vec![row(["POOL", "TIER", "BACKENDS"])];
+    for (name, pool) in config.all_pools() {
+        let tiers = Tier::ALL.into_iter().zip(pool.tiers());
+        // the pool name only on its first line, empty tiers left out
+        for (i, (tier, backends)) in tiers.filter(|(_, b)| !b.is_empty()).enumerate() {
+            let name = if i == 0 { name } else { "" };
+            pools.push(vec![
+                name.to_string(),
+                tier.as_str().to_string(),
+                backends.join(", "),
+            ]);
+        }
+    }
+    println!();
+    print!("{}", table(&pools));
+
+    if config.routes.is_empty() {
+        return;
+    }
+    let mut routes = vec![row(["ROUTE", "NAME", "TARGET", "TIMEOUTS", "OPTIONS"])];
+    for route in &config.routes {
+        let target = match &route.split {
+            Some(split) => {
+                let targets: Vec<String> = split
+                    .targets
+                    .iter()
+                    .map(|t| format!("{}={}", t.pool, t.weight))
+                    .collect();
+                format!("split {}", targets.join(" "))
+            }
+            None => route.pool.as_deref().unwrap_or(DEFAULT_POOL).to_string(),
+        };
+        let t = config.timeouts_for(&route.path_prefix);
+        let timeouts = [
+            ("connect", t.connect()),
+            ("first_byte", t.first_byte()),
+            ("idle", t.idle()),
+            ("total", t.total()),
+        ]
+        .map(|(name, d)| match d {
+            Some(d) => format!("{}={}ms", name, d.as_millis()),
+            None => format!("{}=off", name),
+        })
+        .join(" ");
+        let mut options = Vec::new();
+        if let Some(mirror) = &route.mirror {
+            options.push(format!("mirror {}% to {}", mirror.percent, mirror.pool));
+        }
+        if route.cache {
+            options.push("cache".to_string());
+        }
+        if route.compress.unwrap_or(config.compression.enabled) {
+            options.push("compress".to_string());
+        }
+        if !route.acl.is_empty() {
+            options.push("acl".to_string());
+        }
+        if route.auth.is_some() {
+            options.push("auth".to_string());
+        }
+        if route.maintenance.enabled {
+            options.push("maintenance".to_string());
+        }
+        if !route.rewrite.is_empty() {
+            options.push("rewrite".to_string());
+        }
+        if let Some(redirect) = &route.redirect {
+            options.push(format!("redirect {}", redirect.status));
+        }
+        if !route.faults.is_empty() {
+            options.push(format!("{} faults", route.faults.len()));
+        }
+        routes.push(vec![
+            route.path_prefix.clone(),
+            route.name.clone().unwrap_or_else(|| "-".to_string()),
+            target,
+            timeouts,
+            options.join(", "),
+        ]);
+    }
+    println!();
+    print!("{}", table(&routes));
+}
+
+pub fn status(admin: &str) {
+    let status = match fetch_status(admin) {
+        Ok(status) => status,
+        Err(e) => {
+            log::write(
+                Level::Error,
+                format_args!("querying {} failed: {}", admin, e),
+            );
+            process::exit(1);
+        }
+    };
+    println!(
+        "listen {} ({})",
+        status["listen"].as_str().unwrap_or("?"),
+        status["mode"].as_str().unwrap_or("?")
+    );
+    let mut rows = vec![row([
+        "POOL", "BACKEND", "TIER", "STATE", "WEIGHT", "FAILURES", "ACTIVE", "REQUESTS", "QUEUED",
+    ])];
+    for pool in status["pools"].as_array().into_iter().flatten() {
+        let name = pool["name"].as_str().unwrap_or("?");
+        for (i, backend) in pool["backends"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            // the pool and its counts only on its first line
+            let (name, requests, queued) = match i {
+                0 => (
+                    name.to_string(),
+                    pool["requests"].to_string(),
+                    pool["queued"].to_string(),
+                ),
+                _ => (String::new(), String::new(), String::new()),
+            };
+            rows.push(vec![
+                name,
+                backend["addr"].as_str().unwrap_or("?").to_string(),
+                backend["tier"].as_str().unwrap_or("?").to_string(),
+                backend["state"].as_str().unwrap_or("?").to_string(),
+                format!("{:.0}%", backend["weight"].as_f64().unwrap_or(0.0) * 100.0),
+                backend["failures"].to_string(),
+                backend["active"].to_string(),
+                requests,
+                queued,
+            ]);
+        }
+    }
+    println!();
+    print!("{}", table(&rows));
+
+    let cluster = &status["cluster"];
+    if cluster.is_object() {
+        println!();
+        println!(
+            "cluster member {} (incarnation {})",
+            cluster["name"].as_str().unwrap_or("?"),
+            cluster["incarnation"]
+        );
+        let mut members = vec![row(["MEMBER", "STATE", "INCARNATION"])];
+        for member in cluster["members"].as_array().into_iter().flatten() {
+            members.push(vec![
+                member["name"].as_str().unwrap_or("?").to_string(),
+                member["state"].as_str().unwrap_or("?").to_string(),
+                member["incarnation"].to_string(),
+            ]);
+        }
+        print!("{}", table(&members));
+    }
+}
+
+fn fetch_status(admin: &str) -> io::Result<Value> {
+    let mut stream = TcpStream::connect(admin)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write!(
+        stream,
+        "GET /status HTTP/1.1\r\nhost: {}\r\nconnection: close\r\n\r\n",
+        admin
+    )?;
+    let mut reader = BufReader::new(stream);
+    let head = ResponseHead::read_final(&mut reader)?;
+    let mut body = Vec::new();
+    BodyReader::new(&mut reader, head.body_kind("GET")?).read_to_end(&mut body)?;
+    if head.status != 200 {
+        return Err(io::Error::other(format!(
+            "admin answered {} {}",
+            head.status, head.reason
+        )));
+    }
+    serde_json::from_slice(&body).map_err(io::Error::other)
+}
+
+fn row<const N: usize>(cells: [&str; N]) -> Vec<String> {
+    cells.map(String::from).to_vec()
+}
+
+// Lines up the cells in columns. The last column isn't padded.
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths = Vec::new();
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            line.push_str(cell);
+            if i + 1 < row.len() {
+                let pad = widths[i] - cell.chars().count();
+                line.extend(std::iter::repeat_n(' ', pad));
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
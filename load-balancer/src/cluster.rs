@@ -0,0 +1,785 @@
+/*
+ * Cluster mode: balancer instances gossip with each other over UDP.
+ *
+ * Membership follows SWIM. Every `probe_interval_ms` an instance pings one
+ * member, going round the members in a shuffled order. Without an ack
+ * within `probe_timeout_ms` it asks `indirect_probes` other members to
+ * ping it on its behalf, and if none of that gets through before the
+ * interval is over the member is suspected. A suspect that doesn't refute
+ * the suspicion within `suspect_timeout_ms` is declared dead. A member
+ * refutes by raising its incarnation number, news about a higher
+ * incarnation beats older news everywhere.
+ *
+ * News travels piggybacked on the pings and acks: membership changes and
+ * health verdicts. When one instance takes a backend out (see health.rs)
+ * the others take it out for the rest of the cooldown too, so a dead
+ * backend costs the cluster `health.failures` failed requests rather than
+ * that many per instance. Each piece of news is passed on a few times
+ * log(cluster size), which is enough for it to reach every member with
+ * high probability.
+ *
+ * Messages are small JSON datagrams, each one led by an HMAC-SHA256 of it
+ * under the shared `secret`. Datagrams that don't carry a valid one are
+ * dropped unread. So are messages from members nobody introduced: a sender
+ * must be one of `peers` or have been told about by a known member. That
+ * keeps strangers out but not replays of old messages, the gossip port
+ * still belongs on a private network.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::balancer::LoadBalancer;
+use crate::metrics::Metrics;
+use crate::rng;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    // UDP address to gossip on, cluster mode is off while this is empty
+    pub bind: String,
+    // the address the other instances reach this one on, `bind` if unset
+    pub advertise: Option<String>,
+    // instances to join through, one that is up is enough. Only these and
+    // the members they tell about are listened to
+    pub peers: Vec<String>,
+    // every member signs its messages with this, it must be the same on all
+    pub secret: String,
+    pub probe_interval_ms: u64,
+    // how long a direct ping may take before others are asked to try
+    pub probe_timeout_ms: u64,
+    // members asked to ping a member that didn't answer
+    pub indirect_probes: usize,
+    // how long a suspect has to refute before it is declared dead
+    pub suspect_timeout_ms: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            bind: String::new(),
+            advertise: None,
+            peers: Vec::new(),
+            secret: String::new(),
+            probe_interval_ms: 1_000,
+            probe_timeout_ms: 300,
+            indirect_probes: 3,
+            suspect_timeout_ms: 5_000,
+        }
+    }
+}
+
+impl ClusterConfig {
+    pub fn enabled(&self) -> bool {
+        !self.bind.is_empty()
+    }
+
+    // the name the other members know this instance by
+    pub fn name(&self) -> &str {
+        self.advertise.as_deref().unwrap_or(&self.bind)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let bind: SocketAddr = self
+            .bind
+            .parse()
+            .map_err(|_| format!("cluster bind {:?} is not an ip:port", self.bind))?;
+        if self.advertise.is_none() && bind.ip().is_unspecified() {
+            return Err("cluster advertise is needed when bind is a wildcard address".to_string());
+        }
+        if self.secret.len() < MIN_SECRET {
+            return Err(format!(
+                "cluster secret must be at least {} bytes",
+                MIN_SECRET
+            ));
+        }
+        if self.probe_timeout_ms == 0 || self.probe_timeout_ms >= self.probe_interval_ms {
+            return Err(
+                "cluster probe_timeout_ms must be above 0 and below probe_interval_ms".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.probe_interval_ms)
+    }
+}
+
+// Ordered by precedence: at the same incarnation, news that a member is
+// dead beats news that it is suspected, which beats news that it is alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl MemberState {
+    pub const ALL: [MemberState; 3] = [MemberState::Alive, MemberState::Suspect, MemberState::Dead];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    kind: Kind,
+    seq: u64,
+    // the sender's name and incarnation
+    from: String,
+    incarnation: u64,
+    // ping_req: the member to ping, ack: the member that answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    news: Vec<News>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Ping,
+    PingReq,
+    Ack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum News {
+    Member {
+        name: String,
+        state: MemberState,
+        incarnation: u64,
+    },
+    // the backend is out for this many more milliseconds
+    Down {
+        backend: String,
+        for_ms: u64,
+    },
+}
+
+// the shortest secret taken, anything less is guessed too easily
+const MIN_SECRET: usize = 16;
+// the HMAC-SHA256 ahead of every message
+const TAG_LEN: usize = 32;
+// news at most this many pieces per message, keeping datagrams small
+const MAX_NEWS: usize = 16;
+// news is passed on this many times log2(cluster size)
+const RETRANSMIT_MULT: u32 = 3;
+// a health verdict for a backend that is out until at most this much later
+// than already known is old news
+const DOWN_SLACK: Duration = Duration::from_millis(500);
+
+pub struct Cluster {
+    config: ClusterConfig,
+    name: String,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+}
+
+struct Known {
+    state: MemberState,
+    incarnation: u64,
+    since: Instant,
+}
+
+struct State {
+    incarnation: u64,
+    members: BTreeMap<String, Known>,
+    rumors: Vec<Rumor>,
+    next_seq: u64,
+    probe: Option<Probe>,
+    next_probe: Instant,
+    // members still to be probed this round
+    round: Vec<String>,
+    // pings sent for another member's ping_req, by our seq: where the
+    // ping_req came from and its seq
+    relays: HashMap<u64, (SocketAddr, String, u64, Instant)>,
+    // how long each backend is out, as far as the cluster was told
+    shared_down: HashMap<String, Instant>,
+}
+
+struct Probe {
+    target: String,
+    seq: u64,
+    sent: Instant,
+    indirect: bool,
+}
+
+// A piece of news and how often it was passed on.
+struct Rumor {
+    news: Rumored,
+    sent: u32,
+}
+
+enum Rumored {
+    Member(String),
+    Down(String, Instant),
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Cluster {
+        Cluster {
+            config: config.clone(),
+            name: config.name().to_string(),
+            state: Mutex::new(State {
+                incarnation: 0,
+                members: BTreeMap::new(),
+                rumors: Vec::new(),
+                next_seq: 1,
+                probe: None,
+                next_probe: Instant::now(),
+                round: Vec::new(),
+                relays: HashMap::new(),
+                shared_down: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.state.lock().unwrap().incarnation
+    }
+
+    // the other instances this one knows of
+    pub fn members(&self) -> Vec<Member> {
+        let state = self.state.lock().unwrap();
+        state
+            .members
+            .iter()
+            .map(|(name, known)| Member {
+                name: name.clone(),
+                state: known.state,
+                incarnation: known.incarnation,
+            })
+            .collect()
+    }
+
+    // Does one round of timers and returns how long to wait for a message.
+    fn tick(&self, lb: &LoadBalancer, socket: &UdpSocket) -> Duration {
+        let now = Instant::now();
+        let interval = self.config.interval();
+        let mut state = self.state.lock().unwrap();
+        self.share_health(lb, &mut state, now);
+
+        let timeout = Duration::from_millis(self.config.suspect_timeout_ms);
+        let expired: Vec<(String, u64)> = state
+            .members
+            .iter()
+            .filter(|(_, m)| m.state == MemberState::Suspect && now >= m.since + timeout)
+            .map(|(name, m)| (name.clone(), m.incarnation))
+            .collect();
+        for (name, incarnation) in expired {
+            self.apply_member(&mut state, &name, MemberState::Dead, incarnation);
+        }
+        state
+            .relays
+            .retain(|_, (_, _, _, sent)| now < *sent + interval);
+
+        match &mut state.probe {
+            // nobody got an ack out of it
+            Some(probe) if now >= probe.sent + interval => {
+                let target = probe.target.clone();
+                state.probe = None;
+                if let Some(known) = state.members.get(&target)
+                    && known.state == MemberState::Alive
+                {
+                    let incarnation = known.incarnation;
+                    self.apply_member(&mut state, &target, MemberState::Suspect, incarnation);
+                }
+            }
+            Some(probe)
+                if !probe.indirect
+                    && now >= probe.sent + Duration::from_millis(self.config.probe_timeout_ms) =>
+            {
+                probe.indirect = true;
+                let (target, seq) = (probe.target.clone(), probe.seq);
+                let mut helpers: Vec<String> = state
+                    .members
+                    .iter()
+                    .filter(|(name, m)| m.state == MemberState::Alive && **name != target)
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                shuffle(&mut helpers);
+                helpers.truncate(self.config.indirect_probes);
+                for helper in helpers {
+                    self.send(
+                        &mut state,
+                        socket,
+                        &helper,
+                        None,
+                        Kind::PingReq,
+                        seq,
+                        Some(target.clone()),
+                    );
+                }
+            }
+            _ => {}
+        }
+        if state.probe.is_none() && now >= state.next_probe {
+            state.next_probe = now + interval;
+            self.start_probe(&mut state, socket, now);
+        }
+
+        let mut next = state.next_probe;
+        if let Some(probe) = &state.probe {
+            next = next.min(if probe.indirect {
+                probe.sent + interval
+            } else {
+                probe.sent + Duration::from_millis(self.config.probe_timeout_ms)
+            });
+        }
+        next.saturating_duration_since(now)
+            .clamp(Duration::from_millis(10), interval)
+    }
+
+    fn start_probe(&self, state: &mut State, socket: &UdpSocket, now: Instant) {
+        let live = |state: &State, name: &str| {
+            state
+                .members
+                .get(name)
+                .is_some_and(|m| m.state != MemberState::Dead)
+        };
+        // without anybody to talk to, knock on the peers' doors again; and
+        // now and then on the door of one that is gone, so a cluster split
+        // in two finds back together
+        let lonely = !state.members.values().any(|m| m.state != MemberState::Dead);
+        if lonely || rng::chance(10.0) {
+            let mut gone: Vec<String> = self
+                .config
+                .peers
+                .iter()
+                .chain(state.members.keys())
+                .filter(|name| **name != self.name && !live(state, name))
+                .cloned()
+                .collect();
+            gone.sort();
+            gone.dedup();
+            if !lonely {
+                shuffle(&mut gone);
+                gone.truncate(1);
+            }
+            for name in gone {
+                let seq = state.next_seq();
+                self.send(state, socket, &name, None, Kind::Ping, seq, None);
+            }
+        }
+
+        while let Some(target) = state.round.pop() {
+            if !live(state, &target) {
+                continue;
+            }
+            let seq = state.next_seq();
+            state.probe = Some(Probe {
+                target: target.clone(),
+                seq,
+                sent: now,
+                indirect: false,
+            });
+            self.send(state, socket, &target, None, Kind::Ping, seq, None);
+            return;
+        }
+        // a new round, in a new order
+        let mut round: Vec<String> = state
+            .members
+            .iter()
+            .filter(|(_, m)| m.state != MemberState::Dead)
+            .map(|(name, _)| name.clone())
+            .collect();
+        shuffle(&mut round);
+        if !round.is_empty() {
+            state.next_probe = now;
+        }
+        state.round = round;
+    }
+
+    fn receive(&self, lb: &LoadBalancer, socket: &UdpSocket, message: Message, src: SocketAddr) {
+        if message.from == self.name {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.members.contains_key(&message.from) && !self.config.peers.contains(&message.from)
+        {
+            Metrics::inc(&lb.metrics.cluster_rejected);
+            debug!("gossip from unknown member {} at {}", message.from, src);
+            return;
+        }
+        // a message is news about its sender too
+        self.apply_member(
+            &mut state,
+            &message.from,
+            MemberState::Alive,
+            message.incarnation,
+        );
+        for news in message.news {
+            match news {
+                News::Member {
+                    name,
+                    state: member_state,
+                    incarnation,
+                } => {
+                    self.apply_member(&mut state, &name, member_state, incarnation);
+                }
+                News::Down { backend, for_ms } => {
+                    self.apply_down(lb, &mut state, backend, Duration::from_millis(for_ms));
+                }
+            }
+        }
+
+        match message.kind {
+            Kind::Ping => {
+                self.send(
+                    &mut state,
+                    socket,
+                    &message.from,
+                    Some(src),
+                    Kind::Ack,
+                    message.seq,
+                    None,
+                );
+            }
+            Kind::PingReq => {
+                // only members are pinged on request, nobody else
+                let Some(target) = message.target.filter(|t| state.members.contains_key(t)) else {
+                    return;
+                };
+                let seq = state.next_seq();
+                state
+                    .relays
+                    .insert(seq, (src, message.from, message.seq, Instant::now()));
+                self.send(&mut state, socket, &target, None, Kind::Ping, seq, None);
+            }
+            Kind::Ack => {
+                if let Some((requester, name, seq, _)) = state.relays.remove(&message.seq) {
+                    self.send(
+                        &mut state,
+                        socket,
+                        &name,
+                        Some(requester),
+                        Kind::Ack,
+                        seq,
+                        Some(message.from),
+                    );
+                } else if state
+                    .probe
+                    .as_ref()
+                    .is_some_and(|probe| probe.seq == message.seq)
+                {
+                    state.probe = None;
+                }
+            }
+        }
+    }
+
+    // Takes in news about a member, see MemberState for which news wins.
+    // Accepted news is passed on.
+    fn apply_member(
+        &self,
+        state: &mut State,
+        name: &str,
+        member_state: MemberState,
+        incarnation: u64,
+    ) {
+        if name == self.name {
+            // we are alive, whatever they say
+            if member_state != MemberState::Alive && incarnation >= state.incarnation {
+                state.incarnation = incarnation + 1;
+                info!(
+                    "cluster refutes that {} is {}, now at incarnation {}",
+                    self.name,
+                    member_state.as_str(),
+                    state.incarnation
+                );
+                state.spread(Rumored::Member(self.name.clone()));
+            }
+            return;
+        }
+        let accept = match state.members.get(name) {
+            None => true,
+            Some(known) => {
+                incarnation > known.incarnation
+                    || (incarnation == known.incarnation && member_state > known.state)
+            }
+        };
+        if !accept {
+            return;
+        }
+        let before = state.members.get(name).map(|m| m.state);
+        state.members.insert(
+            name.to_string(),
+            Known {
+                state: member_state,
+                incarnation,
+                since: Instant::now(),
+            },
+        );
+        if before != Some(member_state) {
+            match member_state {
+                MemberState::Alive => info!("cluster member {} is alive", name),
+                MemberState::Suspect => info!("cluster member {} is suspected", name),
+                MemberState::Dead => warn!("cluster member {} is dead", name),
+            }
+        }
+        state.spread(Rumored::Member(name.to_string()));
+    }
+
+    // Another member took a backend out.
+    fn apply_down(
+        &self,
+        lb: &LoadBalancer,
+        state: &mut State,
+        backend: String,
+        duration: Duration,
+    ) {
+        // nobody is out for longer than a cooldown, whatever a datagram says
+        let cooldown = Duration::from_millis(lb.config().health.cooldown_ms);
+        let duration = duration.min(cooldown);
+        let until = Instant::now() + duration;
+        if state
+            .shared_down
+            .get(&backend)
+            .is_some_and(|known| until <= *known + DOWN_SLACK)
+        {
+            return;
+        }
+        state.shared_down.insert(backend.clone(), until);
+        let mut changed = false;
+        for pool in lb.pools.values() {
+            for b in pool.backends.iter().filter(|b| b.addr == backend) {
+                changed |= b.health.mark_down(duration);
+            }
+        }
+        if changed {
+            Metrics::inc(&lb.metrics.cluster_health_received);
+            warn!(
+                "backend {} is down for {}ms, as reported by the cluster",
+                backend,
+                duration.as_millis()
+            );
+        }
+        // members may know backends this one doesn't, pass it on anyway
+        state.spread(Rumored::Down(backend, until));
+    }
+
+    // Tells the cluster about backends this instance took out.
+    fn share_health(&self, lb: &LoadBalancer, state: &mut State, now: Instant) {
+        for backend in lb.pools.values().flat_map(|pool| &pool.backends) {
+            let Some(down_for) = backend.health.down_for() else {
+                continue;
+            };
+            let until = now + down_for;
+            if state
+                .shared_down
+                .get(&backend.addr)
+                .is_some_and(|known| until <= *known + DOWN_SLACK)
+            {
+                continue;
+            }
+            state.shared_down.insert(backend.addr.clone(), until);
+            Metrics::inc(&lb.metrics.cluster_health_shared);
+            state.spread(Rumored::Down(backend.addr.clone(), until));
+        }
+        state.shared_down.retain(|_, until| *until > now);
+    }
+
+    // Sends a message to the member `to`, at `addr` if it is known to be
+    // somewhere else than its name says.
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &self,
+        state: &mut State,
+        socket: &UdpSocket,
+        to: &str,
+        addr: Option<SocketAddr>,
+        kind: Kind,
+        seq: u64,
+        target: Option<String>,
+    ) {
+        let message = Message {
+            kind,
+            seq,
+            from: self.name.clone(),
+            incarnation: state.incarnation,
+            target,
+            news: state.news_for(&self.name, to),
+        };
+        let Ok(json) = serde_json::to_vec(&message) else {
+            return;
+        };
+        let bytes = self.seal(&json);
+        let sent = match addr {
+            Some(addr) => socket.send_to(&bytes, addr),
+            None => to
+                .to_socket_addrs()
+                .and_then(|mut addrs| {
+                    addrs
+                        .next()
+                        .ok_or_else(|| std::io::Error::other("no address"))
+                })
+                .and_then(|addr| socket.send_to(&bytes, addr)),
+        };
+        if let Err(e) = sent {
+            debug!("gossip to {} failed: {}", to, e);
+        }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(self.config.secret.as_bytes())
+            .expect("HMAC takes keys of any length")
+    }
+
+    // A datagram for the wire: the tag, then the message.
+    fn seal(&self, json: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(json);
+        let mut bytes = mac.finalize().into_bytes().to_vec();
+        bytes.extend_from_slice(json);
+        bytes
+    }
+
+    // The message in a datagram, if its tag checks out.
+    fn open<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let (tag, json) = datagram.split_at_checked(TAG_LEN)?;
+        let mut mac = self.mac();
+        mac.update(json);
+        mac.verify_slice(tag).ok()?;
+        Some(json)
+    }
+}
+
+impl State {
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    // Queues news to be passed on, replacing older news about the same
+    // member or backend.
+    fn spread(&mut self, news: Rumored) {
+        let key = |n: &Rumored| match n {
+            Rumored::Member(name) => (0, name.clone()),
+            Rumored::Down(backend, _) => (1, backend.clone()),
+        };
+        let k = key(&news);
+        self.rumors.retain(|r| key(&r.news) != k);
+        self.rumors.push(Rumor { news, sent: 0 });
+    }
+
+    // The news that goes with the next message to `to`, least passed on
+    // first.
+    fn news_for(&mut self, me: &str, to: &str) -> Vec<News> {
+        let now = Instant::now();
+        let mut news = Vec::new();
+        // a member that is thought badly of gets to hear it, so it can refute
+        if let Some(known) = self.members.get(to)
+            && known.state != MemberState::Alive
+        {
+            news.push(News::Member {
+                name: to.to_string(),
+                state: known.state,
+                incarnation: known.incarnation,
+            });
+        }
+        let limit = RETRANSMIT_MULT * (self.members.len() as u32 + 2).ilog2().max(1);
+        self.rumors.sort_by_key(|r| r.sent);
+        for rumor in self.rumors.iter_mut() {
+            if news.len() >= MAX_NEWS {
+                break;
+            }
+            let piece = match &rumor.news {
+                Rumored::Member(name) if name == me => News::Member {
+                    name: name.clone(),
+                    state: MemberState::Alive,
+                    incarnation: self.incarnation,
+                },
+                Rumored::Member(name) => match self.members.get(name) {
+                    Some(known) => News::Member {
+                        name: name.clone(),
+                        state: known.state,
+                        incarnation: known.incarnation,
+                    },
+                    None => continue,
+                },
+                Rumored::Down(backend, until) => News::Down {
+                    backend: backend.clone(),
+                    for_ms: until.saturating_duration_since(now).as_millis() as u64,
+                },
+            };
+            rumor.sent += 1;
+            news.push(piece);
+        }
+        self.rumors.retain(|r| {
+            r.sent < limit && !matches!(r.news, Rumored::Down(_, until) if until <= now)
+        });
+        news
+    }
+}
+
+fn shuffle(items: &mut [String]) {
+    for i in (1..items.len()).rev() {
+        let j = (rng::random_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+// Gossips with the other instances, forever. Runs on its own thread.
+pub fn run(lb: Arc<LoadBalancer>) {
+    let cluster = &lb.cluster;
+    let socket = match UdpSocket::bind(&cluster.config.bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("cluster failed to bind {}: {}", cluster.config.bind, e);
+            return;
+        }
+    };
+    info!(
+        "cluster gossip on {} as {}",
+        cluster.config.bind, cluster.name
+    );
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let wait = cluster.tick(&lb, &socket);
+        let _ = socket.set_read_timeout(Some(wait));
+        let Ok((len, src)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        let Some(json) = cluster.open(&buf[..len]) else {
+            Metrics::inc(&lb.metrics.cluster_rejected);
+            debug!("unsigned gossip from {}", src);
+            continue;
+        };
+        match serde_json::from_slice::<Message>(json) {
+            Ok(message) => cluster.receive(&lb, &socket, message, src),
+            Err(e) => debug!("bad gossip from {}: {}", src, e),
+        }
+    }
+}
+
+// members by state, for the metrics
+pub fn count(members: &[Member], state: MemberState) -> u64 {
+    members.iter().filter(|m| m.state == state).count() as u64
+}
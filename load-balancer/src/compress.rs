@@ -0,0 +1,456 @@
+/*
+ * Response compression.
+ *
+ * When the client accepts gzip or brotli and the backend sent a plain
+ * response of a compressible type, the body is compressed on its way
+ * through. It is streamed like any other body: each piece read from the
+ * backend is compressed and flushed to the client right away, so nothing
+ * is held back beyond what the encoder needs.
+ *
+ * The compressed length isn't known upfront, so the response goes out
+ * chunked (or until close for HTTP/1.0 clients). Vary gets Accept-Encoding
+ * and a strong ETag is made weak, the bytes no longer match the backend's.
+ */
+use std::io::{self, BufRead, Read, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+
+use crate::http::{self, BodyKind, BodyReader, CopyError, Headers, RequestHead, ResponseHead};
+
+// gzip level and brotli quality, both on the fast side since every
+// response is compressed again
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    // compress on routes that don't say otherwise
+    pub enabled: bool,
+    // smaller responses aren't worth it, the framing would eat the gain.
+    // Responses without a length are always compressed.
+    pub min_bytes: u64,
+    // content types to compress, "text/*" matches every text type
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            min_bytes: 1024,
+            types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+// The encoding to compress `response` with, or None to pass it through.
+// `body` is how the backend framed the body.
+pub fn choose(
+    config: &CompressionConfig,
+    request: &RequestHead,
+    response: &ResponseHead,
+    body: BodyKind,
+) -> Option<Encoding> {
+    if response.status != 200
+        || body == BodyKind::Empty
+        || response.headers.get("content-encoding").is_some()
+        || response.headers.has_token("cache-control", "no-transform")
+        || matches!(body, BodyKind::Length(n) if n < config.min_bytes)
+    {
+        return None;
+    }
+    let content_type = response.headers.get("content-type")?;
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let listed = config.types.iter().any(|t| match t.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next() == Some(prefix),
+        None => *t == mime,
+    });
+    if !listed {
+        return None;
+    }
+    negotiate(&request.headers)
+}
+
+// Picks from the client's Accept-Encoding by q-value, brotli on a tie.
+fn negotiate(headers: &Headers) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    let mut wildcard = None;
+    let mut refused = Vec::new();
+    for item in headers.tokens("accept-encoding") {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let encoding = match name.as_str() {
+            "br" => Encoding::Brotli,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            "*" => {
+                wildcard = Some(q);
+                continue;
+            }
+            _ => continue,
+        };
+        if q <= 0.0 {
+            refused.push(encoding);
+        } else if best.is_none_or(|(best, best_q)| {
+            q > best_q || (q == best_q && encoding == Encoding::Brotli && best != encoding)
+        }) {
+            best = Some((encoding, q));
+        }
+    }
+    match (best, wildcard) {
+        (Some((encoding, _)), _) => Some(encoding),
+        (None, Some(q)) if q > 0.0 => [Encoding::Brotli, Encoding::Gzip]
+            .into_iter()
+            .find(|e| !refused.contains(e)),
+        _ => None,
+    }
+}
+
+// Fixes up the headers of a response that is about to be compressed.
+pub fn prepare(response: &mut ResponseHead, encoding: Encoding) {
+    let headers = &mut response.headers;
+    headers.remove("content-length");
+    headers.set("content-encoding", encoding.as_str());
+    if !headers.has_token("vary", "accept-encoding") {
+        let vary = match headers.get("vary") {
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".to_string(),
+        };
+        headers.set("vary", &vary);
+    }
+    if let Some(etag) = headers.get("etag")
+        && !etag.starts_with("W/")
+    {
+        let weak = format!("W/{}", etag);
+        headers.set("etag", &weak);
+    }
+}
+
+// Compresses a whole body in memory, for responses served from the cache.
+pub fn encode(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(encoding, &mut out);
+    // writing to a Vec can't fail
+    let _ = encoder.write_all(body);
+    encoder.finish();
+    out
+}
+
+// Like http::copy_body_with, but the body is compressed before it is framed
+// as `to`. `inspect` sees the uncompressed body. Returns the number of
+// compressed bytes sent.
+pub fn copy_body(
+    reader: &mut impl BufRead,
+    from: BodyKind,
+    writer: &mut impl Write,
+    to: BodyKind,
+    encoding: Encoding,
+    mut inspect: impl FnMut(&[u8]),
+) -> Result<u64, CopyError> {
+    let mut body = BodyReader::new(reader, from);
+    let mut framed = Framed {
+        inner: writer,
+        kind: to,
+        written: 0,
+        error: None,
+    };
+    let mut encoder = Encoder::new(encoding, &mut framed);
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = body.read(&mut buf).map_err(CopyError::Read)?;
+        if n == 0 {
+            break;
+        }
+        inspect(&buf[..n]);
+        encoder
+            .write_all(&buf[..n])
+            .and_then(|_| encoder.flush())
+            .map_err(CopyError::Write)?;
+    }
+    encoder.finish();
+    if let Some(e) = framed.error {
+        return Err(CopyError::Write(e));
+    }
+    http::finish_body(framed.inner, to).map_err(CopyError::Write)?;
+    Ok(framed.written)
+}
+
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Brotli(Box<brotli::CompressorWriter<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(encoding: Encoding, writer: W) -> Encoder<W> {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, Compression::new(GZIP_LEVEL))),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                writer,
+                16 * 1024,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+
+    // Writes the end of the stream. Errors land in the writer, see Framed.
+    fn finish(self) {
+        match self {
+            Encoder::Gzip(encoder) => {
+                let _ = encoder.finish();
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.into_inner();
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Frames the encoder's output for the client and counts it. The brotli
+// encoder drops the error of its final write, so the first error is also
+// kept here.
+struct Framed<'a, W: Write> {
+    inner: &'a mut W,
+    kind: BodyKind,
+    written: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Write for Framed<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match http::write_chunk(self.inner, buf, self.kind) {
+            Ok(()) => {
+                self.written += buf.len() as u64;
+                Ok(buf.len())
+            }
+            Err(e) => {
+                let kind = e.kind();
+                self.error.get_or_insert(e);
+                Err(kind.into())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::http::HeadLimits;
+
+    fn request(accept: &str) -> RequestHead {
+        let text = format!(
+            "GET / HTTP/1.1\r\nhost: a\r\naccept-encoding: {}\r\n\r\n",
+            accept
+        );
+        RequestHead::read(&mut text.as_bytes(), &HeadLimits::NONE)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn response(head: &str) -> ResponseHead {
+        ResponseHead::read(&mut format!("HTTP/1.1 {}\r\n\r\n", head).as_bytes()).unwrap()
+    }
+
+    fn accepted(accept: &str) -> Option<Encoding> {
+        negotiate(&request(accept).headers)
+    }
+
+    fn decode(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match encoding {
+            Encoding::Gzip => GzDecoder::new(body).read_to_end(&mut out),
+            Encoding::Brotli => brotli::Decompressor::new(body, 4096).read_to_end(&mut out),
+        }
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn accept_encoding_is_negotiated_by_q_value() {
+        assert_eq!(accepted("gzip"), Some(Encoding::Gzip));
+        assert_eq!(accepted("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(accepted("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(accepted("BR;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(accepted("gzip;q=1.0, br"), Some(Encoding::Brotli));
+        assert_eq!(accepted("deflate, identity"), None);
+        assert_eq!(accepted("gzip;q=0, br;q=0"), None);
+        assert_eq!(accepted("gzip;q=bogus"), Some(Encoding::Gzip));
+        // the wildcard covers whatever isn't named
+        assert_eq!(accepted("*"), Some(Encoding::Brotli));
+        assert_eq!(accepted("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(accepted("br;q=0, gzip;q=0, *"), None);
+        assert_eq!(accepted("*;q=0"), None);
+        assert_eq!(
+            accepted("identity, *;q=0.1, gzip;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn only_plain_compressible_responses_are_chosen() {
+        let config = CompressionConfig::default();
+        let client = request("gzip");
+        let choose = |head: &str, body| choose(&config, &client, &response(head), body);
+        let html = "200 OK\r\ncontent-type: text/html; charset=utf-8";
+        assert_eq!(choose(html, BodyKind::Length(5000)), Some(Encoding::Gzip));
+        assert_eq!(choose(html, BodyKind::Chunked), Some(Encoding::Gzip));
+        assert_eq!(choose(html, BodyKind::UntilClose), Some(Encoding::Gzip));
+        assert_eq!(
+            choose(
+                "200 OK\r\ncontent-type: Application/JSON",
+                BodyKind::Chunked
+            ),
+            Some(Encoding::Gzip)
+        );
+
+        // too small, or nothing to compress
+        assert_eq!(choose(html, BodyKind::Length(1023)), None);
+        assert_eq!(choose(html, BodyKind::Empty), None);
+        // not a listed type, or no type at all
+        assert_eq!(
+            choose("200 OK\r\ncontent-type: image/png", BodyKind::Chunked),
+            None
+        );
+        assert_eq!(
+            choose("200 OK\r\ncontent-type: textual/x", BodyKind::Chunked),
+            None
+        );
+        assert_eq!(choose("200 OK", BodyKind::Chunked), None);
+        // already encoded, or the backend forbids it
+        assert_eq!(
+            choose(
+                &format!("{}\r\ncontent-encoding: br", html),
+                BodyKind::Chunked
+            ),
+            None
+        );
+        assert_eq!(
+            choose(
+                &format!("{}\r\ncache-control: public, no-transform", html),
+                BodyKind::Chunked
+            ),
+            None
+        );
+        assert_eq!(
+            choose("206 Partial\r\ncontent-type: text/html", BodyKind::Chunked),
+            None
+        );
+        // the client doesn't want it
+        let client = request("identity");
+        assert_eq!(
+            super::choose(&config, &client, &response(html), BodyKind::Chunked),
+            None
+        );
+    }
+
+    #[test]
+    fn prepared_headers() {
+        let mut head = response("200 OK\r\ncontent-length: 5000\r\nvary: Cookie\r\netag: \"v1\"");
+        prepare(&mut head, Encoding::Brotli);
+        assert_eq!(head.headers.get("content-length"), None);
+        assert_eq!(head.headers.get("content-encoding"), Some("br"));
+        assert_eq!(head.headers.get("vary"), Some("Cookie, Accept-Encoding"));
+        assert_eq!(head.headers.get("etag"), Some("W/\"v1\""));
+
+        // nothing is added twice
+        let mut head = response("200 OK\r\nvary: accept-encoding\r\netag: W/\"v1\"");
+        prepare(&mut head, Encoding::Gzip);
+        assert_eq!(head.headers.get("vary"), Some("accept-encoding"));
+        assert_eq!(head.headers.get("etag"), Some("W/\"v1\""));
+        let mut head = response("200 OK");
+        prepare(&mut head, Encoding::Gzip);
+        assert_eq!(head.headers.get("vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        let body = "all work and no play makes jack a dull boy\n".repeat(500);
+        for encoding in [Encoding::Gzip, Encoding::Brotli] {
+            let encoded = encode(encoding, body.as_bytes());
+            assert!(encoded.len() < body.len() / 10);
+            assert_eq!(decode(encoding, &encoded), body.as_bytes());
+
+            // streamed and chunked, from a backend that sent a length
+            let mut from = body.as_bytes();
+            let (mut out, mut seen) = (Vec::new(), 0);
+            let sent = copy_body(
+                &mut from,
+                BodyKind::Length(body.len() as u64),
+                &mut out,
+                BodyKind::Chunked,
+                encoding,
+                |piece| seen += piece.len(),
+            )
+            .unwrap();
+            assert_eq!(seen, body.len());
+            assert!(out.ends_with(b"0\r\n\r\n"));
+            let mut dechunked = Vec::new();
+            BodyReader::new(&mut out.as_slice(), BodyKind::Chunked)
+                .read_to_end(&mut dechunked)
+                .unwrap();
+            assert_eq!(sent, dechunked.len() as u64);
+            assert_eq!(decode(encoding, &dechunked), body.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,551 @@
+/*
+ * Configuration for the load balancer.
+ *
+ * The config lives in a TOML file. Every field has a default, so a missing
+ * file (or a file that only sets a couple of keys) still gives a working
+ * setup that listens on SERVER_ADDR and forwards to two local backends.
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::strategy::StrategyKind;
+use crate::stream;
+
+// The types the config is made of, so code that embeds the load balancer
+// can build a Config without a file. The compiled forms (Acl, Auth, ...)
+// are made from their *Config with TryFrom, like serde does.
+pub use crate::acl::{Acl, AclConfig};
+pub use crate::auth::{Auth, AuthConfig};
+pub use crate::cache::CacheConfig;
+pub use crate::cluster::ClusterConfig;
+pub use crate::compress::CompressionConfig;
+pub use crate::error_page::{ErrorPages, Maintenance, MaintenanceConfig, Template};
+pub use crate::fault::{Fault, FaultConfig};
+pub use crate::grpc::{Code, GrpcConfig};
+pub use crate::health::{Curve, HealthConfig, SlowStartConfig};
+pub use crate::limit::LimitsConfig;
+pub use crate::rewrite::{
+    HeaderRules, HeaderRulesConfig, Redirect, RedirectConfig, Rewrite, RewriteConfig,
+};
+pub use crate::trace::TracingConfig;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // address the proxy accepts clients on, host:port or unix:/path
+    pub listen: String,
+    // stands in for the address of clients on a unix: listener, see
+    // stream.rs
+    pub unix_client_ip: IpAddr,
+    // address of the admin server (metrics etc.)
+    pub admin: String,
+    // what is proxied, HTTP requests or plain TCP connections
+    pub mode: Mode,
+    // in tcp mode, move bytes with splice(2) where the platform allows it
+    pub splice: bool,
+    // accept threads, each with its own listening socket, 0 means one per
+    // CPU core
+    pub workers: usize,
+    // backend addresses, host:port or unix:/path, these make up the
+    // "default" pool
+    pub backends: Vec<String>,
+    // lower priority tiers of the default pool, see balancer.rs
+    pub secondary_backends: Vec<String>,
+    pub backup_backends: Vec<String>,
+    // more pools by name, for example a shadow pool to mirror traffic to
+    pub pools: BTreeMap<String, PoolConfig>,
+    // how each pool picks a backend, see strategy.rs
+    pub strategy: StrategyKind,
+    // idle keep-alive connections kept open towards the backends
+    pub keepalive: KeepAliveConfig,
+    // print one line per request to stdout
+    pub access_log: bool,
+    // clients checked right after accept, denied ones are disconnected
+    pub acl: Acl,
+    // caps on client and backend connections, see limit.rs
+    pub limits: LimitsConfig,
+    // when a backend is taken out of rotation, see health.rs
+    pub health: HealthConfig,
+    // how a backend that comes back is eased into rotation
+    pub slow_start: SlowStartConfig,
+    // gzip/brotli for responses, routes can turn it on or off
+    pub compression: CompressionConfig,
+    // responses stored for routes with `cache = true`
+    pub cache: CacheConfig,
+    // span export, see trace.rs
+    pub tracing: TracingConfig,
+    // gRPC health, retries and streams, see grpc.rs
+    pub grpc: GrpcConfig,
+    // bodies for the errors the load balancer sends itself, see error_page.rs
+    pub error_pages: ErrorPages,
+    // gossip with other instances, see cluster.rs
+    pub cluster: ClusterConfig,
+    // default timeouts, routes can override each one
+    pub timeouts: Timeouts,
+    pub routes: Vec<RouteConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: crate::SERVER_ADDR.to_string(),
+            unix_client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admin: "127.0.0.1:9090".to_string(),
+            mode: Mode::Http,
+            splice: true,
+            workers: 0,
+            backends: vec!["127.0.0.1:3001".to_string(), "127.0.0.1:3002".to_string()],
+            secondary_backends: Vec::new(),
+            backup_backends: Vec::new(),
+            pools: BTreeMap::new(),
+            strategy: StrategyKind::default(),
+            keepalive: KeepAliveConfig::default(),
+            access_log: true,
+            acl: Acl::default(),
+            limits: LimitsConfig::default(),
+            health: HealthConfig::default(),
+            slow_start: SlowStartConfig::default(),
+            compression: CompressionConfig::default(),
+            cache: CacheConfig::default(),
+            tracing: TracingConfig::default(),
+            grpc: GrpcConfig::default(),
+            error_pages: ErrorPages::default(),
+            cluster: ClusterConfig::default(),
+            timeouts: Timeouts::default(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+// Settings given on the command line. They win over the config file, on
+// reload too.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub listen: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // parse requests, route them and pool backend connections
+    Http,
+    // pair each client connection with a backend connection and relay
+    // bytes, only the default pool and the connect and idle timeouts apply
+    Tcp,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAliveConfig {
+    // how many idle connections we keep per backend, 0 disables pooling
+    pub max_idle: usize,
+    // an idle connection older than this is closed instead of reused
+    pub idle_timeout_ms: u64,
+    // a connection is never reused once it has been open this long
+    pub max_lifetime_ms: u64,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        KeepAliveConfig {
+            max_idle: 32,
+            idle_timeout_ms: 30_000,
+            max_lifetime_ms: 300_000,
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout_ms)
+    }
+
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_millis(self.max_lifetime_ms)
+    }
+}
+
+// All timeouts are in milliseconds, 0 turns a timeout off. A field that is
+// left out on a route falls back to the global value.
+//
+// What happens when one expires:
+//   connect     dialing the backend took too long      -> 502
+//   first_byte  the backend didn't start its response  -> 504
+//   idle        no progress reading/writing either side -> connection closed
+//   total       the whole request took too long        -> 504, or closed if
+//                                                         the response started
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub connect_ms: Option<u64>,
+    pub first_byte_ms: Option<u64>,
+    pub idle_ms: Option<u64>,
+    pub total_ms: Option<u64>,
+}
+
+// the pool made of the top level `backends`
+pub const DEFAULT_POOL: &str = "default";
+
+// used for anything neither the route nor the global section sets
+const DEFAULT_TIMEOUTS: Timeouts = Timeouts {
+    connect_ms: Some(5_000),
+    first_byte_ms: Some(60_000),
+    idle_ms: Some(60_000),
+    total_ms: None,
+};
+
+impl Timeouts {
+    // these timeouts with the gaps filled in from `defaults`
+    pub fn or(&self, defaults: &Timeouts) -> Timeouts {
+        Timeouts {
+            connect_ms: self.connect_ms.or(defaults.connect_ms),
+            first_byte_ms: self.first_byte_ms.or(defaults.first_byte_ms),
+            idle_ms: self.idle_ms.or(defaults.idle_ms),
+            total_ms: self.total_ms.or(defaults.total_ms),
+        }
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        millis(self.connect_ms)
+    }
+
+    pub fn first_byte(&self) -> Option<Duration> {
+        millis(self.first_byte_ms)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        millis(self.idle_ms)
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        millis(self.total_ms)
+    }
+}
+
+fn millis(ms: Option<u64>) -> Option<Duration> {
+    ms.filter(|&ms| ms > 0).map(Duration::from_millis)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    // the primary tier
+    pub backends: Vec<String>,
+    // used when the tiers above run short of healthy backends
+    pub secondary_backends: Vec<String>,
+    pub backup_backends: Vec<String>,
+}
+
+impl PoolConfig {
+    // the backends of each tier, primary first
+    pub fn tiers(&self) -> [&[String]; 3] {
+        [
+            &self.backends,
+            &self.secondary_backends,
+            &self.backup_backends,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    // used to refer to the route from the admin API
+    pub name: Option<String>,
+    // requests whose path starts with this use the route
+    pub path_prefix: String,
+    // the pool requests are sent to, unless `split` is set
+    pub pool: Option<String>,
+    pub timeouts: Timeouts,
+    // clients this route refuses with 403
+    pub acl: Acl,
+    // clients must log in, see auth.rs
+    pub auth: Option<Auth>,
+    // copy some of the requests to another pool, see MirrorConfig
+    pub mirror: Option<MirrorConfig>,
+    // divide the requests between several pools, see SplitConfig
+    pub split: Option<SplitConfig>,
+    // serve GET responses from the cache where the backend allows it,
+    // ignored on routes with `auth`
+    pub cache: bool,
+    // compress responses, overrides `compression.enabled`
+    pub compress: Option<bool>,
+    // these win over the top level error_pages
+    pub error_pages: ErrorPages,
+    // answer with 503 while the service behind the route is being worked on
+    pub maintenance: Maintenance,
+    // overrides `limits.max_body_size`
+    pub max_body_size: Option<u64>,
+    // changes the path before the request is forwarded, see rewrite.rs
+    pub rewrite: Rewrite,
+    // changed on the request to the backend and on its response
+    pub request_headers: HeaderRules,
+    pub response_headers: HeaderRules,
+    // answers with a redirect instead of forwarding
+    pub redirect: Option<Redirect>,
+    // delays and failures to inject, see fault.rs
+    pub faults: Vec<Fault>,
+}
+
+// Percentage based traffic splitting, for canary releases.
+//
+// Each client is hashed into a fixed bucket, so the same client keeps going
+// to the same pool as long as the weights stay the same. Raising the weight
+// of the last target only moves new clients over to it, nobody bounces back.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SplitConfig {
+    pub targets: Vec<SplitTarget>,
+    // what identifies a client, the header or cookie wins over the client IP
+    pub sticky_header: Option<String>,
+    pub sticky_cookie: Option<String>,
+    // lets testers force a target by naming its pool in a header or cookie
+    pub override_header: Option<String>,
+    pub override_cookie: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitTarget {
+    pub pool: String,
+    // relative weight, 95 and 5 send 95% and 5% of the clients
+    pub weight: u32,
+}
+
+// Shadow traffic: a copy of the request is sent to `pool` in the background.
+// The shadow response is thrown away and a failing shadow never affects the
+// client.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorConfig {
+    // the shadow pool, whose backends' health is tracked as usual
+    pub pool: String,
+    // share of the requests to copy, 0 to 100
+    pub percent: f64,
+    // requests with a bigger body are not mirrored, the body has to be
+    // held in memory until the shadow request is sent
+    pub max_body_bytes: usize,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        MirrorConfig {
+            pool: String::new(),
+            percent: 100.0,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file. A missing file is not an error, we just run
+    // with the defaults.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("reading {}: {}", path.display(), e)),
+        };
+        let config: Config =
+            toml::from_str(&text).map_err(|e| format!("parsing {}: {}", path.display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.backends.is_empty() {
+            return Err("at least one backend is required".to_string());
+        }
+        if self.mode == Mode::Tcp && !self.routes.is_empty() {
+            return Err("routes only apply in http mode".to_string());
+        }
+        let pools = self.all_pools();
+        let backends = pools.iter().flat_map(|(_, p)| p.tiers()).flatten();
+        let mut addrs = [&self.listen].into_iter().chain(backends);
+        if let Some(addr) = addrs.find(|a| stream::unix_path(a) == Some("")) {
+            return Err(format!("{:?} needs a socket path after unix:", addr));
+        }
+        self.tracing.validate()?;
+        self.cluster.validate()?;
+        if !(1.0..=100.0).contains(&self.slow_start.min_percent) {
+            return Err("slow_start min_percent must be between 1 and 100".to_string());
+        }
+        for (name, pool) in &self.pools {
+            if name == DEFAULT_POOL {
+                return Err(format!(
+                    "pool name {:?} is taken by the top level backends",
+                    name
+                ));
+            }
+            if pool.backends.is_empty() {
+                return Err(format!("pool {:?} has no backends", name));
+            }
+        }
+        for (name, pool) in &pools {
+            let tiers = pool.tiers();
+            let mut addrs: Vec<&String> = tiers.iter().copied().flatten().collect();
+            addrs.sort();
+            if let Some(pair) = addrs.windows(2).find(|pair| pair[0] == pair[1]) {
+                return Err(format!("pool {:?} lists {:?} twice", name, pair[0]));
+            }
+        }
+        if !(1.0..=100.0).contains(&self.health.tier_threshold_percent) {
+            return Err("health tier_threshold_percent must be between 1 and 100".to_string());
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            if !route.path_prefix.starts_with('/') {
+                return Err(format!(
+                    "route path_prefix {:?} must start with /",
+                    route.path_prefix
+                ));
+            }
+            if route.name.is_some()
+                && self.routes[..i]
+                    .iter()
+                    .any(|other| other.name == route.name)
+            {
+                return Err(format!("route name {:?} is used twice", route.name));
+            }
+            if let Some(pool) = &route.pool
+                && !self.has_pool(pool)
+            {
+                return Err(format!(
+                    "route {:?} uses unknown pool {:?}",
+                    route.path_prefix, pool
+                ));
+            }
+            if let Some(split) = &route.split {
+                if split.targets.iter().all(|t| t.weight == 0) {
+                    return Err(format!(
+                        "route {:?} split needs a target with a weight above 0",
+                        route.path_prefix
+                    ));
+                }
+                if let Some(target) = split.targets.iter().find(|t| !self.has_pool(&t.pool)) {
+                    return Err(format!(
+                        "route {:?} splits to unknown pool {:?}",
+                        route.path_prefix, target.pool
+                    ));
+                }
+            }
+            if let Some(mirror) = &route.mirror {
+                if !self.has_pool(&mirror.pool) {
+                    return Err(format!(
+                        "route {:?} mirrors to unknown pool {:?}",
+                        route.path_prefix, mirror.pool
+                    ));
+                }
+                if !(0.0..=100.0).contains(&mirror.percent) {
+                    return Err(format!(
+                        "route {:?} mirror percent must be between 0 and 100",
+                        route.path_prefix
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every pool by name, the default pool made of the top level backends
+    // first.
+    pub fn all_pools(&self) -> Vec<(&str, PoolConfig)> {
+        let default = PoolConfig {
+            backends: self.backends.clone(),
+            secondary_backends: self.secondary_backends.clone(),
+            backup_backends: self.backup_backends.clone(),
+        };
+        let mut pools = vec![(DEFAULT_POOL, default)];
+        pools.extend(
+            self.pools
+                .iter()
+                .map(|(name, pool)| (name.as_str(), pool.clone())),
+        );
+        pools
+    }
+
+    fn has_pool(&self, name: &str) -> bool {
+        name == DEFAULT_POOL || self.pools.contains_key(name)
+    }
+
+    // Checks that `new` only differs from this config in parts that can
+    // change while running. Listeners and pools are set up once at startup.
+    pub fn check_reloadable(&self, new: &Config) -> Result<(), String> {
+        let fixed = [
+            ("listen", self.listen == new.listen),
+            ("admin", self.admin == new.admin),
+            ("mode", self.mode == new.mode),
+            ("splice", self.splice == new.splice),
+            ("workers", self.workers == new.workers),
+            ("backends", self.backends == new.backends),
+            (
+                "secondary_backends",
+                self.secondary_backends == new.secondary_backends,
+            ),
+            (
+                "backup_backends",
+                self.backup_backends == new.backup_backends,
+            ),
+            ("pools", self.pools == new.pools),
+            ("strategy", self.strategy == new.strategy),
+            ("keepalive", self.keepalive == new.keepalive),
+            ("cache", self.cache == new.cache),
+            ("tracing", self.tracing == new.tracing),
+            ("cluster", self.cluster == new.cluster),
+        ];
+        match fixed.iter().find(|(_, same)| !same) {
+            Some((name, _)) => Err(format!("changing `{}` needs a restart", name)),
+            None => Ok(()),
+        }
+    }
+
+    // how many accept threads to start
+    pub fn worker_count(&self) -> usize {
+        match self.workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    pub fn route_named(&mut self, name: &str) -> Option<&mut RouteConfig> {
+        self.routes
+            .iter_mut()
+            .find(|r| r.name.as_deref() == Some(name))
+    }
+
+    // the route with the longest prefix matching `path`
+    pub fn route(&self, path: &str) -> Option<&RouteConfig> {
+        self.routes
+            .iter()
+            .filter(|r| path.starts_with(&r.path_prefix))
+            .max_by_key(|r| r.path_prefix.len())
+    }
+
+    // the timeouts used outside of any request, like between two requests
+    // on a keep-alive connection
+    pub fn global_timeouts(&self) -> Timeouts {
+        self.timeouts.or(&DEFAULT_TIMEOUTS)
+    }
+
+    // the timeouts that apply to a request for `path`
+    pub fn timeouts_for(&self, path: &str) -> Timeouts {
+        match self.route(path) {
+            Some(route) => route.timeouts.or(&self.global_timeouts()),
+            None => self.global_timeouts(),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+/*
+ * Error responses the load balancer makes up itself, and maintenance mode.
+ *
+ * When a request can't be served (no backend, a timeout, a 403 or 401)
+ * the client gets a short text/plain body by default. `error_pages` maps
+ * status codes, or "default", to template files instead, at the top level
+ * and per route. The route's pages win. A template is sent as HTML or JSON
+ * by its file extension and may use these variables:
+ *
+ *   {{status}}      503
+ *   {{reason}}      Service Unavailable
+ *   {{message}}     what went wrong, like "backend connect timeout"
+ *   {{request_id}}  the client's X-Request-Id, else the trace ID, else a
+ *                   fresh random ID
+ *   {{route}}       the route name or path prefix
+ *   {{path}}        the request path
+ *
+ * Values are escaped for the page they go into. The files are read when
+ * the config is loaded and again on every reload.
+ *
+ * A route in maintenance answers every request with 503 and its
+ * maintenance page, except for clients on the `allow` list, which go
+ * through as usual. It is switched in the config or with the admin API.
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::access_log::Termination;
+use crate::acl::IpSet;
+use crate::config::{Config, RouteConfig};
+use crate::http::{self, ResponseHead};
+use crate::proxy;
+use crate::rng;
+use crate::trace::Trace;
+
+// The loaded form of an `error_pages` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct ErrorPages(BTreeMap<String, Template>);
+
+impl TryFrom<BTreeMap<String, String>> for ErrorPages {
+    type Error = String;
+
+    fn try_from(files: BTreeMap<String, String>) -> Result<ErrorPages, String> {
+        let mut pages = BTreeMap::new();
+        for (key, path) in files {
+            let status_code = key.parse::<u16>().is_ok_and(|s| (400..600).contains(&s));
+            if key != "default" && !status_code {
+                return Err(format!(
+                    "error page key {:?} must be a status from 400 to 599 or \"default\"",
+                    key
+                ));
+            }
+            pages.insert(key, Template::load(&path)?);
+        }
+        Ok(ErrorPages(pages))
+    }
+}
+
+impl ErrorPages {
+    pub fn get(&self, status: u16) -> Option<&Template> {
+        self.0
+            .get(&status.to_string())
+            .or_else(|| self.0.get("default"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    content_type: &'static str,
+    escape: Escape,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    Html,
+    Json,
+    None,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Status,
+    Reason,
+    Message,
+    RequestId,
+    Route,
+    Path,
+}
+
+// What a template is filled in with.
+pub struct Details<'a> {
+    pub status: u16,
+    pub termination: Termination,
+    pub request_id: &'a str,
+    pub route: &'a str,
+    pub path: &'a str,
+}
+
+// The response for an error the load balancer sends itself. The connection
+// is closed after it, the request body may not have been read.
+pub fn response(
+    config: &Config,
+    route: Option<&RouteConfig>,
+    details: &Details,
+) -> (ResponseHead, Vec<u8>) {
+    let maintenance = route
+        .filter(|_| details.termination == Termination::Maintenance)
+        .map(|r| &r.maintenance);
+    let template = maintenance
+        .and_then(|m| m.page.as_ref())
+        .or_else(|| route.and_then(|r| r.error_pages.get(details.status)))
+        .or_else(|| config.error_pages.get(details.status));
+    let mut head = ResponseHead::new(details.status);
+    let body = match template {
+        Some(template) => {
+            head.headers.set("content-type", template.content_type());
+            template.render(details)
+        }
+        None => {
+            head.headers.set("content-type", "text/plain");
+            proxy::error_body(details.termination).as_bytes().to_vec()
+        }
+    };
+    head.headers.set("content-length", &body.len().to_string());
+    head.headers.set("connection", "close");
+    if let Some(secs) = maintenance.and_then(|m| m.retry_after_secs) {
+        head.headers.set("retry-after", &secs.to_string());
+    }
+    (head, body)
+}
+
+impl Template {
+    fn load(path: &str) -> Result<Template, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path, e))?;
+        let extension = path.rsplit_once('.').map_or("", |(_, ext)| ext);
+        let (content_type, escape) = match extension.to_ascii_lowercase().as_str() {
+            "html" | "htm" => ("text/html; charset=utf-8", Escape::Html),
+            "json" => ("application/json", Escape::Json),
+            _ => ("text/plain; charset=utf-8", Escape::None),
+        };
+        let mut parts = Vec::new();
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("{}: unclosed {{{{", path))?;
+            parts.push(Part::Text(rest[..start].to_string()));
+            parts.push(match rest[start + 2..start + end].trim() {
+                "status" => Part::Status,
+                "reason" => Part::Reason,
+                "message" => Part::Message,
+                "request_id" => Part::RequestId,
+                "route" => Part::Route,
+                "path" => Part::Path,
+                name => return Err(format!("{}: unknown variable {:?}", path, name)),
+            });
+            rest = &rest[start + end + 2..];
+        }
+        parts.push(Part::Text(rest.to_string()));
+        Ok(Template {
+            content_type,
+            escape,
+            parts,
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    pub(crate) fn render(&self, details: &Details) -> Vec<u8> {
+        let mut out = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Part::Status => &details.status.to_string(),
+                Part::Reason => http::reason(details.status),
+                Part::Message => proxy::error_body(details.termination).trim_end(),
+                Part::RequestId => details.request_id,
+                Part::Route => details.route,
+                Part::Path => details.path,
+            };
+            self.escape.push(&mut out, value);
+        }
+        out.into_bytes()
+    }
+}
+
+impl Escape {
+    fn push(self, out: &mut String, value: &str) {
+        match self {
+            Escape::Html => {
+                for c in value.chars() {
+                    match c {
+                        '&' => out.push_str("&amp;"),
+                        '<' => out.push_str("&lt;"),
+                        '>' => out.push_str("&gt;"),
+                        '"' => out.push_str("&quot;"),
+                        '\'' => out.push_str("&#39;"),
+                        c => out.push(c),
+                    }
+                }
+            }
+            // the template puts the quotes around it
+            Escape::Json => {
+                let quoted = serde_json::Value::from(value).to_string();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Escape::None => out.push_str(value),
+        }
+    }
+}
+
+// The ID an error page shows, so a user's report can be matched with the
+// logs or the trace.
+pub fn request_id(client: Option<&str>, trace: &Trace) -> String {
+    match (client, trace.trace_id()) {
+        (Some(id), _) => id.to_string(),
+        (None, Some(id)) => format!("{:032x}", id),
+        (None, None) => format!("{:016x}{:016x}", rng::random_u64(), rng::random_u64()),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub enabled: bool,
+    // clients that still get through, CIDR prefixes like the ACL
+    pub allow: Vec<String>,
+    // the page shown instead, the 503 error page if unset
+    pub page: Option<String>,
+    // sent as Retry-After
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "MaintenanceConfig")]
+pub struct Maintenance {
+    pub enabled: bool,
+    allow: IpSet,
+    pub page: Option<Template>,
+    pub retry_after_secs: Option<u64>,
+}
+
+impl TryFrom<MaintenanceConfig> for Maintenance {
+    type Error = String;
+
+    fn try_from(config: MaintenanceConfig) -> Result<Maintenance, String> {
+        Ok(Maintenance {
+            enabled: config.enabled,
+            allow: IpSet::parse(&config.allow)?,
+            page: config.page.as_deref().map(Template::load).transpose()?,
+            retry_after_secs: config.retry_after_secs,
+        })
+    }
+}
+
+impl Maintenance {
+    // whether a request from `ip` gets the maintenance page
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        self.enabled && !self.allow.contains(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    // Writes a template for the test `name`, the extension picks the escaping.
+    fn file(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("lb-error-page-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn details<'a>(status: u16, termination: Termination, path: &'a str) -> Details<'a> {
+        Details {
+            status,
+            termination,
+            request_id: "r1",
+            route: "api",
+            path,
+        }
+    }
+
+    fn render(name: &str, text: &str, details: &Details) -> String {
+        let template = Template::load(&file(name, text)).unwrap();
+        String::from_utf8(template.render(details)).unwrap()
+    }
+
+    #[test]
+    fn variables_are_filled_in() {
+        let details = details(504, Termination::FirstByteTimeout, "/a/b");
+        let text = "{{status}} {{ reason }}: {{message}} [{{request_id}} {{route}} {{path}}]";
+        assert_eq!(
+            render("vars.txt", text, &details),
+            "504 Gateway Timeout: backend response timeout [r1 api /a/b]"
+        );
+        assert_eq!(
+            render("plain.txt", "no variables", &details),
+            "no variables"
+        );
+
+        assert!(Template::load(&file("unknown.txt", "{{user}}")).is_err());
+        assert!(Template::load(&file("unclosed.txt", "{{status")).is_err());
+        assert!(Template::load("/nonexistent/error.html").is_err());
+    }
+
+    #[test]
+    fn values_are_escaped_for_the_page() {
+        let path = "/<script>alert('x')</script>&\"";
+        let details = details(404, Termination::BadRequest, path);
+        assert_eq!(
+            render("escape.html", "<p>{{path}}</p>", &details),
+            "<p>/&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&amp;&quot;</p>"
+        );
+        let json = render("escape.json", r#"{"path": "{{path}}"}"#, &details);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["path"], path);
+        let controls = self::details(404, Termination::BadRequest, "a\"\\\n");
+        let json = render("escape-ctl.json", r#"{"path": "{{path}}"}"#, &controls);
+        assert_eq!(json, r#"{"path": "a\"\\\n"}"#);
+        // a text page is sent as text/plain, nothing to escape
+        assert_eq!(render("escape.txt", "{{path}}", &details), path);
+
+        let content_type = |name| Template::load(&file(name, "")).unwrap().content_type();
+        assert_eq!(content_type("type.HTML"), "text/html; charset=utf-8");
+        assert_eq!(content_type("type.json"), "application/json");
+        assert_eq!(content_type("type"), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn default_page_covers_the_other_statuses() {
+        let files = [
+            ("502".to_string(), file("get-502.txt", "bad gateway page")),
+            (
+                "default".to_string(),
+                file("get-default.txt", "default page"),
+            ),
+        ];
+        let pages = ErrorPages::try_from(BTreeMap::from(files)).unwrap();
+        let text = |status| {
+            let details = details(status, Termination::BackendError, "/");
+            String::from_utf8(pages.get(status).unwrap().render(&details)).unwrap()
+        };
+        assert_eq!(text(502), "bad gateway page");
+        assert_eq!(text(503), "default page");
+        assert!(ErrorPages::default().get(502).is_none());
+
+        for key in ["200", "600", "oops"] {
+            let files = BTreeMap::from([(key.to_string(), file("get-bad.txt", ""))]);
+            assert!(ErrorPages::try_from(files).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn maintenance_lets_the_allow_list_through() {
+        let maintenance = Maintenance::try_from(MaintenanceConfig {
+            enabled: true,
+            allow: vec!["10.1.2.0/24".to_string(), "::1/128".to_string()],
+            ..MaintenanceConfig::default()
+        })
+        .unwrap();
+        assert!(maintenance.blocks("10.1.3.1".parse().unwrap()));
+        assert!(!maintenance.blocks("10.1.2.9".parse().unwrap()));
+        assert!(!maintenance.blocks("::1".parse().unwrap()));
+        let off = Maintenance {
+            enabled: false,
+            ..maintenance
+        };
+        assert!(!off.blocks("10.1.3.1".parse().unwrap()));
+
+        let bad = MaintenanceConfig {
+            allow: vec!["10.1.2.0/33".to_string()],
+            ..MaintenanceConfig::default()
+        };
+        assert!(Maintenance::try_from(bad).is_err());
+    }
+
+    #[test]
+    fn responses_pick_the_page_and_set_the_headers() {
+        let text = format!(
+            "backends = [\"127.0.0.1:1\"]\n\
+             [error_pages]\n503 = {:?}\n\
+             [[routes]]\npath_prefix = \"/api\"\n\
+             error_pages = {{ default = {:?} }}\n\
+             maintenance = {{ enabled = true, retry_after_secs = 600, page = {:?} }}\n",
+            file("response-503.html", "<p>{{status}} {{path}}</p>"),
+            file("response-route.json", r#"{"status": {{status}}}"#),
+            file("response-maintenance.html", "<p>back soon</p>"),
+        );
+        let config: Config = toml::from_str(&text).unwrap();
+        let route = config.route("/api");
+
+        let (head, body) = response(
+            &config,
+            route,
+            &details(503, Termination::Maintenance, "/api"),
+        );
+        assert_eq!(body, b"<p>back soon</p>");
+        assert_eq!(head.headers.get("retry-after"), Some("600"));
+        assert_eq!(head.headers.get("content-length"), Some("16"));
+        assert_eq!(head.headers.get("connection"), Some("close"));
+
+        // the route's pages win over the top level ones
+        let (head, body) = response(
+            &config,
+            route,
+            &details(503, Termination::QueueFull, "/api"),
+        );
+        assert_eq!(body, br#"{"status": 503}"#);
+        assert_eq!(head.headers.get("content-type"), Some("application/json"));
+        assert_eq!(head.headers.get("retry-after"), None);
+
+        let (_, body) = response(&config, None, &details(503, Termination::QueueFull, "/<x>"));
+        assert_eq!(body, b"<p>503 /&lt;x&gt;</p>");
+        let (head, body) = response(&config, None, &details(502, Termination::BackendError, "/"));
+        assert_eq!(body, b"bad gateway\n");
+        assert_eq!(head.headers.get("content-type"), Some("text/plain"));
+    }
+}
@@ -0,0 +1,283 @@
+/*
+ * Fault injection, for rehearsing backend failures without touching the
+ * backends.
+ *
+ * A route's `faults` are tried in order on each request. The first one
+ * whose `headers` all match, and whose `percent` dice roll comes up, is
+ * applied: the request is held for the delay, if any, and then aborted
+ * with the `abort` status, cut off with a TCP reset, or forwarded as usual
+ * when it is only a delay. A delay is a fixed `delay_ms`, or drawn evenly
+ * between `delay_ms` and `delay_max_ms`.
+ *
+ * Injected failures end as "fault_injected" in the access log and the
+ * termination counts, so they aren't taken for real ones, and they never
+ * count against a backend's health. gRPC calls are not affected.
+ */
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::http::RequestHead;
+use crate::rng;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    // share of the matching requests to hit, 0 to 100
+    pub percent: f64,
+    // only requests with these header values, "*" for any value
+    pub headers: BTreeMap<String, String>,
+    pub delay_ms: u64,
+    pub delay_max_ms: Option<u64>,
+    // status to answer with instead of forwarding
+    pub abort: Option<u16>,
+    // close the connection with a TCP reset instead of answering
+    pub reset: bool,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            percent: 100.0,
+            headers: BTreeMap::new(),
+            delay_ms: 0,
+            delay_max_ms: None,
+            abort: None,
+            reset: false,
+        }
+    }
+}
+
+// The checked form of a FaultConfig.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "FaultConfig")]
+pub struct Fault {
+    percent: f64,
+    headers: Vec<(String, String)>,
+    delay: (Duration, Duration),
+    outcome: Outcome,
+}
+
+// What happens to a request after the delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Forward,
+    Abort(u16),
+    Reset,
+}
+
+impl TryFrom<FaultConfig> for Fault {
+    type Error = String;
+
+    fn try_from(config: FaultConfig) -> Result<Fault, String> {
+        if !(0.0..=100.0).contains(&config.percent) {
+            return Err("fault percent must be between 0 and 100".to_string());
+        }
+        let delay_max = config.delay_max_ms.unwrap_or(config.delay_ms);
+        if delay_max < config.delay_ms {
+            return Err("fault delay_max_ms must not be below delay_ms".to_string());
+        }
+        let outcome = match (config.abort, config.reset) {
+            (Some(status), false) if (400..600).contains(&status) => Outcome::Abort(status),
+            (Some(status), false) => {
+                return Err(format!(
+                    "fault abort status must be from 400 to 599, not {}",
+                    status
+                ));
+            }
+            (None, true) => Outcome::Reset,
+            (None, false) if delay_max > 0 => Outcome::Forward,
+            (None, false) => return Err("a fault needs a delay, abort or reset".to_string()),
+            (Some(_), true) => return Err("a fault can abort or reset, not both".to_string()),
+        };
+        Ok(Fault {
+            percent: config.percent,
+            headers: config.headers.into_iter().collect(),
+            delay: (
+                Duration::from_millis(config.delay_ms),
+                Duration::from_millis(delay_max),
+            ),
+            outcome,
+        })
+    }
+}
+
+impl Fault {
+    fn matches(&self, head: &RequestHead) -> bool {
+        self.headers
+            .iter()
+            .all(|(name, want)| match head.headers.get(name) {
+                Some(_) if want == "*" => true,
+                Some(value) => value == want,
+                None => false,
+            })
+    }
+
+    fn delay(&self) -> Duration {
+        let (min, max) = self.delay;
+        min + (max - min).mul_f64(rng::random_f64())
+    }
+}
+
+// Picks the fault for `head`, if one hits, and sleeps through its delay.
+// Returns how long that was and what is left to do.
+pub fn inject(faults: &[Fault], head: &RequestHead) -> Option<(Duration, Outcome)> {
+    let fault = faults
+        .iter()
+        .find(|f| f.matches(head) && rng::chance(f.percent))?;
+    let delay = fault.delay();
+    if !delay.is_zero() {
+        thread::sleep(delay);
+    }
+    Some((delay, fault.outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::http::HeadLimits;
+
+    fn fault(config: FaultConfig) -> Fault {
+        Fault::try_from(config).unwrap()
+    }
+
+    fn abort(status: u16, headers: &[(&str, &str)]) -> Fault {
+        fault(FaultConfig {
+            abort: Some(status),
+            headers: headers
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..FaultConfig::default()
+        })
+    }
+
+    fn head(headers: &str) -> RequestHead {
+        let text = format!("GET / HTTP/1.1\r\nhost: h\r\n{}\r\n", headers);
+        RequestHead::read(&mut text.as_bytes(), &HeadLimits::NONE)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn outcome(faults: &[Fault], headers: &str) -> Option<Outcome> {
+        inject(faults, &head(headers)).map(|(_, outcome)| outcome)
+    }
+
+    #[test]
+    fn the_first_matching_fault_applies() {
+        let faults = [
+            abort(503, &[("x-fault", "abort"), ("x-user", "*")]),
+            fault(FaultConfig {
+                reset: true,
+                headers: BTreeMap::from([("x-fault".to_string(), "reset".to_string())]),
+                ..FaultConfig::default()
+            }),
+            abort(500, &[("x-fault", "*")]),
+        ];
+        assert_eq!(
+            outcome(&faults, "x-fault: abort\r\nx-user: a\r\n"),
+            Some(Outcome::Abort(503))
+        );
+        assert_eq!(outcome(&faults, "X-Fault: reset\r\n"), Some(Outcome::Reset));
+        // x-user is missing, so only the catch-all fits
+        assert_eq!(
+            outcome(&faults, "x-fault: abort\r\n"),
+            Some(Outcome::Abort(500))
+        );
+        // values match exactly
+        assert_eq!(
+            outcome(&faults, "x-fault: RESET\r\n"),
+            Some(Outcome::Abort(500))
+        );
+        assert_eq!(outcome(&faults, ""), None);
+    }
+
+    #[test]
+    fn percent_is_the_share_hit() {
+        rng::seed(49);
+        let share = |percent: f64| {
+            let faults = [fault(FaultConfig {
+                percent,
+                abort: Some(503),
+                ..FaultConfig::default()
+            })];
+            (0..10_000)
+                .filter(|_| outcome(&faults, "").is_some())
+                .count()
+        };
+        assert_eq!(share(0.0), 0);
+        assert_eq!(share(100.0), 10_000);
+        let some = share(30.0);
+        assert!((2_700..3_300).contains(&some), "{}", some);
+    }
+
+    #[test]
+    fn delays() {
+        let fixed = fault(FaultConfig {
+            delay_ms: 30,
+            ..FaultConfig::default()
+        });
+        let started = Instant::now();
+        let (delay, outcome) = inject(std::slice::from_ref(&fixed), &head("")).unwrap();
+        assert_eq!(delay, Duration::from_millis(30));
+        assert!(started.elapsed() >= delay);
+        assert_eq!(outcome, Outcome::Forward);
+
+        let ranged = fault(FaultConfig {
+            delay_ms: 100,
+            delay_max_ms: Some(200),
+            abort: Some(504),
+            ..FaultConfig::default()
+        });
+        let delays: Vec<Duration> = (0..1000).map(|_| ranged.delay()).collect();
+        let (min, max) = (Duration::from_millis(100), Duration::from_millis(200));
+        assert!(delays.iter().all(|d| (min..=max).contains(d)));
+        assert!(delays.iter().any(|d| *d < Duration::from_millis(120)));
+        assert!(delays.iter().any(|d| *d > Duration::from_millis(180)));
+    }
+
+    #[test]
+    fn bad_faults() {
+        for (config, error) in [
+            (FaultConfig::default(), "needs a delay, abort or reset"),
+            (
+                FaultConfig {
+                    percent: 101.0,
+                    reset: true,
+                    ..FaultConfig::default()
+                },
+                "between 0 and 100",
+            ),
+            (
+                FaultConfig {
+                    abort: Some(200),
+                    ..FaultConfig::default()
+                },
+                "from 400 to 599",
+            ),
+            (
+                FaultConfig {
+                    abort: Some(503),
+                    reset: true,
+                    ..FaultConfig::default()
+                },
+                "not both",
+            ),
+            (
+                FaultConfig {
+                    delay_ms: 100,
+                    delay_max_ms: Some(50),
+                    ..FaultConfig::default()
+                },
+                "delay_max_ms",
+            ),
+        ] {
+            let result = Fault::try_from(config).unwrap_err();
+            assert!(result.contains(error), "{}", result);
+        }
+    }
+}
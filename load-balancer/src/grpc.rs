@@ -0,0 +1,1253 @@
+/*
+ * gRPC, and HTTP/2 in general, over cleartext connections (h2c).
+ *
+ * A client that opens with the HTTP/2 preface gets an HTTP/2 connection
+ * (see h2.rs) instead of HTTP/1.1. Every stream on it is a call of its own:
+ * it is routed on its path, which for gRPC is /package.Service/Method, and
+ * balanced on its own, so the calls of one long-lived client connection
+ * spread over all the backends. A call has a backend connection to itself
+ * while it runs, afterwards the connection is parked for the next call to
+ * that backend, like the HTTP/1.1 ones in upstream.rs.
+ *
+ * The grpc-status a call ends with counts towards the backend's health: a
+ * code in `grpc.failure_codes` counts like a failed connect (see health.rs),
+ * anything else as a success. A call the backend refused before doing any
+ * work, or answered right away with one of `grpc.retry_codes`, is tried
+ * again on the next backend the pool picks, up to `grpc.retries` times.
+ * That takes the whole request, so a call is only retried once its request
+ * has ended, and only if it fit in `grpc.retry_buffer_bytes`.
+ *
+ * The balancer answers grpc.health.v1.Health/Check itself, so it can be
+ * probed like any gRPC server. A service is SERVING while the pool its
+ * calls are routed to has a backend that is up, the empty service name
+ * stands for the default pool.
+ *
+ * The request limits of limit.rs hold for each call as for an HTTP/1.1
+ * request. A header block over max_header_size, or one that takes longer
+ * than header_timeout_ms, ends the connection with a GOAWAY, too many
+ * headers or too long a path reset the stream. A body over max_body_size
+ * gets a 413 (RESOURCE_EXHAUSTED for gRPC) when its length is known and
+ * is reset once it gets there otherwise, and while any request is still
+ * coming in the connection has to keep up min_body_rate. Streaming calls
+ * that go quiet for long need min_body_rate = 0. A connection runs up to
+ * `grpc.max_concurrent_streams` calls, and the balancer `grpc.max_calls`
+ * over all connections. A call the client reset counts until its thread
+ * is done with the backend, further streams are refused meanwhile.
+ */
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime};
+
+use serde::Deserialize;
+
+use crate::access_log::{Entry, Termination};
+use crate::balancer::{Backend, LoadBalancer};
+use crate::config::{Config, DEFAULT_POOL, KeepAliveConfig, Timeouts};
+use crate::h2::{self, Incoming, Reader, Sender};
+use crate::http::{Headers, RequestHead};
+use crate::limit::{Counter, Violation};
+use crate::metrics::Metrics;
+use crate::proxy;
+use crate::split;
+use crate::strategy::Context;
+use crate::timeout::{self, Expired, Timed};
+use crate::trace::Trace;
+use crate::upstream;
+
+const HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    // answer grpc.health.v1.Health/Check here instead of passing it on
+    pub health_service: bool,
+    // statuses that count against the backend's health
+    pub failure_codes: Vec<Code>,
+    // how many more backends a call may be tried on
+    pub retries: u32,
+    // statuses that make a call worth another try
+    pub retry_codes: Vec<Code>,
+    // calls with a bigger request are never retried
+    pub retry_buffer_bytes: usize,
+    // calls one client connection may have open at once
+    pub max_concurrent_streams: u32,
+    // calls open at once over all client connections, 0 for no limit
+    pub max_calls: usize,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            health_service: true,
+            failure_codes: vec![
+                Code::Unknown,
+                Code::Internal,
+                Code::Unavailable,
+                Code::DataLoss,
+            ],
+            retries: 1,
+            retry_codes: vec![Code::Unavailable],
+            retry_buffer_bytes: 64 * 1024,
+            max_concurrent_streams: 100,
+            max_calls: 10_000,
+        }
+    }
+}
+
+// The status codes of gRPC, numbered as on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Code {
+    Ok,
+    Cancelled,
+    Unknown,
+    InvalidArgument,
+    DeadlineExceeded,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    OutOfRange,
+    Unimplemented,
+    Internal,
+    Unavailable,
+    DataLoss,
+    Unauthenticated,
+}
+
+impl Code {
+    pub const ALL: [Code; 17] = [
+        Code::Ok,
+        Code::Cancelled,
+        Code::Unknown,
+        Code::InvalidArgument,
+        Code::DeadlineExceeded,
+        Code::NotFound,
+        Code::AlreadyExists,
+        Code::PermissionDenied,
+        Code::ResourceExhausted,
+        Code::FailedPrecondition,
+        Code::Aborted,
+        Code::OutOfRange,
+        Code::Unimplemented,
+        Code::Internal,
+        Code::Unavailable,
+        Code::DataLoss,
+        Code::Unauthenticated,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::Ok => "ok",
+            Code::Cancelled => "cancelled",
+            Code::Unknown => "unknown",
+            Code::InvalidArgument => "invalid_argument",
+            Code::DeadlineExceeded => "deadline_exceeded",
+            Code::NotFound => "not_found",
+            Code::AlreadyExists => "already_exists",
+            Code::PermissionDenied => "permission_denied",
+            Code::ResourceExhausted => "resource_exhausted",
+            Code::FailedPrecondition => "failed_precondition",
+            Code::Aborted => "aborted",
+            Code::OutOfRange => "out_of_range",
+            Code::Unimplemented => "unimplemented",
+            Code::Internal => "internal",
+            Code::Unavailable => "unavailable",
+            Code::DataLoss => "data_loss",
+            Code::Unauthenticated => "unauthenticated",
+        }
+    }
+
+    // the grpc-status among `fields`, if there is one
+    fn from_fields(fields: &[(String, String)]) -> Option<Code> {
+        let (_, value) = fields.iter().find(|(name, _)| name == "grpc-status")?;
+        // an unknown number is UNKNOWN, as the spec says
+        let n: usize = value.parse().ok()?;
+        Some(Code::ALL.get(n).copied().unwrap_or(Code::Unknown))
+    }
+
+    // What a call that ended with `termination` before reaching a backend
+    // tells the client.
+    fn for_termination(termination: Termination) -> Code {
+        match termination {
+            Termination::AclDenied => Code::PermissionDenied,
+            Termination::Unauthorized => Code::Unauthenticated,
+            Termination::BadRequest => Code::Internal,
+            Termination::RequestTooLarge => Code::ResourceExhausted,
+            Termination::FirstByteTimeout | Termination::TotalTimeout => Code::DeadlineExceeded,
+            _ => Code::Unavailable,
+        }
+    }
+}
+
+// Idle HTTP/2 connections to the backends.
+pub struct Conns {
+    settings: KeepAliveConfig,
+    idle: Mutex<HashMap<String, Vec<(Instant, Upstream)>>>,
+}
+
+// An HTTP/2 connection to a backend, used by one call at a time.
+struct Upstream {
+    reader: Reader,
+    sender: Arc<Sender>,
+    next_stream: u32,
+    created: Instant,
+}
+
+impl Upstream {
+    fn connect(addr: &str, timeouts: &Timeouts) -> io::Result<Upstream> {
+        let stream = upstream::dial(addr, timeouts.connect())?;
+        let writer = BufWriter::new(Timed::new(stream.try_clone()?, timeouts.idle()));
+        let sender = Arc::new(Sender::new(writer)?);
+        h2::handshake(
+            &sender,
+            &[(h2::ENABLE_PUSH, 0), (h2::INITIAL_WINDOW_SIZE, h2::WINDOW)],
+            true,
+        )?;
+        Ok(Upstream {
+            // backends are trusted with their heads, as in HTTP/1.1
+            reader: Reader::new(
+                BufReader::new(Timed::new(stream, None)),
+                sender.clone(),
+                usize::MAX,
+            ),
+            sender,
+            next_stream: 1,
+            created: Instant::now(),
+        })
+    }
+}
+
+impl Conns {
+    pub fn new(settings: KeepAliveConfig) -> Conns {
+        Conns {
+            settings,
+            idle: Mutex::default(),
+        }
+    }
+
+    // A parked connection to `addr` if there is a good one, otherwise a
+    // new one. The bool says whether it was parked.
+    fn get(&self, addr: &str, timeouts: &Timeouts) -> io::Result<(Upstream, bool)> {
+        let parked = {
+            let mut idle = self.idle.lock().unwrap();
+            let list = idle.entry(addr.to_string()).or_default();
+            let mut found = None;
+            while let Some((since, mut conn)) = list.pop() {
+                // a connection the backend sent anything on while it was
+                // idle, a GOAWAY say, isn't worth the trouble
+                if self.is_fresh(since, &conn)
+                    && !conn.reader.has_buffered()
+                    && upstream::is_quiet(conn.reader.get_mut().get_ref())
+                {
+                    found = Some(conn);
+                    break;
+                }
+                conn.sender.shutdown();
+            }
+            found
+        };
+        match parked {
+            Some(conn) => Ok((conn, true)),
+            None => Ok((Upstream::connect(addr, timeouts)?, false)),
+        }
+    }
+
+    fn put(&self, addr: &str, conn: Upstream) {
+        let now = Instant::now();
+        if self.settings.max_idle == 0 || !self.is_fresh(now, &conn) {
+            conn.sender.shutdown();
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let list = idle.entry(addr.to_string()).or_default();
+        if list.len() >= self.settings.max_idle {
+            list.remove(0).1.sender.shutdown();
+        }
+        list.push((now, conn));
+    }
+
+    // Closes idle connections that expired, see ConnPool::reap.
+    pub fn reap(&self) {
+        for list in self.idle.lock().unwrap().values_mut() {
+            list.retain(|(since, conn)| {
+                let fresh = self.is_fresh(*since, conn);
+                if !fresh {
+                    conn.sender.shutdown();
+                }
+                fresh
+            });
+        }
+    }
+
+    fn is_fresh(&self, since: Instant, conn: &Upstream) -> bool {
+        since.elapsed() < self.settings.idle_timeout()
+            && conn.created.elapsed() < self.settings.max_lifetime()
+    }
+}
+
+// What the connection thread passes on to a call.
+enum Upload {
+    Data {
+        data: Vec<u8>,
+        end_stream: bool,
+        // to give back to the client's window once sent on
+        size: usize,
+    },
+    Reset,
+}
+
+// the calls open on one client connection, by stream
+type Calls = Mutex<HashMap<u32, mpsc::Sender<Upload>>>;
+
+// Serves a client connection that starts with the HTTP/2 preface, until
+// the client closes it.
+pub fn serve(
+    lb: Arc<LoadBalancer>,
+    peer: SocketAddr,
+    mut reader: BufReader<Timed>,
+    writer: BufWriter<Timed>,
+) {
+    let config = lb.config();
+    let limits = &config.limits;
+    // the preface is the first head, so to speak
+    let header_deadline = limits.header_timeout().map(|t| Instant::now() + t);
+    reader.get_mut().set_deadline(header_deadline);
+    let mut preface = [0u8; 24];
+    if reader.read_exact(&mut preface).is_err() || &preface != h2::PREFACE {
+        return;
+    }
+    reader.get_mut().set_deadline(None);
+    let sender = match Sender::new(writer) {
+        Ok(sender) => Arc::new(sender),
+        Err(_) => return,
+    };
+    let settings = [
+        (
+            h2::MAX_CONCURRENT_STREAMS,
+            config.grpc.max_concurrent_streams,
+        ),
+        (h2::INITIAL_WINDOW_SIZE, h2::WINDOW),
+        (
+            h2::MAX_HEADER_LIST_SIZE,
+            limits.max_header_size.try_into().unwrap_or(u32::MAX),
+        ),
+    ];
+    if h2::handshake(&sender, &settings, false).is_err() {
+        return;
+    }
+    let idle = config.global_timeouts().idle();
+    let calls: Arc<Calls> = Arc::default();
+    // call threads still running, a call the client reset included
+    let running = Arc::new(Counter::default());
+    // the requests the client hasn't ended yet: bytes so far and the most
+    // they may come to
+    let mut uploading: HashMap<u32, (u64, Option<u64>)> = HashMap::new();
+    let mut conn = Reader::new(reader, sender.clone(), limits.max_header_size);
+    conn.set_header_timeout(limits.header_timeout());
+    let mut last_stream = 0;
+    loop {
+        // while calls are open it is up to them to time out, but a request
+        // still coming in has to keep up the minimum rate
+        let quiet = calls.lock().unwrap().is_empty();
+        let sending = still_uploading(&calls, &mut uploading);
+        conn.get_mut().set_idle(if quiet { idle } else { None });
+        if sending != conn.get_mut().has_min_rate() {
+            conn.get_mut()
+                .set_min_rate(if sending { limits.min_rate() } else { None });
+        }
+        let incoming = match conn.next() {
+            Ok(Some(incoming)) => incoming,
+            Ok(None) => break,
+            // the calls may have ended the requests that were still open,
+            // without waking us
+            Err(e)
+                if timeout::expired(&e) == Some(Expired::MinRate)
+                    && !conn.mid_frame()
+                    && !still_uploading(&calls, &mut uploading) =>
+            {
+                continue;
+            }
+            Err(e) => {
+                let violation = match (h2::error_code(&e), timeout::expired(&e)) {
+                    (Some(h2::ENHANCE_YOUR_CALM), _) => Some(Violation::HeaderSize),
+                    (_, Some(Expired::Total)) => Some(Violation::HeaderTimeout),
+                    (_, Some(Expired::MinRate)) => Some(Violation::MinRate),
+                    _ => None,
+                };
+                if let Some(violation) = violation {
+                    debug!("{} refused: {}", peer.ip(), e);
+                    lb.metrics.violated(violation);
+                }
+                if let Some(code) = h2::error_code(&e) {
+                    let _ = sender.go_away(last_stream, code);
+                }
+                break;
+            }
+        };
+        match incoming {
+            Incoming::Headers {
+                stream,
+                fields,
+                end_stream,
+            } => {
+                let mut open = calls.lock().unwrap();
+                if let Some(call) = open.get(&stream) {
+                    // trailers, which end the client's side of the call
+                    uploading.remove(&stream);
+                    let _ = call.send(Upload::Data {
+                        data: Vec::new(),
+                        end_stream: true,
+                        size: 0,
+                    });
+                    continue;
+                }
+                if stream % 2 == 0 || stream <= last_stream {
+                    let _ = sender.go_away(last_stream, h2::PROTOCOL_ERROR);
+                    break;
+                }
+                last_stream = stream;
+                let head = request_head(&fields);
+                let violation = if fields.len() > limits.max_headers {
+                    Some(Violation::HeaderCount)
+                } else if head.target.len() > limits.max_request_line {
+                    Some(Violation::RequestLine)
+                } else {
+                    None
+                };
+                if let Some(violation) = violation {
+                    lb.metrics.violated(violation);
+                    let _ = sender.reset(stream, h2::ENHANCE_YOUR_CALM);
+                    continue;
+                }
+                // threads of calls the client reset count until they end
+                if !running.try_claim(config.grpc.max_concurrent_streams as usize) {
+                    let _ = sender.reset(stream, h2::REFUSED_STREAM);
+                    continue;
+                }
+                if !lb.grpc_calls.try_claim(config.grpc.max_calls) {
+                    running.release();
+                    let _ = sender.reset(stream, h2::REFUSED_STREAM);
+                    continue;
+                }
+                let (uploads, received) = mpsc::channel();
+                if end_stream {
+                    let _ = uploads.send(Upload::Data {
+                        data: Vec::new(),
+                        end_stream: true,
+                        size: 0,
+                    });
+                } else {
+                    uploading.insert(stream, (0, max_body(&config, &head)));
+                }
+                open.insert(stream, uploads);
+                sender.open(stream);
+                let call = Call {
+                    lb: lb.clone(),
+                    peer,
+                    client: sender.clone(),
+                    stream,
+                    calls: calls.clone(),
+                    running: running.clone(),
+                };
+                thread::spawn(move || call.run(head, received));
+            }
+            Incoming::Data {
+                stream,
+                data,
+                end_stream,
+                size,
+            } => {
+                let mut open = calls.lock().unwrap();
+                if let Some((sent, max)) = uploading.get_mut(&stream) {
+                    *sent += data.len() as u64;
+                    if max.is_some_and(|max| *sent > max) {
+                        // like a chunked HTTP/1.1 body, see limit.rs
+                        lb.metrics.violated(Violation::BodySize);
+                        uploading.remove(&stream);
+                        if let Some(call) = open.remove(&stream) {
+                            let _ = call.send(Upload::Reset);
+                        }
+                        let _ = sender.reset(stream, h2::CANCEL);
+                        continue;
+                    }
+                }
+                if end_stream {
+                    uploading.remove(&stream);
+                }
+                // data for a call that is over already is dropped
+                if let Some(call) = open.get(&stream) {
+                    let _ = call.send(Upload::Data {
+                        data,
+                        end_stream,
+                        size,
+                    });
+                }
+            }
+            Incoming::Reset { stream, .. } => {
+                uploading.remove(&stream);
+                if let Some(call) = calls.lock().unwrap().remove(&stream) {
+                    let _ = call.send(Upload::Reset);
+                }
+            }
+            // no new calls will come, the open ones go on
+            Incoming::GoAway { .. } => {}
+        }
+    }
+    // the client is gone, and so are its calls
+    sender.shutdown();
+    for (_, call) in calls.lock().unwrap().drain() {
+        let _ = call.send(Upload::Reset);
+    }
+}
+
+// Whether a call that is still open waits for more of its request. Drops
+// the requests of calls that are over.
+fn still_uploading(calls: &Calls, uploading: &mut HashMap<u32, (u64, Option<u64>)>) -> bool {
+    let open = calls.lock().unwrap();
+    uploading.retain(|stream, _| open.contains_key(stream));
+    !uploading.is_empty()
+}
+
+// The most a call's request body may be, see limit.rs.
+fn max_body(config: &Config, head: &RequestHead) -> Option<u64> {
+    let max = config
+        .route(head.path())
+        .and_then(|r| r.max_body_size)
+        .unwrap_or(config.limits.max_body_size);
+    (max > 0).then_some(max)
+}
+
+// The request body of a call, and a copy of it for retries while it is
+// small enough.
+struct RequestBody {
+    // None while an uplink thread has them
+    uploads: Option<Receiver<Upload>>,
+    uplink: Option<(Uplink, Arc<AtomicBool>)>,
+    replay: Replay,
+}
+
+// hands the uploads and the copy back when it is done
+type Uplink = JoinHandle<(Receiver<Upload>, Replay)>;
+
+#[derive(Default)]
+struct Replay {
+    data: Vec<u8>,
+    // the client ended the request
+    complete: bool,
+    // the request outgrew the buffer, it can't be sent again
+    overflow: bool,
+}
+
+impl RequestBody {
+    // Starts sending the request body on `stream` of the backend.
+    fn send(&mut self, call: &Call, backend: Arc<Sender>, stream: u32, limit: usize) {
+        let uploads = self
+            .uploads
+            .take()
+            .expect("the request is being sent already");
+        let replay = mem::take(&mut self.replay);
+        let client = call.client.clone();
+        let client_stream = call.stream;
+        let ended = Arc::new(AtomicBool::new(false));
+        let done = ended.clone();
+        let uplink = thread::spawn(move || {
+            uplink(
+                &backend,
+                stream,
+                &client,
+                client_stream,
+                uploads,
+                replay,
+                limit,
+                &done,
+            )
+        });
+        self.uplink = Some((uplink, ended));
+    }
+
+    // Whether the request could be sent again, which is when the client
+    // has ended it and the copy is complete. The backend stream must be
+    // closed, so the uplink can't be stuck sending on it.
+    fn replayable(&mut self) -> bool {
+        if let Some((uplink, ended)) = self.uplink.take() {
+            if !ended.load(Ordering::SeqCst) {
+                self.uplink = Some((uplink, ended));
+                return false;
+            }
+            match uplink.join() {
+                Ok((uploads, replay)) => {
+                    self.uploads = Some(uploads);
+                    self.replay = replay;
+                }
+                Err(_) => return false,
+            }
+        }
+        self.uploads.is_some() && !self.replay.overflow
+    }
+
+    // whether the client's side of the call was sent completely
+    fn finished(&self) -> bool {
+        match &self.uplink {
+            Some((uplink, ended)) => ended.load(Ordering::SeqCst) && uplink.is_finished(),
+            None => self.replay.complete,
+        }
+    }
+}
+
+// Sends the request body to the backend: first what an earlier attempt
+// already sent, then what the client sends. Returns the rest for a retry.
+#[allow(clippy::too_many_arguments)]
+fn uplink(
+    backend: &Sender,
+    stream: u32,
+    client: &Sender,
+    client_stream: u32,
+    uploads: Receiver<Upload>,
+    mut replay: Replay,
+    limit: usize,
+    ended: &AtomicBool,
+) -> (Receiver<Upload>, Replay) {
+    if !replay.data.is_empty() || replay.complete {
+        let sent = backend.data(stream, &replay.data, replay.complete);
+        if sent.is_err() || replay.complete {
+            ended.store(replay.complete, Ordering::SeqCst);
+            return (uploads, replay);
+        }
+    }
+    while let Ok(upload) = uploads.recv() {
+        match upload {
+            Upload::Data {
+                data,
+                end_stream,
+                size,
+            } => {
+                if !replay.overflow {
+                    if replay.data.len() + data.len() > limit {
+                        replay.overflow = true;
+                        replay.data = Vec::new();
+                    } else {
+                        replay.data.extend_from_slice(&data);
+                    }
+                }
+                replay.complete = end_stream;
+                ended.store(end_stream, Ordering::SeqCst);
+                let sent = backend.data(stream, &data, end_stream);
+                if sent.is_err() || end_stream {
+                    break;
+                }
+                let _ = client.window_update(client_stream, size);
+            }
+            Upload::Reset => {
+                let _ = backend.reset(stream, h2::CANCEL);
+                break;
+            }
+        }
+    }
+    (uploads, replay)
+}
+
+// How one attempt at a call went.
+enum Attempt {
+    // the response went to the client, with the grpc-status it ended with
+    Done(Option<Code>),
+    // nothing reached the client and the call can go to another backend
+    Retry(Termination),
+    // nothing reached the client, it gets an error
+    Failed(Termination),
+}
+
+struct Call {
+    lb: Arc<LoadBalancer>,
+    peer: SocketAddr,
+    client: Arc<Sender>,
+    // the stream on the client connection
+    stream: u32,
+    calls: Arc<Calls>,
+    // the connection's running calls, this one included
+    running: Arc<Counter>,
+}
+
+// the call's thread is done, which frees its place
+impl Drop for Call {
+    fn drop(&mut self) {
+        self.running.release();
+        self.lb.grpc_calls.release();
+    }
+}
+
+impl Call {
+    fn run(self, mut head: RequestHead, uploads: Receiver<Upload>) {
+        let lb = &self.lb;
+        let config = lb.config();
+        Metrics::inc(&lb.metrics.requests);
+        let mut entry = Entry::new(self.peer, &head.method, &head.target);
+        let mut trace = Trace::start(&config.tracing, &head);
+        let route = config
+            .route(head.path())
+            .map(|r| r.name.clone().unwrap_or_else(|| r.path_prefix.clone()));
+
+        let result = if config.grpc.health_service && head.path() == HEALTH_CHECK {
+            self.health_check(&config, uploads, &mut entry)
+        } else {
+            self.forward(&config, &mut head, uploads, &mut entry, &mut trace)
+        };
+        let code = match result {
+            Ok(code) => code,
+            Err(termination) => Some(self.respond_error(&head, termination, &mut entry)),
+        };
+        if entry.termination == Termination::RequestTooLarge {
+            lb.metrics.violated(Violation::BodySize);
+        }
+
+        if let Some(code) = code {
+            Metrics::inc(&lb.metrics.grpc_calls[code as usize]);
+        }
+        if entry.termination.is_backend_failure() {
+            Metrics::inc(&lb.metrics.upstream_errors);
+        }
+        lb.metrics.terminated(entry.termination);
+        trace.finish(&lb.tracer, &entry, route.as_deref());
+        if config.access_log {
+            entry.write();
+        }
+        self.calls.lock().unwrap().remove(&self.stream);
+        self.client.close(self.stream);
+    }
+
+    // Passes the call on to a backend. Err means nothing was sent to the
+    // client yet, so it still gets a proper error.
+    fn forward(
+        &self,
+        config: &Config,
+        head: &mut RequestHead,
+        uploads: Receiver<Upload>,
+        entry: &mut Entry,
+        trace: &mut Trace,
+    ) -> Result<Option<Code>, Termination> {
+        let started = SystemTime::now();
+        let lb = &self.lb;
+        let route = config.route(head.path());
+        if let Some(route) = route
+            && !route.acl.permits(self.peer.ip())
+        {
+            Metrics::inc(&lb.metrics.acl_denied_route);
+            return Err(Termination::AclDenied);
+        }
+        if route.is_some_and(|r| r.maintenance.blocks(self.peer.ip())) {
+            return Err(Termination::Maintenance);
+        }
+        if let Some(auth) = route.and_then(|r| r.auth.as_ref())
+            && let Err(denied) = auth.check(head)
+        {
+            debug!(
+                "{} denied on {}: {}",
+                self.peer.ip(),
+                head.path(),
+                denied.reason
+            );
+            return Err(Termination::Unauthorized);
+        }
+        // a streamed body is cut off in serve()
+        if let (Some(max), Some(length)) =
+            (max_body(config, head), head.headers.get("content-length"))
+            && length.parse::<u64>().is_ok_and(|length| length > max)
+        {
+            return Err(Termination::RequestTooLarge);
+        }
+        let pool_name = match route {
+            Some(route) => match &route.split {
+                Some(split) => split::choose(split, head, self.peer.ip()),
+                None => route.pool.as_deref().unwrap_or(DEFAULT_POOL),
+            },
+            None => DEFAULT_POOL,
+        };
+        let pool = lb.pool(pool_name).expect("route names an unknown pool");
+        Metrics::inc(&pool.requests);
+        let timeouts = config.timeouts_for(head.path());
+        let deadline = timeouts.total().map(|total| entry.start + total);
+
+        let mut headers = head.headers.clone();
+        headers.strip_hop_by_hop();
+        let forwarded_for = match head.headers.get("x-forwarded-for") {
+            Some(prev) => format!("{}, {}", prev, self.peer.ip()),
+            None => self.peer.ip().to_string(),
+        };
+        headers.set("x-forwarded-for", &forwarded_for);
+        trace.inject(&mut headers);
+        let mut request = vec![
+            (":method", head.method.as_str()),
+            (":scheme", "http"),
+            (":path", head.target.as_str()),
+            (":authority", head.headers.get("host").unwrap_or_default()),
+        ];
+        // the one hop-by-hop header HTTP/2 keeps, gRPC needs it
+        if head.headers.has_token("te", "trailers") {
+            request.push(("te", "trailers"));
+        }
+        request.extend(headers.iter().filter(|(name, _)| *name != "host"));
+
+        let context = Context {
+            client: self.peer.ip(),
+            request: Some(head),
+        };
+        let mut body = RequestBody {
+            uploads: Some(uploads),
+            uplink: None,
+            replay: Replay::default(),
+        };
+        let mut retries = config.grpc.retries;
+        loop {
+            let permit = pool
+                .acquire(config, &context, &lb.metrics)
+                .map_err(|rejected| rejected.termination())?;
+            let backend = permit.backend;
+            entry.backend = Some(backend.addr.clone());
+            trace.phase("select backend", started);
+            let attempt = self.attempt(
+                config,
+                backend,
+                &request,
+                &timeouts,
+                deadline,
+                &mut body,
+                retries > 0,
+                entry,
+            );
+            match attempt {
+                Attempt::Done(code) => return Ok(code),
+                Attempt::Retry(termination) if retries > 0 && body.replayable() => {
+                    debug!(
+                        "retrying {} after {} on {}",
+                        head.target,
+                        termination.as_str(),
+                        backend.addr
+                    );
+                    Metrics::inc(&lb.metrics.grpc_retries);
+                    retries -= 1;
+                }
+                Attempt::Retry(termination) | Attempt::Failed(termination) => {
+                    return Err(termination);
+                }
+            }
+        }
+    }
+
+    // Sends the call to `backend` and relays the response.
+    #[allow(clippy::too_many_arguments)]
+    fn attempt(
+        &self,
+        config: &Config,
+        backend: &Backend,
+        request: &[(&str, &str)],
+        timeouts: &Timeouts,
+        deadline: Option<Instant>,
+        body: &mut RequestBody,
+        may_retry: bool,
+        entry: &mut Entry,
+    ) -> Attempt {
+        let lb = &self.lb;
+        let (mut conn, reused) = match lb.grpc_conns.get(&backend.addr, timeouts) {
+            Ok(got) => got,
+            Err(e) => {
+                backend.failed(&config.health, &e);
+                return Attempt::Retry(proxy::connect_termination(&e));
+            }
+        };
+        Metrics::inc(if reused {
+            &lb.metrics.pool_hits
+        } else {
+            &lb.metrics.pool_misses
+        });
+        let stream = conn.next_stream;
+        conn.next_stream += 2;
+        conn.sender.set_timeouts(timeouts.idle(), deadline);
+        conn.reader.get_mut().set_idle(timeouts.idle());
+        conn.reader.get_mut().set_deadline(deadline);
+        conn.sender.open(stream);
+        if let Err(e) = conn.sender.headers(stream, request, false) {
+            // the request didn't get out, so it is safe to send elsewhere
+            conn.sender.shutdown();
+            return Attempt::Retry(proxy::backend_failure(&e));
+        }
+        body.send(
+            self,
+            conn.sender.clone(),
+            stream,
+            config.grpc.retry_buffer_bytes,
+        );
+
+        let first_byte = timeouts.first_byte().map(|limit| Instant::now() + limit);
+        // the response headers went to the client
+        let mut started = false;
+        // the stream ended cleanly and the connection can take another call
+        let mut reusable = true;
+        let attempt = loop {
+            if !started {
+                // frames on the connection itself don't count as an answer
+                conn.reader.get_mut().expect_first_byte(
+                    first_byte.map(|at| at.saturating_duration_since(Instant::now())),
+                );
+            }
+            let incoming = match conn.reader.next() {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => {
+                    let e = io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "backend closed the connection",
+                    );
+                    break self.broken(&e, started, &mut reusable, entry);
+                }
+                Err(e) => {
+                    if let Some(code) = h2::error_code(&e) {
+                        let _ = conn.sender.go_away(0, code);
+                    }
+                    break self.broken(&e, started, &mut reusable, entry);
+                }
+            };
+            match incoming {
+                Incoming::Headers {
+                    stream: s,
+                    fields,
+                    end_stream,
+                } if s == stream => {
+                    let code = Code::from_fields(&fields);
+                    if end_stream {
+                        judge(config, backend, code);
+                    }
+                    // a call that failed right away may go elsewhere
+                    if !started
+                        && end_stream
+                        && may_retry
+                        && code.is_some_and(|c| config.grpc.retry_codes.contains(&c))
+                    {
+                        conn.sender.close(stream);
+                        if body.replayable() {
+                            break Attempt::Retry(Termination::BackendError);
+                        }
+                    }
+                    if !started {
+                        started = true;
+                        entry.status = fields
+                            .iter()
+                            .find(|(name, _)| name == ":status")
+                            .and_then(|(_, status)| status.parse().ok());
+                    }
+                    let fields: Vec<(&str, &str)> = fields
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect();
+                    if self
+                        .client
+                        .headers(self.stream, &fields, end_stream)
+                        .is_err()
+                    {
+                        entry.termination = Termination::ClientClosed;
+                        reusable = false;
+                        break Attempt::Done(code);
+                    }
+                    if end_stream {
+                        break Attempt::Done(code);
+                    }
+                }
+                Incoming::Data {
+                    stream: s,
+                    data,
+                    end_stream,
+                    size,
+                } if s == stream => {
+                    if self.client.data(self.stream, &data, end_stream).is_err() {
+                        entry.termination = Termination::ClientClosed;
+                        reusable = false;
+                        break Attempt::Done(None);
+                    }
+                    entry.bytes += data.len() as u64;
+                    if end_stream {
+                        backend.health.succeeded();
+                        break Attempt::Done(None);
+                    }
+                    if conn.sender.window_update(stream, size).is_err() {
+                        reusable = false;
+                    }
+                }
+                Incoming::Reset { stream: s, code } if s == stream => {
+                    if !started && code == h2::REFUSED_STREAM {
+                        // the backend did no work on it
+                        break Attempt::Retry(Termination::BackendError);
+                    }
+                    entry.termination = Termination::BackendError;
+                    if !started {
+                        break Attempt::Failed(Termination::BackendError);
+                    }
+                    let _ = self.client.reset(self.stream, code);
+                    break Attempt::Done(None);
+                }
+                Incoming::GoAway { last_stream } => {
+                    reusable = false;
+                    if last_stream < stream && !started {
+                        // never looked at, so it can go elsewhere
+                        break Attempt::Retry(Termination::BackendError);
+                    }
+                }
+                // frames for streams of earlier calls
+                _ => {}
+            }
+        };
+
+        conn.sender.close(stream);
+        // the backend may have answered before the client was done
+        if reusable && body.finished() {
+            lb.grpc_conns.put(&backend.addr, conn);
+        } else {
+            conn.sender.shutdown();
+        }
+        attempt
+    }
+
+    // The backend connection broke or timed out with `e`.
+    fn broken(
+        &self,
+        e: &io::Error,
+        started: bool,
+        reusable: &mut bool,
+        entry: &mut Entry,
+    ) -> Attempt {
+        *reusable = false;
+        let termination = proxy::backend_failure(e);
+        if !started {
+            return Attempt::Failed(termination);
+        }
+        entry.termination = termination;
+        let _ = self.client.reset(self.stream, h2::INTERNAL_ERROR);
+        Attempt::Done(None)
+    }
+
+    // Answers a call that never reached a backend. gRPC clients get the
+    // status in a response without a body, others an HTTP error.
+    fn respond_error(
+        &self,
+        head: &RequestHead,
+        termination: Termination,
+        entry: &mut Entry,
+    ) -> Code {
+        entry.termination = termination;
+        let code = Code::for_termination(termination);
+        let message = proxy::error_body(termination);
+        let grpc = head
+            .headers
+            .get("content-type")
+            .is_some_and(|t| t.starts_with("application/grpc"));
+        if grpc {
+            entry.status = Some(200);
+            let status = (code as u32).to_string();
+            let _ = self.client.headers(
+                self.stream,
+                &[
+                    (":status", "200"),
+                    ("content-type", "application/grpc"),
+                    ("grpc-status", &status),
+                    ("grpc-message", message.trim_end()),
+                ],
+                true,
+            );
+        } else {
+            let status = http_status(termination);
+            entry.status = Some(status);
+            let status = status.to_string();
+            let length = message.len().to_string();
+            let _ = self
+                .client
+                .headers(
+                    self.stream,
+                    &[
+                        (":status", &status),
+                        ("content-type", "text/plain"),
+                        ("content-length", &length),
+                    ],
+                    false,
+                )
+                .and_then(|_| self.client.data(self.stream, message.as_bytes(), true));
+        }
+        code
+    }
+
+    // Answers grpc.health.v1.Health/Check.
+    fn health_check(
+        &self,
+        config: &Config,
+        uploads: Receiver<Upload>,
+        entry: &mut Entry,
+    ) -> Result<Option<Code>, Termination> {
+        let mut body = Vec::new();
+        loop {
+            match uploads.recv() {
+                Ok(Upload::Data {
+                    data,
+                    end_stream,
+                    size,
+                }) => {
+                    body.extend_from_slice(&data);
+                    if end_stream {
+                        break;
+                    }
+                    if body.len() > 4096 {
+                        return Err(Termination::BadRequest);
+                    }
+                    let _ = self.client.window_update(self.stream, size);
+                }
+                Ok(Upload::Reset) | Err(_) => {
+                    entry.termination = Termination::ClientClosed;
+                    return Ok(None);
+                }
+            }
+        }
+        let service = match health_service(&body) {
+            Some(service) => service,
+            None => return Err(Termination::BadRequest),
+        };
+        // 1 is SERVING, 2 NOT_SERVING
+        let status = if self.serving(config, &service) { 1 } else { 2 };
+        let mut message = vec![0, 0, 0, 0, 2];
+        message.extend_from_slice(&[0x08, status]);
+        entry.status = Some(200);
+        entry.bytes = message.len() as u64;
+        let sent = self
+            .client
+            .headers(
+                self.stream,
+                &[(":status", "200"), ("content-type", "application/grpc")],
+                false,
+            )
+            .and_then(|_| self.client.data(self.stream, &message, false))
+            .and_then(|_| {
+                self.client
+                    .headers(self.stream, &[("grpc-status", "0")], true)
+            });
+        if sent.is_err() {
+            entry.termination = Termination::ClientClosed;
+        }
+        Ok(Some(Code::Ok))
+    }
+
+    // Whether the pool that calls to `service` go to has a backend up.
+    fn serving(&self, config: &Config, service: &str) -> bool {
+        let route = match service {
+            "" => None,
+            service => config.route(&format!("/{}/", service)),
+        };
+        let pools = match route {
+            Some(route) => match &route.split {
+                Some(split) => split.targets.iter().map(|t| t.pool.as_str()).collect(),
+                None => vec![route.pool.as_deref().unwrap_or(DEFAULT_POOL)],
+            },
+            None => vec![DEFAULT_POOL],
+        };
+        pools
+            .iter()
+            .filter_map(|name| self.lb.pool(name))
+            .any(|pool| pool.backends.iter().any(|b| !b.health.is_down()))
+    }
+}
+
+// Counts the status a backend ended a call with towards its health.
+fn judge(config: &Config, backend: &Backend, code: Option<Code>) {
+    match code {
+        Some(code) if config.grpc.failure_codes.contains(&code) => {
+            backend.call_failed(&config.health, code.as_str())
+        }
+        _ => backend.health.succeeded(),
+    }
+}
+
+// The HTTP status for a call that isn't gRPC, as in proxy.rs.
+fn http_status(termination: Termination) -> u16 {
+    match termination {
+        Termination::BadRequest => 400,
+        Termination::RequestTooLarge => 413,
+        Termination::AclDenied => 403,
+        Termination::Unauthorized => 401,
+        Termination::FirstByteTimeout | Termination::TotalTimeout => 504,
+        Termination::ConnectionLimit
+        | Termination::QueueFull
+        | Termination::QueueTimeout
+        | Termination::Maintenance => 503,
+        _ => 502,
+    }
+}
+
+// The request as the rest of the balancer knows one, for routing, splits,
+// strategies and tracing.
+fn request_head(fields: &[(String, String)]) -> RequestHead {
+    let mut head = RequestHead {
+        method: String::new(),
+        target: String::new(),
+        version: "HTTP/2.0".to_string(),
+        headers: Headers::default(),
+    };
+    for (name, value) in fields {
+        match name.as_str() {
+            ":method" => head.method = value.clone(),
+            ":path" => head.target = value.clone(),
+            ":authority" => head.headers.set("host", value),
+            name if name.starts_with(':') => {}
+            name => head.headers.append(name, value),
+        }
+    }
+    head
+}
+
+// The service a HealthCheckRequest asks about. The body is one gRPC
+// message: a compression flag, a 4 byte length and the protobuf, in which
+// the service is field 1.
+fn health_service(body: &[u8]) -> Option<String> {
+    if body.is_empty() {
+        return Some(String::new());
+    }
+    let (&compressed, rest) = body.split_first()?;
+    if compressed != 0 || rest.len() < 4 {
+        return None;
+    }
+    let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+    let mut message = rest.get(4..4 + len)?;
+    let mut service = String::new();
+    while !message.is_empty() {
+        let key = varint(&mut message)?;
+        let skip = match key & 7 {
+            0 => {
+                varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => varint(&mut message)? as usize,
+            5 => 4,
+            _ => return None,
+        };
+        let value = message.get(..skip)?;
+        if key == (1 << 3 | 2) {
+            service = String::from_utf8(value.to_vec()).ok()?;
+        }
+        message = &message[skip..];
+    }
+    Some(service)
+}
+
+fn varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,544 @@
+/*
+ * A small HTTP/2 implementation (RFC 9113), just enough to relay streams
+ * between two connections. It is used for gRPC, see grpc.rs.
+ *
+ * A connection is split in two halves. The `Sender` is shared by every
+ * thread that writes to the connection: frames go out whole under a lock,
+ * and DATA waits for the peer's flow control window. The `Reader` belongs
+ * to the one thread reading the connection. It answers SETTINGS and PING
+ * itself, feeds WINDOW_UPDATE to the Sender, and hands everything that
+ * belongs to a stream to the caller.
+ *
+ * Flow control towards us: the connection window is given back as soon as
+ * a DATA frame is read, a stream's window only once the caller has passed
+ * the data on (Sender::window_update). So a stream whose other side is slow
+ * stops its peer after one window without holding up the others.
+ */
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::hpack;
+use crate::stream::Stream;
+use crate::timeout::Timed;
+
+// a client with prior knowledge opens with this
+pub const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// frame types
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+// frame flags
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY: u8 = 0x20;
+
+// error codes
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const CANCEL: u32 = 0x8;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// settings
+pub const ENABLE_PUSH: u16 = 0x2;
+pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// the window every stream and connection starts with
+const DEFAULT_WINDOW: i64 = 65_535;
+// the biggest frame we take, which is the smallest one allowed
+const MAX_FRAME: usize = 16_384;
+// the window we give each stream and the connection
+pub const WINDOW: u32 = 1 << 20;
+
+// Starts a connection: our SETTINGS, and a connection window to match the
+// stream windows. A client sends the preface first.
+pub fn handshake(sender: &Sender, settings: &[(u16, u32)], client: bool) -> io::Result<()> {
+    let mut payload = Vec::new();
+    for &(id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    let mut writer = sender.writer.lock().unwrap();
+    if client {
+        writer.write_all(PREFACE)?;
+    }
+    write_frame(&mut *writer, SETTINGS, 0, 0, &payload)?;
+    let increment = WINDOW - DEFAULT_WINDOW as u32;
+    write_frame(&mut *writer, WINDOW_UPDATE, 0, 0, &increment.to_be_bytes())?;
+    writer.flush()
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut head = [0u8; 9];
+    head[..3].copy_from_slice(&len[1..]);
+    head[3] = kind;
+    head[4] = flags;
+    head[5..].copy_from_slice(&stream.to_be_bytes());
+    writer.write_all(&head)?;
+    writer.write_all(payload)
+}
+
+// An error that ends the whole connection, with the code for its GOAWAY.
+#[derive(Debug)]
+struct ConnectionError {
+    code: u32,
+    message: String,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ConnectionError {}
+
+fn connection_error(code: u32, message: &str) -> io::Error {
+    let message = message.to_string();
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        ConnectionError { code, message },
+    )
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    connection_error(PROTOCOL_ERROR, message)
+}
+
+// The code to send GOAWAY with, if `e` is the peer's fault rather than
+// the connection's.
+pub fn error_code(e: &io::Error) -> Option<u32> {
+    Some(e.get_ref()?.downcast_ref::<ConnectionError>()?.code)
+}
+
+// The peer's flow control windows and frame size.
+#[derive(Debug)]
+struct Flow {
+    connection: i64,
+    // what a new stream starts with
+    initial: i64,
+    streams: HashMap<u32, i64>,
+    max_frame: usize,
+    closed: bool,
+}
+
+pub struct Sender {
+    writer: Mutex<BufWriter<Timed>>,
+    // for shutdown, which mustn't wait for a blocked writer
+    socket: Stream,
+    flow: Mutex<Flow>,
+    flow_changed: Condvar,
+}
+
+impl Sender {
+    pub fn new(writer: BufWriter<Timed>) -> io::Result<Sender> {
+        Ok(Sender {
+            socket: writer.get_ref().get_ref().try_clone()?,
+            writer: Mutex::new(writer),
+            flow: Mutex::new(Flow {
+                connection: DEFAULT_WINDOW,
+                initial: DEFAULT_WINDOW,
+                streams: HashMap::new(),
+                max_frame: MAX_FRAME,
+                closed: false,
+            }),
+            flow_changed: Condvar::new(),
+        })
+    }
+
+    fn frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        write_frame(&mut *writer, kind, flags, stream, payload)?;
+        writer.flush()
+    }
+
+    // Timeouts for the writes that follow, see Timed.
+    pub fn set_timeouts(&self, idle: Option<Duration>, deadline: Option<Instant>) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.get_mut().set_idle(idle);
+        writer.get_mut().set_deadline(deadline);
+    }
+
+    // Starts tracking the send window of `stream`.
+    pub fn open(&self, stream: u32) {
+        let mut flow = self.flow.lock().unwrap();
+        let initial = flow.initial;
+        flow.streams.insert(stream, initial);
+    }
+
+    // Forgets `stream`, a send still waiting on it fails.
+    pub fn close(&self, stream: u32) {
+        self.flow.lock().unwrap().streams.remove(&stream);
+        self.flow_changed.notify_all();
+    }
+
+    // Fails every send, now and later, for example once the connection
+    // broke.
+    pub fn shutdown(&self) {
+        self.flow.lock().unwrap().closed = true;
+        self.flow_changed.notify_all();
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    pub fn headers(
+        &self,
+        stream: u32,
+        fields: &[(&str, &str)],
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let block = hpack::encode(fields.iter().copied());
+        let max_frame = self.flow.lock().unwrap().max_frame;
+        let mut chunks: Vec<&[u8]> = block.chunks(max_frame).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        // the frames of one block must not be interleaved with others
+        let mut writer = self.writer.lock().unwrap();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (kind, mut flags) = match i {
+                0 if end_stream => (HEADERS, END_STREAM),
+                0 => (HEADERS, 0),
+                _ => (CONTINUATION, 0),
+            };
+            if i == chunks.len() - 1 {
+                flags |= END_HEADERS;
+            }
+            write_frame(&mut *writer, kind, flags, stream, chunk)?;
+        }
+        writer.flush()
+    }
+
+    // Sends `data` on `stream`, waiting for window as needed.
+    pub fn data(&self, stream: u32, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let n = self.reserve(stream, data.len())?;
+            let (chunk, rest) = data.split_at(n);
+            data = rest;
+            let flags = if end_stream && data.is_empty() {
+                END_STREAM
+            } else {
+                0
+            };
+            if !chunk.is_empty() || flags != 0 {
+                self.frame(DATA, flags, stream, chunk)?;
+            }
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Takes up to `want` bytes of window for `stream`, at least one unless
+    // `want` is 0.
+    fn reserve(&self, stream: u32, want: usize) -> io::Result<usize> {
+        let mut flow = self.flow.lock().unwrap();
+        loop {
+            if flow.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection closed",
+                ));
+            }
+            let connection = flow.connection;
+            let max_frame = flow.max_frame;
+            let Some(window) = flow.streams.get_mut(&stream) else {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"));
+            };
+            if want == 0 {
+                return Ok(0);
+            }
+            let n = want
+                .min(max_frame)
+                .min(connection.min(*window).max(0) as usize);
+            if n > 0 {
+                *window -= n as i64;
+                flow.connection -= n as i64;
+                return Ok(n);
+            }
+            flow = self.flow_changed.wait(flow).unwrap();
+        }
+    }
+
+    pub fn reset(&self, stream: u32, code: u32) -> io::Result<()> {
+        self.close(stream);
+        self.frame(RST_STREAM, 0, stream, &code.to_be_bytes())
+    }
+
+    // Gives the peer `n` more bytes of window on `stream`, 0 for the
+    // connection.
+    pub fn window_update(&self, stream: u32, n: usize) -> io::Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.frame(WINDOW_UPDATE, 0, stream, &(n as u32).to_be_bytes())
+    }
+
+    pub fn go_away(&self, last_stream: u32, code: u32) -> io::Result<()> {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload)
+    }
+
+    // The peer gave us more window.
+    fn grant(&self, stream: u32, n: i64) {
+        let mut flow = self.flow.lock().unwrap();
+        if stream == 0 {
+            flow.connection += n;
+        } else if let Some(window) = flow.streams.get_mut(&stream) {
+            *window += n;
+        }
+        self.flow_changed.notify_all();
+    }
+
+    fn apply_settings(&self, payload: &[u8]) -> io::Result<()> {
+        let mut flow = self.flow.lock().unwrap();
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                INITIAL_WINDOW_SIZE => {
+                    // open streams move by the difference
+                    let delta = value as i64 - flow.initial;
+                    flow.initial = value as i64;
+                    for window in flow.streams.values_mut() {
+                        *window += delta;
+                    }
+                }
+                MAX_FRAME_SIZE => flow.max_frame = value as usize,
+                _ => {}
+            }
+        }
+        drop(flow);
+        self.flow_changed.notify_all();
+        self.frame(SETTINGS, ACK, 0, &[])
+    }
+}
+
+// What the Reader hands on.
+#[derive(Debug)]
+pub enum Incoming {
+    Headers {
+        stream: u32,
+        fields: Vec<(String, String)>,
+        end_stream: bool,
+    },
+    Data {
+        stream: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+        // what the frame took off the window, padding included
+        size: usize,
+    },
+    Reset {
+        stream: u32,
+        code: u32,
+    },
+    GoAway {
+        last_stream: u32,
+    },
+}
+
+pub struct Reader {
+    stream: BufReader<Timed>,
+    decoder: hpack::Decoder,
+    sender: Arc<Sender>,
+    // caps the header block of a stream, and the fields it decodes to
+    max_header_size: usize,
+    // from the first byte of a HEADERS frame to the end of its block
+    header_timeout: Option<Duration>,
+    // part of a frame or header block was read
+    mid_frame: bool,
+}
+
+impl Reader {
+    pub fn new(stream: BufReader<Timed>, sender: Arc<Sender>, max_header_size: usize) -> Reader {
+        Reader {
+            stream,
+            decoder: hpack::Decoder::default(),
+            sender,
+            max_header_size,
+            header_timeout: None,
+            mid_frame: false,
+        }
+    }
+
+    // Makes every header block arrive within `timeout` of its first byte,
+    // on a connection that has no deadline of its own.
+    pub fn set_header_timeout(&mut self, timeout: Option<Duration>) {
+        self.header_timeout = timeout;
+    }
+
+    pub fn get_mut(&mut self) -> &mut Timed {
+        self.stream.get_mut()
+    }
+
+    // whether anything arrived that wasn't read yet
+    pub fn has_buffered(&self) -> bool {
+        !self.stream.buffer().is_empty()
+    }
+
+    // Whether next() failed in the middle of a frame. If not, nothing was
+    // lost and it can be called again, after a timeout say.
+    pub fn mid_frame(&self) -> bool {
+        self.mid_frame
+    }
+
+    // The next frame that concerns a stream, or None once the peer closed
+    // the connection.
+    pub fn next(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            self.mid_frame = false;
+            let Some((kind, flags, stream, payload)) = self.frame()? else {
+                return Ok(None);
+            };
+            match kind {
+                DATA => {
+                    let size = payload.len();
+                    self.sender.window_update(0, size)?;
+                    let data = unpad(flags, &payload)?.to_vec();
+                    return Ok(Some(Incoming::Data {
+                        stream,
+                        data,
+                        end_stream: flags & END_STREAM != 0,
+                        size,
+                    }));
+                }
+                HEADERS => {
+                    let mut block = unpad(flags, &payload)?;
+                    if flags & PRIORITY != 0 {
+                        block = block
+                            .get(5..)
+                            .ok_or_else(|| protocol_error("short HEADERS"))?;
+                    }
+                    let mut block = block.to_vec();
+                    let mut done = flags & END_HEADERS != 0;
+                    while !done {
+                        if block.len() > self.max_header_size {
+                            return Err(too_big());
+                        }
+                        match self.frame()? {
+                            Some((CONTINUATION, more, s, payload)) if s == stream => {
+                                block.extend_from_slice(&payload);
+                                done = more & END_HEADERS != 0;
+                            }
+                            _ => return Err(protocol_error("expected CONTINUATION")),
+                        }
+                    }
+                    if block.len() > self.max_header_size {
+                        return Err(too_big());
+                    }
+                    if self.header_timeout.is_some() {
+                        self.get_mut().set_deadline(None);
+                    }
+                    let fields =
+                        self.decoder
+                            .decode(&block, self.max_header_size)
+                            .map_err(|e| match e {
+                                hpack::Error::TooBig => too_big(),
+                                e => connection_error(COMPRESSION_ERROR, &e.to_string()),
+                            })?;
+                    return Ok(Some(Incoming::Headers {
+                        stream,
+                        fields,
+                        end_stream: flags & END_STREAM != 0,
+                    }));
+                }
+                RST_STREAM => {
+                    let code = u32_at(&payload, 0)?;
+                    self.sender.close(stream);
+                    return Ok(Some(Incoming::Reset { stream, code }));
+                }
+                SETTINGS if flags & ACK == 0 => self.sender.apply_settings(&payload)?,
+                PING if flags & ACK == 0 => self.sender.frame(PING, ACK, 0, &payload)?,
+                WINDOW_UPDATE => {
+                    let n = u32_at(&payload, 0)? & 0x7fff_ffff;
+                    self.sender.grant(stream, n as i64);
+                }
+                GOAWAY => {
+                    let last_stream = u32_at(&payload, 0)? & 0x7fff_ffff;
+                    return Ok(Some(Incoming::GoAway { last_stream }));
+                }
+                // PRIORITY, PUSH_PROMISE (which we turn off) and unknown
+                // frame types are ignored
+                _ => {}
+            }
+        }
+    }
+
+    fn frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.stream.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        self.mid_frame = true;
+        let arrived = Instant::now();
+        let mut head = [0u8; 9];
+        self.stream.read_exact(&mut head)?;
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        if len > MAX_FRAME {
+            return Err(protocol_error("frame is bigger than allowed"));
+        }
+        let stream = u32_at(&head, 5)? & 0x7fff_ffff;
+        // the deadline holds through the CONTINUATION frames, next() lifts
+        // it at the end of the block
+        if head[3] == HEADERS
+            && let Some(timeout) = self.header_timeout
+        {
+            self.get_mut().set_deadline(Some(arrived + timeout));
+        }
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload)?;
+        Ok(Some((head[3], head[4], stream, payload)))
+    }
+}
+
+fn too_big() -> io::Error {
+    connection_error(ENHANCE_YOUR_CALM, "header block is too big")
+}
+
+// type, flags, stream id and payload
+type Frame = (u8, u8, u32, Vec<u8>);
+
+fn u32_at(bytes: &[u8], at: usize) -> io::Result<u32> {
+    let b = bytes
+        .get(at..at + 4)
+        .ok_or_else(|| protocol_error("short frame"))?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn unpad(flags: u8, payload: &[u8]) -> io::Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad, rest) = payload
+        .split_first()
+        .ok_or_else(|| protocol_error("short padded frame"))?;
+    rest.len()
+        .checked_sub(pad as usize)
+        .map(|end| &rest[..end])
+        .ok_or_else(|| protocol_error("padding is longer than the frame"))
+}
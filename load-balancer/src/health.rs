@@ -0,0 +1,214 @@
+/*
+ * Passive health checks and slow start.
+ *
+ * There are no probe requests. A backend is marked down when connecting to
+ * it failed `health.failures` times in a row, and gets traffic again once
+ * `health.cooldown_ms` has passed. If that first attempt fails too it goes
+ * straight back down. A gRPC call that ends with one of `grpc.failure_codes`
+ * counts as a failure too (see grpc.rs). In cluster mode a backend another
+ * instance took out is taken out here as well (see cluster.rs).
+ *
+ * A backend that comes back doesn't get its full share right away. Over
+ * `slow_start.window_ms` its weight ramps from `min_percent` to 100%, so a
+ * service with cold caches or a cold JIT isn't flattened the moment it is
+ * up. The same goes for every backend when the load balancer starts, which
+ * is also the only time backends are added. Strategies get the weight along
+ * with the backend: round robin lets a ramping backend turn down the rest
+ * of its requests, which go on to the next backend instead (see
+ * strategy.rs).
+ */
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    // failed connects in a row that take a backend out, 0 never does
+    pub failures: u32,
+    // how long a backend stays out
+    pub cooldown_ms: u64,
+    // the share of a priority tier's backends that must be up for it to
+    // take all the traffic it is offered, see balancer.rs
+    pub tier_threshold_percent: f64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            failures: 3,
+            cooldown_ms: 10_000,
+            // Envoy's default overprovisioning factor of 1.4
+            tier_threshold_percent: 71.4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowStartConfig {
+    // how long the ramp takes, 0 turns slow start off
+    pub window_ms: u64,
+    pub curve: Curve,
+    // the share a backend starts the ramp with, 1 to 100
+    pub min_percent: f64,
+}
+
+impl Default for SlowStartConfig {
+    fn default() -> Self {
+        SlowStartConfig {
+            window_ms: 0,
+            curve: Curve::Linear,
+            min_percent: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    // the weight grows by the same amount every millisecond
+    Linear,
+    // the weight doubles at a steady rate, so it stays low for longer and
+    // most of the ramp happens at the end
+    Exponential,
+}
+
+impl SlowStartConfig {
+    // The weight, from min_percent/100 to 1, `elapsed_ms` into the ramp.
+    fn weight(&self, elapsed_ms: u64) -> f64 {
+        if self.window_ms == 0 || elapsed_ms >= self.window_ms {
+            return 1.0;
+        }
+        let min = (self.min_percent / 100.0).clamp(0.01, 1.0);
+        let t = elapsed_ms as f64 / self.window_ms as f64;
+        match self.curve {
+            Curve::Linear => min + (1.0 - min) * t,
+            Curve::Exponential => min.powf(1.0 - t),
+        }
+    }
+}
+
+// The health of one backend. Times are in ms since `epoch`.
+#[derive(Debug)]
+pub struct Health {
+    epoch: Instant,
+    // failed connects since the last success
+    failures: AtomicU32,
+    // the backend is out until then
+    down_until: AtomicU64,
+    // when the current slow start ramp began, 0 for none
+    ramp_start: AtomicU64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            epoch: Instant::now(),
+            failures: AtomicU32::new(0),
+            down_until: AtomicU64::new(0),
+            // a new backend ramps up like one that came back, 1 is now
+            ramp_start: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Health {
+    fn now(&self) -> u64 {
+        // +1 keeps 0 free to mean "never"
+        self.epoch.elapsed().as_millis() as u64 + 1
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.now() < self.down_until.load(Ordering::Relaxed)
+    }
+
+    // The share of its requests the backend takes right now, 0 to 1.
+    pub fn weight(&self, slow_start: &SlowStartConfig) -> f64 {
+        if self.is_down() {
+            return 0.0;
+        }
+        match self.ramp_start.load(Ordering::Relaxed) {
+            0 => 1.0,
+            start => slow_start.weight(self.now().saturating_sub(start)),
+        }
+    }
+
+    // failed connects since the last success
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    // Connecting to the backend failed. Returns true if that took the
+    // backend out.
+    pub fn failed(&self, config: &HealthConfig) -> bool {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if config.failures > 0 && failures >= config.failures {
+            let until = self.now() + config.cooldown_ms;
+            self.down_until.store(until, Ordering::Relaxed);
+            // the ramp starts when the backend is back in rotation
+            self.ramp_start.store(until, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    // How much longer the backend stays out, None while it is in.
+    pub fn down_for(&self) -> Option<Duration> {
+        let remaining = self
+            .down_until
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.now());
+        (remaining > 0).then(|| Duration::from_millis(remaining))
+    }
+
+    // Another instance found the backend down, see cluster.rs. Takes it out
+    // for `duration` unless it already is for longer. Returns whether that
+    // changed anything.
+    pub fn mark_down(&self, duration: Duration) -> bool {
+        let until = self.now() + duration.as_millis() as u64;
+        if self.down_until.fetch_max(until, Ordering::Relaxed) >= until {
+            return false;
+        }
+        self.ramp_start.store(until, Ordering::Relaxed);
+        true
+    }
+
+    // A request to the backend got a response.
+    pub fn succeeded(&self) {
+        if self.failures.load(Ordering::Relaxed) != 0 {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_and_returning_backends_ramp_up() {
+        let slow_start = SlowStartConfig {
+            window_ms: 60_000,
+            ..SlowStartConfig::default()
+        };
+        let health = Health::default();
+        let weight = health.weight(&slow_start);
+        assert!((0.1..0.11).contains(&weight), "{}", weight);
+        // without slow start it is all or nothing
+        assert_eq!(health.weight(&SlowStartConfig::default()), 1.0);
+
+        let config = HealthConfig {
+            failures: 2,
+            cooldown_ms: 1,
+            ..HealthConfig::default()
+        };
+        assert!(!health.failed(&config));
+        assert!(health.failed(&config));
+        assert_eq!(health.weight(&slow_start), 0.0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!health.is_down());
+        assert!(health.weight(&slow_start) < 0.11);
+    }
+}
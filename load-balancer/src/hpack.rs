@@ -0,0 +1,638 @@
+/*
+ * HPACK, the header compression of HTTP/2 (RFC 7541).
+ *
+ * The decoder is complete: indexed fields, the dynamic table and Huffman
+ * coded strings, since clients use all of them. The encoder keeps it
+ * simple and sends every field as a literal that is never indexed, which
+ * any peer can read and leaves no table state to keep in sync.
+ */
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+// the table size both sides start with, we never ask for another one
+pub const TABLE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub struct Decoder {
+    // newest entry first
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+}
+
+// Why a header block couldn't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // the fields came to more than the caller takes
+    TooBig,
+    // the block is broken, and with it the table
+    Malformed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooBig => write!(f, "header list is too big"),
+            Error::Malformed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Malformed(message)
+    }
+}
+
+impl Decoder {
+    // Decodes one complete header block into (name, value) pairs. The
+    // fields may come to `max_list` bytes, counted as for
+    // SETTINGS_MAX_HEADER_LIST_SIZE, a few indexed ones can stand for a lot
+    // more than the block itself.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut fields = Vec::new();
+        let mut list = 0usize;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // indexed field
+                let index = integer(&mut block, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // literal, added to the table
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                let size = integer(&mut block, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(format!("table size {} is over the limit", size).into());
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // literal without indexing, or never indexed
+                fields.push(self.literal(&mut block, 4)?);
+            }
+            list = list.saturating_add(fields.last().map_or(0, entry_size));
+            if list > max_list {
+                return Err(Error::TooBig);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String), String> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), String> {
+        if index == 0 {
+            return Err("header index 0".to_string());
+        }
+        if let Some(&(name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()));
+        }
+        self.table
+            .get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or_else(|| format!("header index {} is out of range", index))
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = entry_size(&field);
+        self.evict(size);
+        // an entry bigger than the whole table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    // Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some(old) => self.size -= entry_size(&old),
+                None => break,
+            }
+        }
+    }
+}
+
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+// Encodes a header block, see the top of the file.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0x10);
+        for s in [name, value] {
+            put_integer(&mut block, 0, 7, s.len());
+            block.extend_from_slice(s.as_bytes());
+        }
+    }
+    block
+}
+
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, String> {
+    let truncated = || "truncated header block".to_string();
+    let (&first, rest) = block.split_first().ok_or_else(truncated)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or_else(truncated)?;
+        *block = rest;
+        if shift > 28 {
+            return Err("header integer is too big".to_string());
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn put_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn string(block: &mut &[u8]) -> Result<String, String> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err("truncated header block".to_string());
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    let bytes = if huffman {
+        huffman_decode(raw)?
+    } else {
+        raw.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| "header is not valid UTF-8".to_string())
+}
+
+// Huffman codes are canonical: the codes of one length are consecutive and
+// follow the codes of the shorter lengths. So for each length it is enough
+// to know its first code and which symbols it holds.
+struct Canonical {
+    // per length: the first code, and where its symbols start in `symbols`
+    first: [u32; 31],
+    start: [usize; 31],
+    count: [usize; 31],
+    symbols: Vec<u8>,
+}
+
+fn canonical() -> &'static Canonical {
+    static CANONICAL: OnceLock<Canonical> = OnceLock::new();
+    CANONICAL.get_or_init(|| {
+        let mut symbols: Vec<u8> = (0..=255).collect();
+        symbols.sort_by_key(|&s| (HUFFMAN_LENGTHS[s as usize], HUFFMAN_CODES[s as usize]));
+        let mut table = Canonical {
+            first: [0; 31],
+            start: [0; 31],
+            count: [0; 31],
+            symbols,
+        };
+        for (i, &s) in table.symbols.iter().enumerate().rev() {
+            let len = HUFFMAN_LENGTHS[s as usize] as usize;
+            table.first[len] = HUFFMAN_CODES[s as usize];
+            table.start[len] = i;
+            table.count[len] += 1;
+        }
+        table
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, String> {
+    let table = canonical();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0usize);
+    for byte in data {
+        for bit in (0..8).rev() {
+            code = code << 1 | (*byte >> bit & 1) as u32;
+            len += 1;
+            if len > 30 {
+                return Err("bad Huffman code".to_string());
+            }
+            let offset = code.wrapping_sub(table.first[len]) as usize;
+            if table.count[len] > 0 && code >= table.first[len] && offset < table.count[len] {
+                out.push(table.symbols[table.start[len] + offset]);
+                code = 0;
+                len = 0;
+            }
+        }
+    }
+    // the end is padded with up to 7 one bits
+    if len > 7 || code != (1 << len) - 1 {
+        return Err("bad Huffman padding".to_string());
+    }
+    Ok(out)
+}
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// The code of each byte, from RFC 7541 appendix B.
+const HUFFMAN_CODES: [u32; 256] = [
+    0x1ff8, 0x7fffd8, 0xfffffe2, 0xfffffe3, 0xfffffe4, 0xfffffe5, 0xfffffe6, 0xfffffe7, 0xfffffe8,
+    0xffffea, 0x3ffffffc, 0xfffffe9, 0xfffffea, 0x3ffffffd, 0xfffffeb, 0xfffffec, 0xfffffed,
+    0xfffffee, 0xfffffef, 0xffffff0, 0xffffff1, 0xffffff2, 0x3ffffffe, 0xffffff3, 0xffffff4,
+    0xffffff5, 0xffffff6, 0xffffff7, 0xffffff8, 0xffffff9, 0xffffffa, 0xffffffb, 0x14, 0x3f8,
+    0x3f9, 0xffa, 0x1ff9, 0x15, 0xf8, 0x7fa, 0x3fa, 0x3fb, 0xf9, 0x7fb, 0xfa, 0x16, 0x17, 0x18,
+    0x0, 0x1, 0x2, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x5c, 0xfb, 0x7ffc, 0x20, 0xffb,
+    0x3fc, 0x1ffa, 0x21, 0x5d, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xfc, 0x73, 0xfd, 0x1ffb, 0x7fff0,
+    0x1ffc, 0x3ffc, 0x22, 0x7ffd, 0x3, 0x23, 0x4, 0x24, 0x5, 0x25, 0x26, 0x27, 0x6, 0x74, 0x75,
+    0x28, 0x29, 0x2a, 0x7, 0x2b, 0x76, 0x2c, 0x8, 0x9, 0x2d, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7ffe,
+    0x7fc, 0x3ffd, 0x1ffd, 0xffffffc, 0xfffe6, 0x3fffd2, 0xfffe7, 0xfffe8, 0x3fffd3, 0x3fffd4,
+    0x3fffd5, 0x7fffd9, 0x3fffd6, 0x7fffda, 0x7fffdb, 0x7fffdc, 0x7fffdd, 0x7fffde, 0xffffeb,
+    0x7fffdf, 0xffffec, 0xffffed, 0x3fffd7, 0x7fffe0, 0xffffee, 0x7fffe1, 0x7fffe2, 0x7fffe3,
+    0x7fffe4, 0x1fffdc, 0x3fffd8, 0x7fffe5, 0x3fffd9, 0x7fffe6, 0x7fffe7, 0xffffef, 0x3fffda,
+    0x1fffdd, 0xfffe9, 0x3fffdb, 0x3fffdc, 0x7fffe8, 0x7fffe9, 0x1fffde, 0x7fffea, 0x3fffdd,
+    0x3fffde, 0xfffff0, 0x1fffdf, 0x3fffdf, 0x7fffeb, 0x7fffec, 0x1fffe0, 0x1fffe1, 0x3fffe0,
+    0x1fffe2, 0x7fffed, 0x3fffe1, 0x7fffee, 0x7fffef, 0xfffea, 0x3fffe2, 0x3fffe3, 0x3fffe4,
+    0x7ffff0, 0x3fffe5, 0x3fffe6, 0x7ffff1, 0x3ffffe0, 0x3ffffe1, 0xfffeb, 0x7fff1, 0x3fffe7,
+    0x7ffff2, 0x3fffe8, 0x1ffffec, 0x3ffffe2, 0x3ffffe3, 0x3ffffe4, 0x7ffffde, 0x7ffffdf,
+    0x3ffffe5, 0xfffff1, 0x1ffffed, 0x7fff2, 0x1fffe3, 0x3ffffe6, 0x7ffffe0, 0x7ffffe1, 0x3ffffe7,
+    0x7ffffe2, 0xfffff2, 0x1fffe4, 0x1fffe5, 0x3ffffe8, 0x3ffffe9, 0xffffffd, 0x7ffffe3, 0x7ffffe4,
+    0x7ffffe5, 0xfffec, 0xfffff3, 0xfffed, 0x1fffe6, 0x3fffe9, 0x1fffe7, 0x1fffe8, 0x7ffff3,
+    0x3fffea, 0x3fffeb, 0x1ffffee, 0x1ffffef, 0xfffff4, 0xfffff5, 0x3ffffea, 0x7ffff4, 0x3ffffeb,
+    0x7ffffe6, 0x3ffffec, 0x3ffffed, 0x7ffffe7, 0x7ffffe8, 0x7ffffe9, 0x7ffffea, 0x7ffffeb,
+    0xffffffe, 0x7ffffec, 0x7ffffed, 0x7ffffee, 0x7ffffef, 0x7fffff0, 0x3ffffee,
+];
+
+// The length in bits of each code.
+const HUFFMAN_LENGTHS: [u8; 256] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the examples of RFC 7541 appendix C, written as there
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn check(decoder: &mut Decoder, block: &str, want: &[(&str, &str)], table: &[(&str, &str)]) {
+        assert_eq!(decoder.decode(&hex(block), usize::MAX), Ok(fields(want)));
+        assert_eq!(Vec::from(decoder.table.clone()), fields(table));
+        assert_eq!(
+            decoder.size,
+            fields(table).iter().map(entry_size).sum::<usize>()
+        );
+    }
+
+    const REQUEST_1: [(&str, &str); 4] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+    ];
+    const REQUEST_2: [(&str, &str); 5] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const REQUEST_3: [(&str, &str); 5] = [
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+
+    fn requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::default();
+        check(
+            &mut decoder,
+            blocks[0],
+            &REQUEST_1,
+            &[(":authority", "www.example.com")],
+        );
+        check(
+            &mut decoder,
+            blocks[1],
+            &REQUEST_2,
+            &[
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+        );
+        check(
+            &mut decoder,
+            blocks[2],
+            &REQUEST_3,
+            &[
+                ("custom-key", "custom-value"),
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+        );
+    }
+
+    #[test]
+    fn requests_without_huffman() {
+        // C.3
+        requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        // C.4
+        requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    const DATE_1: &str = "Mon, 21 Oct 2013 20:13:21 GMT";
+    const DATE_2: &str = "Mon, 21 Oct 2013 20:13:22 GMT";
+    const LOCATION: &str = "https://www.example.com";
+    const COOKIE: &str = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+
+    // The responses fill a 256 byte table, so every one of them evicts.
+    fn responses(blocks: [&str; 3]) {
+        let mut decoder = Decoder {
+            max_size: 256,
+            ..Decoder::default()
+        };
+        check(
+            &mut decoder,
+            blocks[0],
+            &[
+                (":status", "302"),
+                ("cache-control", "private"),
+                ("date", DATE_1),
+                ("location", LOCATION),
+            ],
+            &[
+                ("location", LOCATION),
+                ("date", DATE_1),
+                ("cache-control", "private"),
+                (":status", "302"),
+            ],
+        );
+        check(
+            &mut decoder,
+            blocks[1],
+            &[
+                (":status", "307"),
+                ("cache-control", "private"),
+                ("date", DATE_1),
+                ("location", LOCATION),
+            ],
+            &[
+                (":status", "307"),
+                ("location", LOCATION),
+                ("date", DATE_1),
+                ("cache-control", "private"),
+            ],
+        );
+        check(
+            &mut decoder,
+            blocks[2],
+            &[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", DATE_2),
+                ("location", LOCATION),
+                ("content-encoding", "gzip"),
+                ("set-cookie", COOKIE),
+            ],
+            &[
+                ("set-cookie", COOKIE),
+                ("content-encoding", "gzip"),
+                ("date", DATE_2),
+            ],
+        );
+    }
+
+    #[test]
+    fn responses_without_huffman() {
+        // C.5
+        responses([
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133
+             2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70
+             6c65 2e63 6f6d",
+            "4803 3330 37c1 c0bf",
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d
+             54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049
+             5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e
+             3d31",
+        ]);
+    }
+
+    #[test]
+    fn responses_with_huffman() {
+        // C.6
+        responses([
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6
+             2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            "4883 640e ffc1 c0bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab
+             77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f
+             9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ]);
+    }
+
+    #[test]
+    fn size_update_evicts() {
+        let mut decoder = Decoder::default();
+        decoder
+            .decode(
+                &hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d"),
+                usize::MAX,
+            )
+            .unwrap();
+        assert_eq!(decoder.table.len(), 1);
+        // a table size update to 0 and back
+        assert_eq!(
+            decoder.decode(&hex("203f e11f"), usize::MAX),
+            Ok(Vec::new())
+        );
+        assert!(decoder.table.is_empty());
+        assert_eq!(decoder.size, 0);
+        assert_eq!(decoder.max_size, 4096);
+        assert!(decoder.decode(&hex("3fe2 1f"), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn list_size_is_capped() {
+        // 4 fields of 32 bytes each, plus what their names and values take
+        let block = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        let size: usize = fields(&REQUEST_1).iter().map(entry_size).sum();
+        assert!(Decoder::default().decode(&block, size).is_ok());
+        assert_eq!(
+            Decoder::default().decode(&block, size - 1),
+            Err(Error::TooBig)
+        );
+        // one indexed field can stand for a whole table entry
+        let mut decoder = Decoder::default();
+        // "x" with a value of 200 bytes, added to the table
+        let mut block = hex("4001 787f 49");
+        block.extend([b'a'; 200]);
+        block.extend([0xbe; 100]);
+        assert_eq!(decoder.decode(&block, 8192), Err(Error::TooBig));
+    }
+
+    #[test]
+    fn broken_blocks() {
+        let mut decoder = Decoder::default();
+        // index 0, an index past the tables, a string past the end
+        for block in ["80", "ff00", "0f77 0577 77"] {
+            assert!(matches!(
+                decoder.decode(&hex(block), usize::MAX),
+                Err(Error::Malformed(_))
+            ));
+        }
+        // Huffman padding that isn't all ones, and padding of 8 bits
+        assert!(string(&mut &hex("8180")[..]).is_err());
+        assert!(string(&mut &hex("82f1 ff")[..]).is_err());
+    }
+
+    #[test]
+    fn encoded_blocks_decode() {
+        let sent = [("content-type", "application/grpc"), ("grpc-status", "0")];
+        let block = encode(sent);
+        assert_eq!(
+            Decoder::default().decode(&block, usize::MAX),
+            Ok(fields(&sent))
+        );
+        // a long value takes a multi-byte length
+        let long = "x".repeat(300);
+        let block = encode([("x-long", long.as_str())]);
+        assert_eq!(
+            Decoder::default().decode(&block, usize::MAX),
+            Ok(fields(&[("x-long", &long)]))
+        );
+    }
+}
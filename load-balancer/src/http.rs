@@ -0,0 +1,764 @@
+/*
+ * A small HTTP/1.1 implementation, just enough for proxying.
+ *
+ * We parse the request/response heads ourselves and then stream the body
+ * through. Bodies are decoded (chunked encoding removed) while reading and
+ * re-encoded on the way out, so the framing on each side of the proxy can
+ * differ (for example an HTTP/1.0 client that can't take chunked).
+ */
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+// Caps on the size of a message head, in bytes and header lines.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadLimits {
+    pub request_line: usize,
+    // the whole head, request line included
+    pub size: usize,
+    pub headers: usize,
+}
+
+impl HeadLimits {
+    pub const NONE: HeadLimits = HeadLimits {
+        request_line: usize::MAX,
+        size: usize::MAX,
+        headers: usize::MAX,
+    };
+}
+
+// the longest chunk size or trailer line we read
+const MAX_CHUNK_LINE: u64 = 4096;
+
+// The part of a message that went over its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TooLarge {
+    RequestLine,
+    Head,
+    HeaderCount,
+    Body,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TooLarge::RequestLine => write!(f, "request line too long"),
+            TooLarge::Head => write!(f, "header section too large"),
+            TooLarge::HeaderCount => write!(f, "too many header lines"),
+            TooLarge::Body => write!(f, "body too large"),
+        }
+    }
+}
+
+impl Error for TooLarge {}
+
+// Tells which limit was hit, if `e` came from reading a message that was
+// too large.
+pub fn too_large(e: &io::Error) -> Option<TooLarge> {
+    e.get_ref()?.downcast_ref::<TooLarge>().copied()
+}
+
+fn exceeded(what: TooLarge) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // true when a comma separated header (like Connection) lists `token`
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    // the items of a comma separated header, over all its lines
+    pub fn tokens<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+    }
+
+    // the value of cookie `name` from the Cookie header(s)
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    // Drops the hop-by-hop headers, including any header the Connection
+    // header names. These describe a single connection and must not be
+    // forwarded by a proxy.
+    pub fn strip_hop_by_hop(&mut self) {
+        let named: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_string()))
+            .collect();
+        for name in named {
+            self.remove(&name);
+        }
+        for name in [
+            "connection",
+            "keep-alive",
+            "proxy-connection",
+            "te",
+            "trailer",
+            "transfer-encoding",
+            "upgrade",
+        ] {
+            self.remove(name);
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> io::Result<()> {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("header line without a colon"))?;
+        if name.is_empty() || name.ends_with(' ') || name.ends_with('\t') {
+            return Err(invalid("malformed header name"));
+        }
+        self.append(name, value.trim());
+        Ok(())
+    }
+
+    fn write_to(&self, out: &mut String) {
+        for (k, v) in &self.entries {
+            out.push_str(k);
+            out.push_str(": ");
+            out.push_str(v);
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+    }
+}
+
+// How the body of a message is delimited on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+    // HTTP/1.0 style: the body ends when the connection is closed
+    UntilClose,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    // Returns Ok(None) if the peer closed the connection before sending
+    // anything, which is the normal end of a keep-alive connection. A head
+    // over `limits` fails with a TooLarge error, see too_large().
+    pub fn read(reader: &mut impl BufRead, limits: &HeadLimits) -> io::Result<Option<RequestHead>> {
+        let mut lines = match read_head_lines(reader, limits)? {
+            Some(lines) => lines.into_iter(),
+            None => return Ok(None),
+        };
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) if parts.next().is_none() => (m, t, v),
+            _ => return Err(invalid("malformed request line")),
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid("unsupported HTTP version"));
+        }
+        let mut headers = Headers::default();
+        for line in lines {
+            headers.parse_line(&line)?;
+        }
+        Ok(Some(RequestHead {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        }))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        self.headers.write_to(&mut out);
+        writer.write_all(out.as_bytes())
+    }
+
+    pub fn body_kind(&self) -> io::Result<BodyKind> {
+        if self.headers.get("transfer-encoding").is_some() {
+            // the two disagree on where the body ends, and a server behind
+            // us may pick the other one (RFC 9112 section 6.3)
+            if self.headers.get("content-length").is_some() {
+                return Err(invalid("both transfer-encoding and content-length"));
+            }
+            if self.headers.has_token("transfer-encoding", "chunked") {
+                return Ok(BodyKind::Chunked);
+            }
+            return Err(invalid("unsupported transfer-encoding"));
+        }
+        match content_length(&self.headers)? {
+            Some(0) | None => Ok(BodyKind::Empty),
+            Some(n) => Ok(BodyKind::Length(n)),
+        }
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    // the path part of the target, without the query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    // the decoded key=value pairs of the query string, in order
+    pub fn query(&self) -> Vec<(String, String)> {
+        let query = match self.target.split_once('?') {
+            Some((_, query)) => query,
+            None => return Vec::new(),
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(k), percent_decode(v))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn new(status: u16) -> ResponseHead {
+        ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason(status).to_string(),
+            headers: Headers::default(),
+        }
+    }
+
+    pub fn read(reader: &mut impl BufRead) -> io::Result<ResponseHead> {
+        let mut lines = match read_head_lines(reader, &HeadLimits::NONE)? {
+            Some(lines) => lines.into_iter(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before response",
+                ));
+            }
+        };
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid("malformed status line"));
+        }
+        let status = parts
+            .next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .filter(|s| (100..1000).contains(s))
+            .ok_or_else(|| invalid("malformed status code"))?;
+        let reason = parts.next().unwrap_or("");
+        let mut headers = Headers::default();
+        for line in lines {
+            headers.parse_line(&line)?;
+        }
+        Ok(ResponseHead {
+            version: version.to_string(),
+            status,
+            reason: reason.to_string(),
+            headers,
+        })
+    }
+
+    // Reads the final response, skipping 1xx interim responses like
+    // 100 Continue or 103 Early Hints.
+    pub fn read_final(reader: &mut impl BufRead) -> io::Result<ResponseHead> {
+        loop {
+            let response = ResponseHead::read(reader)?;
+            if response.status >= 200 {
+                return Ok(response);
+            }
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        self.headers.write_to(&mut out);
+        writer.write_all(out.as_bytes())
+    }
+
+    // `method` is the request method, a response to HEAD never has a body
+    pub fn body_kind(&self, method: &str) -> io::Result<BodyKind> {
+        if method == "HEAD" || self.status < 200 || self.status == 204 || self.status == 304 {
+            return Ok(BodyKind::Empty);
+        }
+        if self.headers.has_token("transfer-encoding", "chunked") {
+            return Ok(BodyKind::Chunked);
+        }
+        match content_length(&self.headers)? {
+            Some(0) => Ok(BodyKind::Empty),
+            Some(n) => Ok(BodyKind::Length(n)),
+            None => Ok(BodyKind::UntilClose),
+        }
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+}
+
+fn keep_alive(version: &str, headers: &Headers) -> bool {
+    if headers.has_token("connection", "close") {
+        return false;
+    }
+    version == "HTTP/1.1" || headers.has_token("connection", "keep-alive")
+}
+
+// The Content-Length, which may be repeated (or be a list) as long as every
+// copy says the same.
+fn content_length(headers: &Headers) -> io::Result<Option<u64>> {
+    let mut length = None;
+    for value in headers.tokens("content-length") {
+        let n = value
+            .parse::<u64>()
+            .map_err(|_| invalid("bad content-length"))?;
+        if length.is_some_and(|length| length != n) {
+            return Err(invalid("conflicting content-lengths"));
+        }
+        length = Some(n);
+    }
+    if length.is_none() && headers.get("content-length").is_some() {
+        return Err(invalid("bad content-length"));
+    }
+    Ok(length)
+}
+
+// Reads lines up to the blank line that ends a head. Leading empty lines are
+// skipped as RFC 9112 asks. Nothing past `limits` is held in memory.
+fn read_head_lines(
+    reader: &mut impl BufRead,
+    limits: &HeadLimits,
+) -> io::Result<Option<Vec<String>>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let left = limits.size.saturating_sub(size);
+        let (cap, over) = match lines.is_empty() {
+            // the CRLF doesn't count against the request line
+            true if limits.request_line.saturating_add(2) < left => {
+                (limits.request_line.saturating_add(2), TooLarge::RequestLine)
+            }
+            _ => (left, TooLarge::Head),
+        };
+        let n = reader.by_ref().take(cap as u64).read_line(&mut line)?;
+        size += n;
+        if n == cap && !line.ends_with('\n') {
+            return Err(exceeded(over));
+        }
+        if n == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed inside a message head",
+            ));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if lines.is_empty() {
+                continue;
+            }
+            return Ok(Some(lines));
+        }
+        if lines.len() > limits.headers {
+            return Err(exceeded(TooLarge::HeaderCount));
+        }
+        lines.push(line.to_string());
+    }
+}
+
+// Reader that yields the decoded body bytes of one message and stops at the
+// end of it, leaving the underlying reader positioned at the next message.
+pub struct BodyReader<'a, R> {
+    inner: &'a mut R,
+    kind: BodyKind,
+    // bytes left in the current chunk (or the whole body for Length)
+    remaining: u64,
+    done: bool,
+    // decoded bytes so far, and how many may come at most
+    total: u64,
+    max: Option<u64>,
+}
+
+impl<'a, R: BufRead> BodyReader<'a, R> {
+    pub fn new(inner: &'a mut R, kind: BodyKind) -> Self {
+        let (remaining, done) = match kind {
+            BodyKind::Empty => (0, true),
+            BodyKind::Length(n) => (n, n == 0),
+            BodyKind::Chunked | BodyKind::UntilClose => (0, false),
+        };
+        BodyReader {
+            inner,
+            kind,
+            remaining,
+            done,
+            total: 0,
+            max: None,
+        }
+    }
+
+    // Makes reads fail with TooLarge::Body once the body goes past `max`
+    // bytes.
+    pub fn limit(mut self, max: Option<u64>) -> Self {
+        self.max = max;
+        self
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut line = String::new();
+        if self
+            .inner
+            .by_ref()
+            .take(MAX_CHUNK_LINE)
+            .read_line(&mut line)?
+            == 0
+        {
+            return Err(unexpected_eof());
+        }
+        let size = line.trim_end().split(';').next().unwrap_or("").trim();
+        self.remaining = u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+        if self.remaining == 0 {
+            // skip the trailer section up to the final empty line
+            loop {
+                let mut trailer = String::new();
+                if self
+                    .inner
+                    .by_ref()
+                    .take(MAX_CHUNK_LINE)
+                    .read_line(&mut trailer)?
+                    == 0
+                {
+                    return Err(unexpected_eof());
+                }
+                if trailer.len() as u64 == MAX_CHUNK_LINE && !trailer.ends_with('\n') {
+                    return Err(invalid("trailer line too long"));
+                }
+                if trailer.trim_end().is_empty() {
+                    break;
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let mut crlf = String::new();
+        self.inner
+            .by_ref()
+            .take(MAX_CHUNK_LINE)
+            .read_line(&mut crlf)?;
+        if crlf.trim_end().is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("missing CRLF after chunk"))
+        }
+    }
+
+    fn decode(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        match self.kind {
+            BodyKind::Empty => Ok(0),
+            BodyKind::UntilClose => {
+                let n = self.inner.read(buf)?;
+                if n == 0 {
+                    self.done = true;
+                }
+                Ok(n)
+            }
+            BodyKind::Length(_) => {
+                let max = buf.len().min(self.remaining as usize);
+                let n = self.inner.read(&mut buf[..max])?;
+                if n == 0 {
+                    return Err(unexpected_eof());
+                }
+                self.remaining -= n as u64;
+                self.done = self.remaining == 0;
+                Ok(n)
+            }
+            BodyKind::Chunked => {
+                if self.remaining == 0 {
+                    self.next_chunk()?;
+                    if self.done {
+                        return Ok(0);
+                    }
+                }
+                let max = buf.len().min(self.remaining as usize);
+                let n = self.inner.read(&mut buf[..max])?;
+                if n == 0 {
+                    return Err(unexpected_eof());
+                }
+                self.remaining -= n as u64;
+                if self.remaining == 0 {
+                    self.end_chunk()?;
+                }
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decode(buf)?;
+        self.total += n as u64;
+        if self.max.is_some_and(|max| self.total > max) {
+            return Err(exceeded(TooLarge::Body));
+        }
+        Ok(n)
+    }
+}
+
+// Which side of a copy failed. The proxy needs to know whether the peer it
+// was reading from or the one it was writing to went away.
+#[derive(Debug)]
+pub enum CopyError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+// Copies one body from `reader` (framed as `from`) to `writer` (framed as
+// `to`). Returns the number of body bytes copied.
+pub fn copy_body(
+    reader: &mut impl BufRead,
+    from: BodyKind,
+    writer: &mut impl Write,
+    to: BodyKind,
+) -> Result<u64, CopyError> {
+    copy_body_with(reader, from, writer, to, |_| {})
+}
+
+// Same as copy_body, but `inspect` also sees every piece of the decoded body
+// as it passes through.
+pub fn copy_body_with(
+    reader: &mut impl BufRead,
+    from: BodyKind,
+    writer: &mut impl Write,
+    to: BodyKind,
+    inspect: impl FnMut(&[u8]),
+) -> Result<u64, CopyError> {
+    copy_body_limited(reader, from, None, writer, to, inspect)
+}
+
+// Same as copy_body_with, but reading fails with TooLarge::Body once more
+// than `max` bytes came in.
+pub fn copy_body_limited(
+    reader: &mut impl BufRead,
+    from: BodyKind,
+    max: Option<u64>,
+    writer: &mut impl Write,
+    to: BodyKind,
+    mut inspect: impl FnMut(&[u8]),
+) -> Result<u64, CopyError> {
+    let mut body = BodyReader::new(reader, from).limit(max);
+    let mut buf = [0u8; 16 * 1024];
+    let mut total = 0;
+    loop {
+        let n = body.read(&mut buf).map_err(CopyError::Read)?;
+        if n == 0 {
+            break;
+        }
+        inspect(&buf[..n]);
+        write_chunk(writer, &buf[..n], to).map_err(CopyError::Write)?;
+        total += n as u64;
+    }
+    finish_body(writer, to).map_err(CopyError::Write)?;
+    Ok(total)
+}
+
+// Writes a piece of body data, adding chunk framing when needed, and
+// flushes so streamed responses reach the client as they arrive.
+pub fn write_chunk(writer: &mut impl Write, data: &[u8], kind: BodyKind) -> io::Result<()> {
+    if kind == BodyKind::Chunked {
+        write!(writer, "{:x}\r\n", data.len())?;
+        writer.write_all(data)?;
+        writer.write_all(b"\r\n")?;
+    } else {
+        writer.write_all(data)?;
+    }
+    writer.flush()
+}
+
+pub fn finish_body(writer: &mut impl Write, kind: BodyKind) -> io::Result<()> {
+    if kind == BodyKind::Chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    writer.flush()
+}
+
+// Writes a complete response with a small in-memory body, used for the
+// responses the proxy generates itself (errors, admin pages).
+pub fn write_response(
+    writer: &mut impl Write,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    keep_alive: bool,
+) -> io::Result<()> {
+    let mut head = ResponseHead::new(status);
+    head.headers.set("content-type", content_type);
+    head.headers.set("content-length", &body.len().to_string());
+    if !keep_alive {
+        head.headers.set("connection", "close");
+    }
+    head.write_to(writer)?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+// The registered reason phrases, down to the 4xx and 5xx a fault may
+// abort with. Unregistered statuses go out without one.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "I'm a teapot",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        510 => "Not Extended",
+        511 => "Network Authentication Required",
+        _ => "",
+    }
+}
+
+// Decodes %XX escapes and '+' as used in query strings. Broken escapes are
+// kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(&[hi, lo]) if bytes[i] == b'%' => hex_digit(hi)
+                .zip(hex_digit(lo))
+                .map(|(hi, lo)| hi << 4 | lo),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed inside a body",
+    )
+}
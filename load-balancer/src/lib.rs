@@ -0,0 +1,97 @@
+/*
+ * The load balancer as a library.
+ *
+ * Build a LoadBalancer from a Config, give pools a Strategy of your own if
+ * the built-in ones don't fit, and hand it to run():
+ *
+ *   let config = Config::load(Path::new("load-balancer.toml"))?;
+ *   let lb = LoadBalancer::new(config).with_strategy("default", MyStrategy)?;
+ *   load_balancer::run(Arc::new(lb))?;
+ *
+ * The load-balancer binary is a command line around exactly this. To see
+ * how a strategy copes with slow, failing or shrinking backends before it
+ * meets real ones, run it in the simulator (sim.rs).
+ *
+ * A Config can also be built in code instead of loaded, every type it is
+ * made of can be named through the config module.
+ */
+#[macro_use]
+pub mod log;
+
+mod access_log;
+mod acl;
+mod admin;
+mod auth;
+pub mod balancer;
+mod cache;
+mod cluster;
+mod compress;
+pub mod config;
+mod error_page;
+mod fault;
+mod grpc;
+mod h2;
+mod health;
+mod hpack;
+pub mod http;
+mod limit;
+mod listener;
+mod metrics;
+mod mirror;
+mod proxy;
+mod rewrite;
+pub mod rng;
+pub mod sim;
+#[cfg(target_os = "linux")]
+mod splice;
+mod split;
+pub mod strategy;
+mod stream;
+mod tcp;
+mod timeout;
+mod trace;
+mod upstream;
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub use balancer::{Backend, LoadBalancer, Pool};
+pub use config::Config;
+pub use strategy::{BackendStats, Context, Strategy};
+
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+
+// Binds the listeners, starts the admin server and serves clients. Only
+// returns when binding fails.
+pub fn run(lb: Arc<LoadBalancer>) -> io::Result<()> {
+    let listen = lb.config().listen.clone();
+    let workers = lb.config().worker_count();
+    let listeners = listener::bind(&listen, workers)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to bind {}: {}", listen, e)))?;
+    info!("server is {} with {} workers", listen, workers);
+
+    let admin_lb = lb.clone();
+    thread::spawn(move || admin::serve(admin_lb));
+
+    if lb.config().cluster.enabled() {
+        let cluster_lb = lb.clone();
+        thread::spawn(move || cluster::run(cluster_lb));
+    }
+
+    // close expired idle backend connections in the background
+    let reaper_lb = lb.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            reaper_lb.conns.reap();
+            reaper_lb.grpc_conns.reap();
+        }
+    });
+
+    for worker in listener::spawn_workers(&lb, listeners) {
+        let _ = worker.join();
+    }
+    Ok(())
+}
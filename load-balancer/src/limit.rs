@@ -0,0 +1,384 @@
+/*
+ * Connection limits and the backend queue.
+ *
+ * `limits.max_connections` caps the client connections open on the
+ * listener. A client over the cap is turned away right after accept, with a
+ * 503 in http mode.
+ *
+ * `limits.max_backend_connections` caps the connections in use towards each
+ * backend, that is requests in flight in http mode and relayed connections
+ * in tcp mode. Idle pooled connections don't count. When every backend of a
+ * pool is at the cap, a request waits in the pool's queue rather than
+ * piling onto a node that is already busy. The queue is first come first
+ * served: only the request at its head takes a freed slot, so a newcomer
+ * can't overtake one that has been waiting. A request gives up with a 503
+ * after `queue_timeout_ms`, and with more than `queue_size` waiting new
+ * ones get the 503 straight away.
+ *
+ * The rest caps what a single HTTP client may send, so a slow or malicious
+ * one can't hold a connection thread or memory for long:
+ *
+ *   max_request_line, max_header_size, max_headers   400 Bad Request
+ *   max_body_size (routes can override it)            413 Content Too Large
+ *   header_timeout_ms, min_body_rate                  408 Request Timeout
+ *
+ * The header timeout runs from the first byte of a request to the end of
+ * its head, so a client that sends its headers a byte at a time is cut off
+ * no matter how often it sends one. The body then has to come in at
+ * `min_body_rate` bytes per second on average, once `min_rate_grace_ms`
+ * have passed (see timeout.rs). A request body is never buffered, so its
+ * limit is checked as it streams through. Each violation is counted in
+ * lb_limit_violations_total. The calls on an HTTP/2 connection are held to
+ * the same limits, see grpc.rs.
+ */
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::access_log::Termination;
+use crate::http::{HeadLimits, TooLarge};
+use crate::timeout::MinRate;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // client connections on the listener, 0 for no limit
+    pub max_connections: usize,
+    // connections in use per backend, 0 for no limit
+    pub max_backend_connections: usize,
+    // requests that may wait for a backend, per pool
+    pub queue_size: usize,
+    // how long a request waits before it gets a 503
+    pub queue_timeout_ms: u64,
+    // bytes in the request line, the CRLF not counted
+    pub max_request_line: usize,
+    // bytes in a request head, request line included
+    pub max_header_size: usize,
+    // header lines in a request
+    pub max_headers: usize,
+    // bytes in a request body, 0 for no limit
+    pub max_body_size: u64,
+    // from the first byte of a request to the end of its head, 0 for none
+    pub header_timeout_ms: u64,
+    // average request body bytes per second, 0 turns the check off
+    pub min_body_rate: u64,
+    // how long a body may take before min_body_rate applies
+    pub min_rate_grace_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: 0,
+            max_backend_connections: 0,
+            queue_size: 256,
+            queue_timeout_ms: 2_000,
+            max_request_line: 8 * 1024,
+            max_header_size: 32 * 1024,
+            max_headers: 100,
+            max_body_size: 0,
+            header_timeout_ms: 10_000,
+            min_body_rate: 240,
+            min_rate_grace_ms: 5_000,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn head(&self) -> HeadLimits {
+        HeadLimits {
+            request_line: self.max_request_line,
+            size: self.max_header_size,
+            headers: self.max_headers,
+        }
+    }
+
+    pub fn header_timeout(&self) -> Option<Duration> {
+        (self.header_timeout_ms > 0).then(|| Duration::from_millis(self.header_timeout_ms))
+    }
+
+    pub(crate) fn min_rate(&self) -> Option<MinRate> {
+        (self.min_body_rate > 0).then(|| MinRate {
+            bytes_per_sec: self.min_body_rate,
+            grace: Duration::from_millis(self.min_rate_grace_ms),
+        })
+    }
+}
+
+// A client that went over one of the request limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    RequestLine,
+    HeaderSize,
+    HeaderCount,
+    BodySize,
+    HeaderTimeout,
+    MinRate,
+}
+
+impl Violation {
+    pub const ALL: [Violation; 6] = [
+        Violation::RequestLine,
+        Violation::HeaderSize,
+        Violation::HeaderCount,
+        Violation::BodySize,
+        Violation::HeaderTimeout,
+        Violation::MinRate,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Violation::RequestLine => "request_line",
+            Violation::HeaderSize => "header_size",
+            Violation::HeaderCount => "header_count",
+            Violation::BodySize => "body_size",
+            Violation::HeaderTimeout => "header_timeout",
+            Violation::MinRate => "min_rate",
+        }
+    }
+
+    // the status the client is answered with
+    pub fn status(self) -> u16 {
+        match self {
+            Violation::RequestLine | Violation::HeaderSize | Violation::HeaderCount => 400,
+            Violation::BodySize => 413,
+            Violation::HeaderTimeout | Violation::MinRate => 408,
+        }
+    }
+}
+
+impl From<TooLarge> for Violation {
+    fn from(too_large: TooLarge) -> Violation {
+        match too_large {
+            TooLarge::RequestLine => Violation::RequestLine,
+            TooLarge::Head => Violation::HeaderSize,
+            TooLarge::HeaderCount => Violation::HeaderCount,
+            TooLarge::Body => Violation::BodySize,
+        }
+    }
+}
+
+// Things in use, like connections, counted against a cap.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    // Takes one if fewer than `max` are in use, 0 means no cap.
+    pub fn try_claim(&self, max: usize) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (max == 0 || n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    QueueFull,
+    QueueTimeout,
+}
+
+impl Rejected {
+    // the status the client is answered with in http mode
+    pub fn status(self) -> u16 {
+        503
+    }
+
+    pub(crate) fn termination(self) -> Termination {
+        match self {
+            Rejected::QueueFull => Termination::QueueFull,
+            Rejected::QueueTimeout => Termination::QueueTimeout,
+        }
+    }
+}
+
+// Requests waiting for a backend of one pool.
+#[derive(Debug, Default)]
+pub struct Queue {
+    waiting: Mutex<Waiting>,
+    // signalled when a slot is freed or the head of the queue changes
+    changed: Condvar,
+    // the length of the queue, readable without the lock
+    depth: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    tickets: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+impl Queue {
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    // Waits in line, then until `claim` gets a slot. `claim` is only called
+    // by the request at the head of the queue.
+    pub fn wait<T>(
+        &self,
+        config: &LimitsConfig,
+        mut claim: impl FnMut() -> Option<T>,
+    ) -> Result<T, Rejected> {
+        let mut waiting = self.waiting.lock().unwrap();
+        if waiting.tickets.len() >= config.queue_size {
+            return Err(Rejected::QueueFull);
+        }
+        let ticket = waiting.next_ticket;
+        waiting.next_ticket += 1;
+        waiting.tickets.push_back(ticket);
+        self.depth.store(waiting.tickets.len(), Ordering::SeqCst);
+
+        let deadline = Instant::now() + Duration::from_millis(config.queue_timeout_ms);
+        let result = loop {
+            if waiting.tickets.front() == Some(&ticket)
+                && let Some(slot) = claim()
+            {
+                break Ok(slot);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(Rejected::QueueTimeout);
+            }
+            waiting = self
+                .changed
+                .wait_timeout(waiting, deadline - now)
+                .unwrap()
+                .0;
+        };
+        waiting.tickets.retain(|&t| t != ticket);
+        self.depth.store(waiting.tickets.len(), Ordering::SeqCst);
+        // the next in line may find a slot too
+        self.changed.notify_all();
+        result
+    }
+
+    // A slot was given back. The counter must be released before this is
+    // called, a request that queues in between then sees the free slot.
+    pub fn notify(&self) {
+        if self.depth() > 0 {
+            let _waiting = self.waiting.lock().unwrap();
+            self.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    fn limits(queue_size: usize, queue_timeout_ms: u64) -> LimitsConfig {
+        LimitsConfig {
+            queue_size,
+            queue_timeout_ms,
+            ..LimitsConfig::default()
+        }
+    }
+
+    fn wait_for_depth(queue: &Queue, depth: usize) {
+        let started = Instant::now();
+        while queue.depth() != depth {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn counter_caps_claims() {
+        let counter = Counter::default();
+        assert!(counter.try_claim(2) && counter.try_claim(2));
+        assert!(!counter.try_claim(2));
+        assert_eq!(counter.get(), 2);
+        counter.release();
+        assert!(counter.try_claim(2));
+        // 0 is no cap
+        assert!((0..100).all(|_| counter.try_claim(0)));
+    }
+
+    #[test]
+    fn slots_go_to_the_waiters_in_ticket_order() {
+        let queue = Arc::new(Queue::default());
+        let slot = Arc::new(Counter::default());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        assert!(slot.try_claim(1));
+
+        let waiters: Vec<_> = (0..5)
+            .map(|i| {
+                let waiter = {
+                    let (queue, slot, order) = (queue.clone(), slot.clone(), order.clone());
+                    thread::spawn(move || {
+                        let claim = || slot.try_claim(1).then_some(i);
+                        let got = queue.wait(&limits(10, 5_000), claim).unwrap();
+                        order.lock().unwrap().push(got);
+                        slot.release();
+                        queue.notify();
+                    })
+                };
+                // each one is in line before the next one comes
+                wait_for_depth(&queue, i + 1);
+                waiter
+            })
+            .collect();
+        slot.release();
+        queue.notify();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2, 3, 4]);
+        assert_eq!((queue.depth(), slot.get()), (0, 0));
+    }
+
+    #[test]
+    fn waiting_too_long_gets_a_503() {
+        let queue = Queue::default();
+        let started = Instant::now();
+        let result = queue.wait(&limits(10, 50), || None::<()>);
+        assert_eq!(result, Err(Rejected::QueueTimeout));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(Rejected::QueueTimeout.status(), 503);
+        assert_eq!(
+            Rejected::QueueTimeout.termination(),
+            Termination::QueueTimeout
+        );
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn a_full_queue_turns_newcomers_away() {
+        let queue = Arc::new(Queue::default());
+        let waiter = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.wait(&limits(1, 300), || None::<()>))
+        };
+        wait_for_depth(&queue, 1);
+        let started = Instant::now();
+        let result = queue.wait(&limits(1, 300), || Some(()));
+        assert_eq!(result, Err(Rejected::QueueFull));
+        // straight away, not after the timeout
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(Rejected::QueueFull.status(), 503);
+        assert_eq!(Rejected::QueueFull.termination(), Termination::QueueFull);
+        assert_eq!(waiter.join().unwrap(), Err(Rejected::QueueTimeout));
+
+        // a size of 0 queues nobody
+        assert_eq!(
+            queue.wait(&limits(0, 300), || Some(())),
+            Err(Rejected::QueueFull)
+        );
+    }
+}
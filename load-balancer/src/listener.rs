@@ -0,0 +1,168 @@
+/*
+ * Accepting clients on several cores.
+ *
+ * With one accept loop every new connection goes through the same thread
+ * and the same socket queue. Instead each worker thread opens its own
+ * socket on the listen address with SO_REUSEPORT, and the kernel hashes
+ * incoming connections over those sockets. The workers share nothing but
+ * the LoadBalancer, whose hot paths are atomics, a swapped config pointer
+ * and a sharded connection pool.
+ *
+ * Where SO_REUSEPORT doesn't exist all workers accept on clones of a single
+ * socket instead, which still spreads the accept work but shares one queue.
+ * The same goes for a `unix:` listen address.
+ */
+#[cfg(unix)]
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[cfg(unix)]
+use socket2::SockAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::balancer::LoadBalancer;
+use crate::config::Mode;
+use crate::proxy;
+use crate::stream::{self, Stream};
+use crate::tcp;
+use crate::upstream;
+
+// pending connections per listening socket
+const BACKLOG: i32 = 1024;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(l) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+// Binds one listening socket per worker. All of them are bound before any
+// worker starts, so a port that is already taken fails startup as a whole.
+pub fn bind(addr: &str, workers: usize) -> io::Result<Vec<Listener>> {
+    if let Some(path) = stream::unix_path(addr) {
+        return bind_unix(path, workers);
+    }
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    })?;
+    let first = bind_one(addr)?;
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        if cfg!(unix) {
+            listeners.push(Listener::Tcp(bind_one(addr)?));
+        } else {
+            listeners.push(Listener::Tcp(first.try_clone()?));
+        }
+    }
+    listeners.insert(0, Listener::Tcp(first));
+    Ok(listeners)
+}
+
+fn bind_one(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+// A Unix socket path can only be bound once, so all workers accept on
+// clones of one socket. A socket file left behind by an earlier run is
+// replaced, one that somebody still accepts on is not.
+#[cfg(unix)]
+fn bind_unix(path: &str, workers: usize) -> io::Result<Vec<Listener>> {
+    let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        fs::remove_file(path)?;
+    }
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(BACKLOG)?;
+    let first: UnixListener = socket.into();
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        listeners.push(Listener::Unix(first.try_clone()?));
+    }
+    listeners.insert(0, Listener::Unix(first));
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str, _workers: usize) -> io::Result<Vec<Listener>> {
+    Err(stream::unsupported(path))
+}
+
+// Starts one accept loop per listener. Worker `i` also becomes the home
+// shard of every connection it accepts, see upstream::set_home_shard.
+pub fn spawn_workers(lb: &Arc<LoadBalancer>, listeners: Vec<Listener>) -> Vec<JoinHandle<()>> {
+    listeners
+        .into_iter()
+        .enumerate()
+        .map(|(i, listener)| {
+            let lb = lb.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || accept_loop(lb, listener, i))
+                .expect("failed to start worker thread")
+        })
+        .collect()
+}
+
+fn accept_loop(lb: Arc<LoadBalancer>, listener: Listener, worker: usize) {
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                if !proxy::admit(&lb, &stream) {
+                    continue;
+                }
+                if !lb.connections.try_claim(lb.config().limits.max_connections) {
+                    proxy::turn_away(&lb, stream);
+                    continue;
+                }
+                let slot = Slot(lb.clone());
+                let lb = lb.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    match lb.config().mode {
+                        Mode::Http => {
+                            upstream::set_home_shard(worker);
+                            proxy::handle_client(lb, stream)
+                        }
+                        Mode::Tcp => tcp::handle_client(lb, stream),
+                    }
+                });
+            }
+            Err(e) => warn!("accept failed: {}", e),
+        }
+    }
+}
+
+// A client connection counted against limits.max_connections, released
+// when the connection is done.
+struct Slot(Arc<LoadBalancer>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.connections.release();
+    }
+}
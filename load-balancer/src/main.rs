@@ -1,5 +1,35 @@
-const SERVER_ADDR: &str = "127.0.0.1:8080";
+mod cli;
+
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use clap::Parser;
+
+use load_balancer::LoadBalancer;
+use load_balancer::config::Overrides;
+use load_balancer::log::{self, Level};
+
+use cli::{Cli, Command, ConfigArgs};
 
 fn main() {
-    println!("server is {}", SERVER_ADDR);
+    let cli = Cli::parse();
+    log::set_level(cli.log_level);
+    let overrides = cli.overrides();
+    // no subcommand runs the proxy
+    let command = cli.command.unwrap_or(Command::Run(ConfigArgs::default()));
+    match command {
+        Command::Run(args) => run(&args.config, overrides),
+        Command::Check(args) => cli::check(&args.config, &overrides),
+        Command::Status { admin } => cli::status(&admin),
+    }
+}
+
+fn run(config_path: &Path, overrides: Overrides) {
+    let config = cli::load_config(config_path, &overrides);
+    let lb = LoadBalancer::new(config).with_config_file(config_path.to_path_buf(), overrides);
+    if let Err(e) = load_balancer::run(Arc::new(lb)) {
+        log::write(Level::Error, format_args!("{}", e));
+        process::exit(1);
+    }
 }
@@ -0,0 +1,365 @@
+/*
+ * Counters for the admin /metrics endpoint, rendered in the Prometheus text
+ * format. Everything is a plain atomic so the request path never takes a
+ * lock just to count something.
+ */
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::access_log::Termination;
+use crate::grpc::Code;
+use crate::limit::Violation;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub requests: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    // a pooled keep-alive connection was reused
+    pub pool_hits: AtomicU64,
+    // no usable idle connection, a new one had to be dialed
+    pub pool_misses: AtomicU64,
+    // idle connections thrown away because they expired or went bad
+    pub pool_discarded: AtomicU64,
+    // shadow requests sent, failed, and skipped because too many were
+    // already in flight
+    pub mirror_requests: AtomicU64,
+    pub mirror_failures: AtomicU64,
+    pub mirror_skipped: AtomicU64,
+    // clients refused by an ACL, when connecting and per route
+    pub acl_denied_listener: AtomicU64,
+    pub acl_denied_route: AtomicU64,
+    // GET requests on cached routes answered from the cache, sent to the
+    // backend, and stale responses the backend confirmed with a 304
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub cache_revalidated: AtomicU64,
+    // requests answered with a route's redirect
+    pub redirects: AtomicU64,
+    // requests a route's fault injection held up, aborted or reset
+    pub fault_delays: AtomicU64,
+    pub fault_aborts: AtomicU64,
+    pub fault_resets: AtomicU64,
+    // spans sent to the trace exporter, and ones lost because the queue
+    // was full or the export failed
+    pub trace_spans_exported: AtomicU64,
+    pub trace_spans_dropped: AtomicU64,
+    // bytes forwarded in tcp mode, moved in the kernel with splice or
+    // copied through a buffer
+    pub tcp_bytes_spliced: AtomicU64,
+    pub tcp_bytes_copied: AtomicU64,
+    // how long requests waited in a pool queue for a backend
+    pub queue_wait: Histogram,
+    // gRPC calls by the status they ended with, indexed like Code::ALL,
+    // and calls tried again on another backend
+    pub grpc_calls: [AtomicU64; Code::ALL.len()],
+    pub grpc_retries: AtomicU64,
+    // backends this instance took out and told the cluster about, and
+    // ones it took out because another instance said so
+    pub cluster_health_shared: AtomicU64,
+    pub cluster_health_received: AtomicU64,
+    // gossip dropped for a bad signature or an unknown sender
+    pub cluster_rejected: AtomicU64,
+    // clients over a request limit, indexed like Violation::ALL
+    limit_violations: [AtomicU64; Violation::ALL.len()],
+    // finished requests by how they ended, indexed like Termination::ALL
+    terminations: [AtomicU64; Termination::ALL.len()],
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn terminated(&self, termination: Termination) {
+        Metrics::inc(&self.terminations[termination as usize]);
+    }
+
+    pub fn violated(&self, violation: Violation) {
+        Metrics::inc(&self.limit_violations[violation as usize]);
+    }
+
+    pub fn render(
+        &self,
+        client_connections: usize,
+        idle_connections: usize,
+        cache_bytes: usize,
+    ) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "lb_requests_total",
+            "Requests received from clients.",
+            &self.requests,
+        );
+        counter(
+            &mut out,
+            "lb_upstream_errors_total",
+            "Requests that failed talking to a backend.",
+            &self.upstream_errors,
+        );
+        counter(
+            &mut out,
+            "lb_upstream_pool_hits_total",
+            "Requests sent over a reused keep-alive connection.",
+            &self.pool_hits,
+        );
+        counter(
+            &mut out,
+            "lb_upstream_pool_misses_total",
+            "Requests that needed a new backend connection.",
+            &self.pool_misses,
+        );
+        counter(
+            &mut out,
+            "lb_upstream_pool_discarded_total",
+            "Idle connections closed because they expired or failed validation.",
+            &self.pool_discarded,
+        );
+        counter(
+            &mut out,
+            "lb_mirror_requests_total",
+            "Shadow requests sent to a mirror pool.",
+            &self.mirror_requests,
+        );
+        counter(
+            &mut out,
+            "lb_mirror_failures_total",
+            "Shadow requests that failed.",
+            &self.mirror_failures,
+        );
+        counter(
+            &mut out,
+            "lb_mirror_skipped_total",
+            "Requests not mirrored because the body was too big or too many shadow requests were in flight.",
+            &self.mirror_skipped,
+        );
+        labeled(
+            &mut out,
+            "lb_acl_denied_total",
+            "Clients refused by an access control list.",
+            "counter",
+            "level",
+            [
+                ("listener", &self.acl_denied_listener),
+                ("route", &self.acl_denied_route),
+            ]
+            .into_iter()
+            .map(|(level, n)| (level, n.load(Ordering::Relaxed))),
+        );
+        labeled(
+            &mut out,
+            "lb_cache_lookups_total",
+            "Cacheable requests by whether the cache could answer them.",
+            "counter",
+            "result",
+            [
+                ("hit", &self.cache_hits),
+                ("miss", &self.cache_misses),
+                ("revalidated", &self.cache_revalidated),
+            ]
+            .into_iter()
+            .map(|(result, n)| (result, n.load(Ordering::Relaxed))),
+        );
+        counter(
+            &mut out,
+            "lb_redirects_total",
+            "Requests answered with a redirect by their route.",
+            &self.redirects,
+        );
+        labeled(
+            &mut out,
+            "lb_faults_injected_total",
+            "Requests hit by fault injection, by the fault.",
+            "counter",
+            "fault",
+            [
+                ("delay", &self.fault_delays),
+                ("abort", &self.fault_aborts),
+                ("reset", &self.fault_resets),
+            ]
+            .into_iter()
+            .map(|(fault, n)| (fault, n.load(Ordering::Relaxed))),
+        );
+        labeled(
+            &mut out,
+            "lb_trace_spans_total",
+            "Finished spans by whether they were exported.",
+            "counter",
+            "result",
+            [
+                ("exported", &self.trace_spans_exported),
+                ("dropped", &self.trace_spans_dropped),
+            ]
+            .into_iter()
+            .map(|(result, n)| (result, n.load(Ordering::Relaxed))),
+        );
+        labeled(
+            &mut out,
+            "lb_tcp_bytes_total",
+            "Bytes forwarded in tcp mode, by how they were moved.",
+            "counter",
+            "method",
+            [
+                ("splice", &self.tcp_bytes_spliced),
+                ("copy", &self.tcp_bytes_copied),
+            ]
+            .into_iter()
+            .map(|(method, n)| (method, n.load(Ordering::Relaxed))),
+        );
+        labeled(
+            &mut out,
+            "lb_requests_terminated_total",
+            "Finished requests by how they ended.",
+            "counter",
+            "reason",
+            Termination::ALL.iter().map(|t| {
+                (
+                    t.as_str(),
+                    self.terminations[*t as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        labeled(
+            &mut out,
+            "lb_limit_violations_total",
+            "Requests refused for going over a request limit, by limit.",
+            "counter",
+            "limit",
+            Violation::ALL.iter().map(|v| {
+                (
+                    v.as_str(),
+                    self.limit_violations[*v as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        labeled(
+            &mut out,
+            "lb_grpc_calls_total",
+            "Finished gRPC calls by status.",
+            "counter",
+            "code",
+            Code::ALL.iter().map(|c| {
+                (
+                    c.as_str(),
+                    self.grpc_calls[*c as usize].load(Ordering::Relaxed),
+                )
+            }),
+        );
+        counter(
+            &mut out,
+            "lb_grpc_retries_total",
+            "gRPC calls sent to another backend after the first one failed.",
+            &self.grpc_retries,
+        );
+        counter(
+            &mut out,
+            "lb_cluster_health_shared_total",
+            "Backends this instance took out and told the cluster about.",
+            &self.cluster_health_shared,
+        );
+        counter(
+            &mut out,
+            "lb_cluster_health_received_total",
+            "Backends taken out because another instance found them down.",
+            &self.cluster_health_received,
+        );
+        counter(
+            &mut out,
+            "lb_cluster_messages_rejected_total",
+            "Gossip messages dropped for a bad signature or an unknown sender.",
+            &self.cluster_rejected,
+        );
+        self.queue_wait.render(
+            &mut out,
+            "lb_queue_wait_seconds",
+            "Time requests spent waiting for a backend slot.",
+        );
+        gauge(
+            &mut out,
+            "lb_client_connections",
+            "Client connections currently open.",
+            client_connections as u64,
+        );
+        gauge(
+            &mut out,
+            "lb_upstream_pool_idle",
+            "Idle keep-alive connections currently pooled.",
+            idle_connections as u64,
+        );
+        gauge(
+            &mut out,
+            "lb_cache_bytes",
+            "Approximate size of the responses in the cache.",
+            cache_bytes as u64,
+        );
+        out
+    }
+}
+
+// upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // observations per bucket, not cumulative, the last one is +Inf
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(BUCKETS.len());
+        Metrics::inc(&self.buckets[bucket]);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// A metric with one label, for example requests per pool.
+pub fn labeled<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (value, n) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, n);
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * Traffic mirroring (shadowing).
+ *
+ * A route can copy part of its requests to a second pool, for example to try
+ * a new backend version on real traffic. The copy is sent from its own
+ * thread once the client's body has been read, and the shadow response is
+ * read and dropped. Nothing about the shadow request ever reaches the
+ * client: failures are only counted.
+ *
+ * The shadow backends are backends like any other, so a failed connect
+ * counts against their health and a response counts for it. A shadow
+ * backend that keeps failing is taken out like any other, and if it is
+ * also in a pool that serves clients, it is out there too.
+ */
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Instant;
+
+use crate::balancer::LoadBalancer;
+use crate::config::{MirrorConfig, Timeouts};
+use crate::http::{self, BodyKind, RequestHead, ResponseHead};
+use crate::metrics::Metrics;
+use crate::rng;
+use crate::strategy::Context;
+
+// Shadow requests are best effort, when this many are still in flight new
+// ones are skipped instead of piling up threads.
+const MAX_IN_FLIGHT: usize = 64;
+
+// Decides whether this request gets mirrored. Returns a buffer to collect
+// the request body into, or None.
+pub fn sample(lb: &LoadBalancer, mirror: &MirrorConfig) -> Option<Vec<u8>> {
+    if !rng::chance(mirror.percent) {
+        return None;
+    }
+    if lb.mirrors_in_flight.load(Ordering::Relaxed) >= MAX_IN_FLIGHT {
+        Metrics::inc(&lb.metrics.mirror_skipped);
+        return None;
+    }
+    Some(Vec::new())
+}
+
+// Sends the shadow request in the background.
+pub fn spawn(
+    lb: Arc<LoadBalancer>,
+    mirror: &MirrorConfig,
+    client: IpAddr,
+    head: RequestHead,
+    body: Vec<u8>,
+    timeouts: Timeouts,
+) {
+    let pool = mirror.pool.clone();
+    lb.mirrors_in_flight.fetch_add(1, Ordering::Relaxed);
+    thread::spawn(move || {
+        Metrics::inc(&lb.metrics.mirror_requests);
+        if let Err(e) = send(&lb, &pool, client, head, &body, &timeouts) {
+            Metrics::inc(&lb.metrics.mirror_failures);
+            warn!("mirror to pool {} failed: {}", pool, e);
+        }
+        lb.mirrors_in_flight.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+fn send(
+    lb: &LoadBalancer,
+    pool: &str,
+    client: IpAddr,
+    mut head: RequestHead,
+    body: &[u8],
+    timeouts: &Timeouts,
+) -> io::Result<()> {
+    let pool = lb
+        .pool(pool)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such pool"))?;
+    let config = lb.config();
+    // shadow traffic never queues for a busy backend
+    let context = Context {
+        client,
+        request: Some(&head),
+    };
+    let permit = pool
+        .try_acquire(&config, &context)
+        .ok_or_else(|| io::Error::other("every backend is at its connection limit"))?;
+    let backend = permit.backend;
+
+    // the body is complete now, so it always goes out with a length
+    head.headers.remove("transfer-encoding");
+    if body.is_empty() {
+        head.headers.remove("content-length");
+    } else {
+        head.headers.set("content-length", &body.len().to_string());
+    }
+
+    let mut conn = lb
+        .conns
+        .get(&backend.addr, timeouts.connect())
+        .inspect_err(|e| backend.failed(&config.health, e))?;
+    let stream = conn.stream.get_mut();
+    stream.set_idle(timeouts.idle());
+    stream.set_deadline(timeouts.total().map(|total| Instant::now() + total));
+
+    let mut upstream = BufWriter::new(stream);
+    head.write_to(&mut upstream)?;
+    upstream.write_all(body)?;
+    upstream.flush()?;
+    drop(upstream);
+
+    conn.stream
+        .get_mut()
+        .expect_first_byte(timeouts.first_byte());
+    let response = ResponseHead::read_final(&mut conn.stream)?;
+    backend.health.succeeded();
+    let kind = response.body_kind(&head.method)?;
+    http::copy_body(
+        &mut conn.stream,
+        kind,
+        &mut io::sink(),
+        BodyKind::UntilClose,
+    )
+    .map_err(|e| match e {
+        http::CopyError::Read(e) | http::CopyError::Write(e) => e,
+    })?;
+
+    if response.keep_alive() && kind != BodyKind::UntilClose {
+        conn.stream.get_mut().set_deadline(None);
+        lb.conns.put(&backend.addr, conn);
+    }
+    Ok(())
+}
@@ -0,0 +1,815 @@
+/*
+ * The HTTP proxy: one thread per client connection. Each request on the
+ * connection is forwarded to the next backend and the response is streamed
+ * back, until either side closes or asks for `Connection: close`.
+ */
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use crate::access_log::{Entry, Termination};
+use crate::balancer::LoadBalancer;
+use crate::cache::{self, Lookup, Stored};
+use crate::compress::{self, Encoding};
+use crate::config::{Config, DEFAULT_POOL, Mode, RouteConfig, Timeouts};
+use crate::error_page::{self, Details};
+use crate::fault::{self, Outcome};
+use crate::grpc;
+use crate::h2;
+use crate::http::{self, BodyKind, CopyError, RequestHead, ResponseHead};
+use crate::limit::Violation;
+use crate::metrics::Metrics;
+use crate::mirror;
+use crate::split;
+use crate::strategy::Context;
+use crate::stream::Stream;
+use crate::timeout::{self, Expired, Timed};
+use crate::trace::Trace;
+use crate::upstream::UpstreamConn;
+
+type ClientReader = BufReader<Timed>;
+type ClientWriter = BufWriter<Timed>;
+
+enum ProxyError {
+    // nothing was sent to the client yet, so it still gets a proper error
+    // response with this status
+    Respond(u16, Termination),
+    // the response was already started (or the client is gone), all we can
+    // do is close the connection
+    Close(Termination),
+    // the route wants credentials, answered with 401 and these
+    // WWW-Authenticate challenges
+    Unauthorized(Vec<String>),
+}
+
+// A failed read or write during a request, by side.
+enum SendError {
+    Client(io::Error),
+    Backend(io::Error),
+}
+
+// Runs the listener ACL on a freshly accepted connection, before any thread
+// is spent on it. Returns false if the connection must be dropped.
+pub fn admit(lb: &LoadBalancer, client: &Stream) -> bool {
+    let config = lb.config();
+    let peer = match client.peer_addr(config.unix_client_ip) {
+        Ok(peer) => peer,
+        Err(_) => return false,
+    };
+    if config.acl.permits(peer.ip()) {
+        return true;
+    }
+    Metrics::inc(&lb.metrics.acl_denied_listener);
+    if config.access_log {
+        let mut entry = Entry::new(peer, "-", "-");
+        entry.termination = Termination::AclDenied;
+        entry.write();
+    }
+    false
+}
+
+// Turns away a client over limits.max_connections, with a 503 in http
+// mode. This runs on the accept thread, so the write must not block.
+pub fn turn_away(lb: &LoadBalancer, mut client: Stream) {
+    let config = lb.config();
+    if config.mode == Mode::Http && client.set_nonblocking(true).is_ok() {
+        let request_id = error_page::request_id(None, &Trace::off());
+        let details = Details {
+            status: 503,
+            termination: Termination::ConnectionLimit,
+            request_id: &request_id,
+            route: "",
+            path: "",
+        };
+        let (response, body) = error_page::response(&config, None, &details);
+        let _ = response
+            .write_to(&mut client)
+            .and_then(|_| client.write_all(&body));
+    }
+    lb.metrics.terminated(Termination::ConnectionLimit);
+    if config.access_log
+        && let Ok(peer) = client.peer_addr(config.unix_client_ip)
+    {
+        let mut entry = Entry::new(peer, "-", "-");
+        entry.termination = Termination::ConnectionLimit;
+        entry.write();
+    }
+}
+
+pub fn handle_client(lb: Arc<LoadBalancer>, client: Stream) {
+    let _ = client.set_nodelay(true);
+    let peer = match client.peer_addr(lb.config().unix_client_ip) {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    let idle = lb.config().global_timeouts().idle();
+    let mut writer = match client.try_clone() {
+        Ok(stream) => BufWriter::new(Timed::new(stream, idle)),
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(Timed::new(client, idle));
+
+    // h2c with prior knowledge, for gRPC
+    if reader
+        .fill_buf()
+        .is_ok_and(|buf| buf.starts_with(&h2::PREFACE[..4]))
+    {
+        grpc::serve(lb, peer, reader, writer);
+        return;
+    }
+
+    loop {
+        // closed, or an idle keep-alive connection timed out
+        if !reader.fill_buf().is_ok_and(|buf| !buf.is_empty()) {
+            return;
+        }
+        // the head has to be complete in time once it started
+        let config = lb.config();
+        let header_deadline = config.limits.header_timeout().map(|t| Instant::now() + t);
+        reader.get_mut().set_deadline(header_deadline);
+        let head = match RequestHead::read(&mut reader, &config.limits.head()) {
+            Ok(Some(head)) => head,
+            Ok(None) => return,
+            Err(e) => {
+                let violation = match (http::too_large(&e), timeout::expired(&e)) {
+                    (Some(too_large), _) => Some(Violation::from(too_large)),
+                    (None, Some(_)) => Some(Violation::HeaderTimeout),
+                    (None, None) => None,
+                };
+                if let Some(violation) = violation {
+                    debug!("{} refused: {}", peer.ip(), e);
+                    lb.metrics.violated(violation);
+                }
+                let (status, termination) = match violation.map(Violation::status) {
+                    Some(408) => (408, Termination::ClientTooSlow),
+                    _ => (400, Termination::BadRequest),
+                };
+                // no route to pick a page by, the path may not have been read
+                let request_id = error_page::request_id(None, &Trace::off());
+                let details = Details {
+                    status,
+                    termination,
+                    request_id: &request_id,
+                    route: "",
+                    path: "",
+                };
+                let (response, body) = error_page::response(&config, None, &details);
+                let _ = response
+                    .write_to(&mut writer)
+                    .and_then(|_| writer.write_all(&body))
+                    .and_then(|_| writer.flush());
+                return;
+            }
+        };
+        Metrics::inc(&lb.metrics.requests);
+
+        let timeouts = config.timeouts_for(head.path());
+        let mut entry = Entry::new(peer, &head.method, &head.target);
+        let deadline = timeouts.total().map(|total| entry.start + total);
+        reader.get_mut().set_idle(timeouts.idle());
+        reader.get_mut().set_deadline(deadline);
+        writer.get_mut().set_idle(timeouts.idle());
+        writer.get_mut().set_deadline(deadline);
+
+        let mut trace = Trace::start(&config.tracing, &head);
+        let route_config = config.route(head.path());
+        let route = route_config.map(|r| r.name.clone().unwrap_or_else(|| r.path_prefix.clone()));
+        // for error pages, the request itself is gone by then
+        let request_id = head.headers.get("x-request-id").map(str::to_string);
+        let result = forward(
+            &lb,
+            &config,
+            peer,
+            head,
+            &timeouts,
+            deadline,
+            &mut reader,
+            &mut writer,
+            &mut entry,
+            &mut trace,
+        );
+        match result {
+            Err(ProxyError::Respond(_, Termination::RequestTooLarge)) => {
+                lb.metrics.violated(Violation::BodySize)
+            }
+            Err(ProxyError::Respond(_, Termination::ClientTooSlow)) => {
+                lb.metrics.violated(Violation::MinRate)
+            }
+            _ => {}
+        }
+        // the error response gets its own short grace period, the deadline
+        // may be what just ran out
+        let mut respond = |writer: &mut ClientWriter,
+                           status: u16,
+                           termination: Termination,
+                           challenges: &[String]| {
+            writer.get_mut().set_deadline(None);
+            let request_id = error_page::request_id(request_id.as_deref(), &trace);
+            let details = Details {
+                status,
+                termination,
+                request_id: &request_id,
+                route: route.as_deref().unwrap_or(""),
+                path: entry.target.split('?').next().unwrap_or(""),
+            };
+            let (mut response, body) = error_page::response(&config, route_config, &details);
+            for challenge in challenges {
+                response.headers.append("www-authenticate", challenge);
+            }
+            let _ = response
+                .write_to(writer)
+                .and_then(|_| writer.write_all(&body))
+                .and_then(|_| writer.flush());
+            entry.status = Some(status);
+            entry.termination = termination;
+        };
+        let keep_alive = match result {
+            Ok(keep_alive) => keep_alive,
+            Err(ProxyError::Respond(status, termination)) => {
+                respond(&mut writer, status, termination, &[]);
+                false
+            }
+            Err(ProxyError::Unauthorized(challenges)) => {
+                respond(&mut writer, 401, Termination::Unauthorized, &challenges);
+                false
+            }
+            Err(ProxyError::Close(termination)) => {
+                entry.termination = termination;
+                false
+            }
+        };
+
+        if entry.termination.is_backend_failure() {
+            Metrics::inc(&lb.metrics.upstream_errors);
+        }
+        lb.metrics.terminated(entry.termination);
+        trace.finish(&lb.tracer, &entry, route.as_deref());
+        if config.access_log {
+            entry.write();
+        }
+        if !keep_alive {
+            return;
+        }
+        reader.get_mut().set_idle(idle);
+        reader.get_mut().set_deadline(None);
+        writer.get_mut().set_idle(idle);
+        writer.get_mut().set_deadline(None);
+    }
+}
+
+// Answers a request with a stored response, or a 304 when the client
+// already has it.
+fn serve_cached(
+    head: &RequestHead,
+    route: Option<&RouteConfig>,
+    stored: &Stored,
+    encoding: Option<Encoding>,
+    writer: &mut ClientWriter,
+    entry: &mut Entry,
+) -> Result<bool, ProxyError> {
+    let keep_alive = head.keep_alive();
+    let mut response = stored.head.clone();
+    response.version = "HTTP/1.1".to_string();
+    response
+        .headers
+        .set("age", &stored.age().as_secs().to_string());
+    if let Some(route) = route {
+        route.response_headers.apply(&mut response.headers);
+    }
+    let compressed;
+    let body: &[u8] = if cache::not_modified(head, stored) {
+        response.status = 304;
+        response.reason = http::reason(304).to_string();
+        response.headers.remove("content-length");
+        &[]
+    } else if let Some(encoding) = encoding {
+        compress::prepare(&mut response, encoding);
+        compressed = compress::encode(encoding, &stored.body);
+        response
+            .headers
+            .set("content-length", &compressed.len().to_string());
+        &compressed
+    } else {
+        response
+            .headers
+            .set("content-length", &stored.body.len().to_string());
+        &stored.body
+    };
+    if !keep_alive {
+        response.headers.set("connection", "close");
+    }
+    entry.status = Some(response.status);
+    response
+        .write_to(writer)
+        .and_then(|_| writer.write_all(body))
+        .and_then(|_| writer.flush())
+        .map_err(|e| ProxyError::Close(client_failure(&e)))?;
+    entry.bytes = body.len() as u64;
+    Ok(keep_alive)
+}
+
+// Answers with a route's redirect. The connection stays open unless the
+// request has a body, which we don't read.
+fn send_redirect(
+    route: &RouteConfig,
+    status: u16,
+    location: &str,
+    head: &RequestHead,
+    body: BodyKind,
+    writer: &mut ClientWriter,
+    entry: &mut Entry,
+) -> Result<bool, ProxyError> {
+    let keep_alive = head.keep_alive() && body == BodyKind::Empty;
+    let mut response = ResponseHead::new(status);
+    response.headers.set("location", location);
+    response.headers.set("content-length", "0");
+    route.response_headers.apply(&mut response.headers);
+    if !keep_alive {
+        response.headers.set("connection", "close");
+    }
+    entry.status = Some(status);
+    response
+        .write_to(writer)
+        .and_then(|_| writer.flush())
+        .map_err(|e| ProxyError::Close(client_failure(&e)))?;
+    Ok(keep_alive)
+}
+
+// How to compress the response to `head`, if at all.
+fn compression(
+    config: &Config,
+    route: Option<&RouteConfig>,
+    head: &RequestHead,
+    response: &ResponseHead,
+    body: BodyKind,
+) -> Option<Encoding> {
+    let enabled = route
+        .and_then(|r| r.compress)
+        .unwrap_or(config.compression.enabled);
+    if !enabled {
+        return None;
+    }
+    compress::choose(&config.compression, head, response, body)
+}
+
+pub fn error_body(termination: Termination) -> &'static str {
+    match termination {
+        Termination::BadRequest => "bad request\n",
+        Termination::RequestTooLarge => "request body too large\n",
+        Termination::AclDenied => "forbidden\n",
+        Termination::Unauthorized => "unauthorized\n",
+        Termination::Maintenance => "down for maintenance\n",
+        Termination::FaultInjected => "fault injected\n",
+        Termination::ConnectTimeout => "backend connect timeout\n",
+        Termination::FirstByteTimeout => "backend response timeout\n",
+        Termination::TotalTimeout | Termination::ClientTooSlow => "request timeout\n",
+        Termination::ConnectionLimit | Termination::QueueFull | Termination::QueueTimeout => {
+            "service unavailable\n"
+        }
+        _ => "bad gateway\n",
+    }
+}
+
+// Forwards one request and its response. Returns whether the client
+// connection can be kept open for another request.
+#[allow(clippy::too_many_arguments)]
+fn forward(
+    lb: &Arc<LoadBalancer>,
+    config: &Config,
+    peer: SocketAddr,
+    mut head: RequestHead,
+    timeouts: &Timeouts,
+    deadline: Option<Instant>,
+    reader: &mut ClientReader,
+    writer: &mut ClientWriter,
+    entry: &mut Entry,
+    trace: &mut Trace,
+) -> Result<bool, ProxyError> {
+    let started = SystemTime::now();
+    let body = head
+        .body_kind()
+        .map_err(|_| ProxyError::Respond(400, Termination::BadRequest))?;
+    let route = config.route(head.path());
+    if let Some(route) = route
+        && !route.acl.permits(peer.ip())
+    {
+        Metrics::inc(&lb.metrics.acl_denied_route);
+        return Err(ProxyError::Respond(403, Termination::AclDenied));
+    }
+    if route.is_some_and(|r| r.maintenance.blocks(peer.ip())) {
+        return Err(ProxyError::Respond(503, Termination::Maintenance));
+    }
+    let target = match route {
+        Some(route) if !route.rewrite.is_empty() => {
+            route.rewrite.target(&route.path_prefix, &head.target)
+        }
+        _ => head.target.clone(),
+    };
+    // redirected before asking for credentials, which the client should
+    // only send once it is where the redirect leads
+    if let Some(route) = route
+        && let Some(redirect) = &route.redirect
+        && let Some(location) = redirect.location(&head, peer.ip(), &target)
+    {
+        Metrics::inc(&lb.metrics.redirects);
+        return send_redirect(
+            route,
+            redirect.status,
+            &location,
+            &head,
+            body,
+            writer,
+            entry,
+        );
+    }
+    if let Some(auth) = route.and_then(|r| r.auth.as_ref())
+        && let Err(denied) = auth.check(&mut head)
+    {
+        debug!("{} denied on {}: {}", peer.ip(), head.path(), denied.reason);
+        return Err(ProxyError::Unauthorized(denied.challenges));
+    }
+    // a body with a known length is refused before any of it is read,
+    // a chunked one while it streams through
+    let max_body = route
+        .and_then(|r| r.max_body_size)
+        .unwrap_or(config.limits.max_body_size);
+    let max_body = (max_body > 0).then_some(max_body);
+    if let (Some(max), BodyKind::Length(length)) = (max_body, body)
+        && length > max
+    {
+        return Err(ProxyError::Respond(413, Termination::RequestTooLarge));
+    }
+    if let Some((delay, outcome)) = route.and_then(|r| fault::inject(&r.faults, &head)) {
+        if !delay.is_zero() {
+            Metrics::inc(&lb.metrics.fault_delays);
+        }
+        match outcome {
+            Outcome::Forward => {}
+            Outcome::Abort(status) => {
+                Metrics::inc(&lb.metrics.fault_aborts);
+                return Err(ProxyError::Respond(status, Termination::FaultInjected));
+            }
+            // the reset goes out when the connection is dropped
+            Outcome::Reset => {
+                Metrics::inc(&lb.metrics.fault_resets);
+                let _ = writer.get_ref().get_ref().reset();
+                return Err(ProxyError::Close(Termination::FaultInjected));
+            }
+        }
+    }
+
+    let pool_name = match route {
+        Some(route) => match &route.split {
+            Some(split) => split::choose(split, &head, peer.ip()),
+            None => route.pool.as_deref().unwrap_or(DEFAULT_POOL),
+        },
+        None => DEFAULT_POOL,
+    };
+
+    // GETs on cached routes are answered from the cache when possible. Not
+    // on routes with auth: what one user gets another may not see, and the
+    // credentials that would tell are gone from the head by now
+    let cache_key = route
+        .filter(|r| r.cache && r.auth.is_none())
+        .and_then(|_| cache::key(pool_name, &head));
+    let (mut stale, _fill) = match &cache_key {
+        Some(primary) => match lb.cache.lookup(primary, &head) {
+            Lookup::Hit(stored) => {
+                Metrics::inc(&lb.metrics.cache_hits);
+                entry.backend = Some("cache".to_string());
+                let encoding = compression(config, route, &head, &stored.head, stored.body_kind());
+                return serve_cached(&head, route, &stored, encoding, writer, entry);
+            }
+            Lookup::Fetch { key, stale, fill } => {
+                Metrics::inc(&lb.metrics.cache_misses);
+                (stale.map(|stale| (key, stale)), fill)
+            }
+        },
+        None => (None, None),
+    };
+
+    // the config was validated, routes only name pools that exist
+    let pool = lb.pool(pool_name).expect("route names an unknown pool");
+    Metrics::inc(&pool.requests);
+    let context = Context {
+        client: peer.ip(),
+        request: Some(&head),
+    };
+    let permit = pool
+        .acquire(config, &context, &lb.metrics)
+        .map_err(|rejected| ProxyError::Respond(rejected.status(), rejected.termination()))?;
+    let backend = permit.backend;
+    entry.backend = Some(backend.addr.clone());
+    trace.phase("select backend", started);
+
+    let mut upstream_head = head.clone();
+    upstream_head.target = target;
+    upstream_head.version = "HTTP/1.1".to_string();
+    upstream_head.headers.strip_hop_by_hop();
+    if body == BodyKind::Chunked {
+        upstream_head.headers.set("transfer-encoding", "chunked");
+    }
+    let forwarded_for = match head.headers.get("x-forwarded-for") {
+        Some(prev) => format!("{}, {}", prev, peer.ip()),
+        None => peer.ip().to_string(),
+    };
+    upstream_head.headers.set("x-forwarded-for", &forwarded_for);
+    if let Some(route) = route {
+        route.request_headers.apply(&mut upstream_head.headers);
+    }
+    trace.inject(&mut upstream_head.headers);
+
+    // A stale response with validators is revalidated, unless the client
+    // made its own conditional request, which we leave alone.
+    let client_conditional = ["if-none-match", "if-modified-since"]
+        .iter()
+        .any(|name| head.headers.get(name).is_some());
+    if client_conditional {
+        stale = None;
+    }
+    if let Some((_, stored)) = &stale {
+        if let Some(etag) = stored.head.headers.get("etag") {
+            upstream_head.headers.set("if-none-match", etag);
+        }
+        if let Some(modified) = stored.head.headers.get("last-modified") {
+            upstream_head.headers.set("if-modified-since", modified);
+        }
+    }
+
+    // We answer `Expect: 100-continue` ourselves, otherwise the client would
+    // sit waiting for the go-ahead while we wait for its body.
+    if head.headers.has_token("expect", "100-continue") {
+        upstream_head.headers.remove("expect");
+        if body != BodyKind::Empty {
+            writer
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .and_then(|_| writer.flush())
+                .map_err(|e| ProxyError::Close(client_failure(&e)))?;
+        }
+    }
+
+    // when mirroring, the request body is collected on its way upstream
+    let mirror = route.and_then(|r| r.mirror.as_ref());
+    let mut mirror_body = mirror.and_then(|m| mirror::sample(lb, m));
+    let mirror_limit = mirror.map_or(0, |m| m.max_body_bytes);
+    let mut capture = |data: &[u8]| {
+        if let Some(buf) = &mut mirror_body {
+            if buf.len() + data.len() > mirror_limit {
+                Metrics::inc(&lb.metrics.mirror_skipped);
+                mirror_body = None;
+            } else {
+                buf.extend_from_slice(data);
+            }
+        }
+    };
+
+    let connecting = SystemTime::now();
+    let conn = lb.conns.get(&backend.addr, timeouts.connect());
+    trace.phase("connect", connecting);
+    let mut conn = conn.map_err(|e| {
+        backend.failed(&config.health, &e);
+        connect_failure(&e)
+    })?;
+    let sending = SystemTime::now();
+    reader.get_mut().set_min_rate(config.limits.min_rate());
+    let sent = write_request(
+        &mut conn,
+        &upstream_head,
+        (body, max_body),
+        timeouts,
+        deadline,
+        reader,
+        &mut capture,
+    );
+    reader.get_mut().set_min_rate(None);
+    // the copy only goes out once we have the whole client body
+    if let (Some(mirror), Some(mirror_body)) = (mirror, mirror_body)
+        && (sent.is_ok() || body == BodyKind::Empty)
+    {
+        mirror::spawn(
+            lb.clone(),
+            mirror,
+            peer.ip(),
+            upstream_head.clone(),
+            mirror_body,
+            timeouts.clone(),
+        );
+    }
+
+    let mut response = match sent.and_then(|_| read_response(&mut conn, timeouts)) {
+        Ok(response) => response,
+        // A pooled connection can be closed by the backend right as we pick
+        // it up. Without a body nothing is lost, so try once more on a fresh
+        // connection.
+        Err(SendError::Backend(e))
+            if conn.reused && body == BodyKind::Empty && timeout::expired(&e).is_none() =>
+        {
+            conn = UpstreamConn::connect(&backend.addr, timeouts.connect()).map_err(|e| {
+                backend.failed(&config.health, &e);
+                connect_failure(&e)
+            })?;
+            write_request(
+                &mut conn,
+                &upstream_head,
+                (body, None),
+                timeouts,
+                deadline,
+                reader,
+                &mut |_| {},
+            )
+            .and_then(|_| read_response(&mut conn, timeouts))
+            .map_err(request_failure)?
+        }
+        Err(e) => return Err(request_failure(e)),
+    };
+    backend.health.succeeded();
+
+    let fresh_for = cache_key.as_ref().and_then(|_| cache::freshness(&response));
+    if let (Some((key, stored)), 304) = (&stale, response.status) {
+        Metrics::inc(&lb.metrics.cache_revalidated);
+        let stored = match fresh_for {
+            Some(fresh_for) => lb.cache.refresh(key, stored, &response, fresh_for),
+            None => stored.clone(),
+        };
+        if response.keep_alive() {
+            conn.stream.get_mut().set_deadline(None);
+            lb.conns.put(&backend.addr, conn);
+        }
+        let encoding = compression(config, route, &head, &stored.head, stored.body_kind());
+        return serve_cached(&head, route, &stored, encoding, writer, entry);
+    }
+
+    let upstream_body = response
+        .body_kind(&head.method)
+        .map_err(|_| ProxyError::Respond(502, Termination::BackendError))?;
+    let reusable = response.keep_alive() && upstream_body != BodyKind::UntilClose;
+
+    let encoding = compression(config, route, &head, &response, upstream_body);
+
+    // an HTTP/1.0 client can't read chunked, so we fall back to closing.
+    // A compressed body has no length we could announce.
+    let client_body = match upstream_body {
+        _ if encoding.is_some() && head.version == "HTTP/1.1" => BodyKind::Chunked,
+        _ if encoding.is_some() => BodyKind::UntilClose,
+        BodyKind::Chunked if head.version != "HTTP/1.1" => BodyKind::UntilClose,
+        kind => kind,
+    };
+    let keep_alive = head.keep_alive() && client_body != BodyKind::UntilClose;
+
+    entry.status = Some(response.status);
+    response.version = "HTTP/1.1".to_string();
+    response.headers.strip_hop_by_hop();
+    // the response as the cache keeps it, framing is added when serving
+    let cacheable = fresh_for.map(|_| response.clone());
+    if let Some(route) = route {
+        route.response_headers.apply(&mut response.headers);
+    }
+    if let Some(encoding) = encoding {
+        compress::prepare(&mut response, encoding);
+    }
+    // a length from the backend doesn't hold for a chunked or re-encoded body
+    if matches!(client_body, BodyKind::Chunked | BodyKind::UntilClose) {
+        response.headers.remove("content-length");
+    }
+    if client_body == BodyKind::Chunked {
+        response.headers.set("transfer-encoding", "chunked");
+    }
+    if !keep_alive {
+        response.headers.set("connection", "close");
+    }
+    response
+        .write_to(writer)
+        .map_err(|e| ProxyError::Close(client_failure(&e)))?;
+
+    // a cacheable body is collected on its way to the client, up to the
+    // size the cache takes
+    let mut cache_body = cacheable.as_ref().map(|_| Vec::new());
+    let cache_limit = lb.cache.max_object_bytes();
+    let capture = |data: &[u8]| {
+        if let Some(buf) = &mut cache_body {
+            if buf.len() + data.len() > cache_limit {
+                cache_body = None;
+            } else {
+                buf.extend_from_slice(data);
+            }
+        }
+    };
+    let copied = match encoding {
+        Some(encoding) => compress::copy_body(
+            &mut conn.stream,
+            upstream_body,
+            writer,
+            client_body,
+            encoding,
+            capture,
+        ),
+        None => http::copy_body_with(
+            &mut conn.stream,
+            upstream_body,
+            writer,
+            client_body,
+            capture,
+        ),
+    };
+    entry.bytes = copied.map_err(|e| match e {
+        CopyError::Read(e) => ProxyError::Close(backend_failure(&e)),
+        CopyError::Write(e) => ProxyError::Close(client_failure(&e)),
+    })?;
+    if let (Some(primary), Some(cacheable), Some(body), Some(fresh_for)) =
+        (&cache_key, cacheable, cache_body, fresh_for)
+    {
+        lb.cache.insert(primary, &head, cacheable, body, fresh_for);
+    }
+    trace.phase("response", sending);
+
+    if reusable {
+        conn.stream.get_mut().set_deadline(None);
+        lb.conns.put(&backend.addr, conn);
+    }
+    Ok(keep_alive)
+}
+
+// Sends the request head and body upstream. The body may be at most `max`
+// bytes, if given. `inspect` sees it as it passes through.
+fn write_request(
+    conn: &mut UpstreamConn,
+    head: &RequestHead,
+    (body, max): (BodyKind, Option<u64>),
+    timeouts: &Timeouts,
+    deadline: Option<Instant>,
+    client: &mut ClientReader,
+    inspect: &mut dyn FnMut(&[u8]),
+) -> Result<(), SendError> {
+    let stream = conn.stream.get_mut();
+    stream.set_idle(timeouts.idle());
+    stream.set_deadline(deadline);
+
+    let mut upstream = BufWriter::new(stream);
+    head.write_to(&mut upstream).map_err(SendError::Backend)?;
+    http::copy_body_limited(client, body, max, &mut upstream, body, inspect).map_err(
+        |e| match e {
+            CopyError::Read(e) => SendError::Client(e),
+            CopyError::Write(e) => SendError::Backend(e),
+        },
+    )?;
+    Ok(())
+}
+
+fn read_response(conn: &mut UpstreamConn, timeouts: &Timeouts) -> Result<ResponseHead, SendError> {
+    conn.stream
+        .get_mut()
+        .expect_first_byte(timeouts.first_byte());
+    ResponseHead::read_final(&mut conn.stream).map_err(SendError::Backend)
+}
+
+fn request_failure(e: SendError) -> ProxyError {
+    match e {
+        // answered if the request body was too big or too slow, nothing of
+        // the response went out yet
+        SendError::Client(e) if http::too_large(&e).is_some() => {
+            ProxyError::Respond(413, Termination::RequestTooLarge)
+        }
+        SendError::Client(e) if timeout::expired(&e) == Some(Expired::MinRate) => {
+            ProxyError::Respond(408, Termination::ClientTooSlow)
+        }
+        SendError::Client(e) => ProxyError::Close(client_failure(&e)),
+        SendError::Backend(e) => match backend_failure(&e) {
+            Termination::FirstByteTimeout => {
+                ProxyError::Respond(504, Termination::FirstByteTimeout)
+            }
+            Termination::TotalTimeout => ProxyError::Respond(504, Termination::TotalTimeout),
+            Termination::BackendIdleTimeout => ProxyError::Close(Termination::BackendIdleTimeout),
+            termination => ProxyError::Respond(502, termination),
+        },
+    }
+}
+
+fn connect_failure(e: &io::Error) -> ProxyError {
+    ProxyError::Respond(502, connect_termination(e))
+}
+
+pub fn connect_termination(e: &io::Error) -> Termination {
+    if e.kind() == io::ErrorKind::TimedOut {
+        Termination::ConnectTimeout
+    } else {
+        Termination::ConnectFailed
+    }
+}
+
+pub fn backend_failure(e: &io::Error) -> Termination {
+    match timeout::expired(e) {
+        Some(Expired::Idle | Expired::MinRate) => Termination::BackendIdleTimeout,
+        Some(Expired::FirstByte) => Termination::FirstByteTimeout,
+        Some(Expired::Total) => Termination::TotalTimeout,
+        None => Termination::BackendError,
+    }
+}
+
+fn client_failure(e: &io::Error) -> Termination {
+    match timeout::expired(e) {
+        Some(Expired::Total) => Termination::TotalTimeout,
+        Some(_) => Termination::ClientIdleTimeout,
+        None => Termination::ClientClosed,
+    }
+}
@@ -0,0 +1,540 @@
+/*
+ * Rewriting requests on their way through, and redirects.
+ *
+ * A route's `rewrite` changes the path before the request is forwarded.
+ * `strip_prefix` drops the route's path_prefix, then the first match of
+ * `regex` in what is left is replaced by `replacement`, in which $1 or
+ * ${name} stand for the capture groups. The query string stays as it is.
+ * ACLs, auth, the cache and the access log all go by the path the client
+ * sent.
+ *
+ * `request_headers` and `response_headers` remove, set and add headers, in
+ * that order, on the request to the backend and on the response to the
+ * client. Error responses the load balancer makes up itself are left
+ * alone.
+ *
+ * A route with `redirect` answers with a 301, 302, 307 or 308 instead of
+ * forwarding. The Location is the request's own URL with the scheme, host
+ * and port the redirect sets, and the path rewritten as above. A request
+ * that is already where it would be sent goes through as usual, so a route
+ * can send every other host to the canonical one. We only speak plain HTTP,
+ * the scheme a client used is taken from X-Forwarded-Proto when a TLS
+ * terminator in front of us sets it. Only the `trusted_proxies` get a say,
+ * anyone else could send "https" to skip the redirect to it.
+ *
+ * gRPC calls are not rewritten.
+ */
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::acl::IpSet;
+use crate::http::{Headers, RequestHead};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub strip_prefix: bool,
+    pub regex: Option<String>,
+    pub replacement: Option<String>,
+}
+
+// The compiled form of a RewriteConfig.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "RewriteConfig")]
+pub struct Rewrite {
+    strip_prefix: bool,
+    replace: Option<(Regex, String)>,
+}
+
+impl TryFrom<RewriteConfig> for Rewrite {
+    type Error = String;
+
+    fn try_from(config: RewriteConfig) -> Result<Rewrite, String> {
+        let replace = match (config.regex, config.replacement) {
+            (Some(regex), Some(replacement)) => {
+                let regex =
+                    Regex::new(&regex).map_err(|e| format!("rewrite regex {:?}: {}", regex, e))?;
+                if replacement
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control())
+                {
+                    return Err(format!(
+                        "rewrite replacement {:?} may not contain spaces",
+                        replacement
+                    ));
+                }
+                check_groups(&regex, &replacement)?;
+                Some((regex, replacement))
+            }
+            (None, None) => None,
+            _ => return Err("rewrite needs both regex and replacement".to_string()),
+        };
+        Ok(Rewrite {
+            strip_prefix: config.strip_prefix,
+            replace,
+        })
+    }
+}
+
+// Checks that every $group in `replacement` is one `regex` has. The regex
+// crate would quietly put in nothing for the others.
+fn check_groups(regex: &Regex, replacement: &str) -> Result<(), String> {
+    let mut rest = replacement;
+    while let Some(i) = rest.find('$') {
+        rest = &rest[i + 1..];
+        let name = if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        } else if let Some((name, after)) = rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            rest = after;
+            name
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        // a $ without a name is taken as it is
+        if name.is_empty() {
+            continue;
+        }
+        let known = match name.parse::<usize>() {
+            Ok(i) => i < regex.captures_len(),
+            Err(_) => regex.capture_names().flatten().any(|n| n == name),
+        };
+        if !known {
+            return Err(format!(
+                "rewrite replacement {:?}: {:?} has no group {:?} (write ${{1}} when letters follow)",
+                replacement,
+                regex.as_str(),
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl Rewrite {
+    pub fn is_empty(&self) -> bool {
+        !self.strip_prefix && self.replace.is_none()
+    }
+
+    // The target to forward a request for `target` as, on a route with
+    // `prefix`.
+    pub fn target(&self, prefix: &str, target: &str) -> String {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        let mut path = path.to_string();
+        if self.strip_prefix
+            && let Some(rest) = path.strip_prefix(prefix)
+        {
+            path = rest.to_string();
+        }
+        if let Some((regex, replacement)) = &self.replace {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        match query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRulesConfig {
+    pub add: BTreeMap<String, String>,
+    pub set: BTreeMap<String, String>,
+    pub remove: Vec<String>,
+}
+
+// The checked form of a HeaderRulesConfig.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "HeaderRulesConfig")]
+pub struct HeaderRules {
+    add: Vec<(String, String)>,
+    set: Vec<(String, String)>,
+    remove: Vec<String>,
+}
+
+// Framing is up to the proxy, rules can't touch these.
+const RESERVED: [&str; 4] = [
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "upgrade",
+];
+
+impl TryFrom<HeaderRulesConfig> for HeaderRules {
+    type Error = String;
+
+    fn try_from(config: HeaderRulesConfig) -> Result<HeaderRules, String> {
+        let names = config
+            .add
+            .keys()
+            .chain(config.set.keys())
+            .chain(&config.remove);
+        for name in names {
+            let token = name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+            if name.is_empty() || !token {
+                return Err(format!("{:?} is not a header name", name));
+            }
+            if RESERVED.iter().any(|r| name.eq_ignore_ascii_case(r)) {
+                return Err(format!("header {:?} can't be changed by a rule", name));
+            }
+        }
+        let mut values = config.add.values().chain(config.set.values());
+        if let Some(value) = values.find(|v| v.chars().any(|c| c.is_control() && c != '\t')) {
+            return Err(format!("header value {:?} has control characters", value));
+        }
+        Ok(HeaderRules {
+            add: config.add.into_iter().collect(),
+            set: config.set.into_iter().collect(),
+            remove: config.remove,
+        })
+    }
+}
+
+impl HeaderRules {
+    pub fn apply(&self, headers: &mut Headers) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.set(name, value);
+        }
+        for (name, value) in &self.add {
+            headers.append(name, value);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    // 301 or 308 for good, 302 or 307 for now. 307 and 308 make the client
+    // repeat the method and body, the others may turn it into a GET
+    pub status: u16,
+    // "http" or "https", the request's scheme if unset
+    pub scheme: Option<String>,
+    // without the port, the request's Host if unset
+    pub host: Option<String>,
+    // the request's port if unset, unless the scheme changes
+    pub port: Option<u16>,
+    // CIDR prefixes of the TLS terminators whose X-Forwarded-Proto is
+    // believed, requests from anywhere else count as plain http
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        RedirectConfig {
+            status: 301,
+            scheme: None,
+            host: None,
+            port: None,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+// The checked form of a RedirectConfig.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RedirectConfig")]
+pub struct Redirect {
+    pub status: u16,
+    scheme: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    trusted_proxies: IpSet,
+}
+
+impl TryFrom<RedirectConfig> for Redirect {
+    type Error = String;
+
+    fn try_from(config: RedirectConfig) -> Result<Redirect, String> {
+        if ![301, 302, 307, 308].contains(&config.status) {
+            return Err(format!(
+                "redirect status must be 301, 302, 307 or 308, not {}",
+                config.status
+            ));
+        }
+        if let Some(scheme) = &config.scheme
+            && scheme != "http"
+            && scheme != "https"
+        {
+            return Err(format!(
+                "redirect scheme must be \"http\" or \"https\", not {:?}",
+                scheme
+            ));
+        }
+        if let Some(host) = &config.host
+            && (host.is_empty() || host.contains(['/', ':', '?', '#', '@', ' ']))
+        {
+            return Err(format!(
+                "redirect host {:?} must be a bare host name, the port goes in `port`",
+                host
+            ));
+        }
+        Ok(Redirect {
+            status: config.status,
+            scheme: config.scheme,
+            host: config.host,
+            port: config.port,
+            trusted_proxies: IpSet::parse(&config.trusted_proxies)?,
+        })
+    }
+}
+
+impl Redirect {
+    // The Location to send the client at `peer` to, with `target` the
+    // rewritten target. None when the request is already there.
+    pub fn location(&self, head: &RequestHead, peer: IpAddr, target: &str) -> Option<String> {
+        let forwarded_proto = match self.trusted_proxies.contains(peer) {
+            true => head.headers.tokens("x-forwarded-proto").next(),
+            false => None,
+        };
+        let scheme_now = forwarded_proto.unwrap_or("http").to_ascii_lowercase();
+        let authority_now = head.headers.get("host").unwrap_or_default();
+        let (host_now, port_now) = split_port(authority_now);
+
+        let scheme = self.scheme.as_deref().unwrap_or(&scheme_now);
+        let host = self.host.as_deref().unwrap_or(host_now);
+        // the port the client used means nothing under another scheme
+        let port = match (self.port, port_now) {
+            (Some(port), _) => Some(port),
+            (None, Some(port)) if scheme == scheme_now => Some(port),
+            _ => None,
+        };
+        let default_port = if scheme == "https" { 443 } else { 80 };
+        let authority = match port {
+            Some(port) if port != default_port => format!("{}:{}", host, port),
+            _ => host.to_string(),
+        };
+
+        let same_port = port.unwrap_or(default_port) == port_now.unwrap_or(default_port);
+        let arrived = scheme == scheme_now
+            && host.eq_ignore_ascii_case(host_now)
+            && same_port
+            && target == head.target;
+        if arrived || host.is_empty() {
+            return None;
+        }
+        Some(format!("{}://{}{}", scheme, authority, target))
+    }
+}
+
+// "example.com:8080" into the host and the port, which an IPv6 literal
+// like "[::1]:8080" keeps in brackets.
+fn split_port(authority: &str) -> (&str, Option<u16>) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (authority, None),
+        },
+        _ => (authority, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HeadLimits;
+
+    fn rewrite(strip_prefix: bool, replace: Option<(&str, &str)>) -> Result<Rewrite, String> {
+        Rewrite::try_from(RewriteConfig {
+            strip_prefix,
+            regex: replace.map(|(regex, _)| regex.to_string()),
+            replacement: replace.map(|(_, replacement)| replacement.to_string()),
+        })
+    }
+
+    fn redirect(scheme: Option<&str>, host: Option<&str>, port: Option<u16>) -> Redirect {
+        Redirect::try_from(RedirectConfig {
+            status: 308,
+            scheme: scheme.map(str::to_string),
+            host: host.map(str::to_string),
+            port,
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        })
+        .unwrap()
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const PROXY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    fn head(target: &str, headers: &str) -> RequestHead {
+        let text = format!("GET {} HTTP/1.1\r\n{}\r\n", target, headers);
+        RequestHead::read(&mut text.as_bytes(), &HeadLimits::NONE)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn targets() {
+        let strip = rewrite(true, None).unwrap();
+        assert_eq!(strip.target("/api", "/api/users?x=1"), "/users?x=1");
+        assert_eq!(strip.target("/api", "/api"), "/");
+        assert_eq!(strip.target("/api/", "/api/v1"), "/v1");
+        // not under the prefix, left alone
+        assert_eq!(strip.target("/api", "/other"), "/other");
+
+        let versioned =
+            rewrite(true, Some(("^/v(\\d+)/(?P<rest>.*)", "/${rest}/version$1"))).unwrap();
+        assert_eq!(
+            versioned.target("/api", "/api/v2/users?v=1"),
+            "/users/version2?v=1"
+        );
+        // only the path is matched against, only the first match replaced
+        let first = rewrite(false, Some(("a", "b"))).unwrap();
+        assert_eq!(first.target("/", "/aa?a=a"), "/ba?a=a");
+        // the path still starts with a slash however it was rewritten
+        let bare = rewrite(false, Some(("^/", ""))).unwrap();
+        assert_eq!(bare.target("/", "/x"), "/x");
+        assert_eq!(bare.target("/", "/"), "/");
+
+        assert!(rewrite(false, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_rewrites() {
+        for (replace, error) in [
+            (("(", "x"), "rewrite regex"),
+            (("(a)", "$2"), "no group \"2\""),
+            (("(a)", "$1x"), "no group \"1x\""),
+            (("(?P<n>a)", "${m}"), "no group \"m\""),
+            (("a", "/a b"), "may not contain spaces"),
+        ] {
+            let error_text = rewrite(false, Some(replace)).unwrap_err();
+            assert!(error_text.contains(error), "{}", error_text);
+        }
+        for fine in ["${1}x", "$$1", "/cost$", "${n}"] {
+            assert!(rewrite(false, Some(("(?P<n>a)", fine))).is_ok(), "{}", fine);
+        }
+        let half = RewriteConfig {
+            regex: Some("a".to_string()),
+            ..RewriteConfig::default()
+        };
+        assert!(Rewrite::try_from(half).is_err());
+    }
+
+    #[test]
+    fn locations() {
+        let to_https = redirect(Some("https"), None, None);
+        let plain = head("/a?b", "host: example.com:8080\r\n");
+        // the port used with http means nothing for https
+        assert_eq!(
+            to_https.location(&plain, CLIENT, "/a?b").as_deref(),
+            Some("https://example.com/a?b")
+        );
+        // already there, as the TLS terminator says
+        let tls = head("/a", "host: example.com\r\nx-forwarded-proto: HTTPS\r\n");
+        assert_eq!(to_https.location(&tls, PROXY, "/a"), None);
+        // but a client can't say so for itself
+        assert_eq!(
+            to_https.location(&tls, CLIENT, "/a").as_deref(),
+            Some("https://example.com/a")
+        );
+        // unless the path changes
+        assert_eq!(
+            to_https.location(&tls, PROXY, "/b").as_deref(),
+            Some("https://example.com/b")
+        );
+
+        let canonical = redirect(None, Some("www.example.com"), None);
+        assert_eq!(
+            canonical.location(&plain, CLIENT, "/a?b").as_deref(),
+            Some("http://www.example.com:8080/a?b")
+        );
+        let there = head("/", "host: WWW.example.com:80\r\n");
+        assert_eq!(canonical.location(&there, CLIENT, "/"), None);
+
+        let port = redirect(Some("https"), Some("secure.example.com"), Some(8443));
+        assert_eq!(
+            port.location(&plain, CLIENT, "/x").as_deref(),
+            Some("https://secure.example.com:8443/x")
+        );
+        let v6 = head("/", "host: [::1]:8080\r\n");
+        assert_eq!(
+            to_https.location(&v6, CLIENT, "/").as_deref(),
+            Some("https://[::1]/")
+        );
+        // nowhere to send a request without a host
+        assert_eq!(to_https.location(&head("/", ""), CLIENT, "/"), None);
+    }
+
+    #[test]
+    fn bad_redirects() {
+        let config = |status, scheme: Option<&str>, host: Option<&str>| RedirectConfig {
+            status,
+            scheme: scheme.map(str::to_string),
+            host: host.map(str::to_string),
+            ..RedirectConfig::default()
+        };
+        assert!(Redirect::try_from(config(303, None, None)).is_err());
+        assert!(Redirect::try_from(config(301, Some("ftp"), None)).is_err());
+        for host in ["", "a.com:80", "a.com/x", "user@a.com"] {
+            assert!(
+                Redirect::try_from(config(301, None, Some(host))).is_err(),
+                "{}",
+                host
+            );
+        }
+        let proxies = RedirectConfig {
+            trusted_proxies: vec!["10.0.0.0/33".to_string()],
+            ..RedirectConfig::default()
+        };
+        assert!(Redirect::try_from(proxies).is_err());
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(split_port("example.com"), ("example.com", None));
+        assert_eq!(split_port("example.com:8080"), ("example.com", Some(8080)));
+        assert_eq!(split_port("example.com:http"), ("example.com:http", None));
+        assert_eq!(split_port("[::1]:443"), ("[::1]", Some(443)));
+        assert_eq!(split_port("[::1]"), ("[::1]", None));
+        assert_eq!(split_port("::1"), ("::1", None));
+        assert_eq!(split_port("10.0.0.1:99999"), ("10.0.0.1:99999", None));
+    }
+
+    #[test]
+    fn header_rules() {
+        let rules = HeaderRules::try_from(HeaderRulesConfig {
+            add: BTreeMap::from([("x-a".to_string(), "2".to_string())]),
+            set: BTreeMap::from([("x-b".to_string(), "new".to_string())]),
+            remove: vec!["x-a".to_string(), "server".to_string()],
+        })
+        .unwrap();
+        let mut headers = head("/", "x-a: 1\r\nx-b: old\r\nx-b: older\r\nserver: s\r\n").headers;
+        rules.apply(&mut headers);
+        let got: Vec<(&str, &str)> = headers.iter().collect();
+        assert_eq!(got, [("x-b", "new"), ("x-a", "2")]);
+
+        for (name, value) in [
+            ("content-length", "1"),
+            ("Transfer-Encoding", "x"),
+            ("bad name", "x"),
+            ("", "x"),
+            ("x-ok", "line\r\nbreak"),
+        ] {
+            let config = HeaderRulesConfig {
+                set: BTreeMap::from([(name.to_string(), value.to_string())]),
+                ..HeaderRulesConfig::default()
+            };
+            assert!(HeaderRules::try_from(config).is_err(), "{:?}", name);
+        }
+    }
+}
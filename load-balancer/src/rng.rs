@@ -0,0 +1,66 @@
+/*
+ * A tiny pseudo random number generator (SplitMix64). It is not meant for
+ * anything security related, only for decisions like "mirror 5% of the
+ * requests", where pulling in a whole crate would be overkill.
+ */
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // seeded from the random keys std uses for HashMap
+    pub fn from_entropy() -> Rng {
+        Rng::new(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+thread_local! {
+    static THREAD_RNG: Cell<u64> = Cell::new(Rng::from_entropy().next_u64());
+}
+
+// a random number from the current thread's generator
+pub fn random_u64() -> u64 {
+    THREAD_RNG.with(|state| {
+        let mut rng = Rng::new(state.get());
+        let value = rng.next_u64();
+        state.set(rng.state);
+        value
+    })
+}
+
+// Starts the current thread's generator over from `seed`, for runs that
+// must come out the same every time, like the simulator's.
+pub fn seed(seed: u64) {
+    THREAD_RNG.with(|state| state.set(seed));
+}
+
+// uniform in [0, 1)
+pub fn random_f64() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// true for roughly `percent` out of 100 calls
+pub fn chance(percent: f64) -> bool {
+    random_f64() * 100.0 < percent
+}
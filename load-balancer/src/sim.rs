@@ -0,0 +1,553 @@
+/*
+ * Strategies against simulated backends, in virtual time.
+ *
+ * A Simulation sends a number of requests, arriving at random at a given
+ * rate, to backends described by a latency distribution, a failure rate
+ * and a capacity, and asks a Strategy where each one goes. Nothing touches
+ * a socket or a clock: a run of a hundred thousand requests takes a
+ * fraction of a second, and the same seed gives the same Report every
+ * time, so strategies can be compared in tests.
+ *
+ *   let latency = Latency::Exponential(Duration::from_millis(20));
+ *   let report = Simulation::new(42)
+ *       .backend(SimBackend::new("a", latency))
+ *       .backend(SimBackend::new("b", latency).capacity(4))
+ *       .arrivals(400.0)
+ *       .requests(20_000)
+ *       .at(Duration::from_secs(10), "b", Change::Down)
+ *       .run(&LeastConnections::default());
+ *   println!("{}", report);
+ *   assert!(report.percentile(99.0) < Duration::from_millis(200));
+ *
+ * A backend works on up to `capacity` requests at once, the others wait
+ * for a slot in the order they came. Their latency counts from when they
+ * were sent, so the wait is part of it. A failing request (`failure_rate`)
+ * takes its latency like any other and then ends in an error. A backend
+ * that is down refuses connections right away; requests it had already
+ * taken still finish.
+ *
+ * Health works as in the proxy, on simulated time: `health(failures,
+ * cooldown)` takes a backend out after that many refused connections in a
+ * row, and the first request after the cooldown decides whether it stays.
+ * Errors after a request was sent don't count, just as they don't in the
+ * proxy. Slow start, tiers and max_backend_connections are not simulated.
+ *
+ * The strategy's own random choices come from the calling thread's
+ * generator, which a run seeds as well.
+ */
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use crate::http::{Headers, RequestHead};
+use crate::rng::{self, Rng};
+use crate::strategy::{BackendStats, Context, Strategy};
+
+// How long a backend takes to answer.
+#[derive(Debug, Clone, Copy)]
+pub enum Latency {
+    Fixed(Duration),
+    // evenly spread between the two
+    Uniform(Duration, Duration),
+    // with this mean, the classic memoryless service time
+    Exponential(Duration),
+    // with this median. A `sigma` of 0.5 to 1 gives the long tail real
+    // services tend to have
+    LogNormal {
+        median: Duration,
+        sigma: f64,
+    },
+    // `slow` for `slow_percent` of the requests, `fast` for the rest, like
+    // a cache with misses or the odd GC pause
+    Bimodal {
+        fast: Duration,
+        slow: Duration,
+        slow_percent: f64,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Latency::Fixed(d) => d,
+            Latency::Uniform(min, max) => min + (max.saturating_sub(min)).mul_f64(rng.next_f64()),
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+            Latency::LogNormal { median, sigma } => {
+                // Box-Muller
+                let (u, v) = (1.0 - rng.next_f64(), rng.next_f64());
+                let z = (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos();
+                median.mul_f64((sigma * z).exp())
+            }
+            Latency::Bimodal {
+                fast,
+                slow,
+                slow_percent,
+            } => {
+                if rng.next_f64() * 100.0 < slow_percent {
+                    slow
+                } else {
+                    fast
+                }
+            }
+        }
+    }
+}
+
+// A backend as the simulation starts it.
+#[derive(Debug, Clone)]
+pub struct SimBackend {
+    pub name: String,
+    pub latency: Latency,
+    // share of the requests that end in an error, 0 to 1
+    pub failure_rate: f64,
+    // requests it works on at once
+    pub capacity: usize,
+    pub down: bool,
+}
+
+impl SimBackend {
+    pub fn new(name: &str, latency: Latency) -> SimBackend {
+        SimBackend {
+            name: name.to_string(),
+            latency,
+            failure_rate: 0.0,
+            capacity: usize::MAX,
+            down: false,
+        }
+    }
+
+    pub fn failure_rate(mut self, rate: f64) -> SimBackend {
+        self.failure_rate = rate;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> SimBackend {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn down(mut self) -> SimBackend {
+        self.down = true;
+        self
+    }
+}
+
+// Something that happens to a backend during the run.
+#[derive(Debug, Clone, Copy)]
+pub enum Change {
+    Down,
+    Up,
+    Latency(Latency),
+    FailureRate(f64),
+    Capacity(usize),
+}
+
+pub struct Simulation {
+    seed: u64,
+    backends: Vec<SimBackend>,
+    // per second
+    rate: f64,
+    requests: u64,
+    clients: u32,
+    health: (u32, Duration),
+    changes: Vec<(Duration, usize, Change)>,
+}
+
+impl Simulation {
+    // 10000 requests at 100 a second from 100 clients, health as the
+    // config's defaults.
+    pub fn new(seed: u64) -> Simulation {
+        Simulation {
+            seed,
+            backends: Vec::new(),
+            rate: 100.0,
+            requests: 10_000,
+            clients: 100,
+            health: (3, Duration::from_secs(10)),
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn backend(mut self, backend: SimBackend) -> Simulation {
+        self.backends.push(backend);
+        self
+    }
+
+    // requests per second, on average
+    pub fn arrivals(mut self, rate: f64) -> Simulation {
+        assert!(rate > 0.0, "the arrival rate must be above 0");
+        self.rate = rate;
+        self
+    }
+
+    pub fn requests(mut self, requests: u64) -> Simulation {
+        self.requests = requests;
+        self
+    }
+
+    // how many client IPs the requests come from, for strategies that hash
+    // on the client
+    pub fn clients(mut self, clients: u32) -> Simulation {
+        self.clients = clients.max(1);
+        self
+    }
+
+    // like `health.failures` and `health.cooldown_ms`, 0 failures never
+    // takes a backend out
+    pub fn health(mut self, failures: u32, cooldown: Duration) -> Simulation {
+        self.health = (failures, cooldown);
+        self
+    }
+
+    // Applies `change` to the backend called `name` at time `at`. The
+    // backend has to be added first.
+    pub fn at(mut self, at: Duration, name: &str, change: Change) -> Simulation {
+        let backend = self
+            .backends
+            .iter()
+            .position(|b| b.name == name)
+            .unwrap_or_else(|| panic!("no simulated backend called {:?}", name));
+        self.changes.push((at, backend, change));
+        self
+    }
+
+    pub fn run(&self, strategy: &dyn Strategy) -> Report {
+        assert!(!self.backends.is_empty(), "the simulation has no backends");
+        let mut rng = Rng::new(self.seed);
+        rng::seed(rng.next_u64());
+        let mut run = Run {
+            now: Duration::ZERO,
+            rng,
+            events: BinaryHeap::new(),
+            seq: 0,
+            backends: self.backends.iter().map(State::new).collect(),
+            health: self.health,
+            latencies: Vec::new(),
+        };
+        for &(at, backend, change) in &self.changes {
+            run.schedule(at, Kind::Change(backend, change));
+        }
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: Headers::default(),
+        };
+        let mut rejected = 0;
+        let mut sent = 0;
+        if self.requests > 0 {
+            run.schedule(Duration::ZERO, Kind::Arrival);
+        }
+
+        while let Some(event) = run.events.pop() {
+            run.now = event.at;
+            match event.kind {
+                Kind::Arrival => {
+                    sent += 1;
+                    if sent < self.requests {
+                        let gap = -(1.0 - run.rng.next_f64()).ln() / self.rate;
+                        run.schedule(run.now + Duration::from_secs_f64(gap), Kind::Arrival);
+                    }
+                    let client = (run.rng.next_u64() % self.clients as u64) as u32;
+                    let context = Context {
+                        client: IpAddr::V4(Ipv4Addr::from(0x0a00_0000 | client)),
+                        request: Some(&head),
+                    };
+                    let stats = run.stats();
+                    match strategy.pick(&context, &stats) {
+                        Some(i) if i < run.backends.len() => run.send(i),
+                        _ => rejected += 1,
+                    }
+                }
+                Kind::Done(i, sent_at, ok) => run.done(i, sent_at, ok),
+                Kind::Change(i, change) => run.change(i, change),
+            }
+        }
+
+        run.latencies.sort();
+        Report {
+            requests: self.requests,
+            failed: run.backends.iter().map(|b| b.failed).sum(),
+            rejected,
+            duration: run.now,
+            backends: self
+                .backends
+                .iter()
+                .zip(&run.backends)
+                .map(|(backend, state)| BackendReport {
+                    name: backend.name.clone(),
+                    requests: state.requests,
+                    failed: state.failed,
+                    marked_down: state.marked_down,
+                })
+                .collect(),
+            latencies: run.latencies,
+        }
+    }
+}
+
+struct Event {
+    at: Duration,
+    // keeps events at the same time in the order they were scheduled
+    seq: u64,
+    kind: Kind,
+}
+
+enum Kind {
+    Arrival,
+    // backend, when the request was sent, whether it succeeded
+    Done(usize, Duration, bool),
+    Change(usize, Change),
+}
+
+// the earliest first out of the BinaryHeap
+impl Ord for Event {
+    fn cmp(&self, other: &Event) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Event) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Event) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Event {}
+
+// A backend during the run.
+struct State {
+    name: String,
+    latency: Latency,
+    failure_rate: f64,
+    capacity: usize,
+    down: bool,
+    busy: usize,
+    // when each waiting request was sent
+    waiting: VecDeque<Duration>,
+    requests: u64,
+    failed: u64,
+    // refused connections in a row, and until when health keeps it out
+    failures: u32,
+    down_until: Duration,
+    marked_down: u64,
+}
+
+impl State {
+    fn new(backend: &SimBackend) -> State {
+        State {
+            name: backend.name.clone(),
+            latency: backend.latency,
+            failure_rate: backend.failure_rate,
+            capacity: backend.capacity,
+            down: backend.down,
+            busy: 0,
+            waiting: VecDeque::new(),
+            requests: 0,
+            failed: 0,
+            failures: 0,
+            down_until: Duration::ZERO,
+            marked_down: 0,
+        }
+    }
+}
+
+struct Run {
+    now: Duration,
+    rng: Rng,
+    events: BinaryHeap<Event>,
+    seq: u64,
+    backends: Vec<State>,
+    health: (u32, Duration),
+    // of the requests that succeeded
+    latencies: Vec<Duration>,
+}
+
+impl Run {
+    fn schedule(&mut self, at: Duration, kind: Kind) {
+        self.seq += 1;
+        self.events.push(Event {
+            at,
+            seq: self.seq,
+            kind,
+        });
+    }
+
+    fn stats(&self) -> Vec<BackendStats<'_>> {
+        self.backends
+            .iter()
+            .map(|b| BackendStats {
+                addr: &b.name,
+                weight: if self.now < b.down_until { 0.0 } else { 1.0 },
+                active: b.busy + b.waiting.len(),
+                requests: b.requests,
+                failures: b.failures,
+            })
+            .collect()
+    }
+
+    fn send(&mut self, i: usize) {
+        let (failures, cooldown) = self.health;
+        let backend = &mut self.backends[i];
+        backend.requests += 1;
+        if backend.down {
+            backend.failed += 1;
+            backend.failures += 1;
+            if failures > 0 && backend.failures >= failures {
+                backend.down_until = self.now + cooldown;
+                backend.marked_down += 1;
+            }
+            return;
+        }
+        backend.failures = 0;
+        backend.waiting.push_back(self.now);
+        self.fill(i);
+    }
+
+    // a request gets one of the backend's slots
+    fn start(&mut self, i: usize, sent_at: Duration) {
+        let backend = &mut self.backends[i];
+        backend.busy += 1;
+        let took = backend.latency.sample(&mut self.rng);
+        let ok = self.rng.next_f64() >= backend.failure_rate;
+        self.schedule(self.now + took, Kind::Done(i, sent_at, ok));
+    }
+
+    fn done(&mut self, i: usize, sent_at: Duration, ok: bool) {
+        let backend = &mut self.backends[i];
+        backend.busy -= 1;
+        if ok {
+            self.latencies.push(self.now - sent_at);
+        } else {
+            backend.failed += 1;
+        }
+        self.fill(i);
+    }
+
+    // starts waiting requests while there are free slots
+    fn fill(&mut self, i: usize) {
+        while self.backends[i].busy < self.backends[i].capacity {
+            match self.backends[i].waiting.pop_front() {
+                Some(sent_at) => self.start(i, sent_at),
+                None => break,
+            }
+        }
+    }
+
+    fn change(&mut self, i: usize, change: Change) {
+        let backend = &mut self.backends[i];
+        match change {
+            Change::Down => backend.down = true,
+            Change::Up => backend.down = false,
+            Change::Latency(latency) => backend.latency = latency,
+            Change::FailureRate(rate) => backend.failure_rate = rate,
+            Change::Capacity(capacity) => backend.capacity = capacity.max(1),
+        }
+        self.fill(i);
+    }
+}
+
+// What a run came to.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub requests: u64,
+    // ended in an error or a refused connection
+    pub failed: u64,
+    // the strategy picked no backend
+    pub rejected: u64,
+    // virtual time until the last request finished
+    pub duration: Duration,
+    pub backends: Vec<BackendReport>,
+    // of the requests that succeeded, sorted
+    latencies: Vec<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendReport {
+    pub name: String,
+    pub requests: u64,
+    pub failed: u64,
+    // how often health took it out
+    pub marked_down: u64,
+}
+
+impl Report {
+    // failed and rejected requests as a share of all, 0 to 1
+    pub fn failure_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        (self.failed + self.rejected) as f64 / self.requests as f64
+    }
+
+    // the share of the requests `name` got, 0 to 1
+    pub fn share(&self, name: &str) -> f64 {
+        let requests = self
+            .backends
+            .iter()
+            .find(|b| b.name == name)
+            .map_or(0, |b| b.requests);
+        requests as f64 / self.requests.max(1) as f64
+    }
+
+    // The latency `percent` of the successful requests stayed within,
+    // 99.0 for p99. Zero when none succeeded.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.1}s, {:.2}% failed, {} rejected",
+            self.requests,
+            self.duration.as_secs_f64(),
+            self.failure_rate() * 100.0,
+            self.rejected
+        )?;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            "latency ms  mean {:.1}  p50 {:.1}  p90 {:.1}  p99 {:.1}  p99.9 {:.1}  max {:.1}",
+            ms(self.mean()),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)),
+            ms(self.percentile(99.0)),
+            ms(self.percentile(99.9)),
+            ms(self.percentile(100.0))
+        )?;
+        for backend in &self.backends {
+            writeln!(
+                f,
+                "  {:<16} {:>8} requests {:>6.2}%  {} failed  {} times down",
+                backend.name,
+                backend.requests,
+                self.share(&backend.name) * 100.0,
+                backend.failed,
+                backend.marked_down
+            )?;
+        }
+        Ok(())
+    }
+}
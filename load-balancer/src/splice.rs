@@ -0,0 +1,112 @@
+/*
+ * Zero-copy forwarding between two sockets with splice(2), Linux only.
+ *
+ * splice can't go from one socket straight to another, one end has to be a
+ * pipe. So every relay direction gets its own pipe: bytes are spliced from
+ * the source socket into the pipe and from the pipe into the destination
+ * socket. The data stays in kernel pages the whole way.
+ */
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use crate::http::CopyError;
+use crate::stream::Stream;
+use crate::timeout::is_timeout;
+
+// the default pipe capacity, moving more per call only blocks on the pipe
+const CHUNK: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 just created these and nothing else owns them
+        unsafe {
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    loop {
+        // SAFETY: splice only works on the two fds, null offsets mean their
+        // current positions
+        let n = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                // no SPLICE_F_MORE, it corks the socket and holds back the
+                // last segment of a response
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+// Moves bytes from `from` to `to` until `from` reaches EOF. `progress` is
+// called with each amount moved. Read timeouts on `from` are passed to
+// `on_timeout`, which decides whether to keep waiting.
+//
+// Returns None if splice can't be used for these sockets, before anything
+// was moved, so the caller can fall back to copying.
+pub fn copy(
+    from: &Stream,
+    to: &Stream,
+    progress: &mut dyn FnMut(usize),
+    on_timeout: &mut dyn FnMut(io::Error) -> io::Result<()>,
+) -> Option<Result<u64, CopyError>> {
+    let pipe = Pipe::new().ok()?;
+    let mut total = 0;
+    loop {
+        let n = match splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK) {
+            Ok(0) => return Some(Ok(total)),
+            Ok(n) => n,
+            Err(e) if is_timeout(&e) => match on_timeout(e) {
+                Ok(()) => continue,
+                Err(e) => return Some(Err(CopyError::Read(e))),
+            },
+            Err(e) if total == 0 && unsupported(&e) => return None,
+            Err(e) => return Some(Err(CopyError::Read(e))),
+        };
+        let mut left = n;
+        while left > 0 {
+            match splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left) {
+                Ok(0) => return Some(Err(CopyError::Write(io::ErrorKind::WriteZero.into()))),
+                Ok(m) => left -= m,
+                Err(e) => return Some(Err(CopyError::Write(e))),
+            }
+        }
+        total += n as u64;
+        progress(n);
+    }
+}
+
+// errors that mean splice doesn't work on this kind of file at all
+fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+    )
+}
@@ -0,0 +1,189 @@
+/*
+ * Traffic splitting between pools, for canary releases.
+ *
+ * The client key (a header, a cookie or the client IP) is hashed into one
+ * of 10000 buckets and the targets own consecutive ranges of buckets in
+ * proportion to their weight. The hash is fixed (FNV-1a), so a client lands
+ * in the same bucket on every request and on every balancer instance.
+ */
+use std::net::IpAddr;
+
+use crate::config::SplitConfig;
+use crate::http::RequestHead;
+
+const BUCKETS: u64 = 10_000;
+
+// Picks the pool for this request.
+pub fn choose<'a>(split: &'a SplitConfig, head: &RequestHead, client: IpAddr) -> &'a str {
+    // a tester asked for a specific target
+    let forced = split
+        .override_header
+        .as_deref()
+        .and_then(|name| head.headers.get(name))
+        .or_else(|| {
+            split
+                .override_cookie
+                .as_deref()
+                .and_then(|name| head.headers.cookie(name))
+        });
+    if let Some(target) = forced.and_then(|pool| split.targets.iter().find(|t| t.pool == pool)) {
+        return &target.pool;
+    }
+
+    let client_ip = client.to_string();
+    let key = split
+        .sticky_header
+        .as_deref()
+        .and_then(|name| head.headers.get(name))
+        .or_else(|| {
+            split
+                .sticky_cookie
+                .as_deref()
+                .and_then(|name| head.headers.cookie(name))
+        })
+        .unwrap_or(&client_ip);
+
+    let total: u64 = split.targets.iter().map(|t| t.weight as u64).sum();
+    let bucket = fnv1a(key.as_bytes()) % BUCKETS;
+    let mut upper = 0;
+    for target in &split.targets {
+        upper += target.weight as u64 * BUCKETS / total;
+        if bucket < upper {
+            return &target.pool;
+        }
+    }
+    // rounding can leave the last few buckets unowned, they go to the last
+    // target with a weight
+    let last = split.targets.iter().rev().find(|t| t.weight > 0);
+    &last.unwrap_or(&split.targets[0]).pool
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::config::SplitTarget;
+    use crate::http::HeadLimits;
+
+    fn split(weights: &[(&str, u32)]) -> SplitConfig {
+        SplitConfig {
+            targets: weights
+                .iter()
+                .map(|&(pool, weight)| SplitTarget {
+                    pool: pool.to_string(),
+                    weight,
+                })
+                .collect(),
+            ..SplitConfig::default()
+        }
+    }
+
+    fn head(headers: &str) -> RequestHead {
+        let text = format!("GET / HTTP/1.1\r\nhost: h\r\n{}\r\n", headers);
+        RequestHead::read(&mut text.as_bytes(), &HeadLimits::NONE)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn client(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn the_hash_is_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn clients_are_spread_by_weight_and_stay_put() {
+        let config = split(&[("stable", 90), ("canary", 10), ("off", 0)]);
+        let plain = head("");
+        let mut canary = 0;
+        for n in 0..20_000 {
+            let pool = choose(&config, &plain, client(n));
+            assert_ne!(pool, "off");
+            assert_eq!(pool, choose(&config, &plain, client(n)));
+            canary += (pool == "canary") as u32;
+        }
+        assert!((1_700..2_300).contains(&canary), "{}", canary);
+    }
+
+    #[test]
+    fn a_growing_canary_keeps_its_clients() {
+        let before = split(&[("stable", 95), ("canary", 5)]);
+        let after = split(&[("stable", 80), ("canary", 20)]);
+        let plain = head("");
+        let (mut kept, mut gained) = (0, 0);
+        for n in 0..10_000 {
+            let was = choose(&before, &plain, client(n));
+            let is = choose(&after, &plain, client(n));
+            if was == "canary" {
+                assert_eq!(is, "canary");
+                kept += 1;
+            } else if is == "canary" {
+                gained += 1;
+            }
+        }
+        assert!(kept > 0 && gained > kept);
+    }
+
+    #[test]
+    fn sticky_keys_and_overrides() {
+        let mut config = split(&[("a", 50), ("b", 50)]);
+        config.sticky_header = Some("x-user".to_string());
+        config.sticky_cookie = Some("uid".to_string());
+        config.override_header = Some("x-variant".to_string());
+        config.override_cookie = Some("variant".to_string());
+
+        // the same user lands in the same place from any address
+        for user in ["alice", "bob", "carol", "dave"] {
+            let by_header = head(&format!("x-user: {}\r\n", user));
+            let by_cookie = head(&format!("cookie: a=1; uid={}\r\n", user));
+            let pool = choose(&config, &by_header, client(1));
+            for n in 2..50 {
+                assert_eq!(choose(&config, &by_header, client(n)), pool);
+                assert_eq!(choose(&config, &by_cookie, client(n)), pool);
+            }
+        }
+        // the header wins over the cookie
+        let users = ["u1", "u2", "u3", "u4", "u5", "u6"];
+        let pools: Vec<&str> = users
+            .iter()
+            .map(|u| choose(&config, &head(&format!("x-user: {}\r\n", u)), client(0)))
+            .collect();
+        assert!(pools.contains(&"a") && pools.contains(&"b"));
+        for (user, pool) in users.iter().zip(&pools) {
+            let both = head(&format!(
+                "x-user: {}\r\ncookie: uid=other-{}\r\n",
+                user, user
+            ));
+            assert_eq!(choose(&config, &both, client(0)), *pool);
+        }
+
+        // testers pick their pool, as long as it is one of the targets
+        for n in 0..20 {
+            assert_eq!(choose(&config, &head("x-variant: b\r\n"), client(n)), "b");
+            assert_eq!(
+                choose(&config, &head("cookie: variant=a\r\n"), client(n)),
+                "a"
+            );
+        }
+        let plain = choose(&config, &head(""), client(7));
+        assert_eq!(
+            choose(&config, &head("x-variant: nope\r\n"), client(7)),
+            plain
+        );
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Backend selection.
+ *
+ * Each pool asks its Strategy which backend a request goes to. The strategy
+ * gets the request and a snapshot of the backends that can take it right
+ * now: a backend at limits.max_backend_connections isn't offered at all,
+ * and one that is down or in its slow start ramp comes with a weight below
+ * 1. Claiming the connection slot happens after the pick, so a strategy
+ * needs no locking of its own.
+ *
+ * The built-in strategies are chosen with `strategy` in the config. An
+ * embedding program can give a pool its own with
+ * LoadBalancer::with_strategy.
+ */
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+use crate::http::RequestHead;
+use crate::rng;
+
+// What a request is known by when its backend is picked.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub client: IpAddr,
+    // None in tcp mode, where nothing is parsed
+    pub request: Option<&'a RequestHead>,
+}
+
+// One backend as a strategy sees it.
+#[derive(Debug, Clone)]
+pub struct BackendStats<'a> {
+    pub addr: &'a str,
+    // the share of requests the backend should get: 0 while it is down,
+    // between 0 and 1 while it ramps up after coming back, 1 otherwise
+    pub weight: f64,
+    // connections in use
+    pub active: usize,
+    // requests sent to the backend so far
+    pub requests: u64,
+    // failed connects since the last success
+    pub failures: u32,
+}
+
+pub trait Strategy: Send + Sync {
+    // The index in `backends` of the backend to use, or None to leave the
+    // request waiting in the pool queue. `backends` is never empty. A
+    // backend with weight 0 should only be picked when all of them are 0,
+    // trying one that may have recovered beats failing outright.
+    fn pick(&self, context: &Context, backends: &[BackendStats]) -> Option<usize>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Random,
+}
+
+impl StrategyKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            StrategyKind::RoundRobin => "round_robin",
+            StrategyKind::LeastConnections => "least_connections",
+            StrategyKind::Random => "random",
+        }
+    }
+
+    pub fn build(self) -> Box<dyn Strategy> {
+        match self {
+            StrategyKind::RoundRobin => Box::new(RoundRobin::default()),
+            StrategyKind::LeastConnections => Box::new(LeastConnections::default()),
+            StrategyKind::Random => Box::new(Random),
+        }
+    }
+}
+
+impl fmt::Debug for dyn Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Strategy")
+    }
+}
+
+// Takes turns. A backend in its slow start ramp only takes its turn with a
+// probability of its weight, otherwise the turn passes on to the next one.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for RoundRobin {
+    fn pick(&self, _context: &Context, backends: &[BackendStats]) -> Option<usize> {
+        let n = backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut ramping = None;
+        for i in 0..n {
+            let index = (start + i) % n;
+            let weight = backends[index].weight;
+            if weight >= 1.0 || (weight > 0.0 && rng::random_f64() < weight) {
+                return Some(index);
+            }
+            if weight > 0.0 {
+                ramping.get_or_insert(index);
+            }
+        }
+        Some(ramping.unwrap_or(start % n))
+    }
+}
+
+// The backend with the fewest connections in use for its weight. Ties go
+// round robin, so an idle pool still spreads its requests.
+#[derive(Debug, Default)]
+pub struct LeastConnections {
+    next: AtomicUsize,
+}
+
+impl Strategy for LeastConnections {
+    fn pick(&self, _context: &Context, backends: &[BackendStats]) -> Option<usize> {
+        let n = backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let load = |b: &BackendStats| (b.active + 1) as f64 / b.weight;
+        (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|&i| backends[i].weight > 0.0)
+            .min_by(|&a, &b| load(&backends[a]).total_cmp(&load(&backends[b])))
+            .or(Some(start % n))
+    }
+}
+
+// A random backend, with the odds set by the weights.
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl Strategy for Random {
+    fn pick(&self, _context: &Context, backends: &[BackendStats]) -> Option<usize> {
+        let total: f64 = backends.iter().map(|b| b.weight).sum();
+        if total <= 0.0 {
+            return Some(rng::random_u64() as usize % backends.len());
+        }
+        let mut point = rng::random_f64() * total;
+        for (i, backend) in backends.iter().enumerate() {
+            if point < backend.weight {
+                return Some(i);
+            }
+            point -= backend.weight;
+        }
+        backends.iter().rposition(|b| b.weight > 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(weights: &[f64], active: &[usize]) -> Vec<BackendStats<'static>> {
+        weights
+            .iter()
+            .zip(active)
+            .map(|(&weight, &active)| BackendStats {
+                addr: "b",
+                weight,
+                active,
+                requests: 0,
+                failures: 0,
+            })
+            .collect()
+    }
+
+    // How often each backend is picked out of `n` requests.
+    fn counts(strategy: &dyn Strategy, backends: &[BackendStats], n: usize) -> Vec<usize> {
+        let context = Context {
+            client: IpAddr::from([127, 0, 0, 1]),
+            request: None,
+        };
+        let mut counts = vec![0; backends.len()];
+        for _ in 0..n {
+            counts[strategy.pick(&context, backends).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        rng::seed(1);
+        let strategy = StrategyKind::RoundRobin.build();
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0; 3], &[5, 0, 0]), 300),
+            [100; 3]
+        );
+        // a down backend's turn passes on to the next one, unless they are
+        // all down
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0, 0.0, 1.0], &[0; 3]), 300),
+            [100, 0, 200]
+        );
+        assert_eq!(
+            counts(&*strategy, &backends(&[0.0; 3], &[0; 3]), 300),
+            [100; 3]
+        );
+
+        // a ramping backend takes about its weight of its turns
+        let ramping = counts(&*strategy, &backends(&[1.0, 0.25], &[0; 2]), 10_000);
+        assert!((1_000..1_500).contains(&ramping[1]), "{:?}", ramping);
+        let all_ramping = counts(&*strategy, &backends(&[0.1, 0.1], &[0; 2]), 1_000);
+        assert_eq!(all_ramping.iter().sum::<usize>(), 1_000);
+    }
+
+    #[test]
+    fn least_connections_goes_by_load_for_the_weight() {
+        let strategy = StrategyKind::LeastConnections.build();
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0; 3], &[4, 1, 2]), 10),
+            [0, 10, 0]
+        );
+        // half the weight counts each connection double
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0, 0.5], &[2, 1]), 10),
+            [10, 0]
+        );
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0, 0.5], &[4, 1]), 10),
+            [0, 10]
+        );
+        // ties rotate, down backends are left out
+        assert_eq!(
+            counts(&*strategy, &backends(&[1.0; 3], &[2; 3]), 300),
+            [100; 3]
+        );
+        let picked = counts(&*strategy, &backends(&[0.0, 1.0, 1.0], &[0, 9, 9]), 300);
+        assert!(
+            picked[0] == 0 && picked[1] > 0 && picked[2] > 0,
+            "{:?}",
+            picked
+        );
+        assert_eq!(
+            counts(&*strategy, &backends(&[0.0; 2], &[0; 2]), 100),
+            [50, 50]
+        );
+    }
+
+    #[test]
+    fn random_follows_the_weights() {
+        rng::seed(2);
+        let strategy = StrategyKind::Random.build();
+        let picked = counts(&*strategy, &backends(&[1.0, 0.0, 3.0], &[0; 3]), 10_000);
+        assert_eq!(picked[1], 0);
+        assert!((2_200..2_800).contains(&picked[0]), "{:?}", picked);
+        let picked = counts(&*strategy, &backends(&[0.0; 2], &[0; 2]), 1_000);
+        assert!(picked.iter().all(|&n| n > 400), "{:?}", picked);
+    }
+}
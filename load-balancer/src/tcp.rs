@@ -0,0 +1,207 @@
+/*
+ * Plain TCP proxying, used with `mode = "tcp"`.
+ *
+ * Every client connection is paired with a new connection to the next
+ * backend of the default pool, and bytes are relayed both ways until both
+ * directions have seen EOF. Nothing is parsed, so any protocol works,
+ * including TLS that is terminated by the backends.
+ *
+ * On Linux the bytes are moved with splice(2) and never enter user space.
+ * Where splice can't be used they go through a buffer instead.
+ */
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::access_log::{Entry, Termination};
+use crate::balancer::{Backend, LoadBalancer};
+use crate::config::{Config, DEFAULT_POOL};
+use crate::http::CopyError;
+use crate::metrics::Metrics;
+use crate::strategy::Context;
+use crate::stream::Stream;
+use crate::timeout::is_timeout;
+use crate::upstream;
+
+pub fn handle_client(lb: Arc<LoadBalancer>, client: Stream) {
+    let _ = client.set_nodelay(true);
+    let peer = match client.peer_addr(lb.config().unix_client_ip) {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    Metrics::inc(&lb.metrics.requests);
+
+    let config = lb.config();
+    let pool = lb
+        .pool(DEFAULT_POOL)
+        .expect("the default pool always exists");
+    Metrics::inc(&pool.requests);
+    let mut entry = Entry::new(peer, "TCP", "-");
+    let context = Context {
+        client: peer.ip(),
+        request: None,
+    };
+    entry.termination = match pool.acquire(&config, &context, &lb.metrics) {
+        Ok(permit) => {
+            entry.backend = Some(permit.backend.addr.clone());
+            forward(&lb, &config, permit.backend, &client, &mut entry)
+        }
+        Err(rejected) => rejected.termination(),
+    };
+
+    if entry.termination.is_backend_failure() {
+        Metrics::inc(&lb.metrics.upstream_errors);
+    }
+    lb.metrics.terminated(entry.termination);
+    if config.access_log {
+        entry.write();
+    }
+}
+
+// Connects to `backend` and relays until both sides are done.
+fn forward(
+    lb: &LoadBalancer,
+    config: &Config,
+    backend: &Backend,
+    client: &Stream,
+    entry: &mut Entry,
+) -> Termination {
+    let timeouts = config.global_timeouts();
+    match upstream::dial(&backend.addr, timeouts.connect()) {
+        Ok(upstream) => {
+            backend.health.succeeded();
+            let relay = Relay {
+                lb,
+                idle: timeouts.idle(),
+                splice: config.splice,
+                start: Instant::now(),
+                last_progress: AtomicU64::new(0),
+            };
+            let (received, termination) = relay.run(client, &upstream);
+            entry.bytes = received;
+            termination
+        }
+        Err(e) => {
+            backend.failed(&config.health, &e);
+            if e.kind() == io::ErrorKind::TimedOut {
+                Termination::ConnectTimeout
+            } else {
+                Termination::ConnectFailed
+            }
+        }
+    }
+}
+
+// State shared by the two directions of one connection.
+struct Relay<'a> {
+    lb: &'a LoadBalancer,
+    idle: Option<Duration>,
+    splice: bool,
+    start: Instant,
+    // when either direction last moved bytes, in ms since `start`
+    last_progress: AtomicU64,
+}
+
+impl Relay<'_> {
+    // Relays until both sides are done. Returns the bytes sent to the
+    // client and how the connection ended.
+    fn run(&self, client: &Stream, upstream: &Stream) -> (u64, Termination) {
+        for stream in [client, upstream] {
+            let _ = stream.set_read_timeout(self.idle);
+            let _ = stream.set_write_timeout(self.idle);
+        }
+        thread::scope(|scope| {
+            let sent = scope.spawn(|| self.pump(client, upstream));
+            let received = self.pump(upstream, client);
+            let sent = sent.join().expect("relay thread panicked");
+
+            let termination = match (&sent, &received) {
+                (_, Err(CopyError::Read(e))) if is_timeout(e) => Termination::BackendIdleTimeout,
+                (_, Err(CopyError::Read(_))) => Termination::BackendError,
+                (_, Err(CopyError::Write(_))) => Termination::ClientClosed,
+                (Err(CopyError::Read(e)), _) if is_timeout(e) => Termination::ClientIdleTimeout,
+                (Err(CopyError::Read(_)), _) => Termination::ClientClosed,
+                (Err(CopyError::Write(_)), _) => Termination::BackendError,
+                (Ok(_), Ok(_)) => Termination::Completed,
+            };
+            (received.unwrap_or(0), termination)
+        })
+    }
+
+    // One direction. On EOF the write side of `to` is shut down so the
+    // other end sees it too, on an error both sockets are shut down to stop
+    // the other direction as well.
+    fn pump(&self, from: &Stream, to: &Stream) -> Result<u64, CopyError> {
+        let result = self.copy(from, to);
+        match result {
+            Ok(_) => {
+                let _ = to.shutdown(Shutdown::Write);
+            }
+            Err(_) => {
+                let _ = from.shutdown(Shutdown::Both);
+                let _ = to.shutdown(Shutdown::Both);
+            }
+        }
+        result
+    }
+
+    fn copy(&self, from: &Stream, to: &Stream) -> Result<u64, CopyError> {
+        let metrics = &self.lb.metrics;
+        #[cfg(target_os = "linux")]
+        if self.splice {
+            let mut progress = |n: usize| {
+                self.progressed();
+                metrics
+                    .tcp_bytes_spliced
+                    .fetch_add(n as u64, Ordering::Relaxed);
+            };
+            let mut on_timeout = |e| self.keep_waiting(e);
+            if let Some(result) = crate::splice::copy(from, to, &mut progress, &mut on_timeout) {
+                return result;
+            }
+        }
+
+        let (mut from, mut to) = (from, to);
+        let mut buf = [0u8; 16 * 1024];
+        let mut total = 0;
+        loop {
+            let n = match from.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if is_timeout(&e) => {
+                    self.keep_waiting(e).map_err(CopyError::Read)?;
+                    continue;
+                }
+                Err(e) => return Err(CopyError::Read(e)),
+            };
+            to.write_all(&buf[..n]).map_err(CopyError::Write)?;
+            total += n as u64;
+            self.progressed();
+            metrics
+                .tcp_bytes_copied
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn progressed(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_progress.fetch_max(now, Ordering::Relaxed);
+    }
+
+    // A read timed out. The connection only counts as idle when the other
+    // direction didn't move anything either, a client that waits quietly
+    // for a long download is fine.
+    fn keep_waiting(&self, e: io::Error) -> io::Result<()> {
+        let idle = self.idle.unwrap_or(Duration::MAX);
+        let last = Duration::from_millis(self.last_progress.load(Ordering::Relaxed));
+        if self.start.elapsed().saturating_sub(last) < idle {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
+}
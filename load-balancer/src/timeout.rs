@@ -0,0 +1,184 @@
+/*
+ * Timeouts on blocking sockets.
+ *
+ * A socket only knows one read timeout and one write timeout, but a proxied
+ * request has several clocks running at once: the idle timeout between two
+ * reads, the time we allow a backend to produce its first byte, and the
+ * deadline for the whole request. `Timed` wraps a stream and before every
+ * read or write sets the socket timeout to whichever of those ends first, so
+ * a timeout error can be traced back to the clock that actually expired.
+ *
+ * A minimum transfer rate is one more clock: every byte read buys the peer
+ * 1/rate seconds of waiting, on top of a grace period. Only time spent
+ * blocked in reads counts, so a backend that is slow to take a request body
+ * doesn't get the client blamed for it.
+ */
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::stream::Stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    Idle,
+    FirstByte,
+    Total,
+    MinRate,
+}
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expired::Idle => write!(f, "idle timeout"),
+            Expired::FirstByte => write!(f, "first byte timeout"),
+            Expired::Total => write!(f, "request timeout"),
+            Expired::MinRate => write!(f, "transfer rate too low"),
+        }
+    }
+}
+
+impl Error for Expired {}
+
+// Tells which clock ran out, if `e` came from a `Timed` stream timing out.
+pub fn expired(e: &io::Error) -> Option<Expired> {
+    e.get_ref()?.downcast_ref::<Expired>().copied()
+}
+
+// Whether `e` is a socket timeout running out.
+pub fn is_timeout(e: &io::Error) -> bool {
+    // Unix reports an expired socket timeout as WouldBlock, Windows as TimedOut
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MinRate {
+    pub bytes_per_sec: u64,
+    pub grace: Duration,
+}
+
+pub struct Timed {
+    stream: Stream,
+    idle: Option<Duration>,
+    first_byte: Option<Instant>,
+    deadline: Option<Instant>,
+    min_rate: Option<MinRate>,
+    // bytes read and time spent waiting for them since min_rate was set
+    rate_bytes: u64,
+    rate_waited: Duration,
+}
+
+impl Timed {
+    pub fn new(stream: Stream, idle: Option<Duration>) -> Timed {
+        Timed {
+            stream,
+            idle,
+            first_byte: None,
+            deadline: None,
+            min_rate: None,
+            rate_bytes: 0,
+            rate_waited: Duration::ZERO,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Stream {
+        &self.stream
+    }
+
+    pub fn set_idle(&mut self, idle: Option<Duration>) {
+        self.idle = idle;
+    }
+
+    // deadline for everything done on this stream until it is cleared
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    // Makes reads from now on keep up `min_rate` on average, None turns it
+    // off again.
+    pub fn set_min_rate(&mut self, min_rate: Option<MinRate>) {
+        self.min_rate = min_rate;
+        self.rate_bytes = 0;
+        self.rate_waited = Duration::ZERO;
+    }
+
+    pub fn has_min_rate(&self) -> bool {
+        self.min_rate.is_some()
+    }
+
+    // Until the next byte arrives, reads wait at most `limit` instead of the
+    // idle timeout.
+    pub fn expect_first_byte(&mut self, limit: Option<Duration>) {
+        self.first_byte = limit.map(|limit| Instant::now() + limit);
+    }
+
+    // The timeout for the next operation and the clock it belongs to.
+    fn limit(&self, reading: bool) -> io::Result<(Option<Duration>, Expired)> {
+        let now = Instant::now();
+        let mut limit = match self.first_byte {
+            Some(at) if reading => (Some(at.saturating_duration_since(now)), Expired::FirstByte),
+            _ => (self.idle, Expired::Idle),
+        };
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(now);
+            if limit.0.is_none_or(|d| left < d) {
+                limit = (Some(left), Expired::Total);
+            }
+        }
+        if let Some(rate) = self.min_rate.filter(|r| reading && r.bytes_per_sec > 0) {
+            let earned =
+                Duration::from_secs_f64(self.rate_bytes as f64 / rate.bytes_per_sec as f64);
+            let left = rate.grace.max(earned).saturating_sub(self.rate_waited);
+            if limit.0.is_none_or(|d| left < d) {
+                limit = (Some(left), Expired::MinRate);
+            }
+        }
+        // a zero timeout means "block forever" to the OS, so fail right here
+        if limit.0 == Some(Duration::ZERO) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, limit.1));
+        }
+        Ok(limit)
+    }
+}
+
+impl Read for Timed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (timeout, clock) = self.limit(true)?;
+        self.stream.set_read_timeout(timeout)?;
+        let started = Instant::now();
+        let result = self.stream.read(buf);
+        if self.min_rate.is_some() {
+            self.rate_waited += started.elapsed();
+        }
+        match result {
+            Ok(n) => {
+                if n > 0 {
+                    self.first_byte = None;
+                }
+                self.rate_bytes += n as u64;
+                Ok(n)
+            }
+            Err(e) if is_timeout(&e) => Err(io::Error::new(io::ErrorKind::TimedOut, clock)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Write for Timed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (timeout, clock) = self.limit(false)?;
+        self.stream.set_write_timeout(timeout)?;
+        match self.stream.write(buf) {
+            Err(e) if is_timeout(&e) => Err(io::Error::new(io::ErrorKind::TimedOut, clock)),
+            other => other,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
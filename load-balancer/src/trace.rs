@@ -0,0 +1,562 @@
+/*
+ * Distributed tracing with W3C Trace Context.
+ *
+ * Every proxied request gets a span. If the client sent a valid
+ * `traceparent` the span joins that trace, otherwise a new trace starts.
+ * The request goes upstream with our span as the parent and the client's
+ * `tracestate` unchanged, so the backend's spans hang off ours.
+ *
+ * Below the request span there are child spans for picking a backend,
+ * connecting to it and the response (from sending the request until the
+ * last body byte). Finished spans are queued to a background thread that
+ * exports them in batches as OTLP/JSON, to a file (one export request per
+ * line) or to a collector's OTLP/HTTP endpoint.
+ */
+use std::fs::OpenOptions;
+use std::io::{self, BufReader, Write};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::access_log::{Entry, Termination};
+use crate::http::{Headers, RequestHead, ResponseHead};
+use crate::metrics::Metrics;
+use crate::rng;
+use crate::upstream;
+
+// finished spans waiting for the exporter, more are dropped
+const QUEUE: usize = 4096;
+// how long the exporter waits on a collector, spans queue up meanwhile
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub enabled: bool,
+    // append OTLP/JSON export requests to this file, one per line
+    pub file: Option<String>,
+    // or POST them to a collector, like "http://127.0.0.1:4318/v1/traces".
+    // Without a port it is 80, without a path /v1/traces.
+    pub endpoint: Option<String>,
+    // the service.name resource attribute
+    pub service_name: String,
+    // share of new traces that are recorded, 0 to 100. A trace started by
+    // the client keeps the client's sampling decision.
+    pub sample_percent: f64,
+    // spans per export request
+    pub batch_size: usize,
+    // how long a span may wait for its batch to fill up
+    pub flush_ms: u64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            enabled: false,
+            file: None,
+            endpoint: None,
+            service_name: "load-balancer".to_string(),
+            sample_percent: 100.0,
+            batch_size: 256,
+            flush_ms: 1000,
+        }
+    }
+}
+
+impl TracingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.file.is_none() && self.endpoint.is_none() {
+            return Err("tracing needs a file or an endpoint to export to".to_string());
+        }
+        if let Some(endpoint) = &self.endpoint {
+            Endpoint::parse(endpoint)?;
+        }
+        if !(0.0..=100.0).contains(&self.sample_percent) {
+            return Err("tracing sample_percent must be between 0 and 100".to_string());
+        }
+        Ok(())
+    }
+}
+
+// The trace of one request, a no-op when tracing is off.
+pub struct Trace(Option<Recording>);
+
+struct Recording {
+    trace_id: u128,
+    span_id: u64,
+    // the client's span, when it sent a traceparent
+    parent_id: Option<u64>,
+    sampled: bool,
+    state: Option<String>,
+    start: SystemTime,
+    children: Vec<Child>,
+}
+
+struct Child {
+    name: &'static str,
+    span_id: u64,
+    start: SystemTime,
+    end: SystemTime,
+}
+
+impl Trace {
+    pub fn off() -> Trace {
+        Trace(None)
+    }
+
+    // Starts the span of a request, continuing the client's trace if it
+    // sent a valid traceparent.
+    pub fn start(config: &TracingConfig, head: &RequestHead) -> Trace {
+        if !config.enabled {
+            return Trace::off();
+        }
+        let recording = match head.headers.get("traceparent").and_then(parse_traceparent) {
+            Some((trace_id, parent_id, flags)) => Recording {
+                trace_id,
+                span_id: new_span_id(),
+                parent_id: Some(parent_id),
+                sampled: flags & 1 == 1,
+                state: head.headers.get("tracestate").map(str::to_string),
+                start: SystemTime::now(),
+                children: Vec::new(),
+            },
+            None => Recording {
+                trace_id: ((rng::random_u64() as u128) << 64 | rng::random_u64() as u128).max(1),
+                span_id: new_span_id(),
+                parent_id: None,
+                sampled: rng::chance(config.sample_percent),
+                state: None,
+                start: SystemTime::now(),
+                children: Vec::new(),
+            },
+        };
+        Trace(Some(recording))
+    }
+
+    // the trace the request belongs to, if tracing is on
+    pub fn trace_id(&self) -> Option<u128> {
+        self.0.as_ref().map(|r| r.trace_id)
+    }
+
+    // Sets the context headers on the request going upstream.
+    pub fn inject(&self, headers: &mut Headers) {
+        let Some(r) = &self.0 else {
+            return;
+        };
+        let traceparent = format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            r.trace_id, r.span_id, r.sampled as u8
+        );
+        headers.set("traceparent", &traceparent);
+        match &r.state {
+            Some(state) => headers.set("tracestate", state),
+            None => headers.remove("tracestate"),
+        }
+    }
+
+    // Records a child span from `start` until now.
+    pub fn phase(&mut self, name: &'static str, start: SystemTime) {
+        if let Some(r) = &mut self.0 {
+            r.children.push(Child {
+                name,
+                span_id: new_span_id(),
+                start,
+                end: SystemTime::now(),
+            });
+        }
+    }
+
+    // Ends the request span and queues it for export. `route` names the
+    // route that handled the request.
+    pub fn finish(self, tracer: &Tracer, entry: &Entry, route: Option<&str>) {
+        let Some(r) = self.0 else {
+            return;
+        };
+        if !r.sampled {
+            return;
+        }
+        let end = SystemTime::now();
+        let mut attributes = vec![
+            attribute("http.request.method", json!(entry.method)),
+            attribute("url.path", json!(entry.target)),
+            attribute("client.address", json!(entry.peer.ip().to_string())),
+            attribute("lb.termination", json!(entry.termination.as_str())),
+        ];
+        if let Some(route) = route {
+            attributes.push(attribute("http.route", json!(route)));
+        }
+        if let Some(status) = entry.status {
+            attributes.push(attribute("http.response.status_code", json!(status)));
+        }
+        if let Some(backend) = &entry.backend {
+            attributes.push(attribute("lb.backend", json!(backend)));
+        }
+        let failed = entry.termination != Termination::Completed
+            || entry.status.is_some_and(|status| status >= 500);
+
+        let trace_id = format!("{:032x}", r.trace_id);
+        let span_id = format!("{:016x}", r.span_id);
+        let mut spans = vec![json!({
+            "traceId": trace_id,
+            "spanId": span_id,
+            "parentSpanId": r.parent_id.map_or(String::new(), |id| format!("{:016x}", id)),
+            "traceState": r.state.unwrap_or_default(),
+            "name": format!("{} {}", entry.method, route.unwrap_or("")).trim_end().to_string(),
+            // SPAN_KIND_SERVER
+            "kind": 2,
+            "startTimeUnixNano": nanos(r.start),
+            "endTimeUnixNano": nanos(end),
+            "attributes": attributes,
+            // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+            "status": { "code": if failed { 2 } else { 0 } },
+        })];
+        for child in r.children {
+            spans.push(json!({
+                "traceId": trace_id,
+                "spanId": format!("{:016x}", child.span_id),
+                "parentSpanId": span_id,
+                "name": child.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": nanos(child.start),
+                "endTimeUnixNano": nanos(child.end),
+            }));
+        }
+        tracer.queue(spans);
+    }
+}
+
+// Parses "00-<trace id>-<parent id>-<flags>". All-zero ids are invalid.
+fn parse_traceparent(value: &str) -> Option<(u128, u64, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // later versions may append fields, version 00 may not
+    if version.len() != 2
+        || version == "ff"
+        || (version == "00" && parts.next().is_some())
+        || trace_id.len() != 32
+        || parent_id.len() != 16
+        || flags.len() != 2
+    {
+        return None;
+    }
+    let hex = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    if !hex(version) || !hex(trace_id) || !hex(parent_id) || !hex(flags) {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+    if trace_id == 0 || parent_id == 0 {
+        return None;
+    }
+    Some((trace_id, parent_id, u8::from_str_radix(flags, 16).ok()?))
+}
+
+fn new_span_id() -> u64 {
+    rng::random_u64().max(1)
+}
+
+// OTLP/JSON wants 64 bit integers as strings
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        other => json!({ "stringValue": other.as_str().unwrap_or_default() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+// Hands finished spans to the exporter thread.
+pub struct Tracer {
+    queue: Option<SyncSender<Vec<Value>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Tracer {
+    pub fn new(config: &TracingConfig, metrics: Arc<Metrics>) -> Tracer {
+        if !config.enabled {
+            return Tracer {
+                queue: None,
+                metrics,
+            };
+        }
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        let exporter = Exporter {
+            config: config.clone(),
+            metrics: metrics.clone(),
+        };
+        thread::spawn(move || exporter.run(rx));
+        Tracer {
+            queue: Some(tx),
+            metrics,
+        }
+    }
+
+    fn queue(&self, spans: Vec<Value>) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let count = spans.len() as u64;
+        if let Err(TrySendError::Full(_)) = queue.try_send(spans) {
+            self.metrics
+                .trace_spans_dropped
+                .fetch_add(count, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Exporter {
+    config: TracingConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl Exporter {
+    fn run(self, rx: Receiver<Vec<Value>>) {
+        let flush = Duration::from_millis(self.config.flush_ms);
+        let mut batch = Vec::new();
+        let mut oldest = Instant::now();
+        loop {
+            let wait = flush.saturating_sub(oldest.elapsed());
+            match rx.recv_timeout(if batch.is_empty() { flush } else { wait }) {
+                Ok(spans) => {
+                    if batch.is_empty() {
+                        oldest = Instant::now();
+                    }
+                    batch.extend(spans);
+                    if batch.len() < self.config.batch_size {
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if batch.is_empty() => continue,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let count = batch.len() as u64;
+            let body = self.request(std::mem::take(&mut batch)).to_string();
+            match self.export(&body) {
+                Ok(()) => {
+                    self.metrics
+                        .trace_spans_exported
+                        .fetch_add(count, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    self.metrics
+                        .trace_spans_dropped
+                        .fetch_add(count, Ordering::Relaxed);
+                    warn!("span export failed: {}", e);
+                }
+            }
+        }
+    }
+
+    // an ExportTraceServiceRequest
+    fn request(&self, spans: Vec<Value>) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", json!(self.config.service_name))],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "load-balancer", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    fn export(&self, body: &str) -> io::Result<()> {
+        if let Some(path) = &self.config.file {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", body)?;
+        }
+        if let Some(endpoint) = &self.config.endpoint {
+            // validated with the config
+            let endpoint = Endpoint::parse(endpoint).map_err(io::Error::other)?;
+            endpoint.post(body)?;
+        }
+        Ok(())
+    }
+}
+
+// A plain http:// collector URL.
+struct Endpoint {
+    // as written, for the Host header
+    host: String,
+    // host:port to connect to, port 80 when the URL has none
+    addr: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Endpoint, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("tracing endpoint {:?} must start with http://", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/v1/traces"),
+        };
+        // an IPv6 address has colons of its own, the port comes after the ]
+        let name_end = host.rfind(']').map_or(0, |i| i + 1);
+        let addr = match host[name_end..].rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_err() => {
+                return Err(format!("tracing endpoint {:?} has a bad port", url));
+            }
+            Some(_) => host.to_string(),
+            None => format!("{}:80", host),
+        };
+        if host.is_empty() || host.starts_with(':') {
+            return Err(format!("tracing endpoint {:?} has no host", url));
+        }
+        Ok(Endpoint {
+            host: host.to_string(),
+            addr,
+            path: path.to_string(),
+        })
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut stream = upstream::dial(&self.addr, Some(CONNECT_TIMEOUT))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nhost: {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+        let response = ResponseHead::read_final(&mut BufReader::new(stream))?;
+        if !(200..300).contains(&response.status) {
+            return Err(io::Error::other(format!(
+                "collector answered {}",
+                response.status
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN: &str = "00f067aa0ba902b7";
+
+    fn traceparent(version: &str, trace_id: &str, span_id: &str, flags: &str) -> String {
+        format!("{}-{}-{}-{}", version, trace_id, span_id, flags)
+    }
+
+    #[test]
+    fn valid_traceparents() {
+        let ids = (0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7);
+        assert_eq!(
+            parse_traceparent(&traceparent("00", TRACE, SPAN, "01")),
+            Some((ids.0, ids.1, 1))
+        );
+        assert_eq!(
+            parse_traceparent(&format!(" {} ", traceparent("00", TRACE, SPAN, "00"))),
+            Some((ids.0, ids.1, 0))
+        );
+        // unknown flags are kept, a later version may add fields
+        assert_eq!(
+            parse_traceparent(&traceparent("00", TRACE, SPAN, "ff")),
+            Some((ids.0, ids.1, 0xff))
+        );
+        assert_eq!(
+            parse_traceparent(&format!(
+                "{}-what-next",
+                traceparent("cc", TRACE, SPAN, "01")
+            )),
+            Some((ids.0, ids.1, 1))
+        );
+    }
+
+    #[test]
+    fn invalid_traceparents() {
+        let zero_trace = "0".repeat(32);
+        let zero_span = "0".repeat(16);
+        let upper = TRACE.to_ascii_uppercase();
+        let bad = [
+            // version ff is invalid, 00 has exactly four fields
+            traceparent("ff", TRACE, SPAN, "01"),
+            format!("{}-extra", traceparent("00", TRACE, SPAN, "01")),
+            traceparent("0", TRACE, SPAN, "01"),
+            // all-zero ids
+            traceparent("00", &zero_trace, SPAN, "01"),
+            traceparent("00", TRACE, &zero_span, "01"),
+            // wrong lengths
+            traceparent("00", &TRACE[1..], SPAN, "01"),
+            traceparent("00", TRACE, &format!("{}0", SPAN), "01"),
+            traceparent("00", TRACE, SPAN, "1"),
+            traceparent("00", TRACE, SPAN, "001"),
+            // lowercase hex only
+            traceparent("00", &upper, SPAN, "01"),
+            traceparent("00", TRACE, SPAN, "0A"),
+            traceparent("00", TRACE, &SPAN.replace('a', "g"), "01"),
+            format!("00-{}-{}", TRACE, SPAN),
+            String::new(),
+        ];
+        for value in bad {
+            assert_eq!(parse_traceparent(&value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = Endpoint::parse("http://collector/v1/traces").unwrap();
+        assert_eq!(
+            (
+                endpoint.host.as_str(),
+                endpoint.addr.as_str(),
+                endpoint.path.as_str()
+            ),
+            ("collector", "collector:80", "/v1/traces")
+        );
+        let endpoint = Endpoint::parse("http://127.0.0.1:4318").unwrap();
+        assert_eq!(
+            (endpoint.addr.as_str(), endpoint.path.as_str()),
+            ("127.0.0.1:4318", "/v1/traces")
+        );
+        assert_eq!(Endpoint::parse("http://[::1]/x").unwrap().addr, "[::1]:80");
+        assert_eq!(
+            Endpoint::parse("http://[::1]:4318").unwrap().addr,
+            "[::1]:4318"
+        );
+
+        for url in [
+            "https://collector",
+            "collector:4318",
+            "http://",
+            "http:///v1/traces",
+            "http://:4318",
+            "http://collector:port",
+            "http://collector:99999",
+        ] {
+            assert!(Endpoint::parse(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+/*
+ * Connections to the backends.
+ *
+ * Opening a new connection for every request costs a handshake and a
+ * local port that then sits in TIME_WAIT. Instead, once a response has been
+ * read completely and the backend agreed to keep the connection open, we park
+ * the connection in a per-backend idle list and hand it to the next request
+ * for the same backend.
+ *
+ * The idle lists are split into one shard per worker so connections
+ * accepted by different workers don't fight over one lock. A request looks
+ * in its own shard first and only then tries the others, skipping any shard
+ * that is busy right now. Parking works the same way when the home shard
+ * is full. max_idle is split between the shards, so together they never
+ * hold more than that many connections to one backend.
+ */
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::KeepAliveConfig;
+use crate::metrics::Metrics;
+use crate::stream::{self, Stream};
+use crate::timeout::Timed;
+
+thread_local! {
+    static HOME_SHARD: Cell<usize> = const { Cell::new(0) };
+}
+
+// Makes the calling thread use shard `index` (modulo the shard count) for
+// the connections it parks and looks up first.
+pub fn set_home_shard(index: usize) {
+    HOME_SHARD.with(|shard| shard.set(index));
+}
+
+pub struct UpstreamConn {
+    pub stream: BufReader<Timed>,
+    created: Instant,
+    // true when this connection came out of the idle pool
+    pub reused: bool,
+}
+
+impl UpstreamConn {
+    // a fresh connection, see dial()
+    pub fn connect(addr: &str, timeout: Option<Duration>) -> io::Result<UpstreamConn> {
+        let stream = dial(addr, timeout)?;
+        Ok(UpstreamConn {
+            stream: BufReader::new(Timed::new(stream, None)),
+            created: Instant::now(),
+            reused: false,
+        })
+    }
+
+    // Checks that a parked connection is still usable: nothing may be left
+    // in our read buffer, and the backend must not have closed it or sent
+    // anything while it was idle.
+    fn is_alive(&self) -> bool {
+        if !self.stream.buffer().is_empty() {
+            return false;
+        }
+        is_quiet(self.stream.get_ref().get_ref())
+    }
+}
+
+// Whether the peer has neither closed `socket` nor sent anything on it.
+pub fn is_quiet(socket: &Stream) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let quiet = match socket.peek(&mut [0u8; 1]) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        // Ok(0) means the peer closed it, Ok(n) is unexpected data
+        Ok(_) => false,
+    };
+    socket.set_nonblocking(false).is_ok() && quiet
+}
+
+// Opens a connection to `addr`, host:port or a `unix:` path. `timeout`
+// limits each TCP connection attempt, a timed out attempt fails with
+// ErrorKind::TimedOut. A Unix socket connect doesn't wait, it fails right
+// away when nobody listens.
+pub fn dial(addr: &str, timeout: Option<Duration>) -> io::Result<Stream> {
+    if let Some(path) = stream::unix_path(addr) {
+        #[cfg(unix)]
+        return UnixStream::connect(path).map(Stream::Unix);
+        #[cfg(not(unix))]
+        return Err(stream::unsupported(path));
+    }
+    let mut last_err = None;
+    for socket_addr in addr.to_socket_addrs()? {
+        let attempt = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&socket_addr, timeout),
+            None => TcpStream::connect(socket_addr),
+        };
+        match attempt {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(Stream::Tcp(stream));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+struct Idle {
+    conn: UpstreamConn,
+    since: Instant,
+}
+
+pub struct ConnPool {
+    settings: KeepAliveConfig,
+    metrics: Arc<Metrics>,
+    shards: Vec<Mutex<IdleLists>>,
+}
+
+type IdleLists = HashMap<String, Vec<Idle>>;
+
+impl ConnPool {
+    pub fn new(settings: KeepAliveConfig, metrics: Arc<Metrics>, shards: usize) -> ConnPool {
+        ConnPool {
+            settings,
+            metrics,
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn home(&self) -> usize {
+        HOME_SHARD.with(Cell::get) % self.shards.len()
+    }
+
+    // Hands out a pooled connection to `addr` if there is a good one,
+    // otherwise dials a new connection.
+    pub fn get(&self, addr: &str, connect_timeout: Option<Duration>) -> io::Result<UpstreamConn> {
+        if let Some(mut conn) = self.checkout(addr) {
+            Metrics::inc(&self.metrics.pool_hits);
+            conn.reused = true;
+            return Ok(conn);
+        }
+        Metrics::inc(&self.metrics.pool_misses);
+        UpstreamConn::connect(addr, connect_timeout)
+    }
+
+    fn checkout(&self, addr: &str) -> Option<UpstreamConn> {
+        let home = self.home();
+        let shards = self.shards.len();
+        (0..shards).find_map(|i| {
+            let shard = &self.shards[(home + i) % shards];
+            if i == 0 {
+                self.checkout_from(&mut shard.lock().unwrap(), addr)
+            } else {
+                self.checkout_from(&mut *shard.try_lock().ok()?, addr)
+            }
+        })
+    }
+
+    fn checkout_from(&self, lists: &mut IdleLists, addr: &str) -> Option<UpstreamConn> {
+        loop {
+            // take the most recently parked connection, it is the least
+            // likely to have been closed by the backend
+            let idle = lists.get_mut(addr)?.pop()?;
+            if self.is_fresh(&idle) && idle.conn.is_alive() {
+                return Some(idle.conn);
+            }
+            Metrics::inc(&self.metrics.pool_discarded);
+        }
+    }
+
+    // Parks a connection whose last response was read completely.
+    pub fn put(&self, addr: &str, conn: UpstreamConn) {
+        let idle = Idle {
+            conn,
+            since: Instant::now(),
+        };
+        if self.settings.max_idle == 0 || !self.is_fresh(&idle) {
+            return;
+        }
+        // the home shard first, then any other shard with room that isn't
+        // busy right now
+        let home = self.home();
+        let shards = self.shards.len();
+        for i in 0..shards {
+            let index = (home + i) % shards;
+            if self.share(index) == 0 {
+                continue;
+            }
+            let mut lists = match i {
+                0 => self.shards[index].lock().unwrap(),
+                _ => match self.shards[index].try_lock() {
+                    Ok(lists) => lists,
+                    Err(_) => continue,
+                },
+            };
+            let list = lists.entry(addr.to_string()).or_default();
+            if list.len() < self.share(index) {
+                list.push(idle);
+                return;
+            }
+        }
+        // all full, drop the oldest one at home to make room
+        if self.share(home) == 0 {
+            Metrics::inc(&self.metrics.pool_discarded);
+            return;
+        }
+        let mut lists = self.shards[home].lock().unwrap();
+        let list = lists.entry(addr.to_string()).or_default();
+        if list.len() >= self.share(home) {
+            list.remove(0);
+            Metrics::inc(&self.metrics.pool_discarded);
+        }
+        list.push(idle);
+    }
+
+    // How many idle connections to one backend shard `index` may hold. The
+    // shares add up to max_idle, which is per backend across all shards.
+    fn share(&self, index: usize) -> usize {
+        let shards = self.shards.len();
+        self.settings.max_idle / shards + usize::from(index < self.settings.max_idle % shards)
+    }
+
+    // Closes idle connections that expired. Called periodically so sockets
+    // don't linger for backends that stopped getting traffic.
+    pub fn reap(&self) {
+        for shard in &self.shards {
+            for list in shard.lock().unwrap().values_mut() {
+                let before = list.len();
+                list.retain(|idle| self.is_fresh(idle));
+                for _ in list.len()..before {
+                    Metrics::inc(&self.metrics.pool_discarded);
+                }
+            }
+        }
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().values().map(Vec::len).sum::<usize>())
+            .sum()
+    }
+
+    fn is_fresh(&self, idle: &Idle) -> bool {
+        idle.since.elapsed() < self.settings.idle_timeout()
+            && idle.conn.created.elapsed() < self.settings.max_lifetime()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn idle_connections_stay_within_max_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let settings = KeepAliveConfig {
+            max_idle: 5,
+            ..KeepAliveConfig::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let pool = ConnPool::new(settings, metrics.clone(), 4);
+        assert_eq!(
+            (0..4).map(|i| pool.share(i)).collect::<Vec<_>>(),
+            [2, 1, 1, 1]
+        );
+
+        // parked from one worker, they spill over into the other shards
+        let mut accepted = Vec::new();
+        for _ in 0..8 {
+            let conn = UpstreamConn::connect(&addr, None).unwrap();
+            accepted.push(listener.accept().unwrap());
+            pool.put(&addr, conn);
+        }
+        assert_eq!(pool.idle_count(), 5);
+        assert_eq!(metrics.pool_discarded.load(Ordering::Relaxed), 3);
+
+        // and any worker gets them back
+        set_home_shard(3);
+        for _ in 0..5 {
+            assert!(pool.checkout(&addr).is_some());
+        }
+        assert!(pool.checkout(&addr).is_none());
+    }
+}
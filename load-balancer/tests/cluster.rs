@@ -0,0 +1,196 @@
+/*
+ * Cluster mode with real instances gossiping over localhost.
+ *
+ *   cargo test --test cluster
+ *
+ * Each test starts three load balancer processes that list each other as
+ * peers, on timers a few times faster than the defaults, and watches them
+ * through the admin listener: membership in /metrics, backend states and
+ * incarnations in /status.
+ */
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{LbProcess, read_response, start_backend};
+
+fn gossip_port() -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().to_string()
+}
+
+// The config of member `me` out of `gossip`, in front of `backends`.
+fn config(gossip: &[String], me: usize, backends: &[String]) -> String {
+    let peers: Vec<&String> = gossip.iter().filter(|g| **g != gossip[me]).collect();
+    format!(
+        "backends = {:?}\n\
+         strategy = \"round_robin\"\n\
+         [health]\nfailures = 1\ncooldown_ms = 60000\n\
+         [cluster]\nbind = {:?}\npeers = {:?}\n\
+         secret = \"cluster test secret\"\n\
+         probe_interval_ms = 100\nprobe_timeout_ms = 40\nsuspect_timeout_ms = 1000\n",
+        backends, gossip[me], peers
+    )
+}
+
+fn start(name: &str, backends: &[String]) -> (Vec<String>, Vec<LbProcess>) {
+    let gossip: Vec<String> = (0..3).map(|_| gossip_port()).collect();
+    let members = (0..3)
+        .map(|i| LbProcess::start(&format!("{}-{}", name, i), &config(&gossip, i, backends)))
+        .collect();
+    (gossip, members)
+}
+
+fn members(lb: &LbProcess, state: &str) -> u64 {
+    lb.metric(&format!("lb_cluster_members{{state=\"{}\"}}", state))
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(
+            started.elapsed() < Duration::from_secs(15),
+            "timed out waiting until {}",
+            what
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn a_member_that_goes_away_is_suspected_then_dead_and_refutes_on_return() {
+    let (gossip, mut lbs) = start("cluster-members", &[start_backend()]);
+    wait_for("everybody knows everybody", || {
+        lbs.iter().all(|lb| members(lb, "alive") == 2)
+    });
+
+    let gone = lbs.pop().unwrap();
+    drop(gone);
+    let mut suspected = [false, false];
+    wait_for("the other two declare it dead", || {
+        let mut dead = 0;
+        for (i, lb) in lbs.iter().enumerate() {
+            suspected[i] |= members(lb, "suspect") == 1;
+            dead += members(lb, "dead");
+        }
+        dead == 2
+    });
+    // nobody skips a step
+    assert_eq!(suspected, [true, true]);
+    for lb in &lbs {
+        assert_eq!(members(lb, "alive"), 1);
+    }
+
+    // back on the same address it hears that it is dead and refutes
+    let back = LbProcess::start(
+        "cluster-members-back",
+        &config(&gossip, 2, &[start_backend()]),
+    );
+    lbs.push(back);
+    wait_for("everybody is alive again", || {
+        lbs.iter().all(|lb| members(lb, "alive") == 2)
+    });
+    let status = lbs[2].status();
+    assert!(status["cluster"]["incarnation"].as_u64().unwrap() > 0);
+    for lb in &lbs[..2] {
+        let status = lb.status();
+        let member = status["cluster"]["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["name"] == gossip[2].as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(member["state"], "alive");
+        assert!(member["incarnation"].as_u64().unwrap() > 0);
+    }
+}
+
+fn backend_state(lb: &LbProcess, addr: &str) -> String {
+    let status = lb.status();
+    status["pools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|pool| pool["backends"].as_array().unwrap())
+        .find(|b| b["addr"] == addr)
+        .map(|b| b["state"].as_str().unwrap().to_string())
+        .unwrap()
+}
+
+#[test]
+fn a_backend_one_member_takes_out_is_out_everywhere() {
+    // nobody listens on the second one
+    let (up, dead) = (start_backend(), common::free_port());
+    let (_, lbs) = start("cluster-health", &[up.clone(), dead.clone()]);
+    wait_for("everybody knows everybody", || {
+        lbs.iter().all(|lb| members(lb, "alive") == 2)
+    });
+
+    // round robin sends one of the first two requests to the dead backend
+    for _ in 0..2 {
+        let mut stream = lbs[0].connect();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: lb\r\nconnection: close\r\n\r\n")
+            .unwrap();
+        read_response(&mut BufReader::new(stream));
+    }
+    assert_eq!(backend_state(&lbs[0], &dead), "down");
+
+    wait_for("the others take it out too", || {
+        lbs[1..].iter().all(|lb| backend_state(lb, &dead) == "down")
+    });
+    assert_eq!(lbs[0].metric("lb_cluster_health_shared_total"), 1);
+    for lb in &lbs[1..] {
+        assert_eq!(lb.metric("lb_cluster_health_received_total"), 1);
+        assert_eq!(lb.metric("lb_cluster_health_shared_total"), 0);
+        assert_eq!(backend_state(lb, &up), "up");
+    }
+}
+
+#[test]
+fn unsigned_and_unknown_gossip_is_dropped() {
+    let (gossip, lbs) = start("cluster-strangers", &[start_backend()]);
+    wait_for("everybody knows everybody", || {
+        lbs.iter().all(|lb| members(lb, "alive") == 2)
+    });
+
+    // a datagram without a valid signature goes nowhere
+    let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ping = format!(
+        r#"{{"kind":"ping","seq":1,"from":{:?},"incarnation":0}}"#,
+        stranger.local_addr().unwrap().to_string()
+    );
+    let mut forged = vec![0u8; 32];
+    forged.extend_from_slice(ping.as_bytes());
+    stranger.send_to(&forged, &gossip[0]).unwrap();
+    wait_for("the datagram is dropped", || {
+        lbs[0].metric("lb_cluster_messages_rejected_total") == 1
+    });
+    assert_eq!(members(&lbs[0], "alive"), 2);
+
+    // nor does a signed one from a member nobody introduced
+    let (outsider, outsider_gossip) = {
+        let mut gossip = gossip.clone();
+        gossip.push(gossip_port());
+        let lb = LbProcess::start(
+            "cluster-strangers-outsider",
+            &config(&gossip, 3, &[start_backend()]),
+        );
+        (lb, gossip[3].clone())
+    };
+    wait_for("the outsider is turned away", || {
+        lbs.iter()
+            .all(|lb| lb.metric("lb_cluster_messages_rejected_total") >= 2)
+    });
+    for lb in &lbs {
+        assert_eq!(members(lb, "alive"), 2);
+        let status = lb.status();
+        let names = status["cluster"]["members"].as_array().unwrap();
+        assert!(!names.iter().any(|m| m["name"] == outsider_gossip.as_str()));
+    }
+    assert_eq!(members(&outsider, "alive"), 0);
+}
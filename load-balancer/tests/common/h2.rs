@@ -0,0 +1,361 @@
+// Just enough HTTP/2 to make gRPC calls through the load balancer and to
+// answer them behind it. Header blocks are sent as plain literals, which is
+// also all the load balancer sends, so there is no HPACK table to keep.
+
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+fn write_frame(out: &mut impl Write, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut frame = vec![len[1], len[2], len[3], kind, flags];
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    // a peer that hung up shows when reading
+    let _ = out.write_all(&frame);
+}
+
+fn read_frame(input: &mut impl Read) -> Option<Frame> {
+    let mut head = [0u8; 9];
+    input.read_exact(&mut head).ok()?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    let mut payload = vec![0u8; len];
+    input.read_exact(&mut payload).ok()?;
+    Some(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+fn put_integer(block: &mut Vec<u8>, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn integer(block: &mut &[u8], prefix: u8) -> usize {
+    let max = (1usize << prefix) - 1;
+    let mut value = block[0] as usize & max;
+    *block = &block[1..];
+    if value < max {
+        return value;
+    }
+    let mut shift = 0;
+    loop {
+        let byte = block[0];
+        *block = &block[1..];
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        // literal without indexing, new name
+        block.push(0);
+        for s in [name, value] {
+            put_integer(&mut block, 7, s.len());
+            block.extend_from_slice(s.as_bytes());
+        }
+    }
+    block
+}
+
+// Decodes what encode() and the load balancer send, nothing else.
+pub fn decode(mut block: &[u8]) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    while !block.is_empty() {
+        assert_eq!(block[0] & 0xef, 0, "only literals with new names");
+        assert_eq!(integer(&mut block, 4), 0);
+        let mut strings = [String::new(), String::new()];
+        for s in &mut strings {
+            assert_eq!(block[0] & 0x80, 0, "no Huffman");
+            let len = integer(&mut block, 7);
+            *s = String::from_utf8(block[..len].to_vec()).unwrap();
+            block = &block[len..];
+        }
+        let [name, value] = strings;
+        fields.push((name, value));
+    }
+    fields
+}
+
+// A gRPC message: no compression, the length, the bytes.
+pub fn message(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![0];
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+    out
+}
+
+#[derive(Debug, Default)]
+pub struct Response {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, String)>,
+    // the RST_STREAM code, if the stream was reset
+    pub reset: Option<u32>,
+    // the GOAWAY code, if the connection was closed
+    pub goaway: Option<u32>,
+}
+
+impl Response {
+    pub fn status(&self) -> Option<u16> {
+        self.header(":status")?.parse().ok()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // from the trailers, or the headers of a trailers-only response
+    pub fn grpc_status(&self) -> Option<u32> {
+        self.trailers
+            .iter()
+            .chain(&self.headers)
+            .find(|(n, _)| n == "grpc-status")?
+            .1
+            .parse()
+            .ok()
+    }
+
+    // the bytes of the first message in the body
+    pub fn message(&self) -> &[u8] {
+        &self.body[5..]
+    }
+}
+
+// A client connection that makes one call at a time.
+pub struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_stream: u32,
+}
+
+impl Client {
+    pub fn connect(addr: &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer.write_all(PREFACE).unwrap();
+        write_frame(&mut writer, SETTINGS, 0, 0, &[]);
+        Client {
+            writer,
+            reader: BufReader::new(stream),
+            next_stream: 1,
+        }
+    }
+
+    // Calls `path` with `body` as the one request message.
+    pub fn call(&mut self, path: &str, body: &[u8]) -> Response {
+        self.request(
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", path),
+                (":authority", "lb"),
+                ("content-type", "application/grpc"),
+                ("te", "trailers"),
+            ],
+            &message(body),
+        )
+    }
+
+    pub fn request(&mut self, fields: &[(&str, &str)], body: &[u8]) -> Response {
+        let stream = self.next_stream;
+        self.next_stream += 2;
+        let block = encode(fields);
+        write_frame(&mut self.writer, HEADERS, END_HEADERS, stream, &block);
+        write_frame(&mut self.writer, DATA, END_STREAM, stream, body);
+        let mut response = Response::default();
+        loop {
+            let Some(frame) = read_frame(&mut self.reader) else {
+                return response;
+            };
+            match frame.kind {
+                SETTINGS if frame.flags & ACK == 0 => {
+                    write_frame(&mut self.writer, SETTINGS, ACK, 0, &[])
+                }
+                PING if frame.flags & ACK == 0 => {
+                    write_frame(&mut self.writer, PING, ACK, 0, &frame.payload)
+                }
+                GOAWAY => {
+                    let code = frame.payload[4..8].try_into().unwrap();
+                    response.goaway = Some(u32::from_be_bytes(code));
+                    return response;
+                }
+                _ if frame.stream != stream => {}
+                HEADERS => {
+                    let fields = decode(&frame.payload);
+                    if response.headers.is_empty() {
+                        response.headers = fields;
+                    } else {
+                        response.trailers = fields;
+                    }
+                    if frame.flags & END_STREAM != 0 {
+                        return response;
+                    }
+                }
+                DATA => {
+                    response.body.extend_from_slice(&frame.payload);
+                    if frame.flags & END_STREAM != 0 {
+                        return response;
+                    }
+                }
+                RST_STREAM => {
+                    let code = frame.payload[..4].try_into().unwrap();
+                    response.reset = Some(u32::from_be_bytes(code));
+                    return response;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// What a backend answers a call with: the grpc-status and, for OK, the
+// message. Anything else comes back trailers-only.
+pub type Handler = dyn Fn(&str, &[u8]) -> (u32, Vec<u8>) + Send + Sync;
+
+pub struct Backend {
+    pub addr: String,
+    // calls answered
+    pub calls: Arc<AtomicUsize>,
+}
+
+pub fn start_backend(
+    handler: impl Fn(&str, &[u8]) -> (u32, Vec<u8>) + Send + Sync + 'static,
+) -> Backend {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler: Arc<Handler> = Arc::new(handler);
+    let counted = calls.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (handler, calls) = (handler.clone(), counted.clone());
+            thread::spawn(move || serve(stream, &*handler, &calls));
+        }
+    });
+    Backend { addr, calls }
+}
+
+fn serve(stream: TcpStream, handler: &Handler, calls: &AtomicUsize) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut preface = [0u8; 24];
+    if reader.read_exact(&mut preface).is_err() || preface != PREFACE {
+        return;
+    }
+    write_frame(&mut writer, SETTINGS, 0, 0, &[]);
+    // the path and the body so far of every open stream
+    let mut open: HashMap<u32, (String, Vec<u8>)> = HashMap::new();
+    while let Some(frame) = read_frame(&mut reader) {
+        let ended = match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                write_frame(&mut writer, SETTINGS, ACK, 0, &[]);
+                false
+            }
+            HEADERS => {
+                let fields = decode(&frame.payload);
+                let path = fields
+                    .iter()
+                    .find(|(n, _)| n == ":path")
+                    .map(|(_, v)| v.clone())
+                    .unwrap_or_default();
+                open.insert(frame.stream, (path, Vec::new()));
+                frame.flags & END_STREAM != 0
+            }
+            DATA => {
+                if let Some((_, body)) = open.get_mut(&frame.stream) {
+                    body.extend_from_slice(&frame.payload);
+                }
+                frame.flags & END_STREAM != 0
+            }
+            _ => false,
+        };
+        if !ended {
+            continue;
+        }
+        let Some((path, body)) = open.remove(&frame.stream) else {
+            continue;
+        };
+        calls.fetch_add(1, Ordering::SeqCst);
+        let (code, reply) = handler(&path, body.get(5..).unwrap_or_default());
+        let code = code.to_string();
+        let head = [(":status", "200"), ("content-type", "application/grpc")];
+        if code != "0" {
+            let mut fields = head.to_vec();
+            fields.push(("grpc-status", &code));
+            let block = encode(&fields);
+            write_frame(
+                &mut writer,
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                frame.stream,
+                &block,
+            );
+            continue;
+        }
+        write_frame(
+            &mut writer,
+            HEADERS,
+            END_HEADERS,
+            frame.stream,
+            &encode(&head),
+        );
+        write_frame(&mut writer, DATA, 0, frame.stream, &message(&reply));
+        let trailers = encode(&[("grpc-status", "0")]);
+        write_frame(
+            &mut writer,
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            frame.stream,
+            &trailers,
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// Helpers shared by the integration tests: running the load balancer binary
+// with a generated config in front of an in-process backend.
+#![allow(dead_code)]
+
+pub mod h2;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use load_balancer::http::{BodyReader, HeadLimits, RequestHead};
+
+pub fn free_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+pub struct LbProcess {
+    pub addr: String,
+    pub admin: String,
+    child: Child,
+    dir: PathBuf,
+}
+
+impl LbProcess {
+    // Starts the load balancer in a temporary directory. `config` is added
+    // to a config that sets the listen and admin addresses and turns the
+    // access log off.
+    pub fn start(name: &str, config: &str) -> LbProcess {
+        LbProcess::start_on(name, &free_port(), config)
+    }
+
+    // Like start, but listening on `addr`, which may be a unix: socket.
+    pub fn start_on(name: &str, addr: &str, config: &str) -> LbProcess {
+        let dir = env::temp_dir().join(format!("lb-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let addr = addr.to_string();
+        let admin = free_port();
+        let config = format!(
+            "listen = {:?}\nadmin = {:?}\naccess_log = false\n{}",
+            addr, admin, config
+        );
+        fs::write(dir.join("load-balancer.toml"), config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_load-balancer"))
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the load balancer");
+
+        let started = Instant::now();
+        let listening = |addr: &str| match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => UnixStream::connect(path).is_ok(),
+            #[cfg(not(unix))]
+            Some(_) => false,
+            None => TcpStream::connect(addr).is_ok(),
+        };
+        while !listening(&addr) || !listening(&admin) {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "load balancer did not start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        LbProcess {
+            addr,
+            admin,
+            child,
+            dir,
+        }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(&self.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    // The response to a GET on the admin listener, head and all.
+    fn admin_get(&self, path: &str) -> String {
+        let mut stream = TcpStream::connect(&self.admin).unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nhost: admin\r\nconnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut text = String::new();
+        stream.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    // A PUT without a body on the admin listener, returns status and body.
+    pub fn admin_put(&self, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(&self.admin).unwrap();
+        let request = format!(
+            "PUT {} HTTP/1.1\r\nhost: admin\r\nconnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        read_response(&mut BufReader::new(stream))
+    }
+
+    // The value of one line of /metrics, like
+    // `lb_limit_violations_total{limit="body_size"}`.
+    pub fn metric(&self, name: &str) -> u64 {
+        self.admin_get("/metrics")
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or_else(|| panic!("no metric {}", name))
+    }
+
+    // What GET /status answers.
+    pub fn status(&self) -> serde_json::Value {
+        let text = self.admin_get("/status");
+        let (_, body) = text.split_once("\r\n\r\n").expect("no status");
+        serde_json::from_str(body).unwrap()
+    }
+}
+
+impl Drop for LbProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// A keep-alive HTTP backend that reads each request body and answers with
+// its length.
+pub fn start_backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || serve_backend(stream));
+        }
+    });
+    addr
+}
+
+fn serve_backend(stream: TcpStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    while let Ok(Some(head)) = RequestHead::read(&mut reader, &HeadLimits::NONE) {
+        let kind = match head.body_kind() {
+            Ok(kind) => kind,
+            Err(_) => return,
+        };
+        let mut body = Vec::new();
+        if BodyReader::new(&mut reader, kind)
+            .read_to_end(&mut body)
+            .is_err()
+        {
+            return;
+        }
+        let text = body.len().to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+            text.len(),
+            text
+        );
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// Reads one response off `reader`, returns its status and body.
+pub fn read_response(reader: &mut impl BufRead) -> (u16, String) {
+    let head = load_balancer::http::ResponseHead::read(reader).expect("no response");
+    let mut body = String::new();
+    BodyReader::new(reader, head.body_kind("GET").unwrap())
+        .read_to_string(&mut body)
+        .unwrap();
+    (head.status, body)
+}
@@ -0,0 +1,202 @@
+/*
+ * gRPC over h2c: routing, status-driven health and retries, the health
+ * service and the HTTP/2 limits.
+ *
+ *   cargo test --test grpc
+ *
+ * The client and the backends speak just enough HTTP/2 for the job (see
+ * tests/common/h2.rs), each backend answers with its own name.
+ */
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::LbProcess;
+use common::h2::{Client, message, start_backend};
+
+const OK: u32 = 0;
+const INTERNAL: u32 = 13;
+const UNAVAILABLE: u32 = 14;
+
+fn named(name: &'static str) -> common::h2::Backend {
+    start_backend(move |_, _| (OK, name.as_bytes().to_vec()))
+}
+
+fn failing(code: u32) -> common::h2::Backend {
+    start_backend(move |_, _| (code, Vec::new()))
+}
+
+// The calls counted with `code`. A call is counted once the client has its
+// trailers, so this waits a little for the count to catch up.
+fn calls(lb: &LbProcess, code: &str, expected: u64) -> u64 {
+    let name = format!("lb_grpc_calls_total{{code=\"{}\"}}", code);
+    let started = Instant::now();
+    loop {
+        let count = lb.metric(&name);
+        if count == expected || started.elapsed() > Duration::from_secs(2) {
+            return count;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn calls_are_routed_by_service() {
+    let (a, b) = (named("a"), named("b"));
+    let config = format!(
+        "backends = [{:?}]\n\
+         [pools.b]\nbackends = [{:?}]\n\
+         [[routes]]\npath_prefix = \"/pkg.B/\"\npool = \"b\"\n",
+        a.addr, b.addr
+    );
+    let lb = LbProcess::start("grpc-routing", &config);
+    let mut client = Client::connect(&lb.addr);
+    for _ in 0..3 {
+        let response = client.call("/pkg.A/Get", b"");
+        assert_eq!(response.status(), Some(200));
+        assert_eq!(response.grpc_status(), Some(OK));
+        assert_eq!(response.message(), b"a");
+        assert_eq!(client.call("/pkg.B/Get", b"").message(), b"b");
+    }
+    assert_eq!(a.calls.load(Ordering::SeqCst), 3);
+    assert_eq!(b.calls.load(Ordering::SeqCst), 3);
+    assert_eq!(calls(&lb, "ok", 6), 6);
+}
+
+#[test]
+fn unavailable_is_retried_and_takes_the_backend_out() {
+    let (bad, good) = (failing(UNAVAILABLE), named("good"));
+    let config = format!(
+        "backends = [{:?}, {:?}]\n\
+         strategy = \"round_robin\"\n\
+         [health]\nfailures = 2\ncooldown_ms = 60000\n",
+        bad.addr, good.addr
+    );
+    let lb = LbProcess::start("grpc-retry", &config);
+    let mut client = Client::connect(&lb.addr);
+    for _ in 0..6 {
+        let response = client.call("/pkg.S/Get", b"");
+        assert_eq!(response.grpc_status(), Some(OK));
+        assert_eq!(response.message(), b"good");
+    }
+    // two UNAVAILABLEs in a row and it is out, each one retried
+    assert_eq!(bad.calls.load(Ordering::SeqCst), 2);
+    assert_eq!(good.calls.load(Ordering::SeqCst), 6);
+    assert_eq!(lb.metric("lb_grpc_retries_total"), 2);
+    assert_eq!(calls(&lb, "unavailable", 0), 0);
+}
+
+#[test]
+fn failure_codes_reach_the_client() {
+    let bad = failing(INTERNAL);
+    let config = format!(
+        "backends = [{:?}]\n[health]\nfailures = 2\ncooldown_ms = 60000\n",
+        bad.addr
+    );
+    let lb = LbProcess::start("grpc-failures", &config);
+    let mut client = Client::connect(&lb.addr);
+    // INTERNAL counts against the backend but isn't worth a retry
+    for _ in 0..2 {
+        let response = client.call("/pkg.S/Get", b"");
+        assert_eq!(response.grpc_status(), Some(INTERNAL));
+    }
+    assert_eq!(lb.metric("lb_grpc_retries_total"), 0);
+    assert_eq!(calls(&lb, "internal", 2), 2);
+    // and the two took it out
+    let response = client.call("/grpc.health.v1.Health/Check", &health_request(""));
+    assert_eq!(response.message(), [0x08, 2]);
+}
+
+// A HealthCheckRequest for `service`, a protobuf with the name as field 1.
+fn health_request(service: &str) -> Vec<u8> {
+    let mut request = vec![0x0a, service.len() as u8];
+    request.extend_from_slice(service.as_bytes());
+    request
+}
+
+#[test]
+fn health_check_is_answered_per_pool() {
+    let up = named("up");
+    let config = format!(
+        "backends = [{:?}]\n\
+         [pools.gone]\nbackends = [{:?}]\n\
+         [health]\nfailures = 1\ncooldown_ms = 60000\n\
+         [[routes]]\npath_prefix = \"/pkg.Gone/\"\npool = \"gone\"\n",
+        up.addr,
+        common::free_port()
+    );
+    let lb = LbProcess::start("grpc-health", &config);
+    let mut client = Client::connect(&lb.addr);
+    let check = "/grpc.health.v1.Health/Check";
+
+    // SERVING is 1, NOT_SERVING 2
+    for service in ["", "pkg.Up", "pkg.Gone"] {
+        let response = client.call(check, &health_request(service));
+        assert_eq!(response.grpc_status(), Some(OK));
+        assert_eq!(response.message(), [0x08, 1], "{}", service);
+    }
+    // nobody listens behind pkg.Gone, one failed connect takes it out
+    let response = client.call("/pkg.Gone/Get", b"");
+    assert_eq!(response.grpc_status(), Some(UNAVAILABLE));
+    let response = client.call(check, &health_request("pkg.Gone"));
+    assert_eq!(response.message(), [0x08, 2]);
+    let response = client.call(check, &health_request(""));
+    assert_eq!(response.message(), [0x08, 1]);
+    // none of it reached the backend
+    assert_eq!(up.calls.load(Ordering::SeqCst), 0);
+
+    // an empty request asks about the default pool too
+    let response = client.request(
+        &[
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", check),
+            (":authority", "lb"),
+            ("content-type", "application/grpc"),
+        ],
+        b"",
+    );
+    assert_eq!(response.message(), [0x08, 1]);
+}
+
+#[test]
+fn oversized_headers_end_the_connection() {
+    let backend = named("a");
+    let config = format!(
+        "backends = [{:?}]\n[limits]\nmax_header_size = 1024\nmax_headers = 10\n",
+        backend.addr
+    );
+    let lb = LbProcess::start("grpc-limits", &config);
+    let head = [
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", "/pkg.S/Get"),
+        (":authority", "lb"),
+    ];
+
+    // too many headers only cost the stream
+    let mut client = Client::connect(&lb.addr);
+    let mut many = head.to_vec();
+    many.extend([("x-a", "1"); 8]);
+    let response = client.request(&many, &message(b""));
+    assert_eq!(response.reset, Some(0xb));
+    assert_eq!(client.call("/pkg.S/Get", b"").message(), b"a");
+    assert_eq!(
+        lb.metric("lb_limit_violations_total{limit=\"header_count\"}"),
+        1
+    );
+
+    // a header block over the size can't be decoded any further
+    let big = "x".repeat(2000);
+    let mut fields = head.to_vec();
+    fields.push(("x-big", &big));
+    let response = client.request(&fields, &message(b""));
+    assert_eq!(response.goaway, Some(0xb));
+    assert_eq!(
+        lb.metric("lb_limit_violations_total{limit=\"header_size\"}"),
+        1
+    );
+    assert_eq!(backend.calls.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,277 @@
+/*
+ * The request limits against slow and malicious clients.
+ *
+ *   cargo test --test limits
+ *
+ * Every test starts the load balancer binary in front of an in-process
+ * backend and talks to it over a raw socket, the way an attacker would:
+ * heads that never end, headers sent a byte at a time, bodies bigger than
+ * announced, trickling in or framed two ways at once. Each one checks the
+ * status the client gets and, where a limit is involved, the
+ * lb_limit_violations_total counter.
+ */
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{LbProcess, read_response, start_backend};
+
+fn start(name: &str, limits: &str) -> LbProcess {
+    let config = format!(
+        "backends = [{:?}]\n\
+         [limits]\n{}\n\
+         [[routes]]\npath_prefix = \"/big\"\nmax_body_size = 100000\n",
+        start_backend(),
+        limits
+    );
+    LbProcess::start(name, &config)
+}
+
+fn violations(lb: &LbProcess, limit: &str) -> u64 {
+    lb.metric(&format!("lb_limit_violations_total{{limit=\"{}\"}}", limit))
+}
+
+// Sends `request` in one go and reads the answer.
+fn send(lb: &LbProcess, request: &[u8]) -> (u16, String) {
+    let mut stream = lb.connect();
+    // the server may close before it read everything, that's fine
+    let _ = stream.write_all(request);
+    read_response(&mut BufReader::new(stream))
+}
+
+// Writes `data` in pieces of `size` bytes with `pause` in between, until
+// the server stops reading or it is all sent.
+fn trickle(stream: &mut TcpStream, data: &[u8], size: usize, pause: Duration) {
+    for piece in data.chunks(size) {
+        if stream.write_all(piece).is_err() {
+            return;
+        }
+        thread::sleep(pause);
+    }
+}
+
+#[test]
+fn normal_requests_pass() {
+    let lb = start("normal", "max_body_size = 1000");
+    let (status, body) = send(&lb, b"GET / HTTP/1.1\r\nhost: a\r\n\r\n");
+    assert_eq!((status, body.as_str()), (200, "0"));
+
+    let request = format!(
+        "POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 1000\r\n\r\n{}",
+        "x".repeat(1000)
+    );
+    let (status, body) = send(&lb, request.as_bytes());
+    assert_eq!((status, body.as_str()), (200, "1000"));
+}
+
+#[test]
+fn long_request_line_is_refused() {
+    let lb = start("request-line", "max_request_line = 1024");
+    let request = format!("GET /{} HTTP/1.1\r\nhost: a\r\n\r\n", "a".repeat(2000));
+    assert_eq!(send(&lb, request.as_bytes()).0, 400);
+    assert_eq!(violations(&lb, "request_line"), 1);
+
+    // just at the limit is fine
+    let request = format!("GET /{} HTTP/1.1\r\nhost: a\r\n\r\n", "a".repeat(1024 - 14));
+    assert_eq!(send(&lb, request.as_bytes()).0, 200);
+}
+
+#[test]
+fn endless_request_line_is_cut_off() {
+    let lb = start("endless-line", "max_request_line = 1024");
+    let mut stream = lb.connect();
+    // a line with no end, far more than the limit
+    let mut sent = 0;
+    let chunk = vec![b'a'; 64 * 1024];
+    while sent < 64 * 1024 * 1024 && stream.write_all(&chunk).is_ok() {
+        sent += chunk.len();
+    }
+    assert!(sent < 64 * 1024 * 1024, "the server kept reading");
+    assert_eq!(violations(&lb, "request_line"), 1);
+}
+
+#[test]
+fn too_many_headers_are_refused() {
+    let lb = start("header-count", "max_headers = 20");
+    let mut request = String::from("GET / HTTP/1.1\r\nhost: a\r\n");
+    for i in 0..30 {
+        request.push_str(&format!("x-h{}: {}\r\n", i, i));
+    }
+    request.push_str("\r\n");
+    assert_eq!(send(&lb, request.as_bytes()).0, 400);
+    assert_eq!(violations(&lb, "header_count"), 1);
+}
+
+#[test]
+fn large_header_section_is_refused() {
+    let lb = start("header-size", "max_header_size = 4096");
+    let request = format!(
+        "GET / HTTP/1.1\r\nhost: a\r\nx-a: {}\r\nx-b: {}\r\n\r\n",
+        "a".repeat(2000),
+        "b".repeat(2500)
+    );
+    assert_eq!(send(&lb, request.as_bytes()).0, 400);
+    assert_eq!(violations(&lb, "header_size"), 1);
+}
+
+#[test]
+fn ambiguous_body_framing_is_refused() {
+    let lb = start("framing", "");
+    // where the body ends depends on which header the reader believes
+    let both = b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 5\r\n\
+                 transfer-encoding: chunked\r\n\r\n0\r\n\r\n";
+    assert_eq!(send(&lb, both).0, 400);
+    let conflicting = b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 3\r\n\
+                        content-length: 5\r\n\r\nabcde";
+    assert_eq!(send(&lb, conflicting).0, 400);
+    assert_eq!(
+        send(
+            &lb,
+            b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 3, 5\r\n\r\nabcde"
+        )
+        .0,
+        400
+    );
+
+    // repeating the same length is harmless
+    let repeated = b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 3\r\n\
+                     content-length: 3\r\n\r\nabc";
+    assert_eq!(send(&lb, repeated), (200, "3".to_string()));
+}
+
+#[test]
+fn refused_heads_get_the_error_pages() {
+    let page = env::temp_dir().join(format!("lb-test-{}-400.html", std::process::id()));
+    fs::write(&page, "<p>{{status}} {{message}}</p>").unwrap();
+    let config = format!(
+        "backends = [{:?}]\n\
+         [error_pages]\n400 = {:?}\n\
+         [limits]\nmax_headers = 5\n",
+        start_backend(),
+        page.to_str().unwrap()
+    );
+    let lb = LbProcess::start("head-error-page", &config);
+    let mut request = String::from("GET / HTTP/1.1\r\nhost: a\r\n");
+    for i in 0..10 {
+        request.push_str(&format!("x-h{}: {}\r\n", i, i));
+    }
+    request.push_str("\r\n");
+    let (status, body) = send(&lb, request.as_bytes());
+    let _ = fs::remove_file(&page);
+    assert_eq!((status, body.as_str()), (400, "<p>400 bad request</p>"));
+}
+
+#[test]
+fn declared_body_over_the_limit_gets_413() {
+    let lb = start("body-length", "max_body_size = 1000");
+    // refused on the announced length alone, before any body is sent
+    let (status, _) = send(
+        &lb,
+        b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 5000000\r\n\r\n",
+    );
+    assert_eq!(status, 413);
+    assert_eq!(violations(&lb, "body_size"), 1);
+
+    // the route allows more
+    let request = format!(
+        "POST /big HTTP/1.1\r\nhost: a\r\ncontent-length: 5000\r\n\r\n{}",
+        "x".repeat(5000)
+    );
+    assert_eq!(send(&lb, request.as_bytes()), (200, "5000".to_string()));
+}
+
+#[test]
+fn chunked_body_over_the_limit_gets_413() {
+    let lb = start("body-chunked", "max_body_size = 1000");
+    let mut request =
+        String::from("POST / HTTP/1.1\r\nhost: a\r\ntransfer-encoding: chunked\r\n\r\n");
+    for _ in 0..10 {
+        request.push_str(&format!("200\r\n{}\r\n", "x".repeat(0x200)));
+    }
+    request.push_str("0\r\n\r\n");
+    assert_eq!(send(&lb, request.as_bytes()).0, 413);
+    assert_eq!(violations(&lb, "body_size"), 1);
+}
+
+#[test]
+fn slowloris_head_gets_408() {
+    let lb = start("slowloris", "header_timeout_ms = 500");
+    let mut stream = lb.connect();
+    let started = Instant::now();
+    // a byte every 50ms would never hit the idle timeout
+    let head = format!(
+        "GET / HTTP/1.1\r\nhost: a\r\n{}\r\n",
+        "x-slow: 1\r\n".repeat(10)
+    );
+    let reader = stream.try_clone().unwrap();
+    let sender =
+        thread::spawn(move || trickle(&mut stream, head.as_bytes(), 1, Duration::from_millis(50)));
+    let (status, _) = read_response(&mut BufReader::new(reader));
+    assert_eq!(status, 408);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    sender.join().unwrap();
+    assert_eq!(violations(&lb, "header_timeout"), 1);
+}
+
+#[test]
+fn silent_keep_alive_connection_is_not_a_violation() {
+    let lb = start("quiet", "header_timeout_ms = 300");
+    let mut stream = lb.connect();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: a\r\n\r\n")
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    assert_eq!(read_response(&mut reader).0, 200);
+    // waiting between requests is up to the idle timeout, not this one
+    thread::sleep(Duration::from_millis(600));
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: a\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut reader).0, 200);
+    assert_eq!(violations(&lb, "header_timeout"), 0);
+}
+
+#[test]
+fn trickling_body_gets_408() {
+    let lb = start("slow-body", "min_body_rate = 1000\nmin_rate_grace_ms = 300");
+    let mut stream = lb.connect();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 10000\r\n\r\n")
+        .unwrap();
+    let reader = stream.try_clone().unwrap();
+    // 10 bytes every 50ms is 200 bytes a second
+    let body = vec![b'x'; 10000];
+    let sender = thread::spawn(move || trickle(&mut stream, &body, 10, Duration::from_millis(50)));
+    let started = Instant::now();
+    let (status, _) = read_response(&mut BufReader::new(reader));
+    assert_eq!(status, 408);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    sender.join().unwrap();
+    assert_eq!(violations(&lb, "min_rate"), 1);
+}
+
+#[test]
+fn body_at_the_minimum_rate_passes() {
+    let lb = start(
+        "steady-body",
+        "min_body_rate = 1000\nmin_rate_grace_ms = 300",
+    );
+    let mut stream = lb.connect();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nhost: a\r\ncontent-length: 3000\r\n\r\n")
+        .unwrap();
+    let reader = stream.try_clone().unwrap();
+    // 200 bytes every 50ms is 4000 bytes a second
+    let body = vec![b'x'; 3000];
+    trickle(&mut stream, &body, 200, Duration::from_millis(50));
+    assert_eq!(
+        read_response(&mut BufReader::new(reader)),
+        (200, "3000".to_string())
+    );
+    assert_eq!(violations(&lb, "min_rate"), 0);
+}
@@ -0,0 +1,68 @@
+/*
+ * Maintenance mode switched through the admin API.
+ *
+ *   cargo test --test maintenance
+ *
+ * The test client connects from 127.0.0.1, so one route lets it through
+ * its allow list and the other doesn't.
+ */
+mod common;
+
+use std::io::{BufReader, Read, Write};
+
+use load_balancer::http::{BodyReader, ResponseHead};
+
+use common::{LbProcess, start_backend};
+
+// Sends a GET for `path`, returns the response head and body.
+fn get(lb: &LbProcess, path: &str) -> (ResponseHead, String) {
+    let mut stream = lb.connect();
+    let request = format!("GET {} HTTP/1.1\r\nhost: a\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream);
+    let head = ResponseHead::read(&mut reader).unwrap();
+    let mut body = String::new();
+    BodyReader::new(&mut reader, head.body_kind("GET").unwrap())
+        .read_to_string(&mut body)
+        .unwrap();
+    (head, body)
+}
+
+#[test]
+fn maintenance_is_switched_through_the_admin_api() {
+    let config = format!(
+        "backends = [{:?}]\n\
+         [[routes]]\nname = \"shop\"\npath_prefix = \"/shop\"\n\
+         maintenance = {{ retry_after_secs = 120 }}\n\
+         [[routes]]\nname = \"office\"\npath_prefix = \"/office\"\n\
+         maintenance = {{ allow = [\"127.0.0.0/8\"] }}\n",
+        start_backend()
+    );
+    let lb = LbProcess::start("maintenance", &config);
+    assert_eq!(get(&lb, "/shop").0.status, 200);
+
+    let (status, body) = lb.admin_put("/routes/shop/maintenance?enabled=true");
+    assert_eq!((status, body.as_str()), (200, "maintenance on\n"));
+    let (head, body) = get(&lb, "/shop/cart");
+    assert_eq!(
+        (head.status, body.as_str()),
+        (503, "down for maintenance\n")
+    );
+    assert_eq!(head.headers.get("retry-after"), Some("120"));
+    assert_eq!(get(&lb, "/").0.status, 200);
+
+    // the allow list still gets through
+    assert_eq!(
+        lb.admin_put("/routes/office/maintenance?enabled=true").0,
+        200
+    );
+    assert_eq!(get(&lb, "/office").0.status, 200);
+
+    let (status, body) = lb.admin_put("/routes/shop/maintenance?enabled=false");
+    assert_eq!((status, body.as_str()), (200, "maintenance off\n"));
+    assert_eq!(get(&lb, "/shop/cart").0.status, 200);
+
+    assert_eq!(lb.admin_put("/routes/shop/maintenance?enabled=yes").0, 400);
+    assert_eq!(lb.admin_put("/routes/shop/maintenance").0, 400);
+    assert_eq!(lb.admin_put("/routes/nope/maintenance?enabled=true").0, 400);
+}
@@ -0,0 +1,177 @@
+/*
+ * Shadow traffic to a mirror pool.
+ *
+ *   cargo test --test mirror
+ *
+ * The shadow backend records every request it gets, the test compares them
+ * with what the client sent through the primary pool.
+ */
+mod common;
+
+use std::io::{BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use load_balancer::http::{BodyKind, BodyReader, HeadLimits, RequestHead};
+
+use common::{LbProcess, read_response, start_backend};
+
+// method, target and body of a request the shadow got
+type Seen = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+fn shadow_backend() -> (String, Seen) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let seen = Seen::default();
+    let recorded = seen.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let seen = recorded.clone();
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                while let Ok(Some(head)) = RequestHead::read(&mut reader, &HeadLimits::NONE) {
+                    let mut body = Vec::new();
+                    let kind = head.body_kind().unwrap_or(BodyKind::Empty);
+                    if BodyReader::new(&mut reader, kind)
+                        .read_to_end(&mut body)
+                        .is_err()
+                    {
+                        return;
+                    }
+                    seen.lock().unwrap().push((head.method, head.target, body));
+                    let response = b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\n\r\nshadow";
+                    if writer.write_all(response).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (addr, seen)
+}
+
+fn start(name: &str, mirror: &str) -> (LbProcess, Seen) {
+    let (shadow, seen) = shadow_backend();
+    let config = format!(
+        "backends = [{:?}]\n\
+         [pools.shadow]\nbackends = [{:?}]\n\
+         [[routes]]\npath_prefix = \"/\"\nmirror = {{ pool = \"shadow\", {} }}\n",
+        start_backend(),
+        shadow,
+        mirror
+    );
+    (LbProcess::start(name, &config), seen)
+}
+
+fn send(lb: &LbProcess, request: &str) -> (u16, String) {
+    let mut stream = lb.connect();
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut BufReader::new(stream))
+}
+
+fn post(path: &str, body: &str) -> String {
+    format!(
+        "POST {} HTTP/1.1\r\nhost: a\r\ncontent-length: {}\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+}
+
+// Waits until the shadow has everything that was mirrored. The copies are
+// sent in the background, so that can be a moment after the client's
+// answer.
+fn settle(lb: &LbProcess, seen: &Seen) {
+    let started = Instant::now();
+    let mut last = None;
+    loop {
+        let now = (
+            lb.metric("lb_mirror_requests_total"),
+            seen.lock().unwrap().len() as u64,
+        );
+        if now.0 == now.1 && last == Some(now) {
+            return;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", now);
+        last = Some(now);
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn the_shadow_gets_the_same_request() {
+    let (lb, seen) = start("mirror-copy", "percent = 100");
+    // the client only ever sees the primary's answer
+    assert_eq!(
+        send(&lb, &post("/orders?id=7", "hello shadow")),
+        (200, "12".to_string())
+    );
+    assert_eq!(
+        send(&lb, "GET /orders HTTP/1.1\r\nhost: a\r\n\r\n"),
+        (200, "0".to_string())
+    );
+    // a chunked body goes out with its length
+    let chunked = "PUT /items HTTP/1.1\r\nhost: a\r\ntransfer-encoding: chunked\r\n\r\n\
+                   3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+    assert_eq!(send(&lb, chunked), (200, "5".to_string()));
+    settle(&lb, &seen);
+
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort();
+    assert_eq!(
+        seen,
+        [
+            ("GET".to_string(), "/orders".to_string(), Vec::new()),
+            (
+                "POST".to_string(),
+                "/orders?id=7".to_string(),
+                b"hello shadow".to_vec()
+            ),
+            ("PUT".to_string(), "/items".to_string(), b"abcde".to_vec()),
+        ]
+    );
+    assert_eq!(lb.metric("lb_mirror_failures_total"), 0);
+}
+
+#[test]
+fn only_the_configured_share_is_mirrored() {
+    let (lb, seen) = start("mirror-share", "percent = 25");
+    for _ in 0..400 {
+        assert_eq!(send(&lb, &post("/", "x")).0, 200);
+    }
+    settle(&lb, &seen);
+    let mirrored = seen.lock().unwrap().len();
+    assert!(
+        (60..140).contains(&mirrored),
+        "{} of 400 mirrored",
+        mirrored
+    );
+    assert_eq!(lb.metric("lb_mirror_requests_total"), mirrored as u64);
+
+    let (lb, seen) = start("mirror-none", "percent = 0");
+    for _ in 0..50 {
+        assert_eq!(send(&lb, &post("/", "x")).0, 200);
+    }
+    assert_eq!(lb.metric("lb_mirror_requests_total"), 0);
+    assert!(seen.lock().unwrap().is_empty());
+}
+
+#[test]
+fn bodies_over_the_cap_are_not_mirrored() {
+    let (lb, seen) = start("mirror-cap", "percent = 100, max_body_bytes = 100");
+    let big = "x".repeat(101);
+    // the request itself goes through as usual
+    assert_eq!(send(&lb, &post("/big", &big)), (200, "101".to_string()));
+    assert_eq!(
+        send(&lb, &post("/small", &big[..100])),
+        (200, "100".to_string())
+    );
+    settle(&lb, &seen);
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].1, "/small");
+    assert_eq!(lb.metric("lb_mirror_skipped_total"), 1);
+}
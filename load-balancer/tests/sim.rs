@@ -0,0 +1,125 @@
+/*
+ * The built-in strategies in the simulator.
+ *
+ *   cargo test --test sim
+ *
+ * Each test runs a few thousand requests against simulated backends in
+ * virtual time, so none of them waits for anything. `--nocapture` prints
+ * the reports.
+ */
+use std::time::Duration;
+
+use load_balancer::sim::{Change, Latency, Report, SimBackend, Simulation};
+use load_balancer::strategy::{LeastConnections, Random, RoundRobin};
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+// three alike backends, each good for about 250 requests a second
+fn pool(seed: u64) -> Simulation {
+    let latency = Latency::Exponential(ms(20));
+    Simulation::new(seed)
+        .backend(SimBackend::new("a", latency).capacity(5))
+        .backend(SimBackend::new("b", latency).capacity(5))
+        .backend(SimBackend::new("c", latency).capacity(5))
+        .arrivals(450.0)
+        .requests(20_000)
+}
+
+fn show(name: &str, report: &Report) {
+    println!("{}\n{}", name, report);
+}
+
+#[test]
+fn same_seed_same_report() {
+    let run = |seed| {
+        pool(seed)
+            .backend(SimBackend::new("d", Latency::Exponential(ms(20))).failure_rate(0.1))
+            .run(&Random)
+    };
+    let (first, second, other) = (run(7), run(7), run(8));
+    assert_eq!(first.backends, second.backends);
+    assert_eq!(first.duration, second.duration);
+    assert_eq!(first.percentile(99.0), second.percentile(99.0));
+    assert_ne!(first.backends, other.backends);
+}
+
+#[test]
+fn round_robin_spreads_evenly() {
+    let report = pool(1).run(&RoundRobin::default());
+    show("round robin", &report);
+    for name in ["a", "b", "c"] {
+        assert!((report.share(name) - 1.0 / 3.0).abs() < 0.001);
+    }
+    assert_eq!(report.failure_rate(), 0.0);
+}
+
+#[test]
+fn least_connections_avoids_a_slow_backend() {
+    // one backend turns ten times slower a few seconds in
+    let slow = |sim: Simulation| {
+        sim.at(
+            Duration::from_secs(5),
+            "c",
+            Change::Latency(Latency::Exponential(ms(200))),
+        )
+    };
+    let round_robin = slow(pool(2)).run(&RoundRobin::default());
+    let least = slow(pool(2)).run(&LeastConnections::default());
+    show("round robin", &round_robin);
+    show("least connections", &least);
+
+    assert!(least.share("c") < round_robin.share("c") / 2.0);
+    assert!(least.percentile(99.0) * 5 < round_robin.percentile(99.0));
+    assert!(least.mean() < round_robin.mean());
+}
+
+#[test]
+fn health_takes_a_dead_backend_out() {
+    let sim = pool(3)
+        .health(3, Duration::from_secs(5))
+        .at(Duration::from_secs(10), "b", Change::Down)
+        .at(Duration::from_secs(30), "b", Change::Up);
+    let report = sim.run(&RoundRobin::default());
+    show("backend b down from 10s to 30s", &report);
+
+    let b = &report.backends[1];
+    // three refused connects to take it out, one more per cooldown
+    assert_eq!(b.marked_down, 4);
+    assert_eq!(b.failed, 3 + 3);
+    assert!(report.failure_rate() < 0.001);
+
+    // without health checks every third request fails while it is down
+    let report = pool(3)
+        .health(0, Duration::ZERO)
+        .at(Duration::from_secs(10), "b", Change::Down)
+        .at(Duration::from_secs(30), "b", Change::Up)
+        .run(&RoundRobin::default());
+    assert!((report.failure_rate() - 20.0 / report.duration.as_secs_f64() / 3.0).abs() < 0.02);
+}
+
+#[test]
+fn lost_capacity_shows_in_the_tail() {
+    let before = pool(4).run(&LeastConnections::default());
+    let after = pool(4)
+        .at(Duration::from_secs(10), "a", Change::Capacity(1))
+        .at(Duration::from_secs(10), "b", Change::Capacity(1))
+        .run(&LeastConnections::default());
+    show("full capacity", &before);
+    show("a and b down to one slot", &after);
+    assert!(after.percentile(99.0) > before.percentile(99.0) * 2);
+    assert!(after.share("c") > 0.5);
+}
+
+#[test]
+fn failure_rate_counts_backend_errors() {
+    let report = pool(5)
+        .at(Duration::ZERO, "a", Change::FailureRate(0.3))
+        .run(&RoundRobin::default());
+    let a = &report.backends[0];
+    assert!((a.failed as f64 / a.requests as f64 - 0.3).abs() < 0.03);
+    assert!((report.failure_rate() - 0.1).abs() < 0.01);
+    // errors after the request was sent don't make health take it out
+    assert_eq!(a.marked_down, 0);
+}
@@ -0,0 +1,162 @@
+/*
+ * The backend timeouts, each with its own outcome.
+ *
+ *   cargo test --test timeouts
+ *
+ * The backends here misbehave on purpose: one never accepts, one reads the
+ * request and says nothing, one stops halfway through its body. Each test
+ * checks what the client gets and the reason counted in
+ * lb_requests_terminated_total.
+ */
+mod common;
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Socket, Type};
+
+use common::{LbProcess, read_response};
+
+fn start(name: &str, backend: &str, timeouts: &str) -> LbProcess {
+    let config = format!("backends = [{:?}]\n[timeouts]\n{}\n", backend, timeouts);
+    LbProcess::start(name, &config)
+}
+
+// The requests that ended for `reason`. They are counted once the client
+// has its answer, so this waits a little for the count to catch up.
+fn terminated(lb: &LbProcess, reason: &str, expected: u64) -> u64 {
+    let name = format!("lb_requests_terminated_total{{reason=\"{}\"}}", reason);
+    let started = Instant::now();
+    loop {
+        let count = lb.metric(&name);
+        if count == expected || started.elapsed() > Duration::from_secs(2) {
+            return count;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn get(lb: &LbProcess) -> TcpStream {
+    let mut stream = lb.connect();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nhost: a\r\n\r\n")
+        .unwrap();
+    stream
+}
+
+// A backend that reads each request head, writes `response` and then holds
+// the connection open without another word.
+fn stalling_backend(response: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let _ = (&stream).write_all(response);
+                thread::sleep(Duration::from_secs(10));
+            });
+        }
+    });
+    addr
+}
+
+// An address that takes no more connections: its accept queue is full, so
+// new SYNs are dropped and a connect waits until it times out.
+// Drop the listener and the queued connections once done with it.
+fn unresponsive_backend() -> (String, Socket, Vec<TcpStream>) {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    socket.bind(&any.into()).unwrap();
+    socket.listen(0).unwrap();
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+    let mut queued = Vec::new();
+    loop {
+        match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+            Ok(stream) => queued.push(stream),
+            Err(e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => panic!("unexpected connect error: {}", e),
+        }
+        assert!(queued.len() < 1000, "the accept queue never filled");
+    }
+    (addr.to_string(), socket, queued)
+}
+
+#[test]
+fn connect_timeout_gets_502() {
+    let (backend, _listener, _queued) = unresponsive_backend();
+    let lb = start("connect-timeout", &backend, "connect_ms = 300");
+    let started = Instant::now();
+    let (status, _) = read_response(&mut BufReader::new(get(&lb)));
+    assert_eq!(status, 502);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(terminated(&lb, "connect_timeout", 1), 1);
+    assert_eq!(terminated(&lb, "connect_failed", 0), 0);
+}
+
+#[test]
+fn refused_connect_gets_502() {
+    let lb = start("connect-refused", &common::free_port(), "connect_ms = 300");
+    assert_eq!(read_response(&mut BufReader::new(get(&lb))).0, 502);
+    assert_eq!(terminated(&lb, "connect_failed", 1), 1);
+    assert_eq!(terminated(&lb, "connect_timeout", 0), 0);
+}
+
+#[test]
+fn silent_backend_gets_504_after_the_first_byte_timeout() {
+    let backend = stalling_backend(b"");
+    let lb = start(
+        "first-byte",
+        &backend,
+        "first_byte_ms = 300\nidle_ms = 5000",
+    );
+    let started = Instant::now();
+    let (status, _) = read_response(&mut BufReader::new(get(&lb)));
+    assert_eq!(status, 504);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(terminated(&lb, "first_byte_timeout", 1), 1);
+    assert_eq!(terminated(&lb, "total_timeout", 0), 0);
+}
+
+#[test]
+fn total_timeout_gets_504_before_the_response_starts() {
+    let backend = stalling_backend(b"");
+    let lb = start(
+        "total",
+        &backend,
+        "first_byte_ms = 5000\nidle_ms = 5000\ntotal_ms = 300",
+    );
+    let started = Instant::now();
+    let (status, _) = read_response(&mut BufReader::new(get(&lb)));
+    assert_eq!(status, 504);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(terminated(&lb, "total_timeout", 1), 1);
+    assert_eq!(terminated(&lb, "first_byte_timeout", 0), 0);
+}
+
+#[test]
+fn backend_idle_timeout_closes_the_connection() {
+    // ten bytes announced, four sent
+    let backend = stalling_backend(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhalf");
+    let lb = start(
+        "backend-idle",
+        &backend,
+        "first_byte_ms = 5000\nidle_ms = 300",
+    );
+    let mut reader = BufReader::new(get(&lb));
+    let head = load_balancer::http::ResponseHead::read(&mut reader).unwrap();
+    assert_eq!(head.status, 200);
+    // what the backend sent, then the end of the connection
+    let started = Instant::now();
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).unwrap();
+    assert_eq!(body, b"half");
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(terminated(&lb, "backend_idle_timeout", 1), 1);
+}
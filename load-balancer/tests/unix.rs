@@ -0,0 +1,100 @@
+/*
+ * Unix sockets, on the listener and towards the backends.
+ *
+ *   cargo test --test unix
+ *
+ * The load balancer listens on a socket file and proxies to a backend
+ * listening on another one. Clients on a socket have no address, so the
+ * backend sees unix_client_ip in X-Forwarded-For.
+ */
+#![cfg(unix)]
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+
+use load_balancer::http::{BodyReader, HeadLimits, RequestHead};
+
+use common::{LbProcess, read_response};
+
+fn socket_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("lb-test-{}-{}.sock", std::process::id(), name))
+}
+
+// A backend on a socket file that answers with the request's
+// X-Forwarded-For and body.
+fn start_unix_backend(path: &PathBuf) {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                while let Ok(Some(head)) = RequestHead::read(&mut reader, &HeadLimits::NONE) {
+                    let mut body = String::new();
+                    let kind = head.body_kind().unwrap();
+                    if BodyReader::new(&mut reader, kind)
+                        .read_to_string(&mut body)
+                        .is_err()
+                    {
+                        return;
+                    }
+                    let text = format!(
+                        "{} {}",
+                        head.headers.get("x-forwarded-for").unwrap_or("-"),
+                        body
+                    );
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
+                        text.len(),
+                        text
+                    );
+                    if writer.write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn requests_are_proxied_from_a_socket_to_a_socket() {
+    let (listen, backend) = (socket_path("listen"), socket_path("backend"));
+    start_unix_backend(&backend);
+    // a socket file nobody listens on any more is replaced
+    drop(UnixListener::bind(&listen).unwrap());
+    assert!(listen.exists());
+
+    let config = format!(
+        "unix_client_ip = \"10.9.8.7\"\nbackends = [\"unix:{}\"]\n",
+        backend.display()
+    );
+    let lb = LbProcess::start_on("unix", &format!("unix:{}", listen.display()), &config);
+    let stream = UnixStream::connect(&listen).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    for body in ["first", "second"] {
+        let request = format!(
+            "POST / HTTP/1.1\r\nhost: a\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        writer.write_all(request.as_bytes()).unwrap();
+        assert_eq!(
+            read_response(&mut reader),
+            (200, format!("10.9.8.7 {}", body))
+        );
+    }
+    assert_eq!(lb.metric("lb_upstream_pool_hits_total"), 1);
+
+    drop(lb);
+    let _ = fs::remove_file(&listen);
+    let _ = fs::remove_file(&backend);
+}